@@ -0,0 +1,68 @@
+//! Print the location and the status exported by `automattermostatus` on D-Bus
+//! (`dbus_properties`), once and at each change.
+//!
+//! Each line (e.g. `office · :desk: Working at the office (paused)`) may be
+//! shown as is by a GNOME Shell extension or a KDE plasmoid running a command,
+//! or by a status bar such as waybar or polybar.
+//!
+//! Run with `cargo run --example dbus_widget --features dbus`.
+use std::collections::HashMap;
+use zbus::blocking::fdo::PropertiesProxy;
+use zbus::blocking::Connection;
+use zbus::names::InterfaceName;
+use zbus::zvariant::OwnedValue;
+
+use lib::dbus::{BUS_NAME, OBJECT_PATH};
+
+/// Return the line describing `properties`
+fn line(properties: &HashMap<String, OwnedValue>) -> String {
+    let text = |name: &str| {
+        properties
+            .get(name)
+            .and_then(|value| <&str>::try_from(value).ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let paused = properties
+        .get("Paused")
+        .and_then(|value| bool::try_from(value).ok())
+        .unwrap_or_default();
+    let mut res = match text("Location").as_str() {
+        "" => "away".to_string(),
+        location => location.to_string(),
+    };
+    if !text("StatusText").is_empty() {
+        res.push_str(&format!(" · :{}: {}", text("StatusEmoji"), text("StatusText")));
+    }
+    if paused {
+        res.push_str(" (paused)");
+    }
+    res
+}
+
+fn main() -> zbus::Result<()> {
+    let connection = Connection::session()?;
+    let proxy = PropertiesProxy::builder(&connection)
+        .destination(BUS_NAME)?
+        .path(OBJECT_PATH)?
+        .build()?;
+    let interface = InterfaceName::try_from(BUS_NAME)?;
+    let mut properties = proxy.get_all(interface.clone())?;
+    let mut shown = line(&properties);
+    println!("{}", shown);
+    for signal in proxy.receive_properties_changed()? {
+        let args = signal.args()?;
+        if args.interface_name() != &interface {
+            continue;
+        }
+        for (name, value) in args.changed_properties() {
+            properties.insert(name.to_string(), value.into());
+        }
+        // Related properties (e.g. `Paused` and `PausedUntil`) change together
+        if line(&properties) != shown {
+            shown = line(&properties);
+            println!("{}", shown);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! Presence set to *away* after some time at a location (`away_after`)
+//!
+//! Mattermost only sets the presence to *away* after a period without activity
+//! in its clients, which never happens at home when a client stays open. With
+//! `away_after`, the presence is set to *away* once at a given location for
+//! long enough, the custom status being left as is, and set back to *online*
+//! when leaving it.
+use std::collections::HashMap;
+use tracing::{debug, error, info};
+
+use crate::mattermost::{LoggedSession, MMStatus, Status};
+use crate::state::Location;
+
+/// Time spent at the current location, telling when to change the presence
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AwayTimer {
+    /// current location, and the UTC timestamp of its detection
+    arrival: Option<(Location, i64)>,
+    /// the presence has been set to away, as told by [`AwayTimer::presence_set`]
+    away: bool,
+}
+
+impl AwayTimer {
+    /// Create a timer waiting for a location
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `location` is detected at the UTC timestamp `now`, and return
+    /// the presence to set according to the minutes of `away_after` per wifi
+    /// substring, if it shall change. It is returned again until told to be set
+    /// with [`AwayTimer::presence_set`].
+    /// ```
+    /// use lib::away::AwayTimer;
+    /// use lib::{Location, Status};
+    /// let away_after = [("home".to_string(), 30)].into_iter().collect();
+    /// let home = Location::Known("home".to_string());
+    /// let mut timer = AwayTimer::new();
+    /// assert_eq!(timer.update(&home, 0, &away_after), None);
+    /// assert_eq!(timer.update(&home, 30 * 60, &away_after), Some(Status::Away));
+    /// assert_eq!(timer.update(&home, 35 * 60, &away_after), Some(Status::Away));
+    /// timer.presence_set(Status::Away);
+    /// assert_eq!(timer.update(&home, 40 * 60, &away_after), None);
+    /// assert_eq!(timer.update(&Location::Unknown, 50 * 60, &away_after), Some(Status::Online));
+    /// ```
+    pub fn update(
+        &mut self,
+        location: &Location,
+        now: i64,
+        away_after: &HashMap<String, u32>,
+    ) -> Option<Status> {
+        let since = match &self.arrival {
+            Some((current, since)) if current == location => *since,
+            _ => {
+                self.arrival = Some((location.clone(), now));
+                now
+            }
+        };
+        let due = match location {
+            Location::Known(wifi) => away_after
+                .get(wifi)
+                .is_some_and(|minutes| now - since >= i64::from(*minutes) * 60),
+            Location::Unknown => false,
+        };
+        match (self.away, due) {
+            (false, true) => Some(Status::Away),
+            (true, false) => Some(Status::Online),
+            _ => None,
+        }
+    }
+
+    /// Record that the presence returned by [`AwayTimer::update`] has been set
+    pub fn presence_set(&mut self, status: Status) {
+        self.away = status == Status::Away;
+    }
+}
+
+/// Set the presence to `status`, only going from *online* to *away* and back,
+/// so that *do not disturb* and the changes made by hand are kept. Return
+/// `false` if it is to be tried again, the presence being unknown or not set.
+pub fn set_presence(status: Status, session: &mut LoggedSession) -> bool {
+    let from = match status {
+        Status::Away => Status::Online,
+        _ => Status::Away,
+    };
+    match MMStatus::fetch(session) {
+        Ok(current) if current.status == from => {
+            info!("Setting presence to {:?}", status);
+            match MMStatus::new(status, session.user_id.clone()).send(session) {
+                Ok(_) => true,
+                Err(e) => {
+                    error!("Fail to set presence {:?} : {}", status, e);
+                    false
+                }
+            }
+        }
+        Ok(current) => {
+            debug!("Presence {:?} left as is", current.status);
+            true
+        }
+        Err(e) => {
+            debug!("Unable to get current presence: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn only_time_the_configured_locations() {
+        let away_after = [("".to_string(), 0)].into_iter().collect();
+        let mut timer = AwayTimer::new();
+        let office = Location::Known("office".to_string());
+        assert_eq!(timer.update(&office, 0, &away_after), None);
+        assert_eq!(timer.update(&office, 3600, &away_after), None);
+        // Off time is the empty wifi substring
+        let off = Location::Known("".to_string());
+        assert_eq!(timer.update(&off, 7200, &away_after), Some(Status::Away));
+        timer.presence_set(Status::Away);
+        assert_eq!(
+            timer.update(&office, 7300, &away_after),
+            Some(Status::Online)
+        );
+        timer.presence_set(Status::Online);
+        assert_eq!(timer.update(&office, 9000, &away_after), None);
+    }
+
+    #[test]
+    fn retry_presences_not_set() {
+        let away_after = [("home".to_string(), 30)].into_iter().collect();
+        let mut timer = AwayTimer::new();
+        let home = Location::Known("home".to_string());
+        assert_eq!(timer.update(&home, 0, &away_after), None);
+        // Not set while paused
+        assert_eq!(timer.update(&home, 1800, &away_after), Some(Status::Away));
+        assert_eq!(timer.update(&home, 1900, &away_after), Some(Status::Away));
+        timer.presence_set(Status::Away);
+        // Leaving while the server is down
+        let office = Location::Known("office".to_string());
+        assert_eq!(
+            timer.update(&office, 2000, &away_after),
+            Some(Status::Online)
+        );
+        assert_eq!(
+            timer.update(&office, 2100, &away_after),
+            Some(Status::Online)
+        );
+        timer.presence_set(Status::Online);
+        assert_eq!(timer.update(&office, 2200, &away_after), None);
+    }
+}
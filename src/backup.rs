@@ -0,0 +1,228 @@
+//! Backup of the configuration, state and history (`backup`, `restore` and
+//! `reset` subcommands)
+//!
+//! A backup is a single JSON file bundling the configuration file, the state
+//! file and the location history, so that they may be moved to another machine.
+//! The secret written in the configuration file ([`SECRET_KEYS`]) is left out:
+//! it shall be given again on the new machine. Restoring or resetting never
+//! deletes a file: the replaced ones are kept aside as `<file>.bak`.
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::Document;
+use tracing::info;
+
+/// Version of the layout of the backup file
+pub const BACKUP_VERSION: u32 = 1;
+
+/// Keys of the configuration file holding a secret, left out of the backups
+pub const SECRET_KEYS: &[&str] = &["mm_secret"];
+
+/// Content of a backup file, each file being `None` if it did not exist
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+    /// layout of the backup
+    pub version: u32,
+    /// configuration file, without its secret
+    pub config: Option<String>,
+    /// state file
+    pub state: Option<String>,
+    /// location history
+    pub history: Option<String>,
+}
+
+/// Files saved in a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Files {
+    /// configuration file
+    pub config: PathBuf,
+    /// state file
+    pub state: PathBuf,
+    /// location history
+    pub history: PathBuf,
+}
+
+/// Return the configuration `content` without the [`SECRET_KEYS`]
+/// ```
+/// use lib::backup::strip_secrets;
+/// let content = strip_secrets("# my token\nmm_secret = 'xxx'\nmm_user = 'me'\n").unwrap();
+/// assert_eq!(content, "mm_user = 'me'\n");
+/// ```
+pub fn strip_secrets(content: &str) -> Result<String> {
+    let mut document: Document = content.parse().context("Parsing configuration")?;
+    for key in SECRET_KEYS {
+        document.remove(key);
+    }
+    Ok(document.to_string())
+}
+
+/// Read `path`, returning `None` if it does not exist
+fn read(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading {:?}", path)),
+    }
+}
+
+/// Return the path keeping the previous content of `path`
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut res = path.as_os_str().to_owned();
+    res.push(".bak");
+    res.into()
+}
+
+/// Move `path` aside, returning where it was moved if it existed
+fn move_aside(path: &Path) -> Result<Option<PathBuf>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let target = backup_path(path);
+    fs::rename(path, &target).with_context(|| format!("Moving {:?} to {:?}", path, target))?;
+    Ok(Some(target))
+}
+
+impl Bundle {
+    /// Bundle the existing `files`
+    pub fn create(files: &Files) -> Result<Self> {
+        Ok(Self {
+            version: BACKUP_VERSION,
+            config: read(&files.config)?
+                .map(|content| strip_secrets(&content))
+                .transpose()?,
+            state: read(&files.state)?,
+            history: read(&files.history)?,
+        })
+    }
+
+    /// Read the backup file at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Reading backup {:?}", path))?;
+        let res: Self =
+            serde_json::from_str(&content).with_context(|| format!("Parsing backup {:?}", path))?;
+        if res.version > BACKUP_VERSION {
+            bail!(
+                "Backup {:?} has version {}, newer than the supported {}",
+                path,
+                res.version,
+                BACKUP_VERSION
+            );
+        }
+        Ok(res)
+    }
+
+    /// Write the backup file at `path`, readable by its owner only as the
+    /// configuration may hold personal data
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("Writing backup {:?}", path))?;
+        std::io::Write::write_all(&mut file, content.as_bytes())
+            .with_context(|| format!("Writing backup {:?}", path))
+    }
+
+    /// Write the bundled files to `files`, moving the existing ones aside, and
+    /// return the paths of the files written
+    pub fn restore(&self, files: &Files) -> Result<Vec<PathBuf>> {
+        let mut res = Vec::new();
+        for (content, path) in [
+            (&self.config, &files.config),
+            (&self.state, &files.state),
+            (&self.history, &files.history),
+        ] {
+            let Some(content) = content else {
+                continue;
+            };
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).with_context(|| format!("Creating dir {:?}", dir))?;
+            }
+            if let Some(previous) = move_aside(path)? {
+                info!("Previous {:?} kept in {:?}", path, previous);
+            }
+            fs::write(path, content).with_context(|| format!("Writing {:?}", path))?;
+            res.push(path.clone());
+        }
+        Ok(res)
+    }
+}
+
+/// Move the state file aside, and the location history too if `history` is
+/// set, returning where they were moved
+///
+/// The status update loop shall be stopped beforehand, as it would write its
+/// state again.
+pub fn reset(files: &Files, history: bool) -> Result<Vec<PathBuf>> {
+    let mut res = Vec::new();
+    res.extend(move_aside(&files.state)?);
+    if history {
+        res.extend(move_aside(&files.history)?);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    fn files(dir: &Path) -> Files {
+        Files {
+            config: dir.join("conf/automattermostatus.toml"),
+            state: dir.join("state/automattermostatus.state"),
+            history: dir.join("state/automattermostatus.history"),
+        }
+    }
+
+    #[test]
+    fn move_the_files_to_another_machine() -> Result<()> {
+        let old = Temp::new_dir()?;
+        let old_files = files(&old);
+        fs::create_dir_all(old.join("conf"))?;
+        fs::create_dir_all(old.join("state"))?;
+        fs::write(&old_files.config, "mm_secret = 'xxx'\nmm_user = 'me'\n")?;
+        fs::write(&old_files.state, "{}")?;
+        let path = old.join("backup.json");
+        Bundle::create(&old_files)?.save(&path)?;
+        assert!(!fs::read_to_string(&path)?.contains("xxx"));
+
+        let new = Temp::new_dir()?;
+        let new_files = files(&new);
+        fs::create_dir_all(new.join("conf"))?;
+        fs::write(&new_files.config, "mm_user = 'default'\n")?;
+        let written = Bundle::load(&path)?.restore(&new_files)?;
+        assert_eq!(
+            written,
+            vec![new_files.config.clone(), new_files.state.clone()]
+        );
+        assert_eq!(fs::read_to_string(&new_files.config)?, "mm_user = 'me'\n");
+        assert_eq!(
+            fs::read_to_string(backup_path(&new_files.config))?,
+            "mm_user = 'default'\n"
+        );
+        assert!(!new_files.history.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn keep_the_reset_state_aside() -> Result<()> {
+        let dir = Temp::new_dir()?;
+        let files = files(&dir);
+        fs::create_dir_all(dir.join("state"))?;
+        fs::write(&files.state, "{}")?;
+        fs::write(&files.history, "entry\n")?;
+        assert_eq!(reset(&files, false)?, vec![backup_path(&files.state)]);
+        assert!(!files.state.exists());
+        assert!(files.history.exists());
+        // Nothing left to reset
+        assert!(reset(&files, false)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Source of the current time
+//!
+//! Scheduling, expiry and state code ask a [`Clock`] for the current time instead
+//! of calling `Local::now` directly, so that tests may choose the time.
+use chrono::{DateTime, Duration, Local};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+#[cfg(test)]
+use mockall::automock;
+
+/// Trait providing the current time
+#[cfg_attr(test, automock)] // create MockClock Struct for tests
+pub trait Clock {
+    /// Returns current local time
+    fn now(&self) -> DateTime<Local>;
+}
+
+/// The clock of the system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// A clock stopped at a given time
+impl Clock for DateTime<Local> {
+    fn now(&self) -> DateTime<Local> {
+        *self
+    }
+}
+
+/// A clock which only moves when told to
+/// ```
+/// use lib::clock::{Clock, ManualClock};
+/// use chrono::{Duration, Local, TimeZone};
+/// let clock = ManualClock::new(Local.with_ymd_and_hms(2024, 1, 15, 8, 0, 0).unwrap());
+/// clock.advance(Duration::hours(2));
+/// assert_eq!(clock.now().format("%H:%M").to_string(), "10:00");
+/// ```
+#[derive(Debug)]
+pub struct ManualClock(Mutex<DateTime<Local>>);
+
+impl ManualClock {
+    /// Create a clock stopped at `now`
+    pub fn new(now: DateTime<Local>) -> Self {
+        ManualClock(Mutex::new(now))
+    }
+
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().expect("clock lock") += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.0.lock().expect("clock lock")
+    }
+}
+
+/// A [`Clock`] shared between its users, defaulting to [`SystemClock`]
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock + Send + Sync>);
+
+impl SharedClock {
+    /// Share `clock`
+    pub fn new(clock: impl Clock + Send + Sync + 'static) -> Self {
+        SharedClock(Arc::new(clock))
+    }
+}
+
+impl<C: Clock + Send + Sync + 'static> From<Arc<C>> for SharedClock {
+    fn from(clock: Arc<C>) -> Self {
+        SharedClock(clock)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock::new(SystemClock)
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedClock({})", self.0.now())
+    }
+}
+
+impl Clock for SharedClock {
+    fn now(&self) -> DateTime<Local> {
+        self.0.now()
+    }
+}
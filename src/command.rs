@@ -0,0 +1,58 @@
+//! Abstraction over the spawning of platform commands (`nmcli`, `netsh`, `ioreg`, …)
+//!
+//! Platform specific code gets the output of the commands through a
+//! [`CommandRunner`], so that tests can drive it with a `MockCommandRunner`
+//! on any platform.
+use std::io;
+use std::process::{Command, Output, Stdio};
+
+#[cfg(test)]
+use mockall::automock;
+
+/// Run a command and return its standard output
+#[cfg_attr(test, automock)] // create MockCommandRunner struct for tests
+pub trait CommandRunner: Send + Sync {
+    /// Run `program` with `args` and return its standard output (lossily
+    /// converted to UTF-8)
+    #[allow(clippy::needless_lifetimes)] // named lifetime required by automock
+    fn run<'a>(&self, program: &str, args: &[&'a str]) -> io::Result<String>;
+
+    /// Run `program` with `args` and the environment variables `envs`, and
+    /// return its exit status and outputs. The standard error is not captured
+    /// and the standard input is inherited if `interactive` is set, so that the
+    /// program may prompt the user.
+    #[allow(clippy::needless_lifetimes)] // named lifetime required by automock
+    fn output<'a>(
+        &self,
+        program: &str,
+        args: &[&'a str],
+        envs: &[(&'a str, &'a str)],
+        interactive: bool,
+    ) -> io::Result<Output>;
+}
+
+/// [`CommandRunner`] spawning the commands on the host system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<String> {
+        let output = Command::new(program).args(args).output()?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn output(
+        &self,
+        program: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+        interactive: bool,
+    ) -> io::Result<Output> {
+        let mut command = Command::new(program);
+        command.args(args).envs(envs.iter().copied());
+        if interactive {
+            command.stdin(Stdio::inherit()).stderr(Stdio::inherit());
+        }
+        command.output()
+    }
+}
@@ -1,35 +1,103 @@
 #![allow(missing_docs)]
 //! This module holds struct and helpers for parameters and configuration
 //!
-use crate::offtime::{Off, OffDays};
-use crate::utils::parse_from_hmstr;
-use ::structopt::clap::AppSettings;
-use anyhow::{bail, Context, Result};
-use chrono::Local;
+use crate::clock::Clock;
+use crate::error::{Error, Result};
+use crate::i18n;
+use crate::mattermost::client::ClientOptions;
+use crate::mattermost::MMCustomStatus;
+use crate::meeting::MeetingApp;
+use crate::offtime::{FocusBlock, Off, OffDay, OffDays, Schedule};
+use crate::secret::{self, vault::VaultSecret, SecretSource};
+use crate::timeofday::{Expiry, TimeOfDay};
+use crate::wifiscan::{ScanCommand, WiFi, WifiInterface};
+use crate::{import, SystemRunner};
+use anyhow::{anyhow, Context};
+use chrono::NaiveDate;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use derivative::Derivative;
 use directories_next::ProjectDirs;
 use figment::{
-    providers::{Format, Serialized, Toml},
+    providers::{Env, Format, Serialized, Toml},
     Figment,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
-use structopt;
-use structopt::clap::arg_enum;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 use tracing::{debug, info, warn};
 
-arg_enum! {
 /// Enum used to encode `secret_type` parameter (password or token)
 ///
 /// When set to [Password], the secret is used to obtain a session token
 /// by using the login API. When set to [Token], the secret is a private access
 /// token directly usable to access API.
-#[derive(Serialize, Deserialize,Debug)]
+#[derive(ValueEnum, Serialize, Deserialize, JsonSchema, Debug, Clone, Copy)]
 pub enum SecretType {
     Token,
     Password,
 }
+
+/// Whether a scan of wifi networks shall be triggered before getting the visible SSIDs.
+///
+/// Active scans disrupt latency sensitive traffic like video calls, so by default ([No])
+/// we rely on the results cached by the OS. [Auto] lets the OS decide to rescan if its
+/// results are too old, and [Yes] always triggers a scan.
+///
+/// Only used on linux (NetworkManager), as `netsh` on windows always returns cached
+/// results and `airport` on Mac OS always scans.
+#[derive(ValueEnum, Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RescanPolicy {
+    Auto,
+    Yes,
+    No,
+}
+
+/// Parser used for the output of `wifi_scan_cmd`.
+///
+/// [Lines] expects one SSID per line, the other ones parse the output of the
+/// corresponding built-in backend command.
+#[derive(ValueEnum, Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanParser {
+    Lines,
+    Nmcli,
+    Netsh,
+    Airport,
+}
+
+/// What to do with several `status` triplets sharing the same wifi substring.
+///
+/// [Warn] (default) keeps the last one with a warning, [Error] refuses to start,
+/// [Rotate] keeps them all and uses them in turn, a different one each day.
+#[derive(ValueEnum, Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    Warn,
+    Error,
+    Rotate,
+}
+
+/// Format of the output of `status show`, `stats` and `doctor`
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// human readable text
+    #[default]
+    Text,
+    /// JSON, for scripts
+    Json,
+}
+
+/// Subsystems writing presence and custom status, ranked by the `priority` parameter
+/// (see [`crate::priority`]).
+#[derive(ValueEnum, Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusSource {
+    Manual,
+    Mic,
+    Focus,
+    Location,
+    #[value(alias = "offtime")]
+    OffTime,
 }
 
 /// Status that shall be send when a wifi with `wifi_string` is being seen.
@@ -55,14 +123,14 @@ pub struct WifiStatusConfig {
 ///                     text: "Working home".to_owned() });
 /// ```
 impl std::str::FromStr for WifiStatusConfig {
-    type Err = anyhow::Error;
+    type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let splitted: Vec<&str> = s.split("::").collect();
         if splitted.len() != 3 {
-            bail!(
+            return Err(Error::Config(anyhow!(
                 "Expect status argument to contain two and only two :: separator (in '{}')",
                 &s
-            );
+            )));
         }
         Ok(WifiStatusConfig {
             wifi_string: splitted[0].to_owned(),
@@ -72,18 +140,251 @@ impl std::str::FromStr for WifiStatusConfig {
     }
 }
 
+/// Return the path of the configuration file
+pub fn conf_file_path() -> PathBuf {
+    ProjectDirs::from("net", "ams", "automattermostatus")
+        .expect("Unable to find a project dir")
+        .config_dir()
+        .join("automattermostatus.toml")
+}
+
+/// Return the JSON Schema of the configuration file, derived from [`Args`]
+pub fn schema() -> serde_json::Result<String> {
+    serde_json::to_string_pretty(&schemars::schema_for!(Args))
+}
+
+/// Return the keys of the `content` configuration file which are not known
+/// configuration keys
+/// ```
+/// use lib::config::unknown_keys;
+/// assert_eq!(unknown_keys("delay = 3\nmm_ulr = 'x'\n").unwrap(), vec!["mm_ulr"]);
+/// ```
+pub fn unknown_keys(content: &str) -> Result<Vec<String>> {
+    let table: toml::value::Table = toml::from_str(content)
+        .context("Parsing configuration")
+        .map_err(Error::Config)?;
+    let schema = schemars::schema_for!(Args);
+    let known = schema
+        .schema
+        .object
+        .map(|o| o.properties)
+        .unwrap_or_default();
+    Ok(table
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| !known.contains_key(key))
+        .collect())
+}
+
+/// Prefix of the environment variables setting configuration keys, as in
+/// `AMS_DELAY=30` for `delay`
+pub const ENV_PREFIX: &str = "AMS_";
+
+/// Separator of the status triplets given by the `AMS_STATUS` environment variable
+pub const STATUS_ENV_SEPARATOR: char = ';';
+
+/// Subcommands, the default being to run the status update loop
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum SubCommand {
+    /// Run the status update loop (the default), or a single cycle
+    Run {
+        /// perform exactly one detection and update cycle, then exit with `0` on
+        /// success, `2` if no known location was found, `3` if mattermost could
+        /// not be reached and `6` if the authentication failed
+        #[arg(long, conflicts_with = "daemon")]
+        once: bool,
+        /// update the status every `delay` seconds until terminated (the default)
+        #[arg(long)]
+        daemon: bool,
+    },
+    /// Print status triplets for the wifi networks saved by the OS
+    ///
+    /// The printed `status` entry holds the configured triplets followed by a
+    /// triplet for each saved network not already matched. Edit it and copy it in
+    /// the configuration file.
+    ImportNetworks,
+    /// Print the time spent per location during the current week
+    Stats {
+        /// number of previous weeks to print too
+        #[arg(long, default_value_t = 0)]
+        weeks: u32,
+    },
+    /// Set or clear the custom status once, then exit
+    #[command(subcommand)]
+    Status(StatusCommand),
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Pin the location whatever the visible networks, or unpin it
+    #[command(subcommand)]
+    Location(LocationCommand),
+    /// Start or stop a focus session (pomodoro) of the status update loop
+    #[command(subcommand)]
+    Focus(FocusCommand),
+    /// Leave the custom status and presence alone for `duration`
+    ///
+    /// The running status update loop behaves as after a change made by hand.
+    Pause {
+        /// pause duration, such as `30m` or `1h`
+        #[arg(value_parser = crate::utils::parse_duration)]
+        duration: std::time::Duration,
+    },
+    /// Resume the status update loop before the end of the pause
+    Resume,
+    /// Check the platform tools, the keyring, the configuration, the server, the
+    /// clock and the permissions, and print a pass/fail report
+    ///
+    /// Exits with `1` if a check fails.
+    Doctor,
+    /// Save the configuration (without its secret), the state and the location
+    /// history in a single file, to be restored on another machine
+    Backup {
+        /// backup file to write
+        file: PathBuf,
+    },
+    /// Restore the files saved by `backup`, keeping the replaced ones as
+    /// `<file>.bak`
+    ///
+    /// The status update loop shall be stopped beforehand.
+    Restore {
+        /// backup file to read
+        file: PathBuf,
+    },
+    /// Clear the state (location, last status, pause, focus...), keeping it as
+    /// `<file>.bak`
+    ///
+    /// The status update loop shall be stopped beforehand.
+    Reset {
+        /// also clear the location history
+        #[arg(long)]
+        history: bool,
+    },
+    /// Install, run, query or restart automattermostatus as a background service
+    #[command(subcommand)]
+    Service(ServiceCommand),
+}
+
+/// Actions of the `status` subcommand
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum StatusCommand {
+    /// Set the custom status to `emoji` and `text`
+    Set {
+        /// emoji name, without colons
+        emoji: String,
+        /// status description
+        text: String,
+        /// expiration time of the custom status (hh:mm), or duration preset
+        /// (`thirty_minutes`, `one_hour`, `four_hours`, `today` or `this_week`)
+        #[arg(long)]
+        expires_at: Option<Expiry>,
+    },
+    /// Delete the custom status
+    Clear,
+    /// Print the current location, the last status sent and the last error
+    Show {
+        /// also print the custom status and presence currently set on the server
+        #[arg(long)]
+        remote: bool,
+        /// also print the activity counters since the start of the status update
+        /// loop (scans, updates sent, failures, relogins, presences), in Prometheus
+        /// format
+        #[arg(long)]
+        stats: bool,
+    },
+}
+
+/// Actions of the `location` subcommand
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum LocationCommand {
+    /// Pin the location to the one whose wifi substring is `name`
+    ///
+    /// The running status update loop uses it instead of the location matching the
+    /// visible networks, except during off time.
+    Set {
+        /// wifi substring of a configured status
+        name: String,
+        /// pin duration, such as `2h` or `1h30m` (for ever if not given)
+        #[arg(long = "for", value_name = "DURATION", value_parser = crate::utils::parse_duration)]
+        duration: Option<std::time::Duration>,
+    },
+    /// Go back to the location matching the visible networks
+    Clear,
+}
+
+/// Actions of the `focus` subcommand
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum FocusCommand {
+    /// Set *do not disturb* and `focus_session_status` for `duration`
+    ///
+    /// The running status update loop then restores the presence and the custom
+    /// status of the location, as at the end of the `focus_blocks`.
+    Start {
+        /// session duration, such as `25m` or `1h`
+        #[arg(value_parser = crate::utils::parse_duration)]
+        duration: std::time::Duration,
+    },
+    /// Stop the focus session before its end
+    Stop,
+}
+
+/// Actions of the `service` subcommand
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum ServiceCommand {
+    /// Register the service with the service manager and start it
+    Install {
+        /// windows account the service runs as (`DOMAIN\USER`, or `.\USER` for
+        /// a local account) instead of LocalSystem, which can read neither the
+        /// microphone usage nor the keyring of the user
+        ///
+        /// The account needs the "Log on as a service" right.
+        #[arg(long, value_name = "DOMAIN\\USER")]
+        account: Option<String>,
+        /// ask the password of `account` on the terminal
+        #[arg(long, requires = "account")]
+        password_prompt: bool,
+        /// install a systemd system unit (`automattermostatus@.service`) with an
+        /// instance per user, for shared linux machines, instead of a user unit
+        ///
+        /// Run with `sudo`, the instance of the calling user is enabled.
+        #[arg(long, conflicts_with = "account")]
+        system: bool,
+    },
+    /// Stop the service and remove it from the service manager
+    Uninstall {
+        /// remove the systemd system unit instead of the user unit
+        #[arg(long)]
+        system: bool,
+    },
+    /// Run the status update loop, logging to daily files of the `logs`
+    /// directory of `state_dir` (used by the service manager)
+    Run,
+    /// Print the state of the service and the last errors of its log
+    Status,
+    /// Stop the service, then start it again
+    Restart,
+}
+
+/// Actions of the `config` subcommand
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigCommand {
+    /// Print the JSON Schema of the configuration file
+    ///
+    /// Editors can use it to validate and complete `automattermostatus.toml`.
+    Schema,
+}
+
 // Courtesy of structopt_flags crate
-/// [`structopt::StructOpt`] implementing the verbosity parameter
-#[derive(structopt::StructOpt, Debug, Clone)]
+/// [`clap::Args`] implementing the verbosity parameter
+#[derive(clap::Args, Debug, Clone)]
 pub struct QuietVerbose {
     /// Increase the output's verbosity level
     ///
     /// Pass many times to increase verbosity level, up to 3.
-    #[structopt(
-        name = "quietverbose",
+    #[arg(
+        id = "quietverbose",
         long = "verbose",
-        short = "v",
-        parse(from_occurrences),
+        short = 'v',
+        action = ArgAction::Count,
         conflicts_with = "quietquiet",
         global = true
     )]
@@ -93,11 +394,11 @@ pub struct QuietVerbose {
     ///
     /// Used once, it will set error log level.
     /// Used twice, will silent the log completely
-    #[structopt(
-        name = "quietquiet",
+    #[arg(
+        id = "quietquiet",
         long = "quiet",
-        short = "q",
-        parse(from_occurrences),
+        short = 'q',
+        action = ArgAction::Count,
         conflicts_with = "quietverbose",
         global = true
     )]
@@ -179,42 +480,82 @@ impl QuietVerbose {
     }
 }
 
-#[derive(structopt::StructOpt, Serialize, Deserialize, Debug)]
+#[derive(Parser, Serialize, Deserialize, JsonSchema, Debug)]
 /// Automate mattermost status with the help of wifi network
 ///
 /// Use current visible wifi SSID to automate your mattermost status.
 /// This program is meant to either be running in background or be call regularly
-/// with option `--delay 0`.
+/// with `run --once`.
 /// It will then update your mattermost custom status according to the config file
-#[structopt(global_settings(&[AppSettings::ColoredHelp, AppSettings::ColorAuto]))]
+#[command(version)]
 pub struct Args {
+    /// version of the layout of the configuration file (see [`crate::migration`])
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(skip)]
+    pub config_version: Option<i64>,
+
     /// wifi interface name
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(short, long, env, name = "itf_name")]
+    #[arg(short, long, env, value_name = "ITF_NAME")]
     pub interface_name: Option<String>,
 
+    /// Wifi rescan policy. Either `No` (default) to rely on the OS cached
+    /// results, `Auto` or `Yes` to force a scan at each poll
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_enum, ignore_case = true)]
+    pub rescan_policy: Option<RescanPolicy>,
+
+    /// command printing the visible SSIDs, used instead of the built-in wifi scan
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "SCAN COMMAND")]
+    pub wifi_scan_cmd: Option<String>,
+
+    /// Parser of the `wifi_scan_cmd` output. Either `Lines` (default, one SSID
+    /// per line), `Nmcli`, `Netsh` or `Airport`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_enum, ignore_case = true)]
+    pub wifi_scan_parser: Option<ScanParser>,
+
+    /// ignore case when matching visible SSIDs against wifi substrings
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub match_case_insensitive: bool,
+
     /// Status configuration triplets (:: separated)
     ///
     /// Each triplet shall have the format:
     /// "wifi_substring::emoji_name::status_text". If `wifi_substring` is empty, the ssociated
     /// status will be used for off time.
     #[serde(skip_serializing_if = "Vec::is_empty")]
-    #[structopt(short, long, name = "wifi_substr::emoji::text")]
+    #[arg(short, long, value_name = "WIFI_SUBSTR::EMOJI::TEXT")]
     pub status: Vec<String>,
 
+    /// fail at startup on an invalid status triplet, instead of skipping it with
+    /// a warning
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub strict: bool,
+
+    /// What to do with triplets sharing the same wifi substring: `Warn`
+    /// (default) to keep the last one, `Error` to refuse to start or `Rotate` to
+    /// use them in turn, one per day
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_enum, ignore_case = true)]
+    pub duplicate_status: Option<DuplicatePolicy>,
+
     /// mattermost URL
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(short = "u", long, env, name = "url")]
+    #[arg(short = 'u', long, env, value_name = "URL")]
     pub mm_url: Option<String>,
 
     /// User name used for mattermost login or for password or private token lookup in OS keyring.
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(long, env, name = "username")]
+    #[arg(long, env, value_name = "USERNAME")]
     pub mm_user: Option<String>,
 
     /// Type of secret. Either `Password` (default) or `Token`
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(short = "t", long, env, possible_values = &SecretType::variants(), case_insensitive = true)]
+    #[arg(short = 't', long, env, value_enum, ignore_case = true)]
     pub secret_type: Option<SecretType>,
 
     /// Service name used for mattermost secret lookup in OS keyring.
@@ -222,9 +563,49 @@ pub struct Args {
     /// The secret is either a `password` (default) or a`token` according to
     /// `secret_type` option
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(long, env, name = "token service name")]
+    #[arg(long, env, value_name = "TOKEN SERVICE NAME")]
     pub keyring_service: Option<String>,
 
+    /// keyring holding the secret: libsecret collection on linux, credential
+    /// target name on windows
+    ///
+    /// Defaults to the default keyring of the OS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "TARGET")]
+    pub keyring_target: Option<String>,
+
+    /// entry of the `pass` password store containing the mattermost secret
+    /// (e.g. `work/mattermost-token`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "ENTRY")]
+    pub pass_entry: Option<String>,
+
+    /// password manager compatible with `pass` used for `pass_entry` (e.g.
+    /// `gopass`)
+    ///
+    /// Defaults to `pass`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "PROGRAM")]
+    pub pass_program: Option<String>,
+
+    /// 1Password secret reference of the mattermost secret (e.g.
+    /// `op://Work/Mattermost/token`), read with `op`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "REFERENCE")]
+    pub op_item: Option<String>,
+
+    /// name or id of the Bitwarden item whose password is the mattermost secret,
+    /// read with `bw`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "ITEM")]
+    pub bw_item: Option<String>,
+
+    /// keep the session token obtained with a password in the OS keyring, and
+    /// reuse it at the next start instead of logging in again
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub persist_session: bool,
+
     /// mattermost private Token
     ///
     /// Usage of this option may leak your personal token. It is recommended to
@@ -233,7 +614,7 @@ pub struct Args {
     /// The secret is either a `password` (default) or a`token` according to
     /// `secret_type` option
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(long, env, hide_env_values = true, name = "token")]
+    #[arg(long, env, hide_env_values = true, value_name = "TOKEN")]
     pub mm_secret: Option<String>,
 
     /// mattermost secret command
@@ -241,184 +622,1449 @@ pub struct Args {
     /// The secret is either a `password` (default) or a`token` according to
     /// `secret_type` option
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(long, env, name = "command")]
+    #[arg(long, env, value_name = "COMMAND")]
     pub mm_secret_cmd: Option<String>,
 
+    /// file containing the mattermost secret
+    ///
+    /// The trailing new line is ignored. The file may be encrypted with age (see
+    /// `mm_secret_identity`) or GPG.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "PATH")]
+    pub mm_secret_file: Option<PathBuf>,
+
+    /// age identity file used to decrypt `mm_secret_file`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "PATH")]
+    pub mm_secret_identity: Option<PathBuf>,
+
+    /// name of the environment variable containing the mattermost secret
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "VARIABLE")]
+    pub mm_secret_env: Option<String>,
+
+    /// providers of the secret, tried in order until one returns it
+    ///
+    /// Among `Keyring`, `Pass`, `OnePassword`, `Bitwarden`, `Command`, `File`,
+    /// `Env` and `Prompt`. Defaults to all of them but `Prompt`, in this order. `mm_secret` is only used when no
+    /// provider returns a secret.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[arg(long, value_enum, ignore_case = true)]
+    pub secret_providers: Vec<SecretSource>,
+
     /// directory for state file
     ///
-    /// Will use content of XDG_CACHE_HOME if unset.
+    /// Will use `$XDG_STATE_HOME/automattermostatus` on linux and the local data
+    /// directory on other OS if unset.
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(long, env, parse(from_os_str), name = "cache dir")]
+    #[arg(long, env, value_name = "STATE DIR")]
     pub state_dir: Option<PathBuf>,
 
+    /// state file
+    ///
+    /// Defaults to a file of `state_dir` named after the mattermost server and
+    /// user, so that instances using different servers do not share their state.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "STATE FILE")]
+    pub state_file: Option<PathBuf>,
+
     /// beginning of status update with the format hh:mm
     ///
     /// Before this time the status won't be updated
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(short, long, env, name = "begin hh:mm")]
-    pub begin: Option<String>,
+    #[arg(short, long, env, value_name = "BEGIN HH:MM")]
+    #[schemars(with = "Option<String>")]
+    pub begin: Option<TimeOfDay>,
 
     /// end of status update with the format hh:mm
     ///
-    /// After this time the status won't be updated
+    /// After this time the status won't be updated. It may be before `begin` for
+    /// night shifts.
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(short, long, env, name = "end hh:mm")]
-    pub end: Option<String>,
+    #[arg(short, long, env, value_name = "END HH:MM")]
+    #[schemars(with = "Option<String>")]
+    pub end: Option<TimeOfDay>,
 
-    /// Expiration time with the format hh:mm
+    /// Expiration time with the format hh:mm, or duration preset
     ///
-    /// This parameter is used to set the custom status expiration time
-    /// Set to "0" to avoid setting expiration time
+    /// This parameter is used to set the custom status expiration time.
+    /// Defaults to `end` so that the status does not outlive the work day.
+    /// Set to "0" to avoid setting expiration time. The presets of the
+    /// mattermost clients (`thirty_minutes`, `one_hour`, `four_hours`, `today`
+    /// and `this_week`) are shown as such by them.
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(long, env, name = "expiry hh:mm")]
-    pub expires_at: Option<String>,
+    #[arg(long, env, value_name = "EXPIRY HH:MM")]
+    #[schemars(with = "Option<String>")]
+    pub expires_at: Option<Expiry>,
 
     /// delay between wifi SSID polling in seconds
+    ///
+    /// `0` is a deprecated equivalent of `run --once`.
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[structopt(long, env)]
+    #[arg(long, env)]
     pub delay: Option<u32>,
 
+    /// delay between wifi SSID polling in seconds when running on battery
+    ///
+    /// The largest of `delay` and `delay_on_battery` is used when on battery.
+    /// Forced refreshes of the custom status are also skipped on battery.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env)]
+    pub delay_on_battery: Option<u32>,
+
+    /// maximum random delay in seconds added to each polling delay
+    ///
+    /// Avoid synchronizing the API calls of many computers against the mattermost server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env)]
+    pub delay_jitter: Option<u32>,
+
+    /// maximum random delay in seconds to wait before the first scan
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env)]
+    pub startup_delay: Option<u32>,
+
+    /// seconds to wait after start for the network to settle (DHCP, VPN) before
+    /// the first scan and API call of the status update loop
+    ///
+    /// The wait ends as soon as `connectivity_check_url` answers, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "SECONDS")]
+    pub startup_grace_secs: Option<u32>,
+
+    /// number of consecutive scans without known SSID during which the last
+    /// known location is kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env)]
+    pub unknown_grace_scans: Option<u32>,
+
+    /// delay in seconds without known SSID during which the last known location is kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env)]
+    pub unknown_grace_secs: Option<u32>,
+
+    /// delay in seconds during which a custom status changed by hand is not overwritten
+    ///
+    /// Set to 0 to always overwrite the custom status.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env)]
+    pub manual_change_cooldown: Option<u32>,
+
+    /// tag appended to the status texts after a ` · `, so that users with several
+    /// computers know which one set the status
+    ///
+    /// `{hostname}` is replaced by the host name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "TAG")]
+    pub machine_tag: Option<String>,
+
+    /// number of consecutive failed iterations after which the mattermost session
+    /// and wifi handles are rebuilt and the delay is doubled
+    ///
+    /// Set to 0 to never rebuild them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env)]
+    pub max_failures: Option<u32>,
+
+    /// number of consecutive failures to send the custom status after which
+    /// the user is told by a direct message, once it is sent again
+    ///
+    /// Set to 0 (default) to never tell.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "FAILURES")]
+    pub report_failures_after: Option<u32>,
+
+    /// expiry date of the personal access token with the format YYYY-MM-DD
+    ///
+    /// Mattermost does not tell when a token expires: set it to be told before.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "YYYY-MM-DD")]
+    #[schemars(with = "Option<String>")]
+    pub token_expires_on: Option<NaiveDate>,
+
+    /// number of days before `token_expires_on`, or before the expiry of the
+    /// session of a password login, at which the user is told by a direct
+    /// message that the token expires
+    ///
+    /// Set to 0 to never tell.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "DAYS")]
+    pub token_expiry_notice_days: Option<u32>,
+
+    /// URL answering `204 No Content` when internet is reachable, used to detect
+    /// captive portals (e.g. `http://connectivitycheck.gstatic.com/generate_204`)
+    ///
+    /// Mattermost is not updated while the answer differs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "URL")]
+    pub connectivity_check_url: Option<String>,
+
+    /// call the ping endpoint of the mattermost server before each update, and
+    /// skip the API calls and back off while the server is down, instead of
+    /// failing on each of them
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub ping_server: bool,
+
+    /// show a notification for each custom status sent, with `Pause 1h` and
+    /// `Open log` buttons (windows only, not when running as a service)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub toast_notifications: bool,
+
+    /// export the location, the last status and the pause as D-Bus properties
+    /// for desktop widgets (`dbus` feature, linux only)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub dbus_properties: bool,
+
+    /// `User-Agent` header of the requests to mattermost, for proxies filtering
+    /// unknown clients (defaults to `automattermostatus/<version> (<os>)`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "USER AGENT")]
+    pub user_agent: Option<String>,
+
+    /// send a random `X-Request-Id` header with each request to mattermost, to
+    /// find it in the server or proxy logs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub request_id: bool,
+
+    /// path of a companion plugin (e.g. `/plugins/com.example.status`) setting the
+    /// custom status, for servers where personal access tokens are disabled
+    ///
+    /// The plugin receives the same json as the custom status API with a `PUT`
+    /// to `<plugin_path>/status/custom`, and a `DELETE` to clear it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "PATH")]
+    pub plugin_path: Option<String>,
+
+    /// on metered connections (e.g. tethering), poll every `delay_on_battery` seconds
+    /// and skip the periodic refresh of the custom status and the weekly summary
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub respect_metered: bool,
+
+    /// delete the custom status and reset presence to online when terminated by a signal
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub clear_on_exit: bool,
+
+    /// send every Monday a direct message summarizing the time spent per location
+    /// during the previous week
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub weekly_summary: bool,
+
+    /// check daily whether a newer release is published on crates.io, log it and
+    /// tell it once with a direct message
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub update_check: bool,
+
+    /// write a report in the state directory when crashing (`telemetry` feature)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub crash_report: bool,
+
+    /// also send crash reports to this Sentry DSN
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env)]
+    pub crash_report_dsn: Option<String>,
+
+    /// log filter of `service run`, with the `RUST_LOG` syntax (e.g. `debug` or
+    /// `lib=debug,info`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "FILTER")]
+    pub service_log_level: Option<String>,
+
+    /// number of daily log files kept by `service run` (0 keeps them all)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "DAYS")]
+    pub service_log_retention_days: Option<u32>,
+
     /// List of application watched for using the microphone
     #[serde(skip_serializing_if = "Vec::is_empty")]
-    #[structopt(short, long, name = "app binary name")]
+    #[arg(short, long, value_name = "APP BINARY NAME")]
     pub mic_app_names: Vec<String>,
 
+    /// List of meeting applications setting *do not disturb* while in a call, as
+    /// `PROCESS[::WINDOW]`
+    ///
+    /// With `WINDOW`, while a window of the process has a title containing it
+    /// (e.g. `zoom::Zoom Meeting`), so that calls are detected while the
+    /// microphone is muted. Without it, while the process runs and uses the
+    /// microphone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[arg(long, value_name = "PROCESS[::WINDOW]")]
+    pub meeting_app_names: Vec<String>,
+
+    /// List of headsets (substrings of the audio device names, ignoring case)
+    /// whose connection is watched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[arg(long, value_name = "DEVICE NAME")]
+    pub headset_names: Vec<String>,
+
+    /// custom status `EMOJI::TEXT` set while one of `headset_names` is
+    /// connected, outside of off time (e.g. `headphones::focusing`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "EMOJI::TEXT")]
+    pub headset_status: Option<String>,
+
+    /// set *do not disturb* while one of `headset_names` is connected, as when
+    /// the microphone is used
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub headset_dnd: bool,
+
+    /// custom status `EMOJI::TEXT` set during an inbound remote session (SSH,
+    /// RDP or VNC) outside of off time, e.g. `computer::working remotely`
+    ///
+    /// It wins over `headset_status`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "EMOJI::TEXT")]
+    pub remote_status: Option<String>,
+
+    /// set the presence back to online when mattermost sets it away during an
+    /// inbound remote session, no local input being received
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long)]
+    pub remote_keep_online: bool,
+
+    #[arg(skip)]
+    /// Minutes after which the presence is set to away at a location, per wifi
+    /// substring (`""` for off time), the custom status being left as is
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub away_after: HashMap<String, u32>,
+
+    /// recurring focus blocks `[DAYS] BEGIN-END` (e.g. `mon-fri 9:00-11:00`),
+    /// during which *do not disturb* and `focus_status` are set, whatever the
+    /// location
+    ///
+    /// DAYS are comma separated week days or ranges of week days, or `weekdays`;
+    /// every day without them. Focus blocks are ignored during off time.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[arg(long, value_name = "[DAYS] BEGIN-END")]
+    pub focus_blocks: Vec<String>,
+
+    /// custom status `EMOJI::TEXT` set during the `focus_blocks`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "EMOJI::TEXT")]
+    pub focus_status: Option<String>,
+
+    /// custom status `EMOJI::TEXT` set during the sessions of `focus start`,
+    /// `{until}` being replaced by their end (hh:mm)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, env, value_name = "EMOJI::TEXT")]
+    pub focus_session_status: Option<String>,
+
+    /// sources allowed to write presence and custom status, by decreasing priority
+    ///
+    /// Among `Manual`, `Mic`, `Focus`, `Location` and `OffTime`, missing ones being ranked
+    /// after the given ones in this default order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[arg(long, value_enum, ignore_case = true)]
+    pub priority: Vec<StatusSource>,
+
+    /// record the raw results of each scan in this file (JSON lines)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, value_name = "RECORD FILE")]
+    pub record: Option<PathBuf>,
+
+    /// replay a recording made with `--record` without sending anything to mattermost
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long, value_name = "REPLAY FILE", conflicts_with = "record")]
+    pub replay: Option<PathBuf>,
+
+    /// read visible SSIDs from stdin instead of scanning wifi
+    ///
+    /// Each line holds the comma separated SSIDs visible at one scan. Nothing is
+    /// sent to mattermost.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[arg(long, conflicts_with_all = ["record", "replay"])]
+    pub ssid_from_stdin: bool,
+
     #[allow(missing_docs)]
-    #[structopt(flatten)]
+    #[command(flatten)]
     #[serde(deserialize_with = "de_from_str")]
+    #[schemars(with = "String")]
     pub verbose: QuietVerbose,
 
-    #[structopt(skip)]
+    #[arg(skip)]
     /// Days off for which the custom status shall not be changed
     pub offdays: OffDays,
+
+    /// day off, given as `DAY[:PARITY]` (e.g. `wed:odd`), in addition to the
+    /// `offdays` of the configuration file
+    ///
+    /// PARITY is one of `every` (the default), `odd` or `even`, according to the
+    /// iso week number.
+    #[serde(skip)]
+    #[schemars(skip)]
+    #[arg(long = "offday", value_name = "DAY[:PARITY]")]
+    pub offday: Vec<OffDay>,
+
+    /// SSID of the home wifi network, used instead of `home` in the default
+    /// status when the configuration file is created
+    #[serde(skip)]
+    #[schemars(skip)]
+    #[arg(long, value_name = "SSID")]
+    pub home_ssid: Option<String>,
+
+    /// format of the output of `status show`, `stats` and `doctor`: `text` or
+    /// `json` for scripts
+    #[serde(skip)]
+    #[schemars(skip)]
+    #[arg(long, value_enum, global = true, default_value_t)]
+    pub output: OutputFormat,
+
+    /// Vault secret the secret has been read from (see [`Args::resolve_secret`])
+    #[serde(skip)]
+    #[schemars(skip)]
+    #[arg(skip)]
+    pub secret_vault: Option<VaultSecret>,
+
+    #[serde(skip)]
+    #[command(subcommand)]
+    pub command: Option<SubCommand>,
 }
 
 impl Default for Args {
     fn default() -> Args {
-        let res = Args {
+        Args {
+            config_version: Some(crate::migration::CONFIG_VERSION),
             #[cfg(target_os = "linux")]
             interface_name: Some("wlan0".into()),
             #[cfg(target_os = "windows")]
             interface_name: Some("Wireless Network Connection".into()),
             #[cfg(target_os = "macos")]
             interface_name: Some("en0".into()),
-            status: ["home::house::working at home".to_string()].to_vec(),
+            rescan_policy: Some(RescanPolicy::No),
+            wifi_scan_cmd: None,
+            wifi_scan_parser: None,
+            match_case_insensitive: false,
+            status: [format!(
+                "home::house::{}",
+                i18n::tr(i18n::Text::WorkingAtHome, i18n::Lang::from_env())
+            )]
+            .to_vec(),
+            strict: false,
+            duplicate_status: Some(DuplicatePolicy::Warn),
             delay: Some(60),
-            state_dir: Some(
-                ProjectDirs::from("net", "ams", "automattermostatus")
-                    .expect("Unable to find a project dir")
-                    .cache_dir()
-                    .to_owned(),
-            ),
+            delay_on_battery: Some(300),
+            delay_jitter: None,
+            startup_delay: None,
+            startup_grace_secs: None,
+            unknown_grace_scans: None,
+            unknown_grace_secs: None,
+            state_dir: Some(crate::state::default_dir()),
+            state_file: None,
             mm_user: None,
             keyring_service: None,
+            persist_session: false,
             mm_secret: None,
             mm_secret_cmd: None,
+            mm_secret_file: None,
+            mm_secret_identity: None,
+            mm_secret_env: None,
+            secret_providers: Vec::new(),
+            keyring_target: None,
+            pass_entry: None,
+            pass_program: None,
+            op_item: None,
+            bw_item: None,
+            secret_vault: None,
             secret_type: Some(SecretType::Password),
             mm_url: Some("https://mattermost.example.com".into()),
+            manual_change_cooldown: Some(3600),
+            max_failures: Some(5),
+            report_failures_after: None,
+            token_expires_on: None,
+            token_expiry_notice_days: Some(7),
+            machine_tag: None,
+            connectivity_check_url: None,
+            ping_server: false,
+            toast_notifications: false,
+            dbus_properties: false,
+            user_agent: None,
+            request_id: false,
+            plugin_path: None,
+            respect_metered: false,
+            clear_on_exit: false,
+            weekly_summary: false,
+            update_check: false,
+            crash_report: false,
+            crash_report_dsn: None,
+            service_log_level: Some("info".into()),
+            service_log_retention_days: Some(7),
             mic_app_names: Vec::new(),
+            meeting_app_names: Vec::new(),
+            headset_names: Vec::new(),
+            headset_status: None,
+            headset_dnd: false,
+            remote_status: None,
+            remote_keep_online: false,
+            away_after: HashMap::new(),
+            focus_blocks: Vec::new(),
+            focus_status: Some("no_bell::Deep work".to_string()),
+            focus_session_status: Some("tomato::focused until {until}".to_string()),
+            priority: Vec::new(),
+            record: None,
+            replay: None,
+            ssid_from_stdin: false,
             verbose: QuietVerbose {
                 verbosity_level: 1,
                 quiet_level: 0,
             },
-            expires_at: Some("19:30".to_string()),
-            begin: Some("8:00".to_string()),
-            end: Some("19:30".to_string()),
+            expires_at: None,
+            begin: Some("8:00".parse().expect("valid time")),
+            end: Some("19:30".parse().expect("valid time")),
             offdays: OffDays::default(),
-        };
-        res
+            offday: Vec::new(),
+            home_ssid: None,
+            output: OutputFormat::Text,
+            command: None,
+        }
     }
 }
 
-impl Off for Args {
-    fn is_off_time(&self) -> bool {
-        self.offdays.is_off_time() // The day is off, so we are off
-            || if let Some(begin) = parse_from_hmstr(&self.begin) {
-                    Local::now().naive_local() < begin // now is before begin, we are off
-                } else {
-                    false // now is after begin, we are on duty if not after end
-                }
-            || if let Some(end) = parse_from_hmstr(&self.end) {
-                    Local::now().naive_local() > end // now is after end, we are off
-                } else {
-                    false // now is before end, we are on duty
-                }
+/// Credentials used to log in to mattermost, according to `secret_type`
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub enum Credentials {
+    /// private access token
+    Token(#[derivative(Debug = "ignore")] String),
+    /// password of `user`, exchanged for a session token
+    Password {
+        /// user name
+        user: String,
+        /// password of `user`
+        #[derivative(Debug = "ignore")]
+        password: String,
+    },
+}
+
+impl Credentials {
+    /// Return the secret: the token or the password
+    pub fn secret(&self) -> &str {
+        match self {
+            Credentials::Token(token) => token,
+            Credentials::Password { password, .. } => password,
+        }
+    }
+
+    /// Return the same credentials with `secret` instead of their own
+    /// ```
+    /// use lib::config::Credentials;
+    /// let credentials = Credentials::Password {
+    ///     user: "bob".to_string(),
+    ///     password: "old".to_string(),
+    /// };
+    /// assert_eq!(credentials.with_secret("n3w".to_string()).secret(), "n3w");
+    /// ```
+    pub fn with_secret(&self, secret: String) -> Self {
+        match self {
+            Credentials::Token(_) => Credentials::Token(secret),
+            Credentials::Password { user, .. } => Credentials::Password {
+                user: user.clone(),
+                password: secret,
+            },
+        }
     }
 }
 
+/// Validated configuration built from [`Args`] with [`Args::validate`]
+///
+/// Contrary to [`Args`], all mandatory parameters are guaranteed to be set.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Config {
+    /// wifi interface name
+    pub interface_name: String,
+    /// Wifi rescan policy
+    pub rescan_policy: RescanPolicy,
+    /// command printing the visible SSIDs, used instead of the built-in wifi scan
+    pub wifi_scan_cmd: Option<ScanCommand>,
+    /// ignore case when matching visible SSIDs against wifi substrings
+    pub match_case_insensitive: bool,
+    /// Status configuration triplets (:: separated)
+    pub status: Vec<String>,
+    /// fail on invalid status triplets instead of skipping them
+    pub strict: bool,
+    /// What to do with triplets sharing the same wifi substring
+    pub duplicate_status: DuplicatePolicy,
+    /// mattermost URL
+    pub mm_url: String,
+    /// credentials built from `mm_user`, `secret_type` and `mm_secret`
+    pub credentials: Credentials,
+    /// reuse the session token obtained with a password across restarts
+    pub persist_session: bool,
+    /// vault secret `mm_secret` has been read from, read again by the sessions
+    /// when rejected
+    pub secret_vault: Option<VaultSecret>,
+    /// directory for state file
+    pub state_dir: PathBuf,
+    /// state file
+    pub state_file: PathBuf,
+    /// beginning of status update with the format hh:mm
+    pub begin: Option<TimeOfDay>,
+    /// end of status update with the format hh:mm
+    pub end: Option<TimeOfDay>,
+    /// Expiration of the custom status, `None` if it does not expire
+    pub expires_at: Option<Expiry>,
+    /// delay between wifi SSID polling in seconds
+    pub delay: u32,
+    /// perform a single detection and update cycle (`run --once`)
+    pub once: bool,
+    /// delay between wifi SSID polling in seconds when running on battery
+    pub delay_on_battery: u32,
+    /// maximum random delay in seconds added to each polling delay
+    pub delay_jitter: u32,
+    /// maximum random delay in seconds to wait before the first scan
+    pub startup_delay: u32,
+    /// seconds to wait for the network to settle before the first scan
+    pub startup_grace_secs: u32,
+    /// number of scans without known SSID during which the last known location is kept
+    pub unknown_grace_scans: u32,
+    /// delay in seconds without known SSID during which the last known location is kept
+    pub unknown_grace_secs: u32,
+    /// delay in seconds during which a custom status changed by hand is not overwritten
+    pub manual_change_cooldown: u32,
+    /// tag appended to the status texts
+    pub machine_tag: Option<String>,
+    /// number of consecutive failed iterations after which handles are rebuilt
+    pub max_failures: u32,
+    /// number of consecutive send failures told to the user by a direct message
+    pub report_failures_after: u32,
+    /// expiry date of the personal access token
+    pub token_expires_on: Option<NaiveDate>,
+    /// number of days before `token_expires_on` at which the user is told
+    pub token_expiry_notice_days: u32,
+    /// URL used to detect captive portals
+    pub connectivity_check_url: Option<String>,
+    /// ping the server before each update
+    pub ping_server: bool,
+    /// show a windows notification for each custom status sent
+    pub toast_notifications: bool,
+    /// export the location, the last status and the pause on D-Bus
+    pub dbus_properties: bool,
+    /// identification of the requests to mattermost
+    pub client: ClientOptions,
+    /// poll less often and skip non-essential API calls on metered connections
+    pub respect_metered: bool,
+    /// delete the custom status and reset presence to online when terminated
+    pub clear_on_exit: bool,
+    /// send every Monday a summary of the time spent per location
+    pub weekly_summary: bool,
+    /// check daily for newer releases
+    pub update_check: bool,
+    /// write a report in the state directory when crashing
+    pub crash_report: bool,
+    /// Sentry DSN to which crash reports are also sent
+    pub crash_report_dsn: Option<String>,
+    /// log filter of `service run`
+    pub service_log_level: String,
+    /// number of daily log files kept by `service run`
+    pub service_log_retention_days: u32,
+    /// List of application watched for using the microphone
+    pub mic_app_names: Vec<String>,
+    /// meeting applications setting *do not disturb* during calls
+    pub meeting_apps: Vec<MeetingApp>,
+    /// headsets whose connection is watched
+    pub headset_names: Vec<String>,
+    /// custom status set while a headset is connected
+    pub headset_status: Option<MMCustomStatus>,
+    /// set *do not disturb* while a headset is connected
+    pub headset_dnd: bool,
+    /// custom status set during remote sessions
+    pub remote_status: Option<MMCustomStatus>,
+    /// keep the presence online during remote sessions
+    pub remote_keep_online: bool,
+    /// minutes after which the presence is set to away, per wifi substring
+    pub away_after: HashMap<String, u32>,
+    /// recurring focus blocks
+    pub focus_blocks: Vec<FocusBlock>,
+    /// custom status set during the focus blocks
+    pub focus_status: MMCustomStatus,
+    /// custom status set during the focus sessions, with an `{until}` placeholder
+    pub focus_session_status: MMCustomStatus,
+    /// sources allowed to write presence and custom status, by decreasing priority
+    pub priority: Vec<StatusSource>,
+    /// file where raw scan results are recorded
+    pub record: Option<PathBuf>,
+    /// recording to replay instead of scanning
+    pub replay: Option<PathBuf>,
+    /// read visible SSIDs from stdin instead of scanning
+    pub ssid_from_stdin: bool,
+    /// Days off for which the custom status shall not be changed
+    pub offdays: OffDays,
+}
+
+impl Config {
+    /// Return the working period of the day
+    pub fn schedule(&self) -> Schedule {
+        Schedule {
+            begin: self.begin,
+            end: self.end,
+        }
+    }
+
+    /// Return the focus block in progress at the time given by `clock`, if any
+    pub fn focus_at(&self, clock: &dyn Clock) -> Option<&FocusBlock> {
+        let now = clock.now().naive_local();
+        self.focus_blocks.iter().find(|block| block.is_active(now))
+    }
+}
+
+impl Off for Config {
+    fn is_off_at(&self, clock: &dyn Clock) -> bool {
+        self.offdays.is_off_at(clock) // The day is off, so we are off
+            // now is outside of the working period, we are off
+            || self.schedule().is_off_at(clock)
+    }
+}
+
+/// Return an [`Error::Config`] stating that `name` is missing
+fn missing(name: &str) -> Error {
+    Error::Config(anyhow!("`{}` is not defined", name))
+}
+
 impl Args {
-    /// Update `args.mm_secret`  with the one fetched from OS keyring
-    ///
-    pub fn update_secret_with_keyring(mut self) -> Result<Self> {
-        if let Some(user) = &self.mm_user {
-            if let Some(service) = &self.keyring_service {
-                let keyring = keyring::Keyring::new(service, user);
-                let secret = keyring.get_password().with_context(|| {
-                    format!("Querying OS keyring (user: {}, service: {})", user, service)
-                })?;
-                self.mm_secret = Some(secret);
-            } else {
-                warn!("User is defined for keyring lookup but service is not");
-                info!("Skipping keyring lookup");
+    /// Return all the problems of the parameters: missing mandatory ones and
+    /// unmet constraints between them.
+    fn problems(&self) -> Vec<String> {
+        let dry_run = self.replay.is_some() || self.ssid_from_stdin;
+        let mut res: Vec<String> = [
+            ("interface_name", self.interface_name.is_none()),
+            ("mm_url", self.mm_url.is_none()),
+            ("secret_type", self.secret_type.is_none()),
+            // No secret is needed for dry runs as nothing is sent
+            ("mm_secret", self.mm_secret.is_none() && !dry_run),
+            ("state_dir", self.state_dir.is_none()),
+            ("delay", self.delay.is_none()),
+        ]
+        .iter()
+        .filter(|(_, missing)| *missing)
+        .map(|(name, _)| missing(name).to_string())
+        .collect();
+        if let Some(url) = &self.mm_url {
+            match url::Url::parse(url) {
+                Ok(url) if ["http", "https"].contains(&url.scheme()) => (),
+                _ => res.push(format!("`mm_url` ({}) is not an http or https URL", url)),
+            }
+        }
+        if let Some(path) = &self.plugin_path {
+            if !path.starts_with("/plugins/") {
+                res.push(format!(
+                    "`plugin_path` ({}) shall start with `/plugins/`",
+                    path
+                ));
             }
         }
-        Ok(self)
+        if let Some(filter) = &self.service_log_level {
+            if tracing_subscriber::EnvFilter::try_new(filter).is_err() {
+                res.push(format!(
+                    "`service_log_level` ({}) is not a valid log filter",
+                    filter
+                ));
+            }
+        }
+        if let (Some(begin), Some(end)) = (self.begin, self.end) {
+            // `end` before `begin` describes a night shift
+            if begin == end {
+                res.push(format!("`begin` and `end` shall differ ({})", begin));
+            }
+        }
+        for (name, status) in [
+            ("headset_status", &self.headset_status),
+            ("remote_status", &self.remote_status),
+            ("focus_status", &self.focus_status),
+            ("focus_session_status", &self.focus_session_status),
+        ] {
+            if let Some(status) = status {
+                if !status.contains("::") {
+                    res.push(format!("`{}` ({}) shall be `EMOJI::TEXT`", name, status));
+                }
+            }
+        }
+        for block in &self.focus_blocks {
+            if let Err(e) = block.parse::<FocusBlock>() {
+                res.push(format!("`focus_blocks` ({}): {}", block, e));
+            }
+        }
+        if let Some(SecretType::Password) = self.secret_type {
+            if self.mm_user.is_none() && !dry_run {
+                res.push("`mm_user` is required when `secret_type` is `Password`".to_string());
+            }
+        }
+        res
+    }
+
+    /// Return `state_file`, or the default state file of `state_dir` for the
+    /// configured server and user
+    pub fn state_file_path(&self) -> Option<PathBuf> {
+        self.state_file.clone().or_else(|| {
+            Some(self.state_dir.as_ref()?.join(crate::state::file_name(
+                self.mm_url.as_deref().unwrap_or_default(),
+                self.mm_user.as_deref(),
+            )))
+        })
+    }
+
+    /// Check that all mandatory parameters are defined and consistent, and build
+    /// the corresponding [`Config`].
+    ///
+    /// All the problems are reported at once in the returned error.
+    ///
+    /// Shall be called once the secret has been retrieved (see
+    /// [`Args::resolve_secret`]).
+    pub fn validate(self) -> Result<Config> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            return Err(Error::Config(anyhow!("{}", problems.join("\n"))));
+        }
+        let dry_run = self.replay.is_some() || self.ssid_from_stdin;
+        let state_file = self.state_file_path();
+        // Tagged like the statuses of the locations (see `prepare_status`)
+        let tagged_status = |status: Option<&str>| {
+            let (emoji, text) = status?.split_once("::")?;
+            let text = match &self.machine_tag {
+                Some(tag) => format!("{}{}{}", text, crate::state::TAG_SEPARATOR, tag),
+                None => text.to_string(),
+            };
+            Some(MMCustomStatus::new(text, emoji.to_string()))
+        };
+        let headset_status = tagged_status(self.headset_status.as_deref());
+        let remote_status = tagged_status(self.remote_status.as_deref());
+        let focus_status = tagged_status(self.focus_status.as_deref())
+            .unwrap_or_else(|| MMCustomStatus::new("Deep work".to_string(), "no_bell".to_string()));
+        let focus_session_status = tagged_status(self.focus_session_status.as_deref())
+            .unwrap_or_else(|| {
+                MMCustomStatus::new("focused until {until}".to_string(), "tomato".to_string())
+            });
+        let config = Config {
+            interface_name: self
+                .interface_name
+                .ok_or_else(|| missing("interface_name"))?,
+            rescan_policy: self.rescan_policy.unwrap_or(RescanPolicy::No),
+            wifi_scan_cmd: match self.wifi_scan_cmd {
+                Some(command) => Some(
+                    ScanCommand::new(
+                        shell_words::split(&command)
+                            .context("Splitting wifi_scan_cmd into shell words")
+                            .map_err(Error::Config)?,
+                        self.wifi_scan_parser.unwrap_or(ScanParser::Lines),
+                    )
+                    .ok_or_else(|| Error::Config(anyhow!("`wifi_scan_cmd` is empty")))?,
+                ),
+                None => None,
+            },
+            match_case_insensitive: self.match_case_insensitive,
+            status: self.status,
+            strict: self.strict,
+            duplicate_status: self.duplicate_status.unwrap_or(DuplicatePolicy::Warn),
+            mm_url: self.mm_url.ok_or_else(|| missing("mm_url"))?,
+            credentials: match (
+                self.secret_type.ok_or_else(|| missing("secret_type"))?,
+                self.mm_user,
+                self.mm_secret,
+            ) {
+                (SecretType::Token, _, Some(token)) => Credentials::Token(token),
+                (SecretType::Password, Some(user), Some(password)) => {
+                    Credentials::Password { user, password }
+                }
+                // No credentials are needed for dry runs as nothing is sent
+                _ if dry_run => Credentials::Token(String::new()),
+                (SecretType::Password, None, _) => {
+                    return Err(Error::Config(anyhow!(
+                        "`mm_user` is required when `secret_type` is `Password`"
+                    )))
+                }
+                (_, _, None) => return Err(missing("mm_secret")),
+            },
+            persist_session: self.persist_session,
+            secret_vault: self.secret_vault,
+            state_file: state_file.ok_or_else(|| missing("state_dir"))?,
+            state_dir: self.state_dir.ok_or_else(|| missing("state_dir"))?,
+            begin: self.begin,
+            end: self.end,
+            // The status shall not outlive the work day
+            expires_at: match self.expires_at {
+                Some(Expiry::Never) => None,
+                Some(expiry) => Some(expiry),
+                None => self.end.map(Expiry::At),
+            },
+            delay: self.delay.ok_or_else(|| missing("delay"))?,
+            once: self.delay == Some(0),
+            delay_on_battery: self
+                .delay_on_battery
+                .unwrap_or_default()
+                .max(self.delay.unwrap_or_default()),
+            delay_jitter: self.delay_jitter.unwrap_or_default(),
+            startup_delay: self.startup_delay.unwrap_or_default(),
+            startup_grace_secs: self.startup_grace_secs.unwrap_or_default(),
+            unknown_grace_scans: self.unknown_grace_scans.unwrap_or_default(),
+            unknown_grace_secs: self.unknown_grace_secs.unwrap_or_default(),
+            manual_change_cooldown: self.manual_change_cooldown.unwrap_or_default(),
+            machine_tag: self.machine_tag,
+            max_failures: self.max_failures.unwrap_or_default(),
+            report_failures_after: self.report_failures_after.unwrap_or_default(),
+            token_expires_on: self.token_expires_on,
+            token_expiry_notice_days: self.token_expiry_notice_days.unwrap_or_default(),
+            connectivity_check_url: self.connectivity_check_url,
+            ping_server: self.ping_server,
+            toast_notifications: self.toast_notifications,
+            dbus_properties: self.dbus_properties,
+            client: ClientOptions {
+                user_agent: self.user_agent,
+                request_id: self.request_id,
+                plugin_path: self.plugin_path,
+            },
+            respect_metered: self.respect_metered,
+            clear_on_exit: self.clear_on_exit,
+            weekly_summary: self.weekly_summary,
+            update_check: self.update_check,
+            crash_report: self.crash_report,
+            crash_report_dsn: self.crash_report_dsn,
+            service_log_level: self.service_log_level.unwrap_or_else(|| "info".into()),
+            service_log_retention_days: self.service_log_retention_days.unwrap_or_default(),
+            mic_app_names: self.mic_app_names,
+            meeting_apps: self
+                .meeting_app_names
+                .iter()
+                .map(|entry| MeetingApp::parse(entry))
+                .collect(),
+            headset_names: self.headset_names,
+            headset_status,
+            headset_dnd: self.headset_dnd,
+            remote_status,
+            remote_keep_online: self.remote_keep_online,
+            away_after: self.away_after,
+            focus_blocks: self
+                .focus_blocks
+                .iter()
+                .filter_map(|block| block.parse().ok())
+                .collect(),
+            focus_status,
+            focus_session_status,
+            priority: crate::priority::complete(&self.priority),
+            record: self.record,
+            replay: self.replay,
+            ssid_from_stdin: self.ssid_from_stdin,
+            offdays: self.offdays,
+        };
+        #[cfg(not(feature = "telemetry"))]
+        if config.crash_report {
+            warn!("`crash_report` is ignored as the `telemetry` feature is not enabled");
+        }
+        #[cfg(not(all(feature = "dbus", target_os = "linux")))]
+        if config.dbus_properties {
+            warn!("`dbus_properties` is ignored as the `dbus` feature is not enabled on linux");
+        }
+        Ok(config)
     }
 
-    /// Update `args.mm_secret`  with the standard output of
-    /// `args.mm_secret_cmd` if defined.
+    /// Update `args.mm_secret` with the secret of the providers of
+    /// `secret_providers` (see [`secret::resolve`]).
     ///
     /// If the secret is a password, `secret` will be updated later when login to the mattermost
     /// server
-    pub fn update_secret_with_command(mut self) -> Result<Args> {
-        if let Some(command) = &self.mm_secret_cmd {
-            let params =
-                shell_words::split(command).context("Splitting mm_token_cmd into shell words")?;
-            debug!("Running command {}", command);
-            let output = Command::new(&params[0])
-                .args(&params[1..])
-                .output()
-                .context(format!("Error when running {}", &command))?;
-            let secret = String::from_utf8_lossy(&output.stdout);
-            if secret.len() == 0 {
-                bail!("command '{}' returns nothing", &command);
+    ///
+    /// Without secret, it is asked for on the terminal if there is one (see
+    /// [`secret::prompt_fallback`]).
+    pub fn resolve_secret(mut self) -> Result<Args> {
+        let failure = match secret::resolve(&secret::chain(&self)) {
+            Ok(Some((secret, vault))) => {
+                self.mm_secret = Some(secret);
+                self.secret_vault = vault;
+                return Ok(self);
+            }
+            Ok(None) => None,
+            Err(e) if self.mm_secret.is_some() => {
+                warn!(
+                    "Using `mm_secret` as no secret provider succeeded : {:#}",
+                    e
+                );
+                None
+            }
+            Err(e) => Some(e),
+        };
+        // No secret is needed for dry runs as nothing is sent
+        let dry_run = self.replay.is_some() || self.ssid_from_stdin;
+        let prompted = self.secret_providers.contains(&SecretSource::Prompt);
+        if self.mm_secret.is_none() && !dry_run && !prompted {
+            if let Some(secret) = secret::prompt_fallback(&self)? {
+                self.mm_secret = Some(secret);
+                return Ok(self);
             }
-            // /!\ Do not spit secret on stdout on released binary.
-            //debug!("setting secret to {}", secret);
-            self.mm_secret = Some(secret.to_string());
         }
-        Ok(self)
+        failure.map_or(Ok(self), Err)
     }
 
     /// Merge with precedence default [`Args`], config file and command line parameters.
     pub fn merge_config_and_params(&self) -> Result<Args> {
         let default_args = Args::default();
         debug!("default Args : {:#?}", default_args);
-        let conf_dir = ProjectDirs::from("net", "ams", "automattermostatus")
-            .expect("Unable to find a project dir")
-            .config_dir()
-            .to_owned();
-        fs::create_dir_all(&conf_dir)
-            .with_context(|| format!("Creating conf dir {:?}", &conf_dir))?;
-        let conf_file = conf_dir.join("automattermostatus.toml");
+        let conf_file = conf_file_path();
+        if let Some(conf_dir) = conf_file.parent() {
+            fs::create_dir_all(conf_dir)
+                .with_context(|| format!("Creating conf dir {:?}", conf_dir))
+                .map_err(Error::Platform)?;
+        }
         if !conf_file.exists() {
             info!("Write {:?} default config file", &conf_file);
-            fs::write(&conf_file, toml::to_string(&Args::default())?)
-                .unwrap_or_else(|_| panic!("Unable to write default config file {:?}", conf_file));
+            let mut first_run = Args::default();
+            first_run.status = self.first_run_status(&first_run.status);
+            // The default state directory is not pinned, so that it may move
+            first_run.state_dir = None;
+            fs::write(
+                &conf_file,
+                toml::to_string(&first_run)
+                    .context("Serializing default config")
+                    .map_err(Error::Config)?,
+            )
+            .unwrap_or_else(|_| panic!("Unable to write default config file {:?}", conf_file));
         }
+        crate::migration::migrate_file(&conf_file)
+            .context("Migrating conf file")
+            .map_err(Error::Config)?;
 
+        let content = fs::read_to_string(&conf_file)
+            .with_context(|| format!("Reading conf file {:?}", &conf_file))
+            .map_err(Error::Config)?;
+        for key in unknown_keys(&content)? {
+            warn!("Unknown key `{}` in {:?} is ignored", key, &conf_file);
+        }
         let config_args: Args = Figment::from(Toml::file(&conf_file))
             .extract()
-            .with_context(|| format!("Reading conf file {:?}", &conf_file))?;
+            .with_context(|| format!("Reading conf file {:?}", &conf_file))
+            .map_err(Error::Config)?;
         debug!("config Args : {:#?}", config_args);
+        let res = self.merge_with(&conf_file)?;
+        // The state is only moved to the default state directory if no other one
+        // is given
+        let state_dir_given = self.state_dir.is_some()
+            || config_args.state_dir.is_some()
+            || std::env::var_os(format!("{}STATE_DIR", ENV_PREFIX)).is_some();
+        if !state_dir_given {
+            crate::state::migrate(&crate::state::legacy_dir(), &crate::state::default_dir())
+                .unwrap_or_else(|e| warn!("Fail to migrate the state: {:#}", e));
+        }
+        Ok(res)
+    }
+
+    /// Return the default `status` of a new configuration file, whose `home` wifi
+    /// substring is replaced by `--home-ssid`, or by the strongest visible network
+    /// once confirmed on the terminal.
+    fn first_run_status(&self, status: &[String]) -> Vec<String> {
+        let ssid = match &self.home_ssid {
+            Some(ssid) => Some(ssid.to_owned()),
+            None if io::stdin().is_terminal() => {
+                let interface = self
+                    .interface_name
+                    .clone()
+                    .or(Args::default().interface_name)
+                    .unwrap_or_default();
+                WiFi::new(&interface)
+                    .visible_networks(&SystemRunner)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|networks| {
+                        Ok(import::ask_home_ssid(&networks, &mut io::stdin().lock())?)
+                    })
+                    .unwrap_or_else(|e| {
+                        warn!("Fail to suggest the home wifi network: {:#}", e);
+                        None
+                    })
+            }
+            None => {
+                info!("Set the home wifi network of the new configuration with `--home-ssid`");
+                None
+            }
+        };
+        match ssid {
+            Some(ssid) => import::with_home_ssid(status, &ssid),
+            None => status.to_vec(),
+        }
+    }
+
+    /// Merge default values, the `conf_file` configuration file, the environment
+    /// variables prefixed with [`ENV_PREFIX`] and `self`, in increasing priority order.
+    fn merge_with(&self, conf_file: &Path) -> Result<Args> {
         debug!("parameter Args : {:#?}", self);
-        // Merge config Default → Config File → command line args
-        let res = Figment::from(Serialized::defaults(Args::default()))
-            .merge(Toml::file(&conf_file))
+        // Merge config Default → Config File → Environment → command line args
+        let mut figment = Figment::from(Serialized::defaults(Args::default()))
+            .merge(Toml::file(conf_file))
+            .merge(Env::prefixed(ENV_PREFIX).ignore(&["status"]));
+        // A single environment variable holds all the triplets
+        if let Some(status) = status_from_env() {
+            figment = figment.merge(Serialized::default("status", status));
+        }
+        let res = figment
             .merge(Serialized::defaults(self))
+            .merge(Serialized::default(
+                "offdays",
+                self.offday.iter().copied().collect::<OffDays>(),
+            ))
             .extract()
-            .context("Merging configuration file and parameters")?;
+            .context("Merging configuration file, environment and parameters")
+            .map_err(Error::Config)?;
         debug!("Merged config and parameters : {:#?}", res);
         Ok(res)
     }
 }
+
+/// Return the status triplets of the `AMS_STATUS` environment variable,
+/// separated by [`STATUS_ENV_SEPARATOR`] (e.g.
+/// `home::house::wfh;office::corp::on site`)
+fn status_from_env() -> Option<Vec<String>> {
+    let value = std::env::var(format!("{}STATUS", ENV_PREFIX)).ok()?;
+    Some(
+        value
+            .split(STATUS_ENV_SEPARATOR)
+            .map(str::trim)
+            .filter(|triplet| !triplet.is_empty())
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod validate_should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn fail_when_mm_url_is_none() {
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            mm_url: None,
+            ..Default::default()
+        };
+        let err = args.validate().unwrap_err();
+        assert!(matches!(err, Error::Config(_)));
+        assert_eq!(err.to_string(), "`mm_url` is not defined");
+    }
+
+    #[test]
+    fn fail_when_delay_is_none() {
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            delay: None,
+            ..Default::default()
+        };
+        assert_eq!(
+            args.validate().unwrap_err().to_string(),
+            "`delay` is not defined"
+        );
+    }
+
+    #[test]
+    fn require_user_for_password() -> Result<()> {
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Password),
+            mm_user: None,
+            ..Default::default()
+        };
+        assert!(args.validate().is_err());
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Password),
+            mm_user: Some("bob".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            args.validate()?.credentials,
+            Credentials::Password { user, password } if user == "bob" && password == "AAA"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn build_scan_command() -> Result<()> {
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            wifi_scan_cmd: Some("iwctl station 'wlan 0' get-networks".to_string()),
+            wifi_scan_parser: Some(ScanParser::Nmcli),
+            ..Default::default()
+        };
+        assert_eq!(
+            args.validate()?.wifi_scan_cmd,
+            ScanCommand::new(
+                vec![
+                    "iwctl".to_string(),
+                    "station".to_string(),
+                    "wlan 0".to_string(),
+                    "get-networks".to_string()
+                ],
+                ScanParser::Nmcli
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn default_expiry_to_end() -> Result<()> {
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            end: Some("17:00".parse().unwrap()),
+            expires_at: None,
+            ..Default::default()
+        };
+        assert_eq!(args.validate()?.expires_at, Some("17:00".parse().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn report_all_problems() {
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            delay: None,
+            mm_url: Some("mattermost.example.com".to_string()),
+            begin: Some("18:00".parse().unwrap()),
+            end: Some("18:00".parse().unwrap()),
+            service_log_level: Some("lib=loud".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            args.validate().unwrap_err().to_string(),
+            "`delay` is not defined\n\
+             `mm_url` (mattermost.example.com) is not an http or https URL\n\
+             `service_log_level` (lib=loud) is not a valid log filter\n\
+             `begin` and `end` shall differ (18:00)"
+        );
+    }
+
+    #[test]
+    fn build_config() -> Result<()> {
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            delay: Some(30),
+            ..Default::default()
+        };
+        let config = args.validate()?;
+        assert_eq!(config.delay, 30);
+        assert!(matches!(config.credentials, Credentials::Token(token) if token == "AAA"));
+        assert_eq!(config.mm_url, "https://mattermost.example.com");
+        Ok(())
+    }
+
+    #[test]
+    fn tag_the_headset_status() -> Result<()> {
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            machine_tag: Some("laptop".to_string()),
+            headset_status: Some("headphones::focusing".to_string()),
+            ..Default::default()
+        };
+        let status = args.validate()?.headset_status.unwrap();
+        assert_eq!(status.emoji, "headphones");
+        assert_eq!(status.text, "focusing · laptop");
+        let args = Args {
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            headset_status: Some("focusing".to_string()),
+            ..Default::default()
+        };
+        assert!(args.problems()[0].contains("EMOJI::TEXT"));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod merge_should {
+    use super::*;
+    use crate::offtime::{Parity, Weekday};
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    #[allow(clippy::result_large_err)] // the jail returns a `figment::Error`
+    fn let_environment_override_conf_file() {
+        // The jail restores the environment and keeps other jailed tests out
+        figment::Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.toml",
+                "delay = 30\ndelay_jitter = 5\nstatus = []\nmic_app_names = []\n",
+            )?;
+            jail.set_env("AMS_DELAY_JITTER", "7");
+            let args = Args::parse_from(["automattermostatus", "--delay", "10"])
+                .merge_with(Path::new("config.toml"))
+                .map_err(|e| e.to_string())?;
+            assert_eq!(args.delay_jitter, Some(7));
+            // command line parameters still take precedence
+            assert_eq!(args.delay, Some(10));
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)] // the jail returns a `figment::Error`
+    fn split_status_from_environment() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.toml",
+                "status = ['home::house::at home']\nmic_app_names = []\n",
+            )?;
+            jail.set_env("AMS_STATUS", "corp::office::on site; ::sleeping::off ;");
+            let args = Args::parse_from(["automattermostatus"])
+                .merge_with(Path::new("config.toml"))
+                .map_err(|e| e.to_string())?;
+            assert_eq!(args.status, ["corp::office::on site", "::sleeping::off"]);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn add_command_line_offdays() -> Result<()> {
+        let conf_file = Temp::new_file().unwrap().to_path_buf();
+        fs::write(
+            &conf_file,
+            "status = []\nmic_app_names = []\n[offdays]\nsat = 'EveryWeek'\n",
+        )
+        .unwrap();
+        let args = Args::parse_from(["automattermostatus", "--offday", "wed:odd"])
+            .merge_with(&conf_file)?;
+        let expected: OffDays = [
+            OffDay(Weekday::Sat, Parity::EveryWeek),
+            OffDay(Weekday::Wed, Parity::OddWeek),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(args.offdays, expected);
+        assert!(Args::try_parse_from(["automattermostatus", "--offday", "wed:weekly"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn know_every_default_key() -> Result<()> {
+        let content = toml::to_string(&Args::default()).unwrap();
+        assert!(unknown_keys(&content)?.is_empty());
+        assert_eq!(
+            unknown_keys(&format!("mm_ulr = \"x\"\n{}", content))?,
+            vec!["mm_ulr"]
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod schema_should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn describe_config_keys() -> Result<()> {
+        let schema: serde_json::Value = serde_json::from_str(&schema().unwrap()).unwrap();
+        let properties = &schema["properties"];
+        assert!(properties["delay"]["description"]
+            .as_str()
+            .unwrap()
+            .contains("delay"));
+        assert_eq!(properties["verbose"]["type"], "string");
+        assert!(properties["command"].is_null());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod cli_should {
+    use super::*;
+    use clap::CommandFactory;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn be_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn validate_time_of_day() {
+        assert!(Args::try_parse_from(["automattermostatus", "--begin", "8:30"]).is_ok());
+        assert!(Args::try_parse_from(["automattermostatus", "--begin", "12:3O"]).is_err());
+        assert!(Args::try_parse_from(["automattermostatus", "--end", "25:00"]).is_err());
+        assert!(Args::try_parse_from(["automattermostatus", "--expires-at", "0"]).is_ok());
+    }
+
+    #[test]
+    fn parse_run_mode() {
+        let args = Args::try_parse_from(["automattermostatus", "run", "--once"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(SubCommand::Run {
+                once: true,
+                daemon: false
+            })
+        );
+        assert!(Args::try_parse_from(["automattermostatus", "run", "--once", "--daemon"]).is_err());
+    }
+
+    #[test]
+    fn parse_service_account() {
+        let args = Args::try_parse_from([
+            "automattermostatus",
+            "service",
+            "install",
+            "--account",
+            "CORP\\me",
+            "--password-prompt",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.command,
+            Some(SubCommand::Service(ServiceCommand::Install {
+                account: Some("CORP\\me".to_string()),
+                password_prompt: true,
+                system: false
+            }))
+        );
+        assert!(Args::try_parse_from([
+            "automattermostatus",
+            "service",
+            "install",
+            "--password-prompt"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn parse_values_ignoring_case() {
+        let args =
+            Args::try_parse_from(["automattermostatus", "--secret-type", "Token", "-vv"]).unwrap();
+        assert!(matches!(args.secret_type, Some(SecretType::Token)));
+        assert_eq!(args.verbose.get_level_filter(), "Debug");
+        let args =
+            Args::try_parse_from(["automattermostatus", "status", "set", "house", "home"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(SubCommand::Status(StatusCommand::Set {
+                emoji: "house".to_string(),
+                text: "home".to_string(),
+                expires_at: None
+            }))
+        );
+        let args = Args::try_parse_from([
+            "automattermostatus",
+            "location",
+            "set",
+            "office",
+            "--for",
+            "2h",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.command,
+            Some(SubCommand::Location(LocationCommand::Set {
+                name: "office".to_string(),
+                duration: Some(std::time::Duration::from_secs(7200))
+            }))
+        );
+    }
+}
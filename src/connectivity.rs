@@ -0,0 +1,219 @@
+//! Detection of captive portals.
+//!
+//! Behind the captive portal of a hotel or a train wifi, every request to the
+//! mattermost server hangs until timeout. When `connectivity_check_url` is set,
+//! it is requested before each update and the mattermost API calls are skipped
+//! until it answers `204 No Content`, as the connectivity check endpoints of the
+//! OS do (e.g. `http://connectivitycheck.gstatic.com/generate_204`).
+//!
+//! Started at boot, the network may not be up yet: the first login waits until
+//! `connectivity_check_url`, or else the ping endpoint of the mattermost server,
+//! is reachable (see [`wait_online`]).
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Timeout of the connectivity check request
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay between the connectivity checks while the network settles
+const SETTLE_STEP: Duration = Duration::from_secs(2);
+/// Path of the mattermost endpoint answering while the server is up
+pub const PING_PATH: &str = "/api/v4/system/ping";
+
+/// Result of a connectivity check
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Connectivity {
+    /// The check endpoint answered as expected
+    Full,
+    /// The check endpoint answered something else, usually a redirection to
+    /// the login page of a captive portal
+    Portal,
+    /// The check endpoint could not be reached
+    None,
+}
+
+/// Request `url` and return the corresponding [`Connectivity`]
+pub fn check(url: &str) -> Connectivity {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(CHECK_TIMEOUT)
+        .redirects(0)
+        .build();
+    match agent.get(url).call() {
+        Ok(response) if response.status() == 204 => Connectivity::Full,
+        Ok(response) => {
+            debug!("Connectivity check answered {}", response.status());
+            Connectivity::Portal
+        }
+        Err(ureq::Error::Status(code, _)) => {
+            debug!("Connectivity check answered {}", code);
+            Connectivity::Portal
+        }
+        Err(e) => {
+            debug!("Connectivity check failed: {}", e);
+            Connectivity::None
+        }
+    }
+}
+
+/// Return `true` if `url` answers with a success status
+fn reachable(url: &str) -> bool {
+    let agent = ureq::AgentBuilder::new().timeout(CHECK_TIMEOUT).build();
+    match agent.get(url).call() {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("{} is not reachable: {}", url, e);
+            false
+        }
+    }
+}
+
+/// Wait until `check_url` answers as expected, or the mattermost server at
+/// `mm_url` answers its ping if `None`. The delay between two checks starts at
+/// 2s and is doubled after each failure, up to `max_delay` seconds.
+///
+/// Return `false` if `stop` is set meanwhile.
+pub fn wait_online(
+    check_url: Option<&str>,
+    mm_url: &str,
+    max_delay: u32,
+    stop: &AtomicBool,
+) -> bool {
+    let ping_url = format!("{}{}", mm_url.trim_end_matches('/'), PING_PATH);
+    let mut attempts = 0;
+    loop {
+        let online = match check_url {
+            Some(url) => check(url) == Connectivity::Full,
+            None => reachable(&ping_url),
+        };
+        if online {
+            if attempts > 0 {
+                info!("Network is up");
+            }
+            return true;
+        }
+        if attempts == 0 {
+            info!("Waiting for the network before logging in");
+        }
+        let delay = crate::supervisor::backoff(2, attempts).min(max_delay.max(2));
+        crate::wakeup::sleep_until_clock_jump(Duration::from_secs(delay.into()), stop);
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        attempts += 1;
+    }
+}
+
+/// Wait up to `grace` for the network to settle after login (DHCP, VPN), and
+/// return `true` as soon as `url` answers as expected. Return `false` once
+/// `grace` has elapsed or `stop` is set.
+pub fn settle(url: Option<&str>, grace: Duration, stop: &AtomicBool) -> bool {
+    let start = Instant::now();
+    loop {
+        if url.is_some_and(|url| check(url) == Connectivity::Full) {
+            debug!("Network settled after {}s", start.elapsed().as_secs());
+            return true;
+        }
+        let left = grace.saturating_sub(start.elapsed());
+        if left.is_zero() || stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        sleep(left.min(SETTLE_STEP));
+    }
+}
+
+/// Watcher of the connectivity, logging its changes
+#[derive(Debug)]
+pub struct Watcher {
+    url: Option<String>,
+    last: Connectivity,
+}
+
+impl Watcher {
+    /// Check the connectivity with `url`, or always assume it is full if `None`
+    pub fn new(url: Option<String>) -> Self {
+        Self {
+            url,
+            last: Connectivity::Full,
+        }
+    }
+
+    /// Return `true` if the mattermost server may be reached
+    pub fn online(&mut self) -> bool {
+        let connectivity = match &self.url {
+            Some(url) => check(url),
+            None => Connectivity::Full,
+        };
+        if connectivity != self.last {
+            match connectivity {
+                Connectivity::Full => info!("Connectivity is back"),
+                Connectivity::Portal => {
+                    warn!("Captive portal detected, mattermost will not be updated")
+                }
+                Connectivity::None => warn!("No connectivity, mattermost will not be updated"),
+            }
+            self.last = connectivity;
+        }
+        connectivity == Connectivity::Full
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use httpmock::prelude::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn detect_captive_portal() {
+        let server = MockServer::start();
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/generate_204");
+            resp_with.status(204);
+        });
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/portal");
+            resp_with
+                .status(302)
+                .header("Location", "http://login.example.com/");
+        });
+        assert_eq!(check(&server.url("/generate_204")), Connectivity::Full);
+        assert_eq!(check(&server.url("/portal")), Connectivity::Portal);
+        let mut watcher = Watcher::new(Some(server.url("/portal")));
+        assert!(!watcher.online());
+        assert!(Watcher::new(None).online());
+    }
+
+    #[test]
+    fn stop_waiting_once_online() {
+        let server = MockServer::start();
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/generate_204");
+            resp_with.status(204);
+        });
+        let stop = AtomicBool::new(false);
+        let grace = Duration::from_secs(60);
+        assert!(settle(Some(&server.url("/generate_204")), grace, &stop));
+        stop.store(true, Ordering::Relaxed);
+        assert!(!settle(None, grace, &stop));
+    }
+
+    #[test]
+    fn wait_for_the_mattermost_server() {
+        let server = MockServer::start();
+        let ping = server.mock(|expect, resp_with| {
+            expect.method(GET).path(PING_PATH);
+            resp_with.status(200).body(r#"{"status":"OK"}"#);
+        });
+        let stop = AtomicBool::new(false);
+        assert!(wait_online(None, &server.url("/"), 60, &stop));
+        ping.assert();
+        stop.store(true, Ordering::Relaxed);
+        assert!(!wait_online(
+            Some(&server.url("/portal")),
+            &server.url(""),
+            60,
+            &stop
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+//! D-Bus properties for desktop widgets (`dbus` feature, linux only).
+//!
+//! When `dbus_properties` is set, the current location, the last custom status
+//! sent and the pause are exported on the session bus as properties of the
+//! [`BUS_NAME`] interface of the [`OBJECT_PATH`] object, the service owning the
+//! same name. Their changes are signaled with
+//! `org.freedesktop.DBus.Properties.PropertiesChanged`, so that a GNOME Shell
+//! extension or a KDE plasmoid may show them without polling (see
+//! `examples/dbus_widget.rs`).
+use tracing::debug;
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::dbus_interface;
+
+use crate::events::{Event, Observer};
+use crate::mattermost::MMCustomStatus;
+use crate::state::Location;
+
+/// Well-known name of the service, and name of its interface
+pub const BUS_NAME: &str = "com.gitlab.matclab.Automattermostatus";
+/// Path of the object holding the properties
+pub const OBJECT_PATH: &str = "/com/gitlab/matclab/Automattermostatus";
+
+/// Properties changed by an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// `Location`
+    Location,
+    /// `StatusEmoji` and `StatusText`
+    Status,
+    /// `Paused` and `PausedUntil`
+    Pause,
+}
+
+/// Properties exported on the bus
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Properties {
+    location: String,
+    status_emoji: String,
+    status_text: String,
+    paused_until: Option<i64>,
+}
+
+impl Properties {
+    /// Create the properties of `location`, of the last custom `status` sent and
+    /// of the pause ending at the UTC timestamp `paused_until`
+    pub fn new(
+        location: &Location,
+        status: Option<&MMCustomStatus>,
+        paused_until: Option<i64>,
+    ) -> Self {
+        let mut res = Self {
+            paused_until,
+            ..Default::default()
+        };
+        res.apply(&Event::LocationChanged {
+            from: Location::Unknown,
+            to: location.clone(),
+        });
+        if let Some(status) = status {
+            res.apply(&Event::StatusSent(status.clone()));
+        }
+        res
+    }
+
+    /// Update the properties according to `event` and return what changed
+    pub fn apply(&mut self, event: &Event) -> Option<Change> {
+        match event {
+            Event::LocationChanged { to, .. } => {
+                self.location = match to {
+                    Location::Known(wifi) => wifi.clone(),
+                    Location::Unknown => String::new(),
+                };
+                Some(Change::Location)
+            }
+            Event::StatusSent(status) => {
+                self.status_emoji = status.emoji.clone();
+                self.status_text = status.text.clone();
+                Some(Change::Status)
+            }
+            Event::PauseChanged(until) => {
+                self.paused_until = *until;
+                Some(Change::Pause)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Interface of the properties, whose signaling methods (`*_changed`) are
+/// generated by the macro
+mod interface {
+    #![allow(missing_docs)]
+    use super::*;
+
+    #[dbus_interface(name = "com.gitlab.matclab.Automattermostatus")]
+    impl Properties {
+        /// Wifi substring of the current location, empty if unknown or off time
+        #[dbus_interface(property)]
+        pub fn location(&self) -> String {
+            self.location.clone()
+        }
+
+        /// Emoji name of the last custom status sent
+        #[dbus_interface(property)]
+        pub fn status_emoji(&self) -> String {
+            self.status_emoji.clone()
+        }
+
+        /// Text of the last custom status sent
+        #[dbus_interface(property)]
+        pub fn status_text(&self) -> String {
+            self.status_text.clone()
+        }
+
+        /// The status update loop is paused (see `pause`)
+        #[dbus_interface(property)]
+        pub fn paused(&self) -> bool {
+            self.paused_until.is_some()
+        }
+
+        /// UTC timestamp of the end of the pause, `0` if not paused
+        #[dbus_interface(property)]
+        pub fn paused_until(&self) -> i64 {
+            self.paused_until.unwrap_or_default()
+        }
+    }
+}
+
+/// [`Observer`] exporting the [`Properties`] on the session bus
+#[derive(Debug)]
+pub struct Widget {
+    connection: Connection,
+}
+
+impl Widget {
+    /// Own [`BUS_NAME`] on the session bus and export `properties`
+    pub fn serve(properties: Properties) -> zbus::Result<Self> {
+        let connection = ConnectionBuilder::session()?
+            .name(BUS_NAME)?
+            .serve_at(OBJECT_PATH, properties)?
+            .build()?;
+        Ok(Self { connection })
+    }
+
+    /// Apply `event` to the exported properties and signal their changes
+    fn update(&self, event: &Event) -> zbus::Result<()> {
+        let iface = self
+            .connection
+            .object_server()
+            .interface::<_, Properties>(OBJECT_PATH)?;
+        let mut properties = iface.get_mut();
+        let ctxt = iface.signal_context();
+        zbus::block_on(async {
+            match properties.apply(event) {
+                Some(Change::Location) => properties.location_changed(ctxt).await,
+                Some(Change::Status) => {
+                    properties.status_emoji_changed(ctxt).await?;
+                    properties.status_text_changed(ctxt).await
+                }
+                Some(Change::Pause) => {
+                    properties.paused_changed(ctxt).await?;
+                    properties.paused_until_changed(ctxt).await
+                }
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+impl Observer for Widget {
+    fn notify(&self, event: &Event) {
+        if let Err(e) = self.update(event) {
+            debug!("Fail to update the D-Bus properties : {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn follow_the_events() {
+        let status = MMCustomStatus::new("At home".to_string(), "house".to_string());
+        let mut properties =
+            Properties::new(&Location::Known("home".to_string()), Some(&status), None);
+        assert_eq!(properties.location(), "home");
+        assert_eq!(properties.status_text(), "At home");
+        assert!(!properties.paused());
+        assert_eq!(
+            properties.apply(&Event::PauseChanged(Some(1_700_000_000))),
+            Some(Change::Pause)
+        );
+        assert_eq!(properties.paused_until(), 1_700_000_000);
+        assert_eq!(properties.apply(&Event::DndEntered), None);
+        properties.apply(&Event::LocationChanged {
+            from: Location::Known("home".to_string()),
+            to: Location::Unknown,
+        });
+        assert_eq!(properties.location(), "");
+    }
+}
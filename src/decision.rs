@@ -0,0 +1,472 @@
+//! Decision taken at each cycle of the main loop.
+//!
+//! [`decide`] is a pure function of what has just been observed (visible networks,
+//! busy signals, work schedule, time) and of what is remembered from the
+//! previous cycles. The main loop only does the I/O around it: scanning, then
+//! applying the [`Decision`] to mattermost.
+use tracing::debug;
+
+use crate::config::Config;
+use crate::grace::UnknownGrace;
+use crate::priority::{self, StatusSource};
+use crate::signal::{self, Reading};
+use crate::state::{Evidence, Location, LocationRule, Refresh};
+use crate::wifiscan::Network;
+use crate::{find_location, matching_network};
+
+/// What has been observed during a cycle
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Observation<'a> {
+    /// visible wifi networks (none during off time or if the wifi is disabled)
+    pub networks: &'a [Network],
+    /// readings of the busy signals (see [`signal::PresenceSignal`]), with the
+    /// source they feed
+    pub signals: &'a [(StatusSource, Reading)],
+    /// one of the `headset_names` is connected
+    pub headset: bool,
+    /// an inbound remote session is active
+    pub remote: bool,
+    /// we are outside of the work period
+    pub off_time: bool,
+    /// on battery or on a metered connection
+    pub saving: bool,
+    /// the computer has just been resumed, or the clock has jumped
+    pub resumed: bool,
+    /// UTC timestamp of the observation
+    pub now: i64,
+}
+
+/// Hysteresis of [`Previous::default`]
+static NO_GRACE: UnknownGrace = UnknownGrace::new(0, 0);
+
+/// What is remembered from the previous cycles
+#[derive(Debug, Clone, Copy)]
+pub struct Previous<'a> {
+    /// hysteresis of the unknown locations
+    pub grace: &'a UnknownGrace,
+    /// location set by hand with `location set`
+    pub pinned: Option<&'a Location>,
+    /// the custom status has been changed by hand and the cooldown is not over
+    pub manual_change_pending: bool,
+}
+
+impl Default for Previous<'_> {
+    fn default() -> Self {
+        Previous {
+            grace: &NO_GRACE,
+            pinned: None,
+            manual_change_pending: false,
+        }
+    }
+}
+
+/// What shall be done during a cycle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    /// current location
+    pub location: Location,
+    /// why `location` was chosen
+    pub evidence: Option<Evidence>,
+    /// source allowed to write during this cycle (see [`priority::resolve`])
+    pub winner: Option<StatusSource>,
+    /// the custom status of `location` shall be sent
+    pub set_custom_status: bool,
+    /// the *do not disturb* presence shall follow the microphone usage
+    pub update_dnd: bool,
+    /// `headset_status` replaces the custom status of `location`
+    pub headset: bool,
+    /// `remote_status` replaces the custom status of `location`
+    pub remote: bool,
+    /// policy for sending again an unchanged custom status
+    pub refresh: Refresh,
+    /// delay in seconds before the next cycle, before any backoff
+    pub delay: u32,
+    /// hysteresis to remember for the next cycle
+    pub grace: UnknownGrace,
+}
+
+/// Decide what to do according to `observation` and to the `previous` cycles
+pub fn decide(
+    config: &Config,
+    rules: &[LocationRule],
+    observation: &Observation,
+    previous: &Previous,
+) -> Decision {
+    let ssids: Vec<String> = observation
+        .networks
+        .iter()
+        .map(|n| n.ssid.clone())
+        .collect();
+    let found = find_location(
+        rules,
+        &ssids,
+        observation.off_time,
+        config.match_case_insensitive,
+    );
+    let mut grace = previous.grace.clone();
+    let location = grace.filter(found.clone(), observation.now);
+    let evidence = match &found {
+        Location::Known(wifi) if wifi.is_empty() => Some(Evidence::OffTime),
+        Location::Known(_) => {
+            matching_network(&found, observation.networks, config.match_case_insensitive)
+                .map(Evidence::Wifi)
+        }
+        Location::Unknown if location != Location::Unknown => Some(Evidence::Grace),
+        Location::Unknown => None,
+    };
+    // A location set by hand wins over the scans, but not over off time
+    let (location, evidence) = match previous.pinned {
+        Some(pinned) if !observation.off_time => (pinned.clone(), Some(Evidence::Manual)),
+        _ => (location, evidence),
+    };
+    let active = priority::Active {
+        manual: previous.manual_change_pending,
+        mic: signal::aggregate(signal::of(observation.signals, StatusSource::Mic)).is_busy(),
+        focus: signal::aggregate(signal::of(observation.signals, StatusSource::Focus)).is_busy(),
+        location: matches!(&location, Location::Known(wifi) if !wifi.is_empty()),
+        off_time: observation.off_time,
+    };
+    let winner = priority::resolve(&config.priority, active);
+    let refresh = if observation.resumed {
+        Refresh::Now
+    } else if observation.saving {
+        Refresh::Never
+    } else {
+        Refresh::Periodic
+    };
+    let delay = if observation.saving {
+        debug!(
+            "On battery or metered connection, waiting {}s",
+            config.delay_on_battery
+        );
+        config.delay_on_battery
+    } else {
+        config.delay
+    };
+    Decision {
+        location,
+        evidence,
+        winner,
+        set_custom_status: priority::may_set_custom_status(winner),
+        // Leaving do not disturb is always allowed
+        update_dnd: matches!(winner, Some(StatusSource::Mic) | Some(StatusSource::Focus))
+            || !active.mic,
+        headset: observation.headset && config.headset_status.is_some() && !observation.off_time,
+        remote: observation.remote && config.remote_status.is_some() && !observation.off_time,
+        refresh,
+        delay,
+        grace,
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::config::{Args, SecretType};
+    use crate::error::Result;
+    use crate::mattermost::MMCustomStatus;
+    use test_log::test; // Automatically trace tests
+
+    fn config(grace_scans: u32) -> Result<Config> {
+        Args {
+            status: vec![
+                "home::house::at home".to_string(),
+                "::sleeping::off".to_string(),
+            ],
+            mic_app_names: vec!["zoom".to_string()],
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            delay: Some(60),
+            delay_on_battery: Some(300),
+            unknown_grace_scans: Some(grace_scans),
+            ..Default::default()
+        }
+        .validate()
+    }
+
+    fn home() -> Location {
+        Location::Known("home".to_string())
+    }
+
+    fn networks(ssids: &[&str]) -> Vec<Network> {
+        ssids.iter().map(|ssid| Network::new(*ssid)).collect()
+    }
+
+    fn busy(source: StatusSource, reason: &str) -> [(StatusSource, Reading); 1] {
+        [(source, Reading::Busy(vec![reason.to_string()]))]
+    }
+
+    #[test]
+    fn set_the_status_of_a_visible_location() -> Result<()> {
+        let config = config(0)?;
+        let rules = crate::prepare_status(&config)?;
+        let networks = vec![
+            Network {
+                ssid: "home".to_string(),
+                signal: Some(-40),
+            },
+            Network::new("neighbour"),
+        ];
+        let observation = Observation {
+            networks: &networks,
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &observation, &Previous::default());
+        assert_eq!(decision.location, home());
+        assert_eq!(decision.evidence, Some(Evidence::Wifi(networks[0].clone())));
+        assert_eq!(decision.winner, Some(StatusSource::Location));
+        assert!(decision.set_custom_status);
+        assert!(decision.update_dnd);
+        assert_eq!(decision.refresh, Refresh::Periodic);
+        assert_eq!(decision.delay, 60);
+        Ok(())
+    }
+
+    #[test]
+    fn find_nothing_at_an_unknown_location() -> Result<()> {
+        let config = config(0)?;
+        let rules = crate::prepare_status(&config)?;
+        let networks = networks(&["elsewhere"]);
+        let observation = Observation {
+            networks: &networks,
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &observation, &Previous::default());
+        assert_eq!(decision.location, Location::Unknown);
+        assert_eq!(decision.evidence, None);
+        assert_eq!(decision.winner, None);
+        Ok(())
+    }
+
+    #[test]
+    fn use_the_off_time_location() -> Result<()> {
+        let config = config(0)?;
+        let rules = crate::prepare_status(&config)?;
+        let pinned = home();
+        let observation = Observation {
+            off_time: true,
+            ..Default::default()
+        };
+        let previous = Previous {
+            pinned: Some(&pinned),
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &observation, &previous);
+        assert_eq!(decision.location, Location::Known("".to_string()));
+        assert_eq!(decision.evidence, Some(Evidence::OffTime));
+        assert_eq!(decision.winner, Some(StatusSource::OffTime));
+        assert!(decision.set_custom_status);
+        Ok(())
+    }
+
+    #[test]
+    fn prefer_the_pinned_location() -> Result<()> {
+        let config = config(0)?;
+        let rules = crate::prepare_status(&config)?;
+        let pinned = Location::Known("office".to_string());
+        let networks = networks(&["home"]);
+        let observation = Observation {
+            networks: &networks,
+            ..Default::default()
+        };
+        let previous = Previous {
+            pinned: Some(&pinned),
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &observation, &previous);
+        assert_eq!(decision.location, pinned);
+        assert_eq!(decision.evidence, Some(Evidence::Manual));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_the_location_during_the_grace_period() -> Result<()> {
+        let config = config(1)?;
+        let rules = crate::prepare_status(&config)?;
+        let home_networks = networks(&["home"]);
+        let seen = Observation {
+            networks: &home_networks,
+            ..Default::default()
+        };
+        let grace = UnknownGrace::new(config.unknown_grace_scans, 0);
+        let first = decide(
+            &config,
+            &rules,
+            &seen,
+            &Previous {
+                grace: &grace,
+                ..Default::default()
+            },
+        );
+        let lost = Observation::default();
+        let second = decide(
+            &config,
+            &rules,
+            &lost,
+            &Previous {
+                grace: &first.grace,
+                ..Default::default()
+            },
+        );
+        assert_eq!(second.location, home());
+        assert_eq!(second.evidence, Some(Evidence::Grace));
+        let third = decide(
+            &config,
+            &rules,
+            &lost,
+            &Previous {
+                grace: &second.grace,
+                ..Default::default()
+            },
+        );
+        assert_eq!(third.location, Location::Unknown);
+        // The previous state is left untouched
+        assert_eq!(grace, UnknownGrace::new(1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn let_the_microphone_win_over_the_location() -> Result<()> {
+        let config = config(0)?;
+        let rules = crate::prepare_status(&config)?;
+        let networks = networks(&["home"]);
+        let signals = busy(StatusSource::Mic, "zoom");
+        let observation = Observation {
+            networks: &networks,
+            signals: &signals,
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &observation, &Previous::default());
+        assert_eq!(decision.winner, Some(StatusSource::Mic));
+        assert!(!decision.set_custom_status);
+        assert!(decision.update_dnd);
+        Ok(())
+    }
+
+    #[test]
+    fn set_dnd_during_calls_without_microphone() -> Result<()> {
+        let config = config(0)?;
+        let rules = crate::prepare_status(&config)?;
+        let networks = networks(&["home"]);
+        let signals = [
+            (
+                StatusSource::Mic,
+                Reading::Unknown("No sound card".to_string()),
+            ),
+            busy(StatusSource::Mic, "teams-for-linux")[0].clone(),
+        ];
+        let observation = Observation {
+            networks: &networks,
+            signals: &signals,
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &observation, &Previous::default());
+        assert_eq!(decision.winner, Some(StatusSource::Mic));
+        assert!(decision.update_dnd);
+        Ok(())
+    }
+
+    #[test]
+    fn follow_the_connected_headset() -> Result<()> {
+        let config = Config {
+            headset_status: Some(MMCustomStatus::new(
+                "focusing".to_string(),
+                "headphones".to_string(),
+            )),
+            ..config(0)?
+        };
+        let rules = crate::prepare_status(&config)?;
+        let networks = networks(&["home"]);
+        let observation = Observation {
+            networks: &networks,
+            headset: true,
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &observation, &Previous::default());
+        assert_eq!(decision.winner, Some(StatusSource::Location));
+        assert!(decision.set_custom_status);
+        assert!(decision.headset);
+        // With `headset_dnd`, the headset is also a busy signal
+        let signals = busy(StatusSource::Mic, "WH-1000XM4");
+        let observation = Observation {
+            signals: &signals,
+            ..observation
+        };
+        let decision = decide(&config, &rules, &observation, &Previous::default());
+        assert_eq!(decision.winner, Some(StatusSource::Mic));
+        assert!(decision.update_dnd);
+        Ok(())
+    }
+
+    #[test]
+    fn set_dnd_and_status_while_focusing() -> Result<()> {
+        let config = config(0)?;
+        let rules = crate::prepare_status(&config)?;
+        let signals = busy(StatusSource::Focus, "focus time");
+        let observation = Observation {
+            signals: &signals,
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &observation, &Previous::default());
+        assert_eq!(decision.location, Location::Unknown);
+        assert_eq!(decision.winner, Some(StatusSource::Focus));
+        assert!(decision.set_custom_status);
+        assert!(decision.update_dnd);
+        // Focus sessions started by hand win over off time
+        let off_time = Observation {
+            off_time: true,
+            ..observation
+        };
+        let decision = decide(&config, &rules, &off_time, &Previous::default());
+        assert_eq!(decision.winner, Some(StatusSource::Focus));
+        Ok(())
+    }
+
+    #[test]
+    fn respect_manual_changes() -> Result<()> {
+        let config = config(0)?;
+        let rules = crate::prepare_status(&config)?;
+        let networks = networks(&["home"]);
+        let signals = busy(StatusSource::Mic, "zoom");
+        let observation = Observation {
+            networks: &networks,
+            signals: &signals,
+            ..Default::default()
+        };
+        let previous = Previous {
+            manual_change_pending: true,
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &observation, &previous);
+        assert_eq!(decision.winner, Some(StatusSource::Manual));
+        assert!(!decision.set_custom_status);
+        assert!(!decision.update_dnd);
+        // Leaving do not disturb is always allowed
+        let observation = Observation {
+            signals: &[],
+            ..observation
+        };
+        assert!(decide(&config, &rules, &observation, &previous).update_dnd);
+        Ok(())
+    }
+
+    #[test]
+    fn adapt_refresh_and_delay() -> Result<()> {
+        let config = config(0)?;
+        let rules = crate::prepare_status(&config)?;
+        let saving = Observation {
+            saving: true,
+            ..Default::default()
+        };
+        let decision = decide(&config, &rules, &saving, &Previous::default());
+        assert_eq!(decision.refresh, Refresh::Never);
+        assert_eq!(decision.delay, 300);
+        let resumed = Observation {
+            resumed: true,
+            ..saving
+        };
+        let decision = decide(&config, &rules, &resumed, &Previous::default());
+        assert_eq!(decision.refresh, Refresh::Now);
+        Ok(())
+    }
+}
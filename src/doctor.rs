@@ -0,0 +1,353 @@
+//! Diagnosis of the environment (`doctor` subcommand)
+//!
+//! Most installation problems come from a missing platform tool, an unreachable
+//! keyring, an invalid configuration file, a server out of reach or a wrong
+//! clock. Each of them is checked and the [`report`] tells which ones pass,
+//! which ones fail and why.
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::command::CommandRunner;
+use crate::config::{Args, Config};
+use crate::connectivity::{self, Connectivity, PING_PATH};
+use crate::mattermost::client;
+use crate::secret::{KeyringProvider, SecretProvider, DEFAULT_KEYRING_SERVICE};
+
+/// Largest difference between our clock and the one of the server
+pub const MAX_CLOCK_SKEW: chrono::Duration = chrono::Duration::minutes(5);
+
+/// Outcome of a check
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// works as expected
+    Pass,
+    /// not used by the configuration, or optional
+    Warn,
+    /// prevents automattermostatus from working
+    Fail,
+}
+
+/// A diagnosed part of the environment
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    /// what is checked
+    pub name: String,
+    /// outcome of the check
+    pub verdict: Verdict,
+    /// what was found, or why it fails
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, verdict: Verdict, detail: impl Into<String>) -> Self {
+        Check {
+            name: name.into(),
+            verdict,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Platform tool spawned by the scans: program, arguments printing its version
+/// or usage, use and whether the scans fail without it
+type Tool = (&'static str, &'static [&'static str], &'static str, bool);
+
+#[cfg(target_os = "linux")]
+const TOOLS: &[Tool] = &[
+    ("nmcli", &["--version"], "wifi scan", true),
+    ("pactl", &["--version"], "headset detection", false),
+];
+#[cfg(target_os = "macos")]
+const TOOLS: &[Tool] = &[
+    ("networksetup", &["-version"], "wifi state", true),
+    (
+        "/System/Library/PrivateFrameworks/Apple80211.framework/Versions/A/Resources/airport",
+        &["-h"],
+        "wifi scan",
+        true,
+    ),
+    ("ioreg", &["-h"], "microphone scan", true),
+    ("pmset", &["-g", "batt"], "power source", false),
+    (
+        "system_profiler",
+        &["-listDataTypes"],
+        "headset detection",
+        false,
+    ),
+];
+#[cfg(target_os = "windows")]
+const TOOLS: &[Tool] = &[
+    ("netsh", &["wlan", "show", "interfaces"], "wifi scan", true),
+    ("powershell", &["-Help"], "microphone scan", true),
+];
+
+/// Check that the platform tools can be spawned through `runner`
+pub fn tools(runner: &dyn CommandRunner) -> Vec<Check> {
+    TOOLS
+        .iter()
+        .map(|(program, args, usage, required)| {
+            let name = format!("tool {}", program.rsplit('/').next().unwrap_or(program));
+            match runner.run(program, args) {
+                Ok(_) => Check::new(name, Verdict::Pass, format!("found ({})", usage)),
+                Err(e) => {
+                    let verdict = match required {
+                        true => Verdict::Fail,
+                        false => Verdict::Warn,
+                    };
+                    Check::new(name, verdict, format!("{} unavailable: {}", usage, e))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Check that the configuration file and the command line parameters, merged
+/// in `args`, hold a valid configuration, and return it
+pub fn config(args: crate::error::Result<Args>) -> (Check, Option<Config>) {
+    let mut args = match args {
+        Ok(args) => args,
+        Err(e) => {
+            return (
+                Check::new("config", Verdict::Fail, format!("{:#}", e)),
+                None,
+            )
+        }
+    };
+    // The secret is checked on its own
+    args.mm_secret.get_or_insert_with(String::new);
+    match args.validate() {
+        Ok(config) => (Check::new("config", Verdict::Pass, "valid"), Some(config)),
+        Err(e) => (
+            Check::new("config", Verdict::Fail, format!("{:#}", e)),
+            None,
+        ),
+    }
+}
+
+/// Check that the OS keyring answers, with the entry of `mm_user` in
+/// `keyring_service` if set
+pub fn keyring(args: &Args) -> Check {
+    if args.keyring_service.is_some() && args.mm_user.is_some() {
+        let provider = KeyringProvider {
+            service: args.keyring_service.clone(),
+            user: args.mm_user.clone(),
+            target: args.keyring_target.clone(),
+        };
+        return match provider.secret() {
+            Ok(_) => Check::new("keyring", Verdict::Pass, "secret found"),
+            Err(e) => Check::new("keyring", Verdict::Fail, format!("{:#}", e)),
+        };
+    }
+    let probe = keyring::Entry::new(DEFAULT_KEYRING_SERVICE, "doctor")
+        .and_then(|entry| entry.get_password());
+    match probe {
+        Ok(_) | Err(keyring::Error::NoEntry) => Check::new(
+            "keyring",
+            Verdict::Pass,
+            "available (`keyring_service` is not set)",
+        ),
+        Err(e) => Check::new(
+            "keyring",
+            Verdict::Warn,
+            format!("unavailable, the secret shall come from elsewhere: {}", e),
+        ),
+    }
+}
+
+/// Check that the mattermost server of `config` answers its ping, and that our
+/// clock agrees with the `Date` of its answer
+pub fn server(config: &Config) -> Vec<Check> {
+    let mut res = Vec::new();
+    if let Some(url) = &config.connectivity_check_url {
+        res.push(match connectivity::check(url) {
+            Connectivity::Full => Check::new("connectivity", Verdict::Pass, url.as_str()),
+            Connectivity::Portal => {
+                Check::new("connectivity", Verdict::Fail, "behind a captive portal")
+            }
+            Connectivity::None => Check::new(
+                "connectivity",
+                Verdict::Fail,
+                format!("{} unreachable", url),
+            ),
+        });
+    }
+    let uri = config.mm_url.trim_end_matches('/').to_owned() + PING_PATH;
+    let (check, response) = match client::agent(&config.client).get(&uri).call() {
+        Ok(response) => (
+            Check::new("server", Verdict::Pass, config.mm_url.as_str()),
+            response,
+        ),
+        // An unhealthy server still tells its time
+        Err(ureq::Error::Status(code, response)) => (
+            Check::new(
+                "server",
+                Verdict::Fail,
+                format!("{} answered {}", config.mm_url, code),
+            ),
+            response,
+        ),
+        Err(e) => {
+            res.push(Check::new(
+                "server",
+                Verdict::Fail,
+                format!("{} unreachable: {}", config.mm_url, e),
+            ));
+            return res;
+        }
+    };
+    res.push(check);
+    res.push(
+        match response
+            .header("Date")
+            .map(|date| clock_skew(date, Utc::now()))
+        {
+            Some(Ok(skew)) if skew.abs() <= MAX_CLOCK_SKEW => {
+                Check::new("clock", Verdict::Pass, "in sync with the server")
+            }
+            Some(Ok(skew)) => Check::new(
+                "clock",
+                Verdict::Fail,
+                format!(
+                    "{}s off the server clock, custom statuses expire at the wrong time",
+                    skew.num_seconds()
+                ),
+            ),
+            Some(Err(e)) => Check::new("clock", Verdict::Warn, format!("{:#}", e)),
+            None => Check::new("clock", Verdict::Warn, "the server does not tell its time"),
+        },
+    );
+    res
+}
+
+/// Return how far `now` is ahead of the HTTP `date` (RFC 2822)
+/// ```
+/// use lib::doctor::clock_skew;
+/// let now = chrono::DateTime::parse_from_rfc3339("2024-03-15T09:02:00Z").unwrap();
+/// let skew = clock_skew("Fri, 15 Mar 2024 09:00:00 GMT", now.into()).unwrap();
+/// assert_eq!(skew.num_seconds(), 120);
+/// ```
+pub fn clock_skew(date: &str, now: DateTime<Utc>) -> anyhow::Result<chrono::Duration> {
+    let date = DateTime::parse_from_rfc2822(date)
+        .map_err(|e| anyhow!("Invalid server date {:?}: {}", date, e))?;
+    Ok(now.signed_duration_since(date))
+}
+
+/// Check that the state can be written in `dir`
+pub fn permissions(dir: &Path) -> Check {
+    let probe = dir.join(".doctor");
+    let res = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&probe, b""))
+        .and_then(|_| fs::remove_file(&probe));
+    match res {
+        Ok(()) => Check::new("state directory", Verdict::Pass, dir.display().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Check::new(
+            "state directory",
+            Verdict::Fail,
+            format!("{} is not writable", dir.display()),
+        ),
+        Err(e) => Check::new(
+            "state directory",
+            Verdict::Fail,
+            format!("{}: {}", dir.display(), e),
+        ),
+    }
+}
+
+/// Return the report of `checks`, one per line, the verdicts being colored with
+/// ANSI escape codes if `color` is set
+pub fn report(checks: &[Check], color: bool) -> String {
+    let width = checks
+        .iter()
+        .map(|c| c.name.len())
+        .max()
+        .unwrap_or_default();
+    let mut res = String::new();
+    for check in checks {
+        let (label, code) = match check.verdict {
+            Verdict::Pass => ("PASS", "32"),
+            Verdict::Warn => ("WARN", "33"),
+            Verdict::Fail => ("FAIL", "31"),
+        };
+        let label = match color {
+            true => format!("\x1b[{}m{}\x1b[0m", code, label),
+            false => label.to_string(),
+        };
+        let _ = writeln!(
+            res,
+            "[{}] {:width$}  {}",
+            label,
+            check.name,
+            check.detail,
+            width = width
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::command::MockCommandRunner;
+    use httpmock::prelude::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn fail_on_missing_required_tools() {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .returning(|_, _| Err(io::Error::from(io::ErrorKind::NotFound)));
+        let checks = tools(&runner);
+        assert_eq!(checks.len(), TOOLS.len());
+        assert!(checks.iter().any(|c| c.verdict == Verdict::Fail));
+        assert!(checks.iter().all(|c| c.verdict != Verdict::Pass));
+    }
+
+    #[test]
+    fn check_server_and_clock() {
+        let mm = MockServer::start();
+        let date = (Utc::now() - chrono::Duration::hours(1)).to_rfc2822();
+        mm.mock(|when, then| {
+            when.method(GET).path(PING_PATH);
+            then.status(200)
+                .header("Date", date.as_str())
+                .body(r#"{"status":"OK"}"#);
+        });
+        let args = Args {
+            mm_url: Some(mm.url("")),
+            secret_type: Some(crate::config::SecretType::Token),
+            ..Default::default()
+        };
+        let (check, config) = config(Ok(args));
+        assert_eq!(check.verdict, Verdict::Pass);
+        let checks = server(&config.unwrap());
+        assert_eq!(checks[0].verdict, Verdict::Pass);
+        assert_eq!(checks[1].name, "clock");
+        assert_eq!(checks[1].verdict, Verdict::Fail);
+    }
+
+    #[test]
+    fn report_aligned_verdicts() {
+        let checks = [
+            Check::new("config", Verdict::Pass, "valid"),
+            Check::new("tool nmcli", Verdict::Fail, "wifi scan unavailable"),
+        ];
+        assert_eq!(
+            report(&checks, false),
+            "[PASS] config      valid\n[FAIL] tool nmcli  wifi scan unavailable\n"
+        );
+        assert!(report(&checks, true).starts_with("[\x1b[32mPASS\x1b[0m]"));
+    }
+
+    #[test]
+    fn check_the_state_directory() -> anyhow::Result<()> {
+        let dir = mktemp::Temp::new_dir()?;
+        assert_eq!(permissions(&dir).verdict, Verdict::Pass);
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+//! Crate level [`Error`] returned by the public API.
+//!
+//! Each variant wraps the underlying [`anyhow::Error`] (with its context chain), so
+//! that the message is unchanged while embedding applications may match on the
+//! error category:
+//! ```
+//! use lib::{Error, WifiStatusConfig};
+//! match "not a triplet".parse::<WifiStatusConfig>() {
+//!     Err(Error::Config(e)) => println!("Please fix your configuration: {:#}", e),
+//!     _ => unreachable!(),
+//! }
+//! ```
+//!
+//! The category also gives the exit code of the binary (see [`Error::exit_code`]),
+//! so that service managers and monitoring scripts may react to it:
+//!
+//! | code | meaning                                                        |
+//! |------|----------------------------------------------------------------|
+//! | 0    | success                                                        |
+//! | 1    | other failure                                                  |
+//! | 2    | no known location found (`run --once`)                         |
+//! | 3    | network error: mattermost unreachable or answering garbage     |
+//! | 4    | mattermost account deactivated or token revoked                |
+//! | 5    | configuration error                                            |
+//! | 6    | authentication error: wrong or unavailable secret              |
+//! | 7    | platform error: wifi scan, file system, …                      |
+use crate::mattermost::api_error::ApiError;
+use crate::mattermost::MMSError;
+use std::fmt::Display;
+use thiserror::Error;
+
+/// Errors categories of the public API
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Invalid or missing configuration
+    #[error(transparent)]
+    Config(anyhow::Error),
+    /// Authentication failure or secret retrieval failure
+    #[error(transparent)]
+    Auth(anyhow::Error),
+    /// Mattermost server unreachable or returning unexpected data
+    #[error(transparent)]
+    Network(anyhow::Error),
+    /// Mattermost account deactivated or token revoked, retrying is useless
+    #[error(transparent)]
+    Account(anyhow::Error),
+    /// Failure of an OS dependent facility (wifi scan, file system, …)
+    #[error(transparent)]
+    Platform(anyhow::Error),
+}
+
+/// `Result` type using the crate [`Error`] by default
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Exit code of failures outside of the [`Error`] categories
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code of `run --once` when no known location was found
+pub const EXIT_NO_KNOWN_LOCATION: i32 = 2;
+/// Exit code of [`Error::Network`]
+pub const EXIT_NETWORK: i32 = 3;
+/// Exit code of [`Error::Account`], telling the service manager not to restart
+pub const EXIT_ACCOUNT_DISABLED: i32 = 4;
+/// Exit code of [`Error::Config`], telling the service manager not to restart
+pub const EXIT_CONFIG: i32 = 5;
+/// Exit code of [`Error::Auth`]
+pub const EXIT_AUTH: i32 = 6;
+/// Exit code of [`Error::Platform`]
+pub const EXIT_PLATFORM: i32 = 7;
+
+/// Return the exit code of `error`, the one of its [`Error`] category if it has
+/// one
+/// ```
+/// use anyhow::Context;
+/// use lib::error::{exit_code, Error, EXIT_CONFIG, EXIT_FAILURE};
+/// let error = Err::<(), _>(Error::Config(anyhow::anyhow!("missing `mm_url`")))
+///     .context("Validating configuration")
+///     .unwrap_err();
+/// assert_eq!(exit_code(&error), EXIT_CONFIG);
+/// assert_eq!(exit_code(&anyhow::anyhow!("failure")), EXIT_FAILURE);
+/// ```
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    error
+        .downcast_ref::<Error>()
+        .map_or(EXIT_FAILURE, Error::exit_code)
+}
+
+impl Error {
+    /// Return the exit code of the category
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => EXIT_CONFIG,
+            Error::Auth(_) => EXIT_AUTH,
+            Error::Network(_) => EXIT_NETWORK,
+            Error::Account(_) => EXIT_ACCOUNT_DISABLED,
+            Error::Platform(_) => EXIT_PLATFORM,
+        }
+    }
+
+    /// Wrap the underlying error with `context`, keeping the error category
+    pub fn context<C>(self, context: C) -> Self
+    where
+        C: Display + Send + Sync + 'static,
+    {
+        match self {
+            Error::Config(e) => Error::Config(e.context(context)),
+            Error::Auth(e) => Error::Auth(e.context(context)),
+            Error::Network(e) => Error::Network(e.context(context)),
+            Error::Account(e) => Error::Account(e.context(context)),
+            Error::Platform(e) => Error::Platform(e.context(context)),
+        }
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, response) => {
+                ApiError::from_response(status, response).into()
+            }
+            e => Error::Network(e.into()),
+        }
+    }
+}
+
+impl From<ApiError> for Error {
+    fn from(e: ApiError) -> Self {
+        match e.status {
+            _ if e.is_account_deactivated() || e.is_token_revoked() => Error::Account(e.into()),
+            401 | 403 => Error::Auth(e.into()),
+            _ => Error::Network(e.into()),
+        }
+    }
+}
+
+impl From<MMSError> for Error {
+    fn from(e: MMSError) -> Self {
+        match e {
+            MMSError::HTTPRequestError(e) => (*e).into(),
+            MMSError::ApiError(e) => e.into(),
+            MMSError::LoginError(e) => e,
+            MMSError::BadJSONData(_) => Error::Network(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn categorize_http_errors() {
+        let response = ureq::Response::new(401, "Unauthorized", "").unwrap();
+        assert!(matches!(
+            Error::from(ureq::Error::Status(401, response)),
+            Error::Auth(_)
+        ));
+        let response = ureq::Response::new(500, "Internal Server Error", "").unwrap();
+        assert!(matches!(
+            Error::from(MMSError::HTTPRequestError(Box::new(ureq::Error::Status(
+                500, response
+            )))),
+            Error::Network(_)
+        ));
+        let response = ureq::Response::new(
+            401,
+            "Unauthorized",
+            r#"{"id":"api.user.login.inactive.app_error","status_code":401}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            Error::from(ureq::Error::Status(401, response)),
+            Error::Account(_)
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+//! Structured events emitted while the application is running.
+//!
+//! Library users and internal components (tray icon, metrics, notifications) may
+//! subscribe to an [`EventBus`] either with an [`Observer`] implementation (any
+//! `Fn(&Event)` closure is an observer) or by getting a channel [`Receiver`]:
+//! ```
+//! use lib::events::{Event, EventBus};
+//! use lib::Location;
+//! let mut bus = EventBus::new();
+//! let rx = bus.channel();
+//! bus.subscribe(|e: &Event| println!("{:?}", e));
+//! bus.publish(Event::DndEntered);
+//! assert_eq!(rx.try_recv().unwrap(), Event::DndEntered);
+//! ```
+use crate::mattermost::MMCustomStatus;
+use crate::state::Location;
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use tracing::trace;
+
+/// Events published on the [`EventBus`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The detected location has changed
+    LocationChanged {
+        /// previous location
+        from: Location,
+        /// new location
+        to: Location,
+    },
+    /// A custom status has been successfully sent to mattermost
+    StatusSent(MMCustomStatus),
+    /// Sending a custom status or a presence to mattermost failed
+    SendFailed(String),
+    /// Presence has been set to *do not disturb* because a watched application uses the mic
+    DndEntered,
+    /// Presence has been set back to *online* after a *do not disturb* period
+    DndLeft,
+    /// The user has been told that the token or the session expires soon, with
+    /// the message telling it (see [`crate::token_expiry`])
+    ExpiryNotified(String),
+    /// The status update loop has been paused until the given UTC timestamp, or
+    /// resumed
+    PauseChanged(Option<i64>),
+}
+
+/// Trait implemented by the subscribers of an [`EventBus`]
+///
+/// Observers may be notified from any thread.
+pub trait Observer: Send + Sync {
+    /// Called for each event published on the bus
+    fn notify(&self, event: &Event);
+}
+
+impl<F> Observer for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn notify(&self, event: &Event) {
+        self(event)
+    }
+}
+
+impl Observer for Sender<Event> {
+    fn notify(&self, event: &Event) {
+        // The receiver may have been dropped, which only means nobody listens anymore.
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Dispatch [`Event`] to all subscribed [`Observer`]
+#[derive(Default)]
+pub struct EventBus {
+    observers: Vec<Box<dyn Observer>>,
+}
+
+impl fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventBus({} observers)", self.observers.len())
+    }
+}
+
+impl EventBus {
+    /// Create an `EventBus` without any subscriber
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `observer` to the subscribers
+    pub fn subscribe(&mut self, observer: impl Observer + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Subscribe a new channel and return its receiving end
+    pub fn channel(&mut self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribe(tx);
+        rx
+    }
+
+    /// Notify all subscribers of `event`
+    pub fn publish(&self, event: Event) {
+        trace!("Publishing {:?}", event);
+        for observer in &self.observers {
+            observer.notify(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn notify_all_subscribers() {
+        let mut bus = EventBus::new();
+        let rx = bus.channel();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_by_closure = seen.clone();
+        bus.subscribe(move |e: &Event| seen_by_closure.lock().unwrap().push(e.clone()));
+        let event = Event::LocationChanged {
+            from: Location::Unknown,
+            to: Location::Known("home".to_string()),
+        };
+        bus.publish(event.clone());
+        bus.publish(Event::DndLeft);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            [event.clone(), Event::DndLeft]
+        );
+        assert_eq!(*seen.lock().unwrap(), [event, Event::DndLeft]);
+    }
+
+    #[test]
+    fn ignore_dropped_receivers() {
+        let mut bus = EventBus::new();
+        drop(bus.channel());
+        bus.publish(Event::DndEntered);
+    }
+}
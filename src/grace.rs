@@ -0,0 +1,109 @@
+//! Hysteresis before dropping a known location.
+//!
+//! A single scan without any known SSID (driver hiccup, band steering, …) shall not
+//! clear the custom status. The last known location is thus kept as long as fewer
+//! than `unknown_grace_scans` consecutive scans or fewer than `unknown_grace_secs`
+//! seconds found no known SSID.
+use tracing::{debug, info};
+
+use crate::state::Location;
+
+/// Filter of the detected locations, keeping the last known one during a grace period
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UnknownGrace {
+    scans: u32,
+    secs: u32,
+    last_known: Option<Location>,
+    /// Number of consecutive unknown scans and timestamp of the first one
+    unknown_since: Option<(u32, i64)>,
+}
+
+impl UnknownGrace {
+    /// Keep the last known location during `scans` unknown scans or `secs`
+    /// seconds (no grace when both are `0`)
+    pub const fn new(scans: u32, secs: u32) -> Self {
+        Self {
+            scans,
+            secs,
+            last_known: None,
+            unknown_since: None,
+        }
+    }
+
+    /// Return `location`, or the last known location if `location` is unknown
+    /// but the grace period is not over. `now` is an UTC timestamp.
+    pub fn filter(&mut self, location: Location, now: i64) -> Location {
+        match &location {
+            // Off time location is not a wifi location worth keeping
+            Location::Known(wifi) if wifi.is_empty() => self.last_known = None,
+            Location::Known(_) => self.last_known = Some(location.clone()),
+            Location::Unknown => {
+                if let Some(last_known) = &self.last_known {
+                    let (count, since) = self.unknown_since.get_or_insert((0, now));
+                    *count += 1;
+                    let elapsed = now - *since;
+                    if *count <= self.scans || elapsed < self.secs.into() {
+                        info!(
+                            "No known SSID for {} scan(s) during {}s, keeping {:?}",
+                            count, elapsed, last_known
+                        );
+                        return last_known.clone();
+                    }
+                    debug!("Grace period over, dropping {:?}", last_known);
+                    self.last_known = None;
+                }
+            }
+        }
+        self.unknown_since = None;
+        location
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    fn home() -> Location {
+        Location::Known("home".to_string())
+    }
+
+    #[test]
+    fn pass_through_without_grace() {
+        let mut grace = UnknownGrace::new(0, 0);
+        assert_eq!(grace.filter(home(), 0), home());
+        assert_eq!(grace.filter(Location::Unknown, 60), Location::Unknown);
+    }
+
+    #[test]
+    fn keep_location_during_grace_scans() {
+        let mut grace = UnknownGrace::new(2, 0);
+        assert_eq!(grace.filter(home(), 0), home());
+        assert_eq!(grace.filter(Location::Unknown, 60), home());
+        assert_eq!(grace.filter(Location::Unknown, 120), home());
+        assert_eq!(grace.filter(Location::Unknown, 180), Location::Unknown);
+        assert_eq!(grace.filter(Location::Unknown, 240), Location::Unknown);
+        // A known scan starts a new grace period
+        assert_eq!(grace.filter(home(), 300), home());
+        assert_eq!(grace.filter(Location::Unknown, 360), home());
+    }
+
+    #[test]
+    fn keep_location_during_grace_secs() {
+        let mut grace = UnknownGrace::new(0, 100);
+        assert_eq!(grace.filter(home(), 0), home());
+        assert_eq!(grace.filter(Location::Unknown, 60), home());
+        assert_eq!(grace.filter(Location::Unknown, 120), home());
+        assert_eq!(grace.filter(Location::Unknown, 180), Location::Unknown);
+    }
+
+    #[test]
+    fn not_keep_off_time_location() {
+        let mut grace = UnknownGrace::new(2, 0);
+        assert_eq!(
+            grace.filter(Location::Known("".to_string()), 0),
+            Location::Known("".to_string())
+        );
+        assert_eq!(grace.filter(Location::Unknown, 60), Location::Unknown);
+    }
+}
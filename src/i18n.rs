@@ -0,0 +1,167 @@
+//! Localization of the built-in texts and week day names
+//!
+//! Translations are embedded in the binary. The language is taken from the
+//! `LC_ALL`, `LC_MESSAGES` and `LANG` environment variables, falling back to
+//! english.
+use chrono::Weekday;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Language of the built-in texts
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Lang {
+    /// English
+    En,
+    /// French
+    Fr,
+    /// German
+    De,
+    /// Spanish
+    Es,
+    /// Italian
+    It,
+}
+
+impl Lang {
+    /// Return the language of the user locale
+    pub fn from_env() -> Self {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|locale| !locale.is_empty())
+            .map(|locale| Lang::from_locale(&locale))
+            .unwrap_or(Lang::En)
+    }
+
+    /// Return the language of a locale such as `fr_FR.UTF-8`
+    /// ```
+    /// use lib::i18n::Lang;
+    /// assert_eq!(Lang::from_locale("de_CH.UTF-8"), Lang::De);
+    /// assert_eq!(Lang::from_locale("C"), Lang::En);
+    /// ```
+    pub fn from_locale(locale: &str) -> Self {
+        match locale.get(..2).map(str::to_lowercase).as_deref() {
+            Some("fr") => Lang::Fr,
+            Some("de") => Lang::De,
+            Some("es") => Lang::Es,
+            Some("it") => Lang::It,
+            _ => Lang::En,
+        }
+    }
+}
+
+/// Built-in texts
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Text {
+    /// Status text of the default `home` location
+    WorkingAtHome,
+}
+
+/// Return the translation of `text` in `lang`
+pub fn tr(text: Text, lang: Lang) -> &'static str {
+    match (text, lang) {
+        (Text::WorkingAtHome, Lang::En) => "working at home",
+        (Text::WorkingAtHome, Lang::Fr) => "télétravail",
+        (Text::WorkingAtHome, Lang::De) => "im Homeoffice",
+        (Text::WorkingAtHome, Lang::Es) => "teletrabajando",
+        (Text::WorkingAtHome, Lang::It) => "in smart working",
+    }
+}
+
+/// Week day names, starting on monday, for the non english languages
+const WEEKDAY_NAMES: [[&str; 7]; 4] = [
+    [
+        "lundi", "mardi", "mercredi", "jeudi", "vendredi", "samedi", "dimanche",
+    ],
+    [
+        "montag",
+        "dienstag",
+        "mittwoch",
+        "donnerstag",
+        "freitag",
+        "samstag",
+        "sonntag",
+    ],
+    [
+        "lunes",
+        "martes",
+        "miércoles",
+        "jueves",
+        "viernes",
+        "sábado",
+        "domingo",
+    ],
+    [
+        "lunedì",
+        "martedì",
+        "mercoledì",
+        "giovedì",
+        "venerdì",
+        "sabato",
+        "domenica",
+    ],
+];
+
+/// Lower case `s` and remove its accents
+fn fold(s: &str) -> String {
+    s.trim()
+        .to_lowercase()
+        .nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect()
+}
+
+/// Parse a week day name in any supported language, or its first three letters
+/// (or more). Case and accents are ignored.
+/// ```
+/// use lib::i18n::parse_weekday;
+/// use chrono::Weekday;
+/// assert_eq!(parse_weekday("Sat"), Some(Weekday::Sat));
+/// assert_eq!(parse_weekday("lundi"), Some(Weekday::Mon));
+/// assert_eq!(parse_weekday("Montag"), Some(Weekday::Mon));
+/// assert_eq!(parse_weekday("miercoles"), Some(Weekday::Wed));
+/// assert_eq!(parse_weekday("ven"), Some(Weekday::Fri));
+/// assert_eq!(parse_weekday("ma"), None);
+/// ```
+pub fn parse_weekday(s: &str) -> Option<Weekday> {
+    if let Ok(day) = s.trim().parse::<Weekday>() {
+        return Some(day);
+    }
+    let s = fold(s);
+    if s.chars().count() < 3 {
+        return None;
+    }
+    WEEKDAY_NAMES.iter().find_map(|names| {
+        names
+            .iter()
+            .position(|name| fold(name).starts_with(&s))
+            .and_then(|i| Weekday::try_from(i as u8).ok())
+    })
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn parse_every_weekday_in_every_language() {
+        for names in WEEKDAY_NAMES {
+            let days: Vec<_> = names.iter().map(|n| parse_weekday(n)).collect();
+            assert_eq!(
+                days,
+                [
+                    Weekday::Mon,
+                    Weekday::Tue,
+                    Weekday::Wed,
+                    Weekday::Thu,
+                    Weekday::Fri,
+                    Weekday::Sat,
+                    Weekday::Sun
+                ]
+                .map(Some)
+            );
+        }
+        assert_eq!(parse_weekday("DIMANCHE"), Some(Weekday::Sun));
+        assert_eq!(parse_weekday("lundo"), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! Generation of status triplets for the wifi networks saved by the OS
+//!
+//! Used by the `import-networks` subcommand to jump-start the configuration, and
+//! when writing the configuration file at first run.
+use serde::Serialize;
+use std::io::{self, BufRead, Write};
+use tracing::warn;
+
+use crate::config::WifiStatusConfig;
+use crate::utils::normalize;
+use crate::wifiscan::Network;
+
+/// Return the `status` triplets followed by a new triplet for each of the
+/// `saved_ssids` not already matched by one of them.
+///
+/// New triplets use the `house` emoji and a generic text, to be edited by the user.
+/// ```
+/// use lib::import::import_status;
+/// let status = vec!["corp::office::At work".to_string()];
+/// let saved = vec!["corp-5G".to_string(), "HomeNet".to_string()];
+/// assert_eq!(
+///     import_status(&status, &saved, false),
+///     ["corp::office::At work", "HomeNet::house::Working from HomeNet"]
+/// );
+/// ```
+pub fn import_status(
+    status: &[String],
+    saved_ssids: &[String],
+    case_insensitive: bool,
+) -> Vec<String> {
+    let wifi_strings: Vec<String> = status
+        .iter()
+        .filter_map(|s| s.parse::<WifiStatusConfig>().ok())
+        .map(|sc| normalize(&sc.wifi_string, case_insensitive))
+        .filter(|w| !w.is_empty())
+        .collect();
+    let mut res = status.to_vec();
+    for ssid in saved_ssids {
+        if ssid.contains("::") {
+            warn!("Skipping {:?}, as `::` is the status separator", ssid);
+            continue;
+        }
+        let normalized = normalize(ssid, case_insensitive);
+        if !wifi_strings.iter().any(|w| normalized.contains(w.as_str())) {
+            res.push(format!("{}::house::Working from {}", ssid, ssid));
+        }
+    }
+    res
+}
+
+/// Return the TOML `status` entry holding `status`, ready to be copied in the
+/// configuration file
+pub fn to_toml(status: &[String]) -> Result<String, toml::ser::Error> {
+    #[derive(Serialize)]
+    struct Status<'a> {
+        status: &'a [String],
+    }
+    toml::to_string(&Status { status })
+}
+
+/// Return `status` where the wifi substring `home` of the default triplet is
+/// replaced by `ssid`
+/// ```
+/// use lib::import::with_home_ssid;
+/// let status = vec!["home::house::working at home".to_string()];
+/// assert_eq!(
+///     with_home_ssid(&status, "Livebox-12AB"),
+///     ["Livebox-12AB::house::working at home"]
+/// );
+/// ```
+pub fn with_home_ssid(status: &[String], ssid: &str) -> Vec<String> {
+    if ssid.contains("::") {
+        warn!("Skipping {:?}, as `::` is the status separator", ssid);
+        return status.to_vec();
+    }
+    status
+        .iter()
+        .map(|s| match s.strip_prefix("home::") {
+            Some(rest) => format!("{}::{}", ssid, rest),
+            None => s.to_owned(),
+        })
+        .collect()
+}
+
+/// Return the visible network with the strongest signal, or the first one if
+/// the signal strengths are unknown
+pub fn strongest(networks: &[Network]) -> Option<&Network> {
+    networks
+        .iter()
+        .rev()
+        .max_by_key(|network| network.signal.unwrap_or(i32::MIN))
+}
+
+/// Ask on the standard error whether the strongest of `networks` is the home
+/// network, and return the SSID answered on `input`: the suggested one if the
+/// answer is empty or `y`, none if it is `n`, or the answer itself.
+/// ```
+/// use lib::import::ask_home_ssid;
+/// use lib::wifiscan::Network;
+/// let networks = [Network::with_quality("guest", 40), Network::with_quality("Livebox", 90)];
+/// assert_eq!(ask_home_ssid(&networks, &mut "\n".as_bytes()).unwrap(), Some("Livebox".to_string()));
+/// assert_eq!(ask_home_ssid(&networks, &mut "n\n".as_bytes()).unwrap(), None);
+/// assert_eq!(ask_home_ssid(&networks, &mut "MyHome\n".as_bytes()).unwrap(), Some("MyHome".to_string()));
+/// ```
+pub fn ask_home_ssid(networks: &[Network], input: &mut dyn BufRead) -> io::Result<Option<String>> {
+    let Some(suggested) = strongest(networks) else {
+        return Ok(None);
+    };
+    eprint!(
+        "Is `{}` your home wifi network? [Y/n or the home SSID] ",
+        suggested
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(match answer.trim() {
+        "" | "y" | "Y" | "yes" => Some(suggested.ssid.clone()),
+        "n" | "N" | "no" => None,
+        ssid => Some(ssid.to_string()),
+    })
+}
@@ -1,26 +1,74 @@
 #![warn(missing_docs)]
 //! Automattermostatus main components and helper functions used by `main`
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Datelike, Local, TimeZone};
+use signal_hook::consts::TERM_SIGNALS;
 use std::fs;
 use std::path::PathBuf;
-use std::thread::sleep;
-use std::{collections::HashMap, time};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time;
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter};
 
+pub mod away;
+pub mod backup;
+pub mod clock;
+pub mod command;
 pub mod config;
+pub mod connectivity;
+#[cfg(all(feature = "dbus", target_os = "linux"))]
+pub mod dbus;
+pub mod decision;
+pub mod doctor;
+pub mod error;
+pub mod events;
+pub mod grace;
+pub mod i18n;
+pub mod import;
 pub mod mattermost;
+pub mod meeting;
+pub mod metered;
 pub mod micscan;
+pub mod migration;
 pub mod offtime;
+pub mod power;
+pub mod priority;
+pub mod remote;
+pub mod replay;
+pub mod secret;
+pub mod service;
+pub mod signal;
 pub mod state;
+pub mod stats;
+pub mod supervisor;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
+pub mod timeofday;
+pub mod toast;
+pub mod token_expiry;
+pub mod update;
 pub mod utils;
+pub mod wakeup;
 pub mod wifiscan;
-pub use config::{Args, SecretType, WifiStatusConfig};
-pub use mattermost::{BaseSession, LoggedSession, MMCustomStatus, Session};
+use clock::Clock;
+pub use command::{CommandRunner, SystemRunner};
+pub use config::{
+    Args, Config, ConfigCommand, Credentials, DuplicatePolicy, FocusCommand, LocationCommand,
+    OutputFormat, SecretType, ServiceCommand, StatusCommand, SubCommand, WifiStatusConfig,
+};
+pub use error::Error;
+use error::Result;
+pub use events::{Event, EventBus, Observer};
+pub use mattermost::{BaseSession, LoggedSession, MMCustomStatus, MMStatus, Session, Status};
 use offtime::Off;
-pub use state::{Cache, Location, State};
-pub use wifiscan::{WiFi, WifiInterface};
+use priority::StatusSource;
+use signal::{PresenceSignal, Reading};
+pub use state::{Cache, Evidence, Location, LocationRule, Refresh, State};
+use timeofday::Expiry;
+pub use wifiscan::{Network, WiFi, WifiHealth, WifiInterface};
 
 /// Setup logging to stdout
 /// (Tracing is a bit more involving to set up but will provide much more feature if needed)
@@ -35,170 +83,1120 @@ pub fn setup_tracing(args: &Args) -> Result<()> {
     Ok(())
 }
 
-/// Return a [`Cache`] used to persist state.
-pub fn get_cache(dir: Option<PathBuf>) -> Result<Cache> {
-    let mut state_file_name: PathBuf;
-    if let Some(ref state_dir) = dir {
-        state_file_name = PathBuf::from(state_dir);
-        fs::create_dir_all(state_dir)
-            .with_context(|| format!("Creating cache dir {:?}", &state_dir))?;
-    } else {
-        bail!("Internal Error, no `state_dir` configured");
+/// Setup logging to the daily files of the service log directory, with the
+/// `service_log_level` filter
+///
+/// On mac os, the standard output and error files written by launchd are also
+/// rotated.
+pub fn setup_service_tracing(config: &Config) -> Result<()> {
+    let dir = service::log_dir(&config.state_dir);
+    if service::Manager::current() == service::Manager::Launchd {
+        service::launchd::rotate_output(&dir)?;
     }
+    let writer = service::log::DailyFile::new(&dir, config.service_log_retention_days)
+        .with_context(|| format!("Creating log directory {:?}", dir))
+        .map_err(Error::Platform)?;
+    let fmt_layer = fmt::layer()
+        .with_target(false)
+        .with_ansi(false)
+        .with_writer(std::sync::Mutex::new(writer));
+    let filter_layer = EnvFilter::try_new(&config.service_log_level)
+        .with_context(|| format!("Parsing log filter {}", config.service_log_level))
+        .map_err(Error::Config)?;
+
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(fmt_layer)
+        .init();
+    Ok(())
+}
 
-    state_file_name.push("automattermostatus.state");
-    Ok(Cache::new(state_file_name))
+/// Return a [`Cache`] used to persist state in `file`.
+///
+/// The state file shared by all the instances in previous versions is reused if
+/// `file` does not exist yet.
+pub fn get_cache(file: Option<PathBuf>) -> Result<Cache> {
+    let file =
+        file.ok_or_else(|| Error::Config(anyhow!("Internal Error, no `state_dir` configured")))?;
+    if let Some(state_dir) = file.parent() {
+        fs::create_dir_all(state_dir)
+            .with_context(|| format!("Creating state dir {:?}", &state_dir))
+            .map_err(Error::Platform)?;
+        let shared = state_dir.join(state::STATE_FILE_NAME);
+        if !file.exists() && shared.exists() {
+            info!("Moving {:?} to {:?}", shared, file);
+            fs::rename(&shared, &file)
+                .with_context(|| format!("Moving {:?} to {:?}", shared, file))
+                .map_err(Error::Platform)?;
+        }
+    }
+    Ok(Cache::new(file))
 }
 
-/// Prepare a dictionnary of [`MMCustomStatus`] ready to be send to mattermost
-/// server depending upon the location being found.
-pub fn prepare_status(args: &Args) -> Result<HashMap<Location, MMCustomStatus>> {
-    let mut res = HashMap::new();
-    for s in &args.status {
-        let sc: WifiStatusConfig = s.parse().with_context(|| format!("Parsing {}", s))?;
+/// Prepare the [`LocationRule`]s, in the order of the `status` triplets, giving
+/// the [`MMCustomStatus`] ready to be send to mattermost server depending upon
+/// the location being found.
+///
+/// Invalid triplets are skipped with a warning, unless `config.strict` is set.
+/// It fails if none of the configured triplets is valid, or if several of them
+/// share the same wifi substring while `config.duplicate_status` is
+/// [`DuplicatePolicy::Error`]. With [`DuplicatePolicy::Warn`], only the last of
+/// them is kept.
+pub fn prepare_status(config: &Config) -> Result<Vec<LocationRule>> {
+    let mut res: Vec<LocationRule> = Vec::new();
+    let mut skipped = 0;
+    for (i, s) in config.status.iter().enumerate() {
+        let sc: WifiStatusConfig = match s.parse() {
+            Ok(sc) => sc,
+            Err(e) if config.strict => {
+                return Err(e.context(format!("Parsing status #{} `{}`", i + 1, s)))
+            }
+            Err(e) => {
+                warn!("Skipping status #{} `{}`: {}", i + 1, s, e);
+                skipped += 1;
+                continue;
+            }
+        };
         debug!("Adding : {:?}", sc);
-        res.insert(
-            Location::Known(sc.wifi_string),
-            MMCustomStatus::new(sc.text, sc.emoji),
-        );
+        let text = match &config.machine_tag {
+            Some(tag) => format!("{}{}{}", sc.text, state::TAG_SEPARATOR, tag),
+            None => sc.text,
+        };
+        let location = Location::Known(sc.wifi_string);
+        let status = MMCustomStatus::new(text, sc.emoji);
+        match res.iter_mut().find(|rule| rule.location == location) {
+            Some(rule) => rule.statuses.push(status),
+            None => res.push(LocationRule {
+                location,
+                statuses: vec![status],
+            }),
+        }
+    }
+    if skipped > 0 && skipped == config.status.len() {
+        return Err(Error::Config(anyhow!(
+            "None of the `status` triplets is valid"
+        )));
+    }
+    if config.duplicate_status == DuplicatePolicy::Rotate {
+        return Ok(res);
+    }
+    let duplicates: Vec<String> = res
+        .iter()
+        .filter(|rule| rule.statuses.len() > 1)
+        .filter_map(|rule| match &rule.location {
+            Location::Known(wifi) => Some(format!("`{}`", wifi)),
+            Location::Unknown => None,
+        })
+        .collect();
+    if duplicates.is_empty() {
+        return Ok(res);
+    }
+    if config.duplicate_status == DuplicatePolicy::Error {
+        return Err(Error::Config(anyhow!(
+            "Several `status` triplets share the wifi substring {}: keep only one, \
+             or set `duplicate_status` to `Rotate` to use them in turn",
+            duplicates.join(", ")
+        )));
+    }
+    warn!(
+        "Several `status` triplets share the wifi substring {}: using the last one, \
+         set `duplicate_status` to `Rotate` to use them in turn",
+        duplicates.join(", ")
+    );
+    for rule in &mut res {
+        rule.statuses.drain(..rule.statuses.len() - 1);
     }
     Ok(res)
 }
 
-/// Create [`Session`] according to `args.secret_type`.
-pub fn create_session(args: &Args) -> LoggedSession {
-    args.mm_url.as_ref().expect("Mattermost URL is not defined");
-    args.secret_type
-        .as_ref()
-        .expect("Internal Error: secret_type is not defined");
-    args.mm_secret.as_ref().expect("Secret is not defined");
-    let delay_duration = time::Duration::new(
-        args.delay
-            .expect("Internal error: args.delay shouldn't be None")
-            .into(),
-        0,
-    );
-    let mut session = Session::new(args.mm_url.as_ref().unwrap());
-    let mut session: Box<dyn BaseSession> = match args.secret_type.as_ref().unwrap() {
-        SecretType::Password => Box::new(session.with_credentials(
-            args.mm_user.as_ref().unwrap(),
-            args.mm_secret.as_ref().unwrap(),
-        )),
-        SecretType::Token => Box::new(session.with_token(args.mm_secret.as_ref().unwrap())),
+/// Return the index of the status used on the day of `now` among the `len`
+/// statuses of a location
+/// ```
+/// use chrono::{Local, TimeZone};
+/// use lib::rotation_index;
+/// let monday = Local.with_ymd_and_hms(2024, 1, 8, 9, 0, 0).unwrap();
+/// let tuesday = Local.with_ymd_and_hms(2024, 1, 9, 9, 0, 0).unwrap();
+/// assert_eq!(rotation_index(1, monday), 0);
+/// assert_ne!(rotation_index(2, monday), rotation_index(2, tuesday));
+/// ```
+pub fn rotation_index(len: usize, now: DateTime<Local>) -> usize {
+    match len {
+        0 => 0,
+        len => now.date_naive().num_days_from_ce().rem_euclid(len as i32) as usize,
+    }
+}
+
+/// Create [`Session`] according to `config.credentials` and log in once.
+///
+/// With `config.persist_session`, the token obtained with a password is stored
+/// in the OS keyring and reused while valid (see [`mattermost::token_store`]).
+///
+/// If the secret comes from a vault, it is read again from the vault when it is
+/// rejected, in case it has been rotated (see [`secret::vault`]): now, and by
+/// the session when logging in again.
+///
+/// The age of a stored token is checked against `clock`.
+pub fn login(config: &Config, clock: &dyn Clock) -> Result<LoggedSession> {
+    let Some(vault) = &config.secret_vault else {
+        return login_with(config, &config.credentials, clock);
     };
+    let mut vault = vault.clone();
+    let res = login_with(config, &config.credentials, clock);
+    let res = match res {
+        Err(Error::Auth(_)) | Err(Error::Account(_)) => match vault.refetch() {
+            Ok(rotated) if rotated != config.credentials.secret() => {
+                info!(
+                    "The secret has been changed in {}, logging in again",
+                    vault.item.vault.name()
+                );
+                login_with(config, &config.credentials.with_secret(rotated), clock)
+            }
+            Ok(_) => res,
+            Err(e) => {
+                warn!(
+                    "Fail to read the secret again from {} : {:#}",
+                    vault.item.vault.name(),
+                    e
+                );
+                res
+            }
+        },
+        res => res,
+    };
+    res.map(|mut session| {
+        session.follow_vault(vault);
+        session
+    })
+}
+
+/// Log in with `credentials` instead of `config.credentials`
+fn login_with(
+    config: &Config,
+    credentials: &Credentials,
+    clock: &dyn Clock,
+) -> Result<LoggedSession> {
+    let mut session = Session::new(&config.mm_url);
+    session.with_client_options(config.client.clone());
+    let mut session: Box<dyn BaseSession> = match credentials {
+        Credentials::Password { user, password } if config.persist_session => {
+            return mattermost::token_store::login(
+                &mattermost::token_store::KeyringStore::new(&config.mm_url, user),
+                &config.mm_url,
+                &config.client,
+                user,
+                password,
+                clock.now().timestamp(),
+            );
+        }
+        Credentials::Password { user, password } => {
+            Box::new(session.with_credentials(user, password))
+        }
+        Credentials::Token(token) => Box::new(session.with_token(token)),
+    };
+    session.login()
+}
+
+/// Create [`Session`] according to `config.credentials`, retrying to log in
+/// until it succeeds. The delay between two attempts starts at `config.delay`
+/// and is doubled after each failure (see [`supervisor::backoff`]).
+///
+/// It fails without retrying if the account is deactivated or the token
+/// revoked ([`Error::Account`]). Each failure is given to `on_failure`.
+pub fn create_session(
+    config: &Config,
+    clock: &dyn Clock,
+    mut on_failure: impl FnMut(&Error),
+) -> Result<LoggedSession> {
+    let mut attempts = 0;
     loop {
-        let res = session.login();
+        let res = login(config, clock);
+        if let Err(e) = &res {
+            on_failure(e);
+        }
         if let Ok(session) = res {
             debug!("LoggedSession {:?}", session);
-            return session;
+            return Ok(session);
+        } else if let Err(Error::Account(e)) = res {
+            return Err(Error::Account(e.context("Login to mattermost")));
         } else {
-            error!("Failed to access mattermost API {:?}", res);
-            sleep(delay_duration);
+            let delay = supervisor::backoff(config.delay, attempts);
+            error!(
+                "Failed to access mattermost API {:?}, retrying in {}s",
+                res, delay
+            );
+            sleep(time::Duration::new(delay.into(), 0));
+            attempts += 1;
+        }
+    }
+}
+
+/// Return the wifi interface, either the built-in one or `config.wifi_scan_cmd`
+fn wifi_interface(config: &Config) -> Box<dyn WifiInterface> {
+    match &config.wifi_scan_cmd {
+        Some(command) => Box::new(command.clone()),
+        None => {
+            Box::new(WiFi::new(&config.interface_name).with_rescan_policy(config.rescan_policy))
         }
     }
 }
 
+/// Outcome of the status update loop
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outcome {
+    /// The status has been updated, or the loop has been stopped
+    Updated,
+    /// No known location was found during the single cycle of `run --once`
+    NoKnownLocation,
+}
+
 /// Main application loop, looking for a known SSID and updating
 /// mattermost custom status accordingly.
+///
+/// With `config.once`, a single cycle is performed and its [`Outcome`] returned.
+/// Otherwise the loop returns once `stop` is set, which the terminating signals
+/// also do with `config.clear_on_exit`.
+///
+/// Subscribers of `events` are notified of location and status changes.
 pub fn get_wifi_and_update_status_loop(
-    args: Args,
-    mut status_dict: HashMap<Location, MMCustomStatus>,
-) -> Result<()> {
-    let cache = get_cache(args.state_dir.to_owned()).context("Reading cached state")?;
-    let mut state = State::new(&cache).context("Creating cache")?;
-    let delay_duration = time::Duration::new(
-        args.delay
-            .expect("Internal error: args.delay shouldn't be None")
-            .into(),
-        0,
-    );
-    let wifi = WiFi::new(
-        &args
-            .interface_name
-            .clone()
-            .expect("Internal error: args.interface_name shouldn't be None"),
-    );
-    if !wifi
-        .is_wifi_enabled()
-        .context("Checking if wifi is enabled")?
+    config: Config,
+    rules: Vec<LocationRule>,
+    events: &EventBus,
+    stop: Arc<AtomicBool>,
+) -> Result<Outcome> {
+    let cache = get_cache(Some(config.state_file.to_owned()))
+        .map_err(|e| e.context("Reading cached state"))?;
+    let mut state = State::new(&cache)
+        .context("Creating cache")
+        .map_err(Error::Platform)?
+        .with_manual_change_cooldown(config.manual_change_cooldown)
+        .with_failure_report(config.report_failures_after)
+        .with_machine_tag(config.machine_tag.is_some());
+    let runner = SystemRunner;
+    let mut wifi = wifi_interface(&config);
+    let mut wifi_health = check_wifi_health(wifi.as_ref(), &runner, None);
+    if config.startup_delay > 0 {
+        let startup_delay = utils::with_jitter(time::Duration::ZERO, config.startup_delay);
+        info!("Waiting {}s before starting", startup_delay.as_secs());
+        sleep(startup_delay);
+    }
+    let mut recorder = match &config.record {
+        Some(path) => Some(replay::Recorder::new(path).map_err(Error::Platform)?),
+        None => None,
+    };
+    if config.clear_on_exit {
+        register_stop_signals(&stop)
+            .context("Registering signal handlers")
+            .map_err(Error::Platform)?;
+    }
+    if config.startup_grace_secs > 0 && !config.once {
+        info!(
+            "Waiting up to {}s for the network to settle",
+            config.startup_grace_secs
+        );
+        connectivity::settle(
+            config.connectivity_check_url.as_deref(),
+            time::Duration::from_secs(config.startup_grace_secs.into()),
+            &stop,
+        );
+    }
+    let mut history = stats::History::new(config.state_dir.join(stats::HISTORY_FILE_NAME));
+    let mut grace = grace::UnknownGrace::new(config.unknown_grace_scans, config.unknown_grace_secs);
+    // Started at boot, the network may not be up yet
+    if !config.once
+        && !connectivity::wait_online(
+            config.connectivity_check_url.as_deref(),
+            &config.mm_url,
+            config.delay,
+            &stop,
+        )
     {
-        error!("wifi is disabled");
+        info!("Exiting");
+        return Ok(Outcome::Updated);
+    }
+    // Running once, a login failure is reported by the exit code instead of
+    // being retried
+    let clock = state.clock().clone();
+    let mut record_login_failure = |e: &Error| {
+        if let Err(e) = state.record_login_failure(e, &cache) {
+            error!("Fail to update state : {:#}", e)
+        }
+    };
+    let mut session = if config.once {
+        login(&config, &clock)
+            .inspect_err(&mut record_login_failure)
+            .map_err(|e| e.context("Login to mattermost"))?
     } else {
-        info!("Wifi is enabled");
+        create_session(&config, &clock, record_login_failure)?
+    };
+    if let Err(e) = state.reset_counters(&cache) {
+        error!("Fail to reset counters : {:#}", e)
+    }
+    let custom_statuses = custom_statuses_enabled(&mut session);
+    if config.once && !custom_statuses {
+        return Err(Error::Config(anyhow!(
+            "Custom statuses are disabled on the server (`EnableCustomUserStatuses`)"
+        )));
     }
-    let mut session = create_session(&args);
-    let mut micusage = &mut micscan::MicUsage::new();
+    let mut micusage = micscan::MicUsage::new().with_clock(state.clock().clone());
+    let signals = signal::configured(&config);
+    let mut supervisor = supervisor::Supervisor::new(config.max_failures);
+    let mut connectivity = connectivity::Watcher::new(config.connectivity_check_url.clone());
+    let mut resumed = false;
+    // Timestamp of the last check for a new release, done at startup then daily
+    let mut update_checked = None;
+    let mut first = true;
+    let mut paused = state.paused_until();
+    let mut away = away::AwayTimer::new();
+    let watch_remote = config.remote_status.is_some() || config.remote_keep_online;
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_default();
     loop {
-        if !&args.is_off_time() {
-            let ssids = wifi.visible_ssid().context("Getting visible SSIDs")?;
-            debug!("Visible SSIDs {:#?}", ssids);
-            let mut found_ssid = false;
-            // Search for known wifi in visible ssids
-            for (l, mmstatus) in status_dict.iter_mut() {
-                if let Location::Known(wifi_substring) = l {
-                    if ssids.iter().any(|x| x.contains(wifi_substring)) {
-                        if wifi_substring.is_empty() {
-                            debug!("We do not match against empty SSID reserved for off time");
-                            continue;
-                        }
-                        debug!("known wifi '{}' detected", wifi_substring);
-                        found_ssid = true;
-                        mmstatus.expires_at(&args.expires_at);
-                        if let Err(e) = state.update_status(
-                            l.clone(),
-                            Some(mmstatus),
-                            &mut session,
-                            &cache,
-                            delay_duration.as_secs(),
-                        ) {
-                            error!("Fail to update status : {}", e)
-                        }
-                        break;
+        let on_battery = power::on_battery(&runner).unwrap_or_else(|e| {
+            debug!("Unable to get power source: {:?}", e);
+            false
+        });
+        let metered = config.respect_metered
+            && metered::is_metered(&runner).unwrap_or_else(|e| {
+                debug!("Unable to know if the connection is metered: {:?}", e);
+                false
+            });
+        let off_time = config.is_off_at(state.clock());
+        if !off_time {
+            if let WifiHealth::Unavailable(_) = wifi_health {
+                // The backend may have been restarted or the adapter plugged again
+                wifi = wifi_interface(&config);
+            }
+            wifi_health = check_wifi_health(wifi.as_ref(), &runner, Some(&wifi_health));
+        }
+        let Scans {
+            networks,
+            mut readings,
+            headset,
+            remote,
+        } = scan(
+            &config,
+            &signals,
+            wifi.as_ref(),
+            &wifi_health,
+            off_time,
+            resumed,
+            watch_remote,
+            &user,
+            &runner,
+        );
+        let networks = networks
+            .context("Getting visible SSIDs")
+            .map_err(Error::Platform);
+        let scanned = networks.is_ok();
+        let networks = match networks {
+            Ok(networks) => networks,
+            // Running once, the failure is reported by the exit code
+            Err(e) if config.once => return Err(e),
+            Err(e) => {
+                error!("{:#}", e);
+                Vec::new()
+            }
+        };
+        if let Some(recorder) = recorder.as_mut() {
+            let now = state.clock().now();
+            record_scan(recorder, now, off_time, &networks, &signals, &readings);
+        }
+        state.reload_requests(&cache);
+        if state.paused_until() != paused {
+            paused = state.paused_until();
+            events.publish(Event::PauseChanged(paused));
+        }
+        let focus = config.focus_at(state.clock()).filter(|_| !off_time);
+        let focus_until = state.focus_until();
+        let focus_time = signal::FocusTime {
+            active: focus.is_some() || focus_until.is_some(),
+        };
+        readings.push((focus_time.source(), focus_time.read(&runner)));
+        let observation = decision::Observation {
+            networks: &networks,
+            signals: &readings,
+            headset: headset.is_some(),
+            remote: !remote.is_empty(),
+            off_time,
+            saving: on_battery || metered,
+            resumed,
+            now: state.clock().now().timestamp(),
+        };
+        let previous = decision::Previous {
+            grace: &grace,
+            pinned: state.pinned_location(),
+            // A pause is handled as a change made by hand
+            manual_change_pending: state.manual_change_pending() || state.paused_until().is_some(),
+        };
+        let decision = decision::decide(&config, &rules, &observation, &previous);
+        grace = decision.grace.clone();
+        if let Err(e) = history.record(&decision.location, decision.evidence.as_ref()) {
+            error!("Fail to record location history : {:#}", e)
+        }
+        let known = decision.location != Location::Unknown;
+        let presence = away.update(
+            &decision.location,
+            state.clock().now().timestamp(),
+            &config.away_after,
+        );
+        let busy = busy_reading(&decision, &readings);
+        let status_override =
+            status_override(&config, &decision, focus, focus_until, state.clock());
+        let delay = supervisor.delay(decision.delay);
+        let online = connectivity.online();
+        // A server down is told apart from the failures of each API call, and
+        // backed off like them
+        let server_up = !online || !config.ping_server || {
+            let res = session.ping();
+            state.record_ping(&res);
+            if let Err(e) = &res {
+                warn!("Mattermost server is down, skipping update : {:#}", e);
+            }
+            res.is_ok()
+        };
+        // Mattermost API calls are done while we are already waiting for the next scan
+        let updated = thread::scope(|s| {
+            let updated = s.spawn(|| {
+                let mut updated = true;
+                if !online {
+                    return updated;
+                }
+                if !server_up {
+                    return false;
+                }
+                if custom_statuses {
+                    updated = update_location_status(
+                        &config,
+                        &decision,
+                        status_override.as_ref(),
+                        off_time,
+                        &rules,
+                        &mut state,
+                        &mut session,
+                        &cache,
+                        events,
+                    );
+                }
+                if config.remote_keep_online && !remote.is_empty() && !off_time {
+                    keep_online(&decision, &mut session);
+                }
+                update_away_presence(&decision, presence, &mut away, &mut session);
+                updated &= update_dnd(
+                    &decision,
+                    &busy,
+                    time::Duration::from_secs((delay + config.delay_jitter).into()),
+                    &mut micusage,
+                    &mut state,
+                    &mut session,
+                    events,
+                );
+                if config.weekly_summary && !off_time && !metered {
+                    send_weekly_summary(&history, &mut state, &mut session, &cache);
+                }
+                notify_token_expiry(&config, &mut state, &mut session, &cache, events);
+                if config.update_check && !metered {
+                    let now = state.clock().now().timestamp();
+                    // A failed check is tried again on the next cycle
+                    if update::check_due(update_checked, now)
+                        && notify_update(&mut state, &mut session, &cache)
+                    {
+                        update_checked = Some(now);
                     }
                 }
+                updated
+            });
+            // The first update is waited for, so that its result is reported
+            // before sleeping
+            if !config.once && !first {
+                resumed = wakeup::sleep_until_clock_jump(
+                    utils::with_jitter(time::Duration::new(delay.into(), 0), config.delay_jitter),
+                    &stop,
+                );
             }
-            if !found_ssid {
-                debug!("Unknown wifi");
-                if let Err(e) = state.update_status(
-                    Location::Unknown,
-                    None,
-                    &mut session,
-                    &cache,
-                    delay_duration.as_secs(),
-                ) {
-                    error!("Fail to update status : {}", e)
+            updated.join().unwrap_or(false)
+        });
+        if let Err(e) = state.count_cycle(session.take_relogins(), &cache) {
+            error!("Fail to update counters : {:#}", e)
+        }
+        if config.once {
+            // Running once, the failure is reported by the exit code
+            return if !updated {
+                Err(Error::Network(anyhow!("Fail to update mattermost status")))
+            } else if known {
+                Ok(Outcome::Updated)
+            } else {
+                Ok(Outcome::NoKnownLocation)
+            };
+        }
+        if first {
+            if updated {
+                info!("Initial update done");
+            } else {
+                warn!("Initial update failed, retrying in {}s", delay);
+            }
+            first = false;
+            resumed = wakeup::sleep_until_clock_jump(
+                utils::with_jitter(time::Duration::new(delay.into(), 0), config.delay_jitter),
+                &stop,
+            );
+        }
+        if supervisor.record(scanned && updated) == supervisor::Action::Rebuild {
+            wifi = wifi_interface(&config);
+            session = create_session(&config, &clock, |e| {
+                if let Err(e) = state.record_login_failure(e, &cache) {
+                    error!("Fail to update state : {:#}", e)
                 }
+            })?;
+        }
+        if stop.load(Ordering::Relaxed) {
+            info!("Exiting");
+            if config.clear_on_exit {
+                clear_status(&mut state, &mut session, &cache);
             }
-        } else {
-            // Send status for Off time (the one with empty wifi_substring).
-            let off_location = Location::Known("".to_string());
-            if let Some(offstatus) = status_dict.get_mut(&off_location) {
-                debug!("Setting state for Offtime");
-                if let Err(e) = state.update_status(
-                    off_location,
-                    Some(offstatus),
-                    &mut session,
-                    &cache,
-                    delay_duration.as_secs(),
-                ) {
-                    error!("Fail to update status : {}", e)
+            break;
+        }
+    }
+    Ok(Outcome::Updated)
+}
+
+/// Results of the scans of a cycle
+struct Scans {
+    /// visible wifi networks
+    networks: std::result::Result<Vec<Network>, wifiscan::WifiError>,
+    /// readings of the busy signals
+    readings: Vec<(StatusSource, Reading)>,
+    /// connected headset, if any
+    headset: Option<String>,
+    /// remote sessions in progress
+    remote: Vec<String>,
+}
+
+/// Scan concurrently the wifi networks, the busy `signals`, the headsets and
+/// the remote sessions. Only the failure of the wifi scan is returned, the
+/// others being logged.
+///
+/// Once `resumed`, the wifi networks cached by the OS before the suspend are
+/// not trusted and a new wifi scan is done.
+#[allow(clippy::too_many_arguments)]
+fn scan(
+    config: &Config,
+    signals: &[Box<dyn PresenceSignal>],
+    wifi: &dyn WifiInterface,
+    wifi_health: &WifiHealth,
+    off_time: bool,
+    resumed: bool,
+    watch_remote: bool,
+    user: &str,
+    runner: &SystemRunner,
+) -> Scans {
+    // Wifi and busy signal scans are slow, so we run them concurrently
+    thread::scope(|s| {
+        let readings: Vec<_> = signals
+            .iter()
+            .map(|signal| s.spawn(move || signal.read(runner)))
+            .collect();
+        let headset = s.spawn(|| match config.headset_status {
+            Some(_) => micscan::find_headset(&config.headset_names, runner),
+            None => Ok(None),
+        });
+        let remote = s.spawn(|| match watch_remote {
+            true => remote::active_sessions(user, runner),
+            false => Ok(Vec::new()),
+        });
+        // A disabled wifi is not a scan failure, the location is just unknown
+        let networks = match wifi_health {
+            _ if off_time => Ok(Vec::new()),
+            WifiHealth::Enabled if resumed => wifi.rescanned_networks(runner),
+            WifiHealth::Enabled => wifi.visible_networks(runner),
+            WifiHealth::Disabled => Ok(Vec::new()),
+            WifiHealth::Unavailable(reason) => {
+                Err(wifiscan::WifiError::Unavailable(reason.clone()))
+            }
+        };
+        Scans {
+            networks,
+            readings: signals
+                .iter()
+                .zip(readings)
+                .map(|(signal, reading)| {
+                    let reading = reading.join().unwrap_or_else(|_| {
+                        Reading::Unknown(format!("{} scan panicked", signal.name()))
+                    });
+                    if let Reading::Unknown(e) = &reading {
+                        error!("Fail to read the {} signal : {}", signal.name(), e);
+                    }
+                    (signal.source(), reading)
+                })
+                .collect(),
+            headset: headset
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Headset scan panicked")))
+                .unwrap_or_else(|e| {
+                    error!("Fail to detect headsets : {:#}", e);
+                    None
+                }),
+            remote: remote
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Remote sessions scan panicked")))
+                .unwrap_or_else(|e| {
+                    error!("Fail to detect remote sessions : {:#}", e);
+                    Vec::new()
+                }),
+        }
+    })
+}
+
+/// Record the scans of a cycle done at `now` with `recorder`, to be replayed
+/// later
+fn record_scan(
+    recorder: &mut replay::Recorder,
+    now: DateTime<Local>,
+    off_time: bool,
+    networks: &[Network],
+    signals: &[Box<dyn PresenceSignal>],
+    readings: &[(StatusSource, Reading)],
+) {
+    let scan = replay::Scan {
+        timestamp: now,
+        off_time,
+        ssids: networks.iter().map(|n| n.ssid.clone()).collect(),
+        mic_apps: match signals
+            .iter()
+            .zip(readings)
+            .find(|(signal, _)| signal.name() == "microphone")
+        {
+            Some((_, (_, Reading::Busy(apps)))) => Ok(apps.clone()),
+            Some((_, (_, Reading::Unknown(e)))) => Err(e.clone()),
+            _ => Ok(Vec::new()),
+        },
+    };
+    if let Err(e) = recorder.record(&scan) {
+        error!("Fail to record scan : {:#}", e)
+    }
+}
+
+/// Return the health of the wifi, logging the changes from `previous`.
+///
+/// An unavailable backend is reported as a scan failure, so that the
+/// [`supervisor`] backs off until it recovers.
+fn check_wifi_health(
+    wifi: &dyn WifiInterface,
+    runner: &dyn CommandRunner,
+    previous: Option<&WifiHealth>,
+) -> WifiHealth {
+    let health = wifi.health(runner);
+    if previous == Some(&health) {
+        return health;
+    }
+    match (&health, previous) {
+        (WifiHealth::Enabled, Some(WifiHealth::Unavailable(_))) => {
+            info!("Wifi backend recovered")
+        }
+        (WifiHealth::Enabled, _) => info!("Wifi is enabled"),
+        (WifiHealth::Disabled, _) => {
+            warn!("Wifi is disabled, the location is unknown until it is enabled again")
+        }
+        (WifiHealth::Unavailable(reason), _) => {
+            warn!(
+                "Wifi backend unavailable ({}), checking again at each update",
+                reason
+            )
+        }
+    }
+    health
+}
+
+/// Return the location of the first of the `rules` matching the visible `ssids`,
+/// or the off time location (the one with an empty wifi substring) when
+/// `off_time` is set.
+///
+/// SSIDs and wifi substrings are compared once normalized (see
+/// [`utils::normalize`]). [`Location::Unknown`] is returned if no configured
+/// location matches.
+pub fn find_location(
+    rules: &[LocationRule],
+    ssids: &[String],
+    off_time: bool,
+    case_insensitive: bool,
+) -> Location {
+    if off_time {
+        let off_location = Location::Known("".to_string());
+        if rules.iter().any(|rule| rule.location == off_location) {
+            debug!("Setting state for Offtime");
+            return off_location;
+        }
+        return Location::Unknown;
+    }
+    debug!("Visible SSIDs {:#?}", ssids);
+    let ssids: Vec<String> = ssids
+        .iter()
+        .map(|s| utils::normalize(s, case_insensitive))
+        .collect();
+    // Search for known wifi in visible ssids
+    for rule in rules {
+        if let Location::Known(wifi_substring) = &rule.location {
+            let wifi_substring = utils::normalize(wifi_substring, case_insensitive);
+            if ssids.iter().any(|x| x.contains(&wifi_substring)) {
+                if wifi_substring.is_empty() {
+                    debug!("We do not match against empty SSID reserved for off time");
+                    continue;
                 }
+                debug!("known wifi '{}' detected", wifi_substring);
+                return rule.location.clone();
             }
         }
-        micusage = micusage.update_dnd_status(&args, &mut session);
-        if let Some(0) = args.delay {
-            break;
-        } else {
-            sleep(delay_duration);
+    }
+    debug!("Unknown wifi");
+    Location::Unknown
+}
+
+/// Return the visible network with the strongest signal among the `networks`
+/// matching `location`.
+pub fn matching_network(
+    location: &Location,
+    networks: &[Network],
+    case_insensitive: bool,
+) -> Option<Network> {
+    let wifi_substring = match location {
+        Location::Known(wifi) if !wifi.is_empty() => utils::normalize(wifi, case_insensitive),
+        _ => return None,
+    };
+    networks
+        .iter()
+        .filter(|n| utils::normalize(&n.ssid, case_insensitive).contains(&wifi_substring))
+        .max_by_key(|n| n.signal)
+        .cloned()
+}
+
+/// Update custom status according to `location`, `status_override` replacing
+/// the status of the location (e.g. `headset_status` while a headset is
+/// connected). It is also sent at unknown locations, where it is left until it
+/// expires once over.
+///
+/// The expiry of `status_override`, if any, is kept. Nothing is sent unless
+/// `decision` tells to set the custom status.
+#[allow(clippy::too_many_arguments)]
+fn update_location_status(
+    config: &Config,
+    decision: &decision::Decision,
+    status_override: Option<&MMCustomStatus>,
+    off_time: bool,
+    rules: &[LocationRule],
+    state: &mut State,
+    session: &mut LoggedSession,
+    cache: &Cache,
+    events: &EventBus,
+) -> bool {
+    if !decision.set_custom_status {
+        return true;
+    }
+    let (location, evidence, refresh) = (
+        decision.location.clone(),
+        decision.evidence.as_ref(),
+        decision.refresh,
+    );
+    let status = match status_override {
+        Some(status) => Some(status),
+        None => rules
+            .iter()
+            .find(|rule| rule.location == location)
+            .map(|rule| rule.status(state.clock().now())),
+    };
+    let res = match status {
+        Some(status) => {
+            let mut mmstatus = status.clone();
+            if !off_time && mmstatus.expires_at.is_none() {
+                mmstatus.expires_at(config.expires_at, state.clock());
+                mmstatus.keep_expiry_of(state.last_status(), state.clock());
+            }
+            state.update_status(
+                location,
+                Some(&mmstatus),
+                evidence,
+                session,
+                cache,
+                events,
+                refresh,
+            )
+        }
+        None => state.update_status(location, None, None, session, cache, events, refresh),
+    };
+    if let Err(e) = &res {
+        error!("Fail to update status : {}", e)
+    }
+    res.is_ok()
+}
+
+/// Return the custom status replacing the one of the location according to the
+/// winner of `decision`, if any: the focus session ending at `focus_until` or
+/// the `focus` block, `remote_status` or `headset_status`
+fn status_override(
+    config: &Config,
+    decision: &decision::Decision,
+    focus: Option<&offtime::FocusBlock>,
+    focus_until: Option<i64>,
+    clock: &dyn Clock,
+) -> Option<MMCustomStatus> {
+    let focused = decision.winner == Some(StatusSource::Focus);
+    let session_end = focus_until.and_then(|until| Local.timestamp_opt(until, 0).single());
+    match (
+        &config.remote_status,
+        &config.headset_status,
+        focus,
+        session_end,
+    ) {
+        // A session started by hand wins over the focus blocks
+        (_, _, _, Some(end)) if focused => Some(session_status(&config.focus_session_status, end)),
+        // Focus time ends with its block
+        (_, _, Some(block), _) if focused => {
+            let mut status = config.focus_status.clone();
+            status.expires_at(block.end().map(Expiry::At), clock);
+            Some(status)
+        }
+        (Some(status), _, _, _) if decision.remote => Some(status.clone()),
+        (_, Some(status), _, _) if decision.headset => Some(status.clone()),
+        _ => None,
+    }
+}
+
+/// Return the reading setting *do not disturb*, from the microphone and from
+/// focus time when it is the winner of `decision`
+fn busy_reading(decision: &decision::Decision, readings: &[(StatusSource, Reading)]) -> Reading {
+    let focused = decision.winner == Some(StatusSource::Focus);
+    signal::aggregate(
+        readings
+            .iter()
+            .filter(|(source, _)| {
+                *source == StatusSource::Mic || (focused && *source == StatusSource::Focus)
+            })
+            .map(|(_, reading)| reading),
+    )
+}
+
+/// Set the presence returned by the [`away::AwayTimer`], if the custom status
+/// is set by `decision`. It is set again on the next cycle if it failed.
+fn update_away_presence(
+    decision: &decision::Decision,
+    presence: Option<Status>,
+    away: &mut away::AwayTimer,
+    session: &mut LoggedSession,
+) {
+    if let Some(presence) = presence.filter(|_| decision.set_custom_status) {
+        if away::set_presence(presence, session) {
+            away.presence_set(presence);
         }
     }
+}
+
+/// Let *do not disturb* follow `busy` if `decision` tells so, and return
+/// `false` if it cannot be set
+fn update_dnd(
+    decision: &decision::Decision,
+    busy: &Reading,
+    next_check: time::Duration,
+    micusage: &mut micscan::MicUsage,
+    state: &mut State,
+    session: &mut LoggedSession,
+    events: &EventBus,
+) -> bool {
+    if !decision.update_dnd {
+        return true;
+    }
+    let sent = micusage.update_dnd_status(busy, next_check, session, events);
+    if let Err(e) = &sent {
+        error!("{:#}", e);
+    }
+    state.record_presence(&sent);
+    sent.is_ok()
+}
+
+/// Set `stop` when the process is asked to terminate. A second signal
+/// terminates the process immediately.
+fn register_stop_signals(stop: &Arc<AtomicBool>) -> std::io::Result<()> {
+    for sig in TERM_SIGNALS {
+        signal_hook::flag::register_conditional_shutdown(*sig, 1, Arc::clone(stop))?;
+        signal_hook::flag::register(*sig, Arc::clone(stop))?;
+    }
     Ok(())
 }
 
+/// Return `false`, after telling the user, if custom statuses are disabled on
+/// the server. Only the presence is then updated.
+fn custom_statuses_enabled(session: &mut LoggedSession) -> bool {
+    match session.custom_statuses_enabled() {
+        Ok(Some(false)) => {
+            error!(
+                "Custom statuses are disabled on the server, only the presence will be \
+                 updated. Ask your administrator to set `EnableCustomUserStatuses`"
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            warn!("Unable to check if custom statuses are enabled : {}", e);
+            true
+        }
+    }
+}
+
+/// Return the custom status of a focus session ending at `end`, whose `{until}`
+/// placeholder is replaced by the end time
+/// ```
+/// use chrono::{Local, TimeZone};
+/// use lib::{session_status, MMCustomStatus};
+/// let template = MMCustomStatus::new("focused until {until}".to_string(), "tomato".to_string());
+/// let end = Local.with_ymd_and_hms(2024, 3, 15, 14, 25, 0).unwrap();
+/// let status = session_status(&template, end);
+/// assert_eq!(status.text, "focused until 14:25");
+/// assert_eq!(status.expires_at, Some(end));
+/// ```
+pub fn session_status(template: &MMCustomStatus, end: DateTime<Local>) -> MMCustomStatus {
+    let mut status = template.clone();
+    status.text = status
+        .text
+        .replace("{until}", &end.format("%H:%M").to_string());
+    status.expires_at = Some(end);
+    status.duration = Some("date_and_time".to_owned());
+    status
+}
+
+/// Set the presence back to online if mattermost has set it away by itself,
+/// unless the microphone is the winner of `decision`
+fn keep_online(decision: &decision::Decision, session: &mut LoggedSession) {
+    if decision.winner == Some(StatusSource::Mic) {
+        return;
+    }
+    match MMStatus::fetch(session) {
+        Ok(current) if current.status == Status::Away && !current.manual => {
+            info!("Remote session in progress, setting presence back to online");
+            if let Err(e) = MMStatus::new(Status::Online, session.user_id.clone()).send(session) {
+                error!("Fail to set presence online : {}", e)
+            }
+        }
+        Ok(_) => (),
+        Err(e) => debug!("Unable to get current presence: {}", e),
+    }
+}
+
+/// Delete the custom status and reset presence to online
+fn clear_status(state: &mut State, session: &mut LoggedSession, cache: &Cache) {
+    info!("Clearing custom status");
+    if let Err(e) = MMCustomStatus::clear(session) {
+        error!("Fail to clear custom status : {}", e)
+    }
+    if let Err(e) = MMStatus::new(Status::Online, session.user_id.clone()).send(session) {
+        error!("Fail to reset presence : {}", e)
+    }
+    if let Err(e) = state.forget_status(cache) {
+        error!("Fail to update state : {:#}", e)
+    }
+}
+
+/// Warn about a newer release, and tell the user about it once with a direct
+/// message. Return `false` if the latest release cannot be fetched.
+fn notify_update(state: &mut State, session: &mut LoggedSession, cache: &Cache) -> bool {
+    let latest = match update::latest_version(&session.agent, update::RELEASES_URL) {
+        Ok(latest) => latest,
+        Err(e) => {
+            warn!("Fail to check for a new release : {}", e);
+            return false;
+        }
+    };
+    if !update::is_newer(&latest, update::CURRENT_VERSION) {
+        debug!(
+            "automattermostatus {} is up to date",
+            update::CURRENT_VERSION
+        );
+        return true;
+    }
+    warn!(
+        "automattermostatus {} is available (running {})",
+        latest,
+        update::CURRENT_VERSION
+    );
+    if state.notified_version() == Some(latest.as_str()) {
+        return true;
+    }
+    let res = session
+        .send_direct_message(&update::message(&latest))
+        .map_err(anyhow::Error::from)
+        .and_then(|_| state.set_notified_version(&latest, cache));
+    if let Err(e) = res {
+        error!("Fail to send new release notification : {:#}", e)
+    }
+    true
+}
+
+/// Tell the user once with a direct message and an event that the personal
+/// access token expires at `config.token_expires_on`, or that the session of a
+/// password login expires, `config.token_expiry_notice_days` days before.
+fn notify_token_expiry(
+    config: &Config,
+    state: &mut State,
+    session: &mut LoggedSession,
+    cache: &Cache,
+    events: &EventBus,
+) {
+    let (credential, expires_on) = match config.token_expires_on {
+        Some(expires_on) => (token_expiry::Credential::Token, expires_on),
+        None if !matches!(config.credentials, Credentials::Password { .. }) => return,
+        None => match session.expires_on() {
+            Ok(Some(expires_on)) => (token_expiry::Credential::Session, expires_on),
+            Ok(None) => return,
+            Err(e) => {
+                debug!("Unable to get the session expiry : {}", e);
+                return;
+            }
+        },
+    };
+    let today = state.clock().now().date_naive();
+    if !token_expiry::notice_due(
+        expires_on,
+        config.token_expiry_notice_days,
+        today,
+        state.notified_token_expiry(),
+    ) {
+        return;
+    }
+    warn!("The mattermost {:?} expires on {}", credential, expires_on);
+    let message = token_expiry::message(credential, expires_on, today);
+    let res = session
+        .send_direct_message(&message)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| state.set_notified_token_expiry(expires_on, cache));
+    match res {
+        Ok(()) => events.publish(Event::ExpiryNotified(message)),
+        Err(e) => error!("Fail to send token expiry notification : {:#}", e),
+    }
+}
+
+/// Send the summary of the previous week to the user if it is due (see
+/// [`stats::weekly_summary_due`]).
+fn send_weekly_summary(
+    history: &stats::History,
+    state: &mut State,
+    session: &mut LoggedSession,
+    cache: &Cache,
+) {
+    let now = state.clock().now();
+    if !stats::weekly_summary_due(state.summary_timestamp(), now) {
+        return;
+    }
+    let res = history.entries().and_then(|entries| {
+        let summary = stats::weekly_summary(&entries, now - chrono::Duration::days(7), now);
+        session.send_direct_message(&summary)?;
+        state.set_summary_timestamp(now.timestamp(), cache)
+    });
+    if let Err(e) = res {
+        error!("Fail to send weekly summary : {:#}", e)
+    }
+}
+
 #[cfg(test)]
 mod get_cache_should {
     use super::*;
-    use anyhow::anyhow;
+    use anyhow::{anyhow, Result};
     use test_log::test; // Automatically trace tests
 
     #[test]
@@ -208,10 +1206,91 @@ mod get_cache_should {
             Ok(_) => Err(anyhow!("Expected an error")),
             Err(e) => {
                 assert_eq!(e.to_string(), "Internal Error, no `state_dir` configured");
+                assert!(matches!(e, Error::Config(_)));
                 Ok(())
             }
         }
     }
+
+    #[test]
+    fn reuse_shared_state_file() -> Result<()> {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        fs::write(dir.join(state::STATE_FILE_NAME), "shared")?;
+        let file = dir.join(state::file_name("https://mm.example.com", None));
+        get_cache(Some(file.clone()))?;
+        assert_eq!(fs::read_to_string(&file)?, "shared");
+        assert!(!dir.join(state::STATE_FILE_NAME).exists());
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod check_wifi_health_should {
+    use super::*;
+    use command::MockCommandRunner;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn not_fail_when_wifi_cannot_be_checked() {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .returning(|_, _| Ok("disabled\n".to_string()));
+        assert_eq!(
+            check_wifi_health(&WiFi::new("wlan0"), &runner, Some(&WifiHealth::Enabled)),
+            WifiHealth::Disabled
+        );
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .returning(|_, _| Err(std::io::Error::other("no nmcli")));
+        assert!(matches!(
+            check_wifi_health(&WiFi::new("wlan0"), &runner, Some(&WifiHealth::Disabled)),
+            WifiHealth::Unavailable(_)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod send_weekly_summary_should {
+    use super::*;
+    use crate::clock::{ManualClock, SharedClock};
+    use crate::mattermost::testing::logged_session;
+    use anyhow::Result;
+    use httpmock::prelude::*;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn send_on_mondays_of_the_state_clock() -> Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        server.mock(|expect, resp_with| {
+            expect.method(POST).path("/api/v4/channels/direct");
+            resp_with
+                .status(201)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"channel_id"}));
+        });
+        let post_mock = server.mock(|expect, resp_with| {
+            expect.method(POST).path("/api/v4/posts");
+            resp_with.status(201).body("{}");
+        });
+        let temp = Temp::new_dir().unwrap();
+        let cache = Cache::new(temp.join("state.json"));
+        let history = stats::History::new(temp.join(stats::HISTORY_FILE_NAME));
+        let monday = Local.with_ymd_and_hms(2024, 1, 15, 9, 0, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(monday - chrono::Duration::days(1)));
+        let mut state = State::new(&cache)?.with_clock(SharedClock::from(clock.clone()));
+        send_weekly_summary(&history, &mut state, &mut session, &cache);
+        post_mock.assert_hits(0);
+        clock.advance(chrono::Duration::days(1));
+        send_weekly_summary(&history, &mut state, &mut session, &cache);
+        send_weekly_summary(&history, &mut state, &mut session, &cache);
+        post_mock.assert_hits(1);
+        assert_eq!(state.summary_timestamp(), monday.timestamp());
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -221,61 +1300,172 @@ mod prepare_status_should {
 
     #[test]
     fn prepare_expected_status() -> Result<()> {
-        let args = Args {
+        let config = Args {
             status: vec!["a::b::c", "d::e::f", "::off::off text"]
                 .iter()
                 .map(|s| s.to_string())
                 .collect(),
             mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
             ..Default::default()
+        }
+        .validate()?;
+        let res = prepare_status(&config)?;
+        let rule = |wifi: &str, text: &str, emoji: &str| LocationRule {
+            location: Location::Known(wifi.to_string()),
+            statuses: vec![MMCustomStatus::new(text.to_string(), emoji.to_string())],
         };
-        let res = prepare_status(&args)?;
-        let mut expected: HashMap<state::Location, mattermost::MMCustomStatus> = HashMap::new();
-        expected.insert(
-            Location::Known("".to_string()),
-            MMCustomStatus::new("off text".to_string(), "off".to_string()),
-        );
-        expected.insert(
-            Location::Known("a".to_string()),
-            MMCustomStatus::new("c".to_string(), "b".to_string()),
-        );
-        expected.insert(
-            Location::Known("d".to_string()),
-            MMCustomStatus::new("f".to_string(), "e".to_string()),
-        );
+        let expected = vec![
+            rule("a", "c", "b"),
+            rule("d", "f", "e"),
+            rule("", "off text", "off"),
+        ];
         assert_eq!(res, expected);
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod create_session_should {
-    use super::*;
     #[test]
-    #[should_panic(expected = "Mattermost URL is not defined")]
-    fn panic_when_mm_url_is_none() {
-        let args = Args {
+    fn skip_invalid_status_unless_strict() -> Result<()> {
+        let config = |status: &[&str], strict| {
+            Args {
+                status: status.iter().map(|s| s.to_string()).collect(),
+                mm_secret: Some("AAA".to_string()),
+                secret_type: Some(SecretType::Token),
+                strict,
+                ..Default::default()
+            }
+            .validate()
+        };
+        let res = prepare_status(&config(&["a::b::c", "d:e::f"], false)?)?;
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].location, Location::Known("a".to_string()));
+        let err = prepare_status(&config(&["a::b::c", "d:e::f"], true)?).unwrap_err();
+        assert!(err.to_string().starts_with("Parsing status #2 `d:e::f`"));
+        assert!(prepare_status(&config(&["d:e::f"], false)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn append_machine_tag() -> Result<()> {
+        let config = Args {
             status: vec!["a::b::c".to_string()],
             mm_secret: Some("AAA".to_string()),
-            mm_url: None,
+            secret_type: Some(SecretType::Token),
+            machine_tag: Some("laptop".to_string()),
             ..Default::default()
+        }
+        .validate()?;
+        let res = prepare_status(&config)?;
+        assert_eq!(res[0].statuses[0].text, "c · laptop");
+        Ok(())
+    }
+
+    #[test]
+    fn warn_refuse_or_rotate_duplicate_wifi_strings() -> Result<()> {
+        let config = |duplicate_status| {
+            Args {
+                status: ["a::b::c", "d::e::f", "a::g::h"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+                mm_secret: Some("AAA".to_string()),
+                secret_type: Some(SecretType::Token),
+                duplicate_status: Some(duplicate_status),
+                ..Default::default()
+            }
+            .validate()
         };
-        let _res = create_session(&args);
+        let res = prepare_status(&config(DuplicatePolicy::Warn)?)?;
+        assert_eq!(res.len(), 2);
+        assert_eq!(
+            res[0].statuses,
+            vec![MMCustomStatus::new("h".to_string(), "g".to_string())]
+        );
+        let err = prepare_status(&config(DuplicatePolicy::Error)?).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Several `status` triplets share the wifi substring `a`"));
+        let res = prepare_status(&config(DuplicatePolicy::Rotate)?)?;
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].location, Location::Known("a".to_string()));
+        assert_eq!(
+            res[0].statuses,
+            vec![
+                MMCustomStatus::new("c".to_string(), "b".to_string()),
+                MMCustomStatus::new("h".to_string(), "g".to_string()),
+            ]
+        );
+        assert_eq!(res[1].statuses.len(), 1);
+        Ok(())
     }
 }
 
 #[cfg(test)]
-mod main_loop_should {
+mod find_location_should {
     use super::*;
+    use test_log::test; // Automatically trace tests
 
-    #[test]
-    #[should_panic(expected = "Internal error: args.delay shouldn't be None")]
-    fn panic_when_args_delay_is_none() {
-        let args = Args {
-            status: vec!["a::b::c".to_string()],
-            delay: None,
-            ..Default::default()
+    fn rules() -> Vec<LocationRule> {
+        let rule = |wifi: &str, text: &str, emoji: &str| LocationRule {
+            location: Location::Known(wifi.to_string()),
+            statuses: vec![MMCustomStatus::new(text.to_string(), emoji.to_string())],
         };
-        let _res = get_wifi_and_update_status_loop(args, HashMap::new());
+        vec![
+            rule("corp-office", "at work", "office"),
+            rule("corp", "at a corp site", "building"),
+            rule("Caf\u{e9}", "at café", "coffee"),
+        ]
+    }
+
+    #[test]
+    fn match_normalized_ssid() {
+        let ssids = vec!["Cafe\u{301}-guest".to_string()];
+        assert_eq!(
+            find_location(&rules(), &ssids, false, false),
+            Location::Known("Caf\u{e9}".to_string())
+        );
+    }
+
+    #[test]
+    fn evaluate_rules_in_order() {
+        let ssids = vec!["corp-office".to_string()];
+        let mut rules = rules();
+        assert_eq!(
+            find_location(&rules, &ssids, false, false),
+            Location::Known("corp-office".to_string())
+        );
+        rules.swap(0, 1);
+        assert_eq!(
+            find_location(&rules, &ssids, false, false),
+            Location::Known("corp".to_string())
+        );
+    }
+
+    #[test]
+    fn ignore_case_only_when_asked() {
+        let ssids = vec!["CORP-Office".to_string()];
+        assert_eq!(
+            find_location(&rules(), &ssids, false, false),
+            Location::Unknown
+        );
+        assert_eq!(
+            find_location(&rules(), &ssids, false, true),
+            Location::Known("corp-office".to_string())
+        );
+    }
+
+    #[test]
+    fn return_strongest_matching_network() {
+        let networks = vec![
+            Network::with_quality("corp-office-FL2", 40),
+            Network::with_quality("guest", 100),
+            Network::with_quality("corp-office-FL3", 96),
+        ];
+        let location = Location::Known("corp-office".to_string());
+        assert_eq!(
+            matching_network(&location, &networks, false),
+            Some(Network::with_quality("corp-office-FL3", 96))
+        );
+        assert_eq!(matching_network(&Location::Unknown, &networks, false), None);
     }
 }
@@ -1,21 +1,488 @@
 #![warn(missing_docs)]
 #![doc = include_str!("../README.md")]
 
+use ::lib::clock::SystemClock;
 use ::lib::config::Args;
+use ::lib::offtime::Off;
 use ::lib::*;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::io::{self, BufRead, IsTerminal};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
-#[paw::main]
-fn main(args: Args) -> Result<()> {
-    setup_tracing(&args).context("Setting up tracing")?;
-    let args = args
-        .merge_config_and_params()?
+fn main() {
+    if let Err(e) = run_command(Args::parse()) {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(error::exit_code(&e));
+    }
+}
+
+/// Run the subcommand of `args`, the status update loop by default.
+fn run_command(args: Args) -> Result<()> {
+    // The service logs to files once its configuration is known
+    if args.command != Some(SubCommand::Service(ServiceCommand::Run)) {
+        setup_tracing(&args).context("Setting up tracing")?;
+    }
+    if let Some(command) = args.command.clone() {
+        return match command {
+            SubCommand::ImportNetworks => import_networks(&args),
+            SubCommand::Stats { weeks } => print_stats(&args, weeks),
+            SubCommand::Status(StatusCommand::Show { remote, stats }) => {
+                show_state(args, remote, stats)
+            }
+            SubCommand::Status(command) => update_custom_status(&load_config(args)?, &command),
+            SubCommand::Config(ConfigCommand::Schema) => {
+                println!("{}", config::schema()?);
+                Ok(())
+            }
+            SubCommand::Location(command) => pin_location(&args, &command),
+            SubCommand::Focus(command) => start_focus(&args, &command),
+            SubCommand::Pause { duration } => pause(&args, Some(duration)),
+            SubCommand::Resume => pause(&args, None),
+            SubCommand::Doctor => diagnose(&args),
+            SubCommand::Backup { file } => backup(&args, &file),
+            SubCommand::Restore { file } => restore(&args, &file),
+            SubCommand::Reset { history } => reset(&args, history),
+            SubCommand::Run { once, daemon } => run(args, once, daemon),
+            SubCommand::Service(command) => manage_service(args, &command),
+        };
+    }
+    run(args, false, false)
+}
+
+/// Run the status update loop, or a single cycle if `once` is set.
+fn run(args: Args, once: bool, daemon: bool) -> Result<()> {
+    run_config(load_config(args)?, once, daemon, Arc::default())
+}
+
+/// Run the status update loop of `config` until `stop` is set, or a single
+/// cycle if `once` is set.
+fn run_config(mut config: Config, once: bool, daemon: bool, stop: Arc<AtomicBool>) -> Result<()> {
+    if config.once && !once {
+        if daemon {
+            bail!("`delay` shall be positive with `run --daemon`");
+        }
+        tracing::warn!("`delay = 0` is deprecated, use `run --once` instead");
+    }
+    config.once |= once;
+    #[cfg(feature = "telemetry")]
+    let reporter = crash_reporter(&config)?;
+    let rules = prepare_status(&config).context("Building custom status messages")?;
+    if let Some(recording) = &config.replay {
+        let scans = replay::load(recording)?;
+        replay::replay(&config, &rules, scans, &EventBus::new());
+    } else if config.ssid_from_stdin {
+        let scans = io::stdin()
+            .lock()
+            .lines()
+            .map_while(std::result::Result::ok)
+            .map(|line| replay::Scan::from_ssid_line(&line, config.is_off_time()));
+        replay::replay(&config, &rules, scans, &EventBus::new());
+    } else {
+        let once = config.once;
+        #[cfg(target_os = "linux")]
+        if !once {
+            service::activation::serve(service::activation::listeners(), config.state_file.clone());
+        }
+        let events = events(&config)?;
+        // Errors give the exit code of their category (see `lib::error`)
+        let res = get_wifi_and_update_status_loop(config, rules, &events, stop)
+            .map_err(anyhow::Error::from);
+        #[cfg(feature = "telemetry")]
+        if let (Err(e), Some(reporter)) = (&res, &reporter) {
+            reporter.report_error(e);
+        }
+        if let (Ok(Outcome::NoKnownLocation), true) = (&res, once) {
+            std::process::exit(error::EXIT_NO_KNOWN_LOCATION);
+        }
+        res?;
+    }
+    Ok(())
+}
+
+/// Return the event bus of the status update loop, showing the status changes
+/// with `toast_notifications` and exporting them with `dbus_properties`.
+fn events(config: &Config) -> Result<EventBus> {
+    let mut events = EventBus::new();
+    if config.once {
+        return Ok(events);
+    }
+    if config.toast_notifications {
+        #[cfg(target_os = "windows")]
+        toast::register_uri_scheme(&std::env::current_exe()?)
+            .context("Registering the URI scheme of the notifications")?;
+        #[cfg(not(target_os = "windows"))]
+        tracing::warn!("`toast_notifications` is only supported on windows");
+        events.subscribe(toast::Toasts {
+            log_dir: service::log_dir(&config.state_dir),
+            runner: SystemRunner,
+        });
+    }
+    #[cfg(all(feature = "dbus", target_os = "linux"))]
+    if config.dbus_properties {
+        let state = State::new(&get_cache(Some(config.state_file.clone()))?)?;
+        let properties =
+            dbus::Properties::new(state.location(), state.last_status(), state.paused_until());
+        match dbus::Widget::serve(properties) {
+            Ok(widget) => events.subscribe(widget),
+            Err(e) => tracing::warn!("Unable to export the D-Bus properties: {}", e),
+        }
+    }
+    Ok(events)
+}
+
+/// Merge configuration file and parameters, retrieve the secret and validate
+/// the result.
+fn load_config(args: Args) -> Result<Config> {
+    args.merge_config_and_params()?
         // Retrieve token if possible
-        .update_secret_with_command()
-        .context("Get secret from mm_secret_cmd")?
-        .update_secret_with_keyring()
-        .context("Get secret from OS keyring")?;
-    let status_dict = prepare_status(&args).context("Building custom status messages")?;
-    get_wifi_and_update_status_loop(args, status_dict)?;
+        .resolve_secret()
+        .context("Get secret")?
+        .validate()
+        .context("Validating configuration")
+}
+
+/// Return the crash reporter, with its panic hook installed, if `crash_report`
+/// is set.
+#[cfg(feature = "telemetry")]
+fn crash_reporter(config: &Config) -> Result<Option<telemetry::Reporter>> {
+    if !config.crash_report {
+        return Ok(None);
+    }
+    let reporter = telemetry::Reporter::new(
+        config.state_dir.join(telemetry::REPORT_FILE_NAME),
+        config.crash_report_dsn.as_deref(),
+    )?;
+    reporter.clone().install_panic_hook();
+    Ok(Some(reporter))
+}
+
+/// Set or clear the custom status once with the configured session.
+fn update_custom_status(config: &Config, command: &StatusCommand) -> Result<()> {
+    let mut session = login(config, &SystemClock).context("Login to mattermost")?;
+    match command {
+        StatusCommand::Set {
+            emoji,
+            text,
+            expires_at,
+        } => {
+            let mut status = MMCustomStatus::new(text.to_owned(), emoji.to_owned());
+            status.expires_at(*expires_at, &SystemClock);
+            status
+                .send(&mut session)
+                .map_err(Error::from)
+                .context("Setting custom status")?;
+            println!("Custom status set to {}", status);
+        }
+        StatusCommand::Clear => {
+            MMCustomStatus::clear(&mut session)
+                .map_err(Error::from)
+                .context("Clearing custom status")?;
+            println!("Custom status cleared");
+        }
+        StatusCommand::Show { .. } => unreachable!("Handled by show_state"),
+    }
+    Ok(())
+}
+
+/// Print the state persisted by the status update loop, its counters if `stats`
+/// is set, and the custom status and presence read from the server if `remote`
+/// is set.
+fn show_state(args: Args, remote: bool, stats: bool) -> Result<()> {
+    let output = args.output;
+    let merged = args.merge_config_and_params()?;
+    let cache = get_cache(merged.state_file_path())?;
+    let state = State::new(&cache)?;
+    let mut summary = state.summary();
+    if stats {
+        summary.counters = Some(state.counters().clone());
+    }
+    if remote {
+        let mut session =
+            login(&load_config(args)?, state.clock()).context("Login to mattermost")?;
+        summary.remote = Some(state::Remote {
+            custom_status: MMCustomStatus::fetch(&mut session).map_err(Error::from)?,
+            presence: MMStatus::fetch(&mut session).map_err(Error::from)?.status,
+        });
+    }
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+        return Ok(());
+    }
+    print!("{}", state);
+    if let Some(counters) = &summary.counters {
+        print!("{}", counters);
+    }
+    if let Some(remote) = &summary.remote {
+        match &remote.custom_status {
+            Some(status) => println!("Current custom status: {}", status),
+            None => println!("Current custom status: none"),
+        }
+        println!("Current presence: {:?}", remote.presence);
+    }
+    Ok(())
+}
+
+/// Pin the location used by the status update loop, or unpin it.
+fn pin_location(args: &Args, command: &LocationCommand) -> Result<()> {
+    let args = args.merge_config_and_params()?;
+    let cache = get_cache(args.state_file_path())?;
+    let mut state = State::new(&cache)?;
+    match command {
+        LocationCommand::Set { name, duration } => {
+            if !args
+                .status
+                .iter()
+                .filter_map(|s| s.parse::<WifiStatusConfig>().ok())
+                .any(|s| &s.wifi_string == name)
+            {
+                bail!("No status is configured for location `{}`", name);
+            }
+            let until = match duration {
+                Some(duration) => {
+                    Some(chrono::Utc::now().timestamp() + i64::try_from(duration.as_secs())?)
+                }
+                None => None,
+            };
+            state.pin(Location::Known(name.to_owned()), until, &cache)?;
+            println!("Location pinned to {}", name);
+        }
+        LocationCommand::Clear => {
+            state.unpin(&cache)?;
+            println!("Location unpinned");
+        }
+    }
+    Ok(())
+}
+
+/// Start or stop the focus session of the status update loop.
+fn start_focus(args: &Args, command: &FocusCommand) -> Result<()> {
+    let args = args.merge_config_and_params()?;
+    let cache = get_cache(args.state_file_path())?;
+    let mut state = State::new(&cache)?;
+    match command {
+        FocusCommand::Start { duration } => {
+            let until = chrono::Local::now() + chrono::Duration::from_std(*duration)?;
+            state.start_focus(until.timestamp(), &cache)?;
+            println!("Focus until {}", until.format("%H:%M"));
+        }
+        FocusCommand::Stop => {
+            state.stop_focus(&cache)?;
+            println!("Focus stopped");
+        }
+    }
+    Ok(())
+}
+
+/// Pause the status update loop for `duration`, or resume it if `None`.
+fn pause(args: &Args, duration: Option<std::time::Duration>) -> Result<()> {
+    let args = args.merge_config_and_params()?;
+    let cache = get_cache(args.state_file_path())?;
+    let mut state = State::new(&cache)?;
+    match duration {
+        Some(duration) => {
+            let until = chrono::Local::now() + chrono::Duration::from_std(duration)?;
+            state.pause(until.timestamp(), &cache)?;
+            println!("Paused until {}", until.format("%H:%M"));
+        }
+        None => {
+            state.resume(&cache)?;
+            println!("Resumed");
+        }
+    }
+    Ok(())
+}
+
+/// Print the diagnosis of the environment, failing if a check fails.
+fn diagnose(args: &Args) -> Result<()> {
+    let merged = args.merge_config_and_params();
+    let mut others = Vec::new();
+    if let Ok(merged) = &merged {
+        others.push(doctor::keyring(merged));
+        if let Some(dir) = &merged.state_dir {
+            others.push(doctor::permissions(dir));
+        }
+    }
+    let (check, config) = doctor::config(merged);
+    let mut checks = vec![check];
+    checks.extend(others);
+    checks.extend(doctor::tools(&SystemRunner));
+    if let Some(config) = &config {
+        checks.extend(doctor::server(config));
+    }
+    match args.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&checks)?),
+        OutputFormat::Text => {
+            let color = io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+            print!("{}", doctor::report(&checks, color));
+        }
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.verdict == doctor::Verdict::Fail)
+        .count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+/// Return the files saved by `backup`.
+fn backup_files(args: &Args) -> Result<backup::Files> {
+    let args = args.merge_config_and_params()?;
+    let state_dir = args
+        .state_dir
+        .clone()
+        .context("`state_dir` is not defined")?;
+    Ok(backup::Files {
+        config: config::conf_file_path(),
+        state: args
+            .state_file_path()
+            .context("`state_dir` is not defined")?,
+        history: state_dir.join(stats::HISTORY_FILE_NAME),
+    })
+}
+
+/// Save the configuration, the state and the history in `file`.
+fn backup(args: &Args, file: &std::path::Path) -> Result<()> {
+    backup::Bundle::create(&backup_files(args)?)?.save(file)?;
+    println!(
+        "Saved to {:?}, without the secret of the configuration file",
+        file
+    );
+    Ok(())
+}
+
+/// Restore the configuration, the state and the history saved in `file`.
+fn restore(args: &Args, file: &std::path::Path) -> Result<()> {
+    let bundle = backup::Bundle::load(file)?;
+    for path in bundle.restore(&backup_files(args)?)? {
+        println!("Restored {:?}", path);
+    }
+    Ok(())
+}
+
+/// Clear the state, and the location history too if `history` is set.
+fn reset(args: &Args, history: bool) -> Result<()> {
+    let moved = backup::reset(&backup_files(args)?, history)?;
+    if moved.is_empty() {
+        println!("Nothing to reset");
+    }
+    for path in moved {
+        println!("Kept previous state in {:?}", path);
+    }
+    Ok(())
+}
+
+/// Number of log errors printed by `service status`
+const SERVICE_LOG_ERRORS: usize = 5;
+
+/// Run the status update loop under the windows service control manager.
+#[cfg(target_os = "windows")]
+fn run_service(config: Config) -> Result<()> {
+    service::windows::run(move |stop| run_config(config, false, true, stop))
+        .context("Starting windows service")
+}
+
+/// Run the status update loop under the service manager.
+#[cfg(not(target_os = "windows"))]
+fn run_service(config: Config) -> Result<()> {
+    run_config(config, false, true, Arc::default())
+}
+
+/// Install, run, query or restart the background service.
+fn manage_service(args: Args, command: &ServiceCommand) -> Result<()> {
+    let manager = service::Manager::current();
+    match command {
+        ServiceCommand::Install {
+            account,
+            password_prompt,
+            system,
+        } => {
+            let account = match account {
+                Some(name) => Some(service::Account {
+                    name: name.to_owned(),
+                    password: match password_prompt {
+                        true => Some(service::prompt_password(name)?),
+                        false => None,
+                    },
+                }),
+                None => None,
+            };
+            let exe = std::env::current_exe().context("Locating automattermostatus")?;
+            let options = service::InstallOptions {
+                account,
+                system: *system,
+            };
+            manager.install(&SystemRunner, &exe, &options)?;
+            println!("Service {} installed and started", service::SERVICE_NAME);
+        }
+        ServiceCommand::Uninstall { system } => {
+            manager.uninstall(&SystemRunner, *system)?;
+            println!("Service {} uninstalled", service::SERVICE_NAME);
+        }
+        ServiceCommand::Run => {
+            let config = load_config(args)?;
+            setup_service_tracing(&config).context("Setting up service logs")?;
+            run_service(config)?;
+        }
+        ServiceCommand::Status => {
+            println!(
+                "Service {}: {}",
+                service::SERVICE_NAME,
+                manager.state(&SystemRunner)?
+            );
+            let args = args.merge_config_and_params()?;
+            let state_dir = args.state_dir.context("`state_dir` is not defined")?;
+            let errors = service::last_errors(&service::log_dir(&state_dir), SERVICE_LOG_ERRORS)?;
+            if !errors.is_empty() {
+                println!("Last errors:");
+                for error in errors {
+                    println!("  {}", error);
+                }
+            }
+        }
+        ServiceCommand::Restart => {
+            manager.restart(&SystemRunner)?;
+            println!("Service {} restarted", service::SERVICE_NAME);
+        }
+    }
+    Ok(())
+}
+
+/// Print the configured status and the ones generated for the wifi networks
+/// saved by the OS.
+fn import_networks(args: &Args) -> Result<()> {
+    let args = args.merge_config_and_params()?;
+    let wifi = WiFi::new(args.interface_name.as_deref().unwrap_or_default());
+    let saved = wifi
+        .saved_ssid(&SystemRunner)
+        .context("Listing saved wifi networks")?;
+    let status = import::import_status(&args.status, &saved, args.match_case_insensitive);
+    print!("{}", import::to_toml(&status)?);
+    Ok(())
+}
+
+/// Print the time spent per location during the current and the `weeks`
+/// previous weeks.
+fn print_stats(args: &Args, weeks: u32) -> Result<()> {
+    let output = args.output;
+    let args = args.merge_config_and_params()?;
+    let state_dir = args.state_dir.context("`state_dir` is not defined")?;
+    let entries = stats::History::new(state_dir.join(stats::HISTORY_FILE_NAME)).entries()?;
+    let now = chrono::Local::now();
+    let weeks: Vec<stats::Week> = (0..=weeks)
+        .rev()
+        .map(|week| stats::week(&entries, now - chrono::Duration::weeks(week.into()), now))
+        .collect();
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&weeks)?),
+        OutputFormat::Text => {
+            for week in weeks {
+                println!("{}", week);
+            }
+        }
+    }
     Ok(())
 }
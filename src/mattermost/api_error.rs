@@ -0,0 +1,164 @@
+//! Errors returned by the mattermost API.
+//!
+//! Mattermost answers a failed call with a json body like
+//! `{"id": "api.user.login.invalid_credentials_email_username", "message": "…"}`.
+//! [`ApiError`] keeps this identifier and message so that they end up in the
+//! logs instead of a bare HTTP status code, with a hint for the failures a user
+//! can fix (credentials, custom statuses disabled on the server, …).
+use serde::Deserialize;
+use std::fmt;
+
+/// Failed mattermost API call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    /// URL of the call
+    pub url: String,
+    /// HTTP status code
+    pub status: u16,
+    /// mattermost error identifier (e.g. `api.user.login.invalid_credentials`)
+    pub id: Option<String>,
+    /// human readable message of the server
+    pub message: Option<String>,
+}
+
+/// Body of a mattermost error response
+#[derive(Deserialize)]
+struct Body {
+    id: Option<String>,
+    message: Option<String>,
+}
+
+impl ApiError {
+    /// Parse the error `response` of status code `status`
+    pub fn from_response(status: u16, response: ureq::Response) -> Self {
+        let url = response.get_url().to_string();
+        let body = response
+            .into_string()
+            .ok()
+            .and_then(|body| serde_json::from_str::<Body>(&body).ok());
+        ApiError {
+            url,
+            status,
+            id: body.as_ref().and_then(|b| b.id.clone()),
+            message: body.and_then(|b| b.message),
+        }
+    }
+
+    /// Return `true` if the error identifier is `id`
+    pub fn is(&self, id: &str) -> bool {
+        self.id.as_deref() == Some(id)
+    }
+
+    /// Return `true` if the mattermost account of the user is deactivated
+    pub fn is_account_deactivated(&self) -> bool {
+        self.id
+            .as_deref()
+            .is_some_and(|id| id.contains(".inactive") || id.contains("deactivated"))
+    }
+
+    /// Return `true` if the token used is revoked or expired
+    pub fn is_token_revoked(&self) -> bool {
+        self.status == 401
+            && (self.is("api.context.session_expired.app_error")
+                || self.is("api.context.invalid_token.error"))
+    }
+
+    /// Return what the user may do about the error, if known
+    /// ```
+    /// use lib::mattermost::api_error::ApiError;
+    /// let error = ApiError {
+    ///     url: "https://mm.example.com/api/v4/users/me/status/custom".to_string(),
+    ///     status: 501,
+    ///     id: Some("api.custom_status.disabled".to_string()),
+    ///     message: Some("Custom status is disabled".to_string()),
+    /// };
+    /// assert!(error.hint().unwrap().contains("EnableCustomUserStatuses"));
+    /// ```
+    pub fn hint(&self) -> Option<&'static str> {
+        let id = self.id.as_deref()?;
+        if id == "api.custom_status.disabled" {
+            Some(
+                "custom statuses are disabled on the server, \
+                 ask your administrator to set `EnableCustomUserStatuses`",
+            )
+        } else if id.starts_with("api.user.login.invalid_credentials") {
+            Some("check `mm_user` and the secret")
+        } else if self.is_token_revoked() {
+            Some("the token is invalid, expired or revoked")
+        } else if self.is_account_deactivated() {
+            Some("your account is deactivated")
+        } else if id.starts_with("api.context.permissions") {
+            Some("your account lacks the permission required by this call")
+        } else if id.contains("license") {
+            Some("the licence of the server does not allow this call")
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: status code {}", self.url, self.status)?;
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        if let Some(id) = &self.id {
+            write!(f, " ({})", id)?;
+        }
+        if let Some(hint) = self.hint() {
+            write!(f, ", {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn parse_mattermost_error_body() {
+        let response = ureq::Response::new(
+            401,
+            "Unauthorized",
+            r#"{"id":"api.user.login.invalid_credentials_email_username",
+                "message":"Enter a valid email or username and/or password.",
+                "status_code":401}"#,
+        )
+        .unwrap();
+        let error = ApiError::from_response(401, response);
+        assert!(error.is("api.user.login.invalid_credentials_email_username"));
+        assert!(error.to_string().ends_with(
+            "status code 401: Enter a valid email or username and/or password. \
+             (api.user.login.invalid_credentials_email_username), check `mm_user` and the secret"
+        ));
+    }
+
+    #[test]
+    fn keep_status_code_without_json_body() {
+        let response = ureq::Response::new(502, "Bad Gateway", "<html>").unwrap();
+        let error = ApiError::from_response(502, response);
+        assert_eq!(error.id, None);
+        assert_eq!(error.hint(), None);
+        assert!(error.to_string().ends_with(": status code 502"));
+    }
+
+    #[test]
+    fn recognize_deactivated_accounts_and_revoked_tokens() {
+        let error = |status, id: &str| ApiError {
+            url: "https://mm.example.com/api/v4/users/login".to_string(),
+            status,
+            id: Some(id.to_string()),
+            message: None,
+        };
+        assert!(error(401, "api.user.login.inactive.app_error").is_account_deactivated());
+        assert!(error(403, "api.user.update_active.user_deactivated").is_account_deactivated());
+        assert!(!error(401, "api.user.login.inactive.app_error").is_token_revoked());
+        assert!(error(401, "api.context.session_expired.app_error").is_token_revoked());
+        assert!(!error(500, "api.context.session_expired.app_error").is_token_revoked());
+    }
+}
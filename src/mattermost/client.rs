@@ -0,0 +1,289 @@
+//! HTTP client shared by all the mattermost API calls.
+//!
+//! [`LoggedSession::call`] injects the session token, logs in again once when the
+//! token is rejected (`401 Unauthorized`) and retries when the server limits the
+//! rate of requests (`429 Too Many Requests`), is temporarily unavailable or can
+//! not be reached. Custom status, presence and posts calls all go through it.
+//!
+//! Every request is identified by a `User-Agent` header (see [`ClientOptions`])
+//! for proxies filtering unknown clients.
+use crate::mattermost::api_error::ApiError;
+use crate::mattermost::{LoggedSession, MMSError};
+use std::thread::sleep;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Timeout of each request
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of retries after a transient failure
+const MAX_RETRIES: u32 = 2;
+
+/// Delay before the first retry, doubled at each retry, unless the server tells
+/// how long to wait
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay accepted before a retry
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Default path of the custom status endpoint
+const CUSTOM_STATUS_PATH: &str = "/api/v4/users/me/status/custom";
+
+/// Options of the requests sent to mattermost
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// `User-Agent` header, [`default_user_agent`] if `None`
+    pub user_agent: Option<String>,
+    /// send a random `X-Request-Id` header with each request, to find it in
+    /// the server or proxy logs
+    pub request_id: bool,
+    /// path of a companion plugin (e.g. `/plugins/com.example.status`) whose
+    /// `status/custom` endpoint is used instead of the custom status API, for
+    /// servers where personal access tokens are disabled
+    pub plugin_path: Option<String>,
+}
+
+impl ClientOptions {
+    /// Return the path of the endpoint setting (`PUT`) and deleting (`DELETE`)
+    /// the custom status
+    /// ```
+    /// use lib::mattermost::client::ClientOptions;
+    /// let options = ClientOptions {
+    ///     plugin_path: Some("/plugins/com.example.status/".to_string()),
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(options.custom_status_path(), "/plugins/com.example.status/status/custom");
+    /// ```
+    pub fn custom_status_path(&self) -> String {
+        match &self.plugin_path {
+            Some(plugin) => format!("{}/status/custom", plugin.trim_end_matches('/')),
+            None => CUSTOM_STATUS_PATH.to_string(),
+        }
+    }
+}
+
+/// Return `automattermostatus/<version> (<os>)`
+pub fn default_user_agent() -> String {
+    format!(
+        "automattermostatus/{} ({})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS
+    )
+}
+
+/// Return the HTTP agent used to reach mattermost
+pub fn agent(options: &ClientOptions) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(TIMEOUT)
+        .user_agent(
+            &options
+                .user_agent
+                .clone()
+                .unwrap_or_else(default_user_agent),
+        )
+        .build()
+}
+
+/// Add a random `X-Request-Id` header to `request` if asked by `options`
+pub(crate) fn identify(request: ureq::Request, options: &ClientOptions) -> ureq::Request {
+    if !options.request_id {
+        return request;
+    }
+    let id: String = std::iter::repeat_with(fastrand::alphanumeric)
+        .take(26)
+        .collect::<String>()
+        .to_lowercase();
+    debug!("X-Request-Id: {}", id);
+    request.set("X-Request-Id", &id)
+}
+
+/// Return how long to wait before retrying the `attempt`th time after `error`,
+/// or `None` if the request shall not be retried.
+fn retry_delay(error: &ureq::Error, attempt: u32) -> Option<Duration> {
+    let backoff = RETRY_DELAY * 2u32.pow(attempt - 1);
+    match error {
+        ureq::Error::Status(429 | 502 | 503 | 504, response) => Some(
+            ["Retry-After", "X-Ratelimit-Reset"]
+                .iter()
+                .find_map(|header| response.header(header)?.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(backoff)
+                .min(MAX_RETRY_DELAY),
+        ),
+        ureq::Error::Transport(_) => Some(backoff),
+        ureq::Error::Status(..) => None,
+    }
+}
+
+impl LoggedSession {
+    /// Send a `method` request to `api_path` (e.g. "/api/v4/users/me"), with
+    /// `body` as json if given.
+    ///
+    /// The request is sent again after a new login in case of `401` failure,
+    /// and up to [`MAX_RETRIES`] times after transient failures.
+    pub fn call(
+        &mut self,
+        method: &str,
+        api_path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<ureq::Response, MMSError> {
+        let uri = self.base_uri.to_owned() + api_path;
+        let mut relogged = false;
+        let mut attempt = 0;
+        loop {
+            debug!("{} {}", method, uri);
+            let request = identify(self.agent.request(method, &uri), &self.client)
+                .set("Authorization", &("Bearer ".to_owned() + &self.token));
+            let result = match body {
+                Some(body) => request.send_json(body),
+                None => request.call(),
+            };
+            let error = match result {
+                Ok(response) => return Ok(response),
+                Err(e) => e,
+            };
+            match (&error, retry_delay(&error, attempt + 1)) {
+                (ureq::Error::Status(401, _), _) if !relogged => {
+                    // relogin and retry
+                    self.relogin().map_err(MMSError::LoginError)?;
+                    relogged = true;
+                }
+                (_, Some(delay)) if attempt < MAX_RETRIES => {
+                    attempt += 1;
+                    warn!(
+                        "{} {} failed ({}), retrying in {:?}",
+                        method, uri, error, delay
+                    );
+                    sleep(delay);
+                }
+                _ => {
+                    return Err(match error {
+                        ureq::Error::Status(status, response) => {
+                            MMSError::ApiError(ApiError::from_response(status, response))
+                        }
+                        e => MMSError::HTTPRequestError(Box::new(e)),
+                    })
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::error::Result;
+    use crate::mattermost::testing::logged_session;
+    use crate::mattermost::{BaseSession, Session};
+    use httpmock::prelude::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn relogin_on_unauthorized() -> Result<()> {
+        let server = MockServer::start();
+        server.mock(|expect, resp_with| {
+            expect.method(POST).path("/api/v4/users/login");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .header("Token", "new")
+                .json_body(serde_json::json!({"id":"user_id"}));
+        });
+        let expired = server.mock(|expect, resp_with| {
+            expect
+                .method(GET)
+                .path("/api/v4/users/me/status")
+                .header("Authorization", "Bearer old");
+            resp_with.status(401);
+        });
+        let renewed = server.mock(|expect, resp_with| {
+            expect
+                .method(GET)
+                .path("/api/v4/users/me/status")
+                .header("Authorization", "Bearer new");
+            resp_with.status(200).body("{}");
+        });
+        let mut session = Session::new(&server.url(""))
+            .with_credentials("user", "password")
+            .login()?;
+        session.token = "old".to_string();
+        session.call("GET", "/api/v4/users/me/status", None)?;
+        expired.assert();
+        renewed.assert();
+        assert_eq!(session.token, "new");
+        assert_eq!(session.take_relogins(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn retry_when_rate_limited() -> Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let limited = server.mock(|expect, resp_with| {
+            expect.method(PUT).path("/api/v4/users/me/status");
+            resp_with.status(429).header("X-Ratelimit-Reset", "0");
+        });
+        let failing = server.mock(|expect, resp_with| {
+            expect.method(PUT).path("/api/v4/users/me/status/custom");
+            resp_with.status(500);
+        });
+        let body = serde_json::json!({});
+        assert!(session
+            .call("PUT", "/api/v4/users/me/status", Some(&body))
+            .is_err());
+        limited.assert_hits(1 + MAX_RETRIES as usize);
+        // Other errors are not retried
+        assert!(session
+            .call("PUT", "/api/v4/users/me/status/custom", Some(&body))
+            .is_err());
+        failing.assert_hits(1);
+        Ok(())
+    }
+
+    #[test]
+    fn identify_requests() -> Result<()> {
+        let server = MockServer::start();
+        let login_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(GET)
+                .path("/api/v4/users/me")
+                .header("User-Agent", default_user_agent());
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"user_id"}));
+        });
+        let status_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(GET)
+                .path("/api/v4/users/user_id/status")
+                .header("User-Agent", "corporate-client/1.0")
+                .header_exists("X-Request-Id");
+            resp_with.status(200).body("{}");
+        });
+        Session::new(&server.url("")).with_token("token").login()?;
+        login_mock.assert();
+        let custom_login_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(GET)
+                .path("/api/v4/users/me")
+                .header("User-Agent", "corporate-client/1.0");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"user_id"}));
+        });
+        let mut session = Session::new(&server.url(""))
+            .with_client_options(ClientOptions {
+                user_agent: Some("corporate-client/1.0".to_string()),
+                request_id: true,
+                ..Default::default()
+            })
+            .with_token("token")
+            .login()?;
+        session.call("GET", "/api/v4/users/user_id/status", None)?;
+        custom_login_mock.assert();
+        status_mock.assert();
+        Ok(())
+    }
+}
@@ -1,6 +1,13 @@
 //! This module exports [Session], [MMStatus] and [MMCustomStatus]
 //!
+pub mod api_error;
+pub mod client;
+pub mod post;
+pub mod server;
 pub mod session;
 pub mod status;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod token_store;
 pub use session::*;
 pub use status::*;
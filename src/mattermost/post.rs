@@ -0,0 +1,58 @@
+//! Module responsible for posting messages to the user on mattermost.
+use crate::error::{Error, Result};
+use crate::mattermost::LoggedSession;
+use anyhow::anyhow;
+use tracing::debug;
+
+impl LoggedSession {
+    /// Post `message` in the direct channel of the user with themself
+    pub fn send_direct_message(&mut self, message: &str) -> Result<()> {
+        let members = serde_json::json!([self.user_id, self.user_id]);
+        let channel: serde_json::Value = self
+            .call("POST", "/api/v4/channels/direct", Some(&members))?
+            .into_json()
+            .map_err(|e| Error::Network(e.into()))?;
+        let channel_id = channel["id"]
+            .as_str()
+            .ok_or_else(|| Error::Network(anyhow!("Received channel id is not a string")))?;
+        debug!("Posting to direct channel {}", channel_id);
+        let post = serde_json::json!({"channel_id": channel_id, "message": message});
+        self.call("POST", "/api/v4/posts", Some(&post))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use crate::error::Result;
+    use crate::mattermost::testing::logged_session;
+    use httpmock::prelude::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn post_in_direct_channel() -> Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let channel_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(POST)
+                .path("/api/v4/channels/direct")
+                .json_body(serde_json::json!(["user_id", "user_id"]));
+            resp_with
+                .status(201)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"channel_id"}));
+        });
+        let post_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(POST)
+                .path("/api/v4/posts")
+                .json_body(serde_json::json!({"channel_id":"channel_id","message":"hello"}));
+            resp_with.status(201).body("{}");
+        });
+        session.send_direct_message("hello")?;
+        channel_mock.assert();
+        post_mock.assert();
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! Module querying the configuration of the mattermost server.
+use crate::connectivity::PING_PATH;
+use crate::error::{Error, Result};
+use crate::mattermost::LoggedSession;
+use anyhow::anyhow;
+use tracing::debug;
+
+/// Endpoint of the configuration visible to the clients
+const CLIENT_CONFIG_PATH: &str = "/api/v4/config/client?format=old";
+
+impl LoggedSession {
+    /// Check that the server is up with its ping endpoint, without retrying nor
+    /// logging in again
+    pub fn ping(&self) -> Result<()> {
+        let uri = self.base_uri.to_owned() + PING_PATH;
+        debug!("GET {}", uri);
+        let health: serde_json::Value = match self.agent.get(&uri).call() {
+            Ok(response) => response.into_json().map_err(|e| Error::Network(e.into()))?,
+            // An unhealthy server tells why with a server error
+            Err(ureq::Error::Status(code, response)) => {
+                return Err(Error::Network(anyhow!(
+                    "ping answered {}: {}",
+                    code,
+                    response.into_string().unwrap_or_default().trim()
+                )))
+            }
+            Err(e) => return Err(Error::Network(e.into())),
+        };
+        match health["status"].as_str() {
+            Some("OK") => Ok(()),
+            status => Err(Error::Network(anyhow!("ping answered {:?}", status))),
+        }
+    }
+
+    /// Return whether custom statuses are enabled on the server
+    /// (`EnableCustomUserStatuses`), `None` if the server does not tell
+    pub fn custom_statuses_enabled(&mut self) -> Result<Option<bool>> {
+        let config: serde_json::Value = self
+            .call("GET", CLIENT_CONFIG_PATH, None)?
+            .into_json()
+            .map_err(|e| Error::Network(e.into()))?;
+        let enabled = config["EnableCustomUserStatuses"].as_str();
+        debug!("EnableCustomUserStatuses: {:?}", enabled);
+        Ok(enabled.map(|enabled| enabled == "true"))
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use crate::error::Result;
+    use crate::mattermost::testing::logged_session;
+    use httpmock::prelude::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn read_custom_statuses_setting() -> Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let mut config_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(GET)
+                .path("/api/v4/config/client")
+                .query_param("format", "old");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"EnableCustomUserStatuses": "false"}));
+        });
+        assert_eq!(session.custom_statuses_enabled()?, Some(false));
+        config_mock.delete();
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/config/client");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"Version": "5.30.0"}));
+        });
+        assert_eq!(session.custom_statuses_enabled()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn tell_when_the_server_is_down() -> Result<()> {
+        let server = MockServer::start();
+        let session = logged_session(&server)?;
+        let mut ping = server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/system/ping");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"status": "OK"}));
+        });
+        session.ping()?;
+        ping.delete();
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/system/ping");
+            resp_with
+                .status(500)
+                .json_body(serde_json::json!({"status": "unhealthy"}));
+        });
+        let e = session.ping().unwrap_err();
+        assert!(matches!(e, crate::error::Error::Network(_)));
+        assert!(e.to_string().contains("500"));
+        Ok(())
+    }
+}
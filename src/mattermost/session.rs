@@ -55,10 +55,14 @@
 //! - Session → SessionWithToken → LoggedSession
 //! - Session → SessionWithCredentials → LoggedSession
 
-use anyhow::{anyhow, Result};
+use crate::error::{Error, Result};
+use crate::mattermost::client::{self, ClientOptions};
+use crate::secret::vault::VaultSecret;
+use anyhow::anyhow;
+use chrono::{DateTime, Local, NaiveDate};
 use serde::{Deserialize, Serialize};
 use std::mem;
-use tracing::debug;
+use tracing::{debug, info, warn};
 
 /// Trait implementing function necessary to establish a session (getting a authenticating token).
 pub trait BaseSession {
@@ -77,6 +81,8 @@ pub struct Session {
     #[allow(rustdoc::bare_urls)]
     /// base URL of the mattermost server like https://mattermost.example.com
     base_uri: String,
+    /// identification of the requests
+    client: ClientOptions,
 }
 
 /// Implement [Session] authenticated with a private access token.
@@ -87,6 +93,8 @@ pub struct SessionWithToken {
     /// private access token for current user on the `base_uri` mattermost instance
     /// (either permanent and given at init or renewable with the help of login function)
     token: String,
+    /// identification of the requests
+    client: ClientOptions,
 }
 ///
 /// Implement a session authenticated with a login and password
@@ -101,6 +109,8 @@ pub struct SessionWithCredentials {
     user: String,
     /// user password
     password: String,
+    /// identification of the requests
+    client: ClientOptions,
 }
 
 ///  Session once logged
@@ -113,9 +123,18 @@ pub struct LoggedSession {
     pub token: String,
     /// Mattermost internal user_id
     pub user_id: String,
-    // Used to relog when logged out
-    user: Option<String>,
-    password: Option<String>,
+    // User and password used to relog when logged out
+    credentials: Option<(String, String)>,
+    /// HTTP agent used for all the API calls of the session
+    pub(crate) agent: ureq::Agent,
+    /// identification of the requests
+    pub(crate) client: ClientOptions,
+    /// number of logins after the token expired, not yet counted in the state
+    relogins: u64,
+    /// expiry date of the session, once fetched (see [`LoggedSession::expires_on`])
+    expires_on: Option<Option<NaiveDate>>,
+    /// vault the secret is read again from when rejected
+    vault: Option<VaultSecret>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -124,18 +143,38 @@ struct LoginData {
     password: String,
 }
 
+/// Session of the user, as listed by `/api/v4/users/me/sessions`
+#[derive(Deserialize)]
+struct SessionInfo {
+    /// token of the session, left empty by recent servers
+    #[serde(default)]
+    token: String,
+    /// creation time in milliseconds
+    create_at: i64,
+    /// expiry time in milliseconds, 0 for the personal access tokens
+    #[serde(default)]
+    expires_at: i64,
+}
+
 impl Session {
     /// Create new empty [Session] to the `base_uri` mattermost server
     pub fn new(base_uri: &str) -> Self {
         Session {
             base_uri: base_uri.into(),
+            client: ClientOptions::default(),
         }
     }
+    /// Identify the requests of the session according to `options`
+    pub fn with_client_options(&mut self, options: ClientOptions) -> &mut Self {
+        self.client = options;
+        self
+    }
     /// Add existing token to current [Session]
     pub fn with_token(&mut self, token: &str) -> SessionWithToken {
         SessionWithToken {
             token: token.into(),
             base_uri: mem::take(&mut self.base_uri),
+            client: mem::take(&mut self.client),
         }
     }
     /// Add login credentials to current [Session]
@@ -145,6 +184,7 @@ impl Session {
             password: password.into(),
             token: None,
             base_uri: mem::take(&mut self.base_uri),
+            client: mem::take(&mut self.client),
         }
     }
 }
@@ -158,20 +198,26 @@ impl BaseSession for SessionWithToken {
     }
     fn login(&mut self) -> Result<LoggedSession> {
         let uri = self.base_uri.to_owned() + "/api/v4/users/me";
-        let json: serde_json::Value = ureq::get(&uri)
+        let agent = client::agent(&self.client);
+        let json: serde_json::Value = client::identify(agent.get(&uri), &self.client)
             .set("Authorization", &("Bearer ".to_owned() + &self.token))
             .call()?
-            .into_json()?;
+            .into_json()
+            .map_err(|e| Error::Network(e.into()))?;
         debug!("User info: {:?}", json);
         Ok(LoggedSession {
             base_uri: mem::take(&mut self.base_uri),
             token: mem::take(&mut self.token),
             user_id: json["id"]
                 .as_str()
-                .ok_or(anyhow!("Received id is not a string"))?
+                .ok_or_else(|| Error::Network(anyhow!("Received id is not a string")))?
                 .to_string(),
-            user: None,
-            password: None,
+            credentials: None,
+            agent,
+            client: mem::take(&mut self.client),
+            relogins: 0,
+            expires_on: None,
+            vault: None,
         })
     }
 }
@@ -181,7 +227,9 @@ impl BaseSession for SessionWithCredentials {
         if let Some(token) = &self.token {
             Ok(token)
         } else {
-            Err(anyhow!("No token available, did login succeed ?"))
+            Err(Error::Auth(anyhow!(
+                "No token available, did login succeed ?"
+            )))
         }
     }
     fn base_uri(&self) -> &str {
@@ -190,53 +238,150 @@ impl BaseSession for SessionWithCredentials {
 
     fn login(&mut self) -> Result<LoggedSession> {
         let uri = self.base_uri.to_owned() + "/api/v4/users/login";
-        let response = ureq::post(&uri).send_json(serde_json::to_value(LoginData {
+        let agent = client::agent(&self.client);
+        let response = client::identify(agent.post(&uri), &self.client).send_json(LoginData {
             login_id: self.user.clone(),
             password: self.password.clone(),
-        })?)?;
+        })?;
         let Some(token) = response.header("Token") else {
-            return Err(anyhow!("Login authentication failed"));
+            return Err(Error::Auth(anyhow!("Login authentication failed")));
         };
         let token = token.to_string();
-        let json: serde_json::Value = response.into_json()?;
+        let json: serde_json::Value = response.into_json().map_err(|e| Error::Network(e.into()))?;
         let user_id = json["id"]
             .as_str()
-            .ok_or(anyhow!("Received id is not a string"))?
+            .ok_or_else(|| Error::Network(anyhow!("Received id is not a string")))?
             .to_string();
         Ok(LoggedSession {
             base_uri: mem::take(&mut self.base_uri),
             token,
             user_id,
-            user: Some(self.user.clone()),
-            password: Some(self.password.clone()),
+            credentials: Some((self.user.clone(), self.password.clone())),
+            agent,
+            client: mem::take(&mut self.client),
+            relogins: 0,
+            expires_on: None,
+            vault: None,
         })
     }
 }
 
 impl LoggedSession {
+    /// Return the number of logins after the token expired since the last call
+    pub fn take_relogins(&mut self) -> u64 {
+        mem::take(&mut self.relogins)
+    }
+
+    /// Keep `user` and `password` to log in again when the token of a session
+    /// created with [`Session::with_token`] expires
+    pub fn remember_credentials(&mut self, user: &str, password: &str) {
+        self.credentials = Some((user.into(), password.into()));
+    }
+
+    /// Read the secret again from `vault` when it is rejected: the token of a
+    /// session created with [`Session::with_token`], the password otherwise
+    pub fn follow_vault(&mut self, vault: VaultSecret) {
+        self.vault = Some(vault);
+    }
+
     /// relog in case of a short lived session token obtained wia login/password
+    ///
+    /// If the secret is rejected and comes from a vault (see
+    /// [`LoggedSession::follow_vault`]), it is read again in case it has been
+    /// rotated, and the new one is used from now on.
     pub fn relogin(&mut self) -> Result<&mut LoggedSession> {
-        let (Some(password), Some(user)) = (self.password.clone(), self.user.clone()) else {
+        let res = match (self.credentials.clone(), &self.vault) {
+            (Some((user, password)), _) => self.login_again(&user, &password),
+            // Only a token rotated in the vault may replace a rejected token
+            (None, Some(_)) => Err(Error::Auth(anyhow!("Token rejected"))),
             // No login/password, we bail out without doing anything.
-            return Ok(self);
+            (None, None) => return Ok(self),
+        };
+        let Some(vault) = self.vault.as_mut() else {
+            return res.map(|_| self);
         };
+        let Err(Error::Auth(e)) = res else {
+            return res.map(|_| self);
+        };
+        let current = match &self.credentials {
+            Some((_, password)) => password,
+            None => &self.token,
+        };
+        let rotated = match vault.refetch() {
+            Ok(rotated) if rotated != *current => rotated,
+            Ok(_) => return Err(Error::Auth(e)),
+            Err(error) => {
+                warn!(
+                    "Fail to read the secret again from {} : {:#}",
+                    vault.item.vault.name(),
+                    error
+                );
+                return Err(Error::Auth(e));
+            }
+        };
+        info!(
+            "The secret has been changed in {}, logging in again",
+            vault.item.vault.name()
+        );
+        match self.credentials.clone() {
+            Some((user, _)) => {
+                self.login_again(&user, &rotated)?;
+                self.credentials = Some((user, rotated));
+            }
+            None => {
+                self.token = rotated;
+                self.relogins += 1;
+                self.expires_on = None;
+            }
+        }
+        Ok(self)
+    }
 
+    /// Log in again as `user`, replacing the token of the session
+    fn login_again(&mut self, user: &str, password: &str) -> Result<()> {
         let uri = self.base_uri.to_owned() + "/api/v4/users/login";
-        let response = ureq::post(&uri).send_json(serde_json::to_value(LoginData {
-            login_id: user,
-            password,
-        })?)?;
+        let response =
+            client::identify(self.agent.post(&uri), &self.client).send_json(LoginData {
+                login_id: user.to_string(),
+                password: password.to_string(),
+            })?;
         let Some(token) = response.header("Token") else {
-            return Err(anyhow!("Login authentication failed"));
+            return Err(Error::Auth(anyhow!("Login authentication failed")));
         };
         self.token = token.to_string();
-        Ok(self)
+        self.relogins += 1;
+        self.expires_on = None;
+        Ok(())
+    }
+
+    /// Return the local date at which the session expires, `None` if it does not
+    /// (personal access tokens). It is asked once to the server, the session being
+    /// found by its token if listed, else being the last one created.
+    pub fn expires_on(&mut self) -> Result<Option<NaiveDate>> {
+        if let Some(expires_on) = self.expires_on {
+            return Ok(expires_on);
+        }
+        let sessions: Vec<SessionInfo> = self
+            .call("GET", "/api/v4/users/me/sessions", None)?
+            .into_json()
+            .map_err(|e| Error::Network(e.into()))?;
+        let expires_on = sessions
+            .iter()
+            .find(|s| s.token == self.token)
+            .or_else(|| sessions.iter().max_by_key(|s| s.create_at))
+            .filter(|s| s.expires_at > 0)
+            .and_then(|s| DateTime::from_timestamp_millis(s.expires_at))
+            .map(|expires_at| expires_at.with_timezone(&Local).date_naive());
+        debug!("Session expires on {:?}", expires_on);
+        self.expires_on = Some(expires_on);
+        Ok(expires_on)
     }
 }
 
 #[cfg(test)]
 mod should {
     use super::*;
+    use chrono::TimeZone;
     use httpmock::prelude::*;
     use test_log::test; // Automatically trace tests
     #[test]
@@ -272,6 +417,60 @@ mod should {
         assert_eq!(session.base_uri, server.url(""));
         Ok(())
     }
+    #[test]
+    fn report_rejected_credentials() {
+        let server = MockServer::start();
+        server.mock(|expect, resp_with| {
+            expect.method(POST).path("/api/v4/users/login");
+            resp_with.status(401).json_body(serde_json::json!({
+                "id": "api.user.login.invalid_credentials_email_username",
+                "message": "Enter a valid email or username and/or password.",
+                "status_code": 401
+            }));
+        });
+        let res = Session::new(&server.url(""))
+            .with_credentials("username", "wrong")
+            .login();
+        let Err(Error::Auth(e)) = res else {
+            panic!("Authentication error expected")
+        };
+        assert!(e
+            .to_string()
+            .contains("(api.user.login.invalid_credentials_email_username)"));
+    }
+
+    #[test]
+    fn tell_when_the_session_expires() -> Result<()> {
+        let server = MockServer::start();
+        server.mock(|expect, resp_with| {
+            expect.method(POST).path("/api/v4/users/login");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .header("Token", "xyzxyz")
+                .json_body(serde_json::json!({"id":"user_id"}));
+        });
+        let expires_at = Local.with_ymd_and_hms(2024, 4, 14, 12, 0, 0).unwrap();
+        let sessions = server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/users/me/sessions");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!([
+                    {"token": "", "create_at": 1_710_000_000_000_i64, "expires_at": 0},
+                    {"token": "", "create_at": 1_710_500_000_000_i64,
+                     "expires_at": expires_at.timestamp_millis()},
+                ]));
+        });
+        let mut session = Session::new(&server.url(""))
+            .with_credentials("username", "passwordtext")
+            .login()?;
+        assert_eq!(session.expires_on()?, Some(expires_at.date_naive()));
+        assert_eq!(session.expires_on()?, Some(expires_at.date_naive()));
+        sessions.assert_hits(1);
+        Ok(())
+    }
+
     #[test]
     fn return_token() -> Result<()> {
         let session = Session::new("https://mattermost.example.com").with_token("xyzxyz");
@@ -1,14 +1,16 @@
 //! Module responsible for sending custom status change to mattermost.
+use crate::clock::Clock;
+use crate::error::Result;
+use crate::mattermost::api_error::ApiError;
 use crate::mattermost::LoggedSession;
-use crate::utils::parse_from_hmstr;
-use anyhow::Result;
-use chrono::{DateTime, Local, TimeZone};
+use crate::timeofday::{Expiry, Preset};
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Utc};
 use derivative::Derivative;
 use serde::{Deserialize, Serialize};
 use serde_json as json;
 use std::fmt;
 use thiserror::Error;
-use tracing::{debug, error};
+use tracing::debug;
 
 /// Implement errors specific to `MMCustomStatus`
 #[allow(missing_docs)]
@@ -16,90 +18,42 @@ use tracing::{debug, error};
 pub enum MMSError {
     #[error("Bad json data")]
     BadJSONData(#[from] serde_json::error::Error),
+    // Boxed as `ureq::Error` holds a whole response
     #[error("HTTP request error")]
-    HTTPRequestError(#[from] ureq::Error),
+    HTTPRequestError(#[from] Box<ureq::Error>),
+    #[error(transparent)]
+    ApiError(#[from] ApiError),
     #[error("Mattermost login error")]
-    LoginError(#[from] anyhow::Error),
+    LoginError(#[from] crate::Error),
 }
 
 trait MMSendable {
-    fn _send_at_once(
-        &self,
-        session: &LoggedSession,
-        api_path: &str,
-    ) -> Result<ureq::Response, ureq::Error>;
     fn send_at(
         &mut self,
         session: &mut LoggedSession,
         api_path: &str,
     ) -> Result<ureq::Response, MMSError>;
-    fn to_json(&self) -> Result<String, MMSError>;
-    #[allow(unused)]
-    fn set_user_id(&mut self, user_id: String) {
-        // empty implementation. Would be overrided if needed
-    }
 }
 
 impl<T> MMSendable for T
 where
     T: Serialize + std::fmt::Debug + Clone,
 {
-    /// This function is essentially used for debugging or testing
-    fn to_json(&self) -> Result<String, MMSError> {
-        json::to_string(&self).map_err(MMSError::BadJSONData)
-    }
-
-    /// Send self once as json
-    /// `api_path` looks like "/api/v4/users/me/status/custom"
-    #[allow(clippy::borrowed_box)] // Box needed beacause we can get two different types.
-    fn _send_at_once(
-        &self,
-        session: &LoggedSession,
-        api_path: &str,
-    ) -> Result<ureq::Response, ureq::Error> {
-        let token = session.token.clone();
-        let uri = session.base_uri.to_owned() + api_path;
-        debug!("Sending {:?} to {}", self, uri);
-        ureq::put(&uri)
-            .set("Authorization", &("Bearer ".to_owned() + &token))
-            .send_json(serde_json::to_value(self).unwrap_or_else(|e| {
-                panic!(
-                    "Serialization of MMCustomStatus '{:?}' failed with {:?}",
-                    &self, &e
-                )
-            }))
-    }
-
-    /// Send self as json, trying to login once in case of 401 failure.
+    /// Send self as json with a PUT request (see [`LoggedSession::call`])
     /// `api_path` looks like "/api/v4/users/me/status/custom"
     fn send_at(
         &mut self,
         session: &mut LoggedSession,
         api_path: &str,
     ) -> Result<ureq::Response, MMSError> {
-        debug!("Post status: {}", self.to_owned().to_json()?);
-        match self._send_at_once(session, api_path) {
-            Ok(response) => Ok(response),
-            Err(ureq::Error::Status(code, response)) => {
-                /* the server returned an unexpected status
-                code (such as 400, 500 etc) */
-                if code == 401 {
-                    // relogin and retry
-                    let _ = session.relogin().map_err(MMSError::LoginError)?;
-                    //self.set_user_id(loggedsession.user_id);
-                    self._send_at_once(session, api_path)
-                } else {
-                    Err(ureq::Error::Status(code, response))
-                }
-            }
-            Err(e) => Err(e),
-        }
-        .map_err(MMSError::HTTPRequestError)
+        let body = json::to_value(self)?;
+        debug!("Post status: {}", body);
+        session.call("PUT", api_path, Some(&body))
     }
 }
 
 /// Authorized status values for MM Status API
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
     /// User is online
     #[serde(rename = "online")]
@@ -115,14 +69,23 @@ pub enum Status {
     Dnd,
 }
 
-/// Standard Mattermost status wire representation
-#[derive(Derivative, Serialize, Deserialize, Clone)]
+/// Presence of a user, read from and sent to `/api/v4/users/{user_id}/status`.
+/// For a description of these fields see the [MatterMost OpenApi sources](https://github.com/mattermost/mattermost-api-reference/blob/master/v4/source/status.yaml)
+#[derive(Derivative, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[derivative(Debug)]
 pub struct MMStatus {
     user_id: String,
     /// the requested status
     pub status: Status,
-    dnd_end_time: i64,
+    /// the status was set by the user instead of being deduced from activity
+    #[serde(default, skip_serializing)]
+    pub manual: bool,
+    /// id of the channel the user was last active in
+    #[serde(default, skip_serializing)]
+    pub active_channel: Option<String>,
+    /// end of *do not disturb* as a UTC timestamp, `0` meaning none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dnd_end_time: Option<i64>,
 }
 
 impl MMStatus {
@@ -131,25 +94,50 @@ impl MMStatus {
         MMStatus {
             user_id,
             status,
-            dnd_end_time: Local::now().timestamp() + 300,
+            manual: false,
+            active_channel: None,
+            dnd_end_time: None,
         }
     }
 
+    /// End the *do not disturb* status at `end`, so that it does not outlive
+    /// the application
+    pub fn with_dnd_end_time(mut self, end: DateTime<Local>) -> Self {
+        self.dnd_end_time = Some(end.timestamp());
+        self
+    }
+
+    /// Return the end of the *do not disturb* status, if any
+    pub fn dnd_end_time(&self) -> Option<DateTime<Local>> {
+        self.dnd_end_time
+            .filter(|t| *t != 0)
+            .and_then(|t| Local.timestamp_opt(t, 0).single())
+    }
+
+    /// Get the current presence of the user, which may have been set by another
+    /// client
+    pub fn fetch(session: &mut LoggedSession) -> Result<MMStatus, MMSError> {
+        let api_path = format!("/api/v4/users/{}/status", session.user_id);
+        let status = session
+            .call("GET", &api_path, None)?
+            .into_json()
+            .map_err(|e| MMSError::HTTPRequestError(Box::new(e.into())))?;
+        debug!("Current presence: {:?}", status);
+        Ok(status)
+    }
+
     /// set user_id
     pub fn set_user_id(&mut self, user_id: String) {
         self.user_id = user_id;
     }
-    /// Send self as json, trying to login once in case of 401 failure.
-    pub fn send(&mut self, session: &mut LoggedSession) {
-        match self.send_at(session, "/api/v4/users/me/status") {
-            Ok(_response) => (),
-            Err(MMSError::HTTPRequestError(response)) => {
-                /* the server returned an unexpected status
-                code (such as 400, 500 etc) */
-                error!("Unexpected response {:?}", response);
-            }
-            Err(_e) => (),
-        };
+
+    /// Send self as json, trying to login once in case of 401 failure, and
+    /// return the presence stored by the server.
+    pub fn send(&mut self, session: &mut LoggedSession) -> Result<MMStatus, MMSError> {
+        let api_path = format!("/api/v4/users/{}/status", self.user_id);
+        self.send_at(session, &api_path)?
+            .into_json()
+            .map_err(|e| MMSError::HTTPRequestError(Box::new(e.into())))
     }
 }
 
@@ -157,6 +145,7 @@ impl MMStatus {
 /// For a description of these fields see the [MatterMost OpenApi sources](https://github.com/mattermost/mattermost-api-reference/blob/master/v4/source/status.yaml)
 #[derive(Derivative, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
 #[derivative(Debug)]
+#[serde(default)]
 pub struct MMCustomStatus {
     /// custom status text description
     pub text: String,
@@ -165,11 +154,54 @@ pub struct MMCustomStatus {
     /// custom status duration
     #[serde(skip_serializing_if = "Option::is_none")]
     pub duration: Option<String>,
-    /// custom status expiration
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// custom status expiration, sent in RFC 3339 UTC (see [`utc_string`])
+    #[serde(skip_serializing_if = "Option::is_none", with = "rfc3339_utc")]
     pub expires_at: Option<DateTime<Local>>,
 }
 
+/// Return `time` in RFC 3339 UTC, the wire format of the expiry times, so that
+/// the server does not depend on the local offset to tell the expiry instant
+/// ```
+/// use chrono::{DateTime, FixedOffset, TimeZone};
+/// use lib::mattermost::utc_string;
+/// let paris = FixedOffset::east_opt(3600).unwrap();
+/// let time = paris.with_ymd_and_hms(2024, 3, 15, 18, 0, 0).unwrap();
+/// assert_eq!(utc_string(&time), "2024-03-15T17:00:00Z");
+/// ```
+pub fn utc_string<Tz: TimeZone>(time: &DateTime<Tz>) -> String {
+    time.with_timezone(&Utc)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// (De)serialization of an optional expiry time, written in RFC 3339 UTC and
+/// read from RFC 3339 with any offset
+mod rfc3339_utc {
+    use super::*;
+    use serde::{de::Error, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<DateTime<Local>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_str(&utc_string(time)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Local>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|time| {
+                DateTime::parse_from_rfc3339(&time)
+                    .map(|time| time.with_timezone(&Local))
+                    .map_err(D::Error::custom)
+            })
+            .transpose()
+    }
+}
+
 impl fmt::Display for MMCustomStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -191,35 +223,110 @@ impl MMCustomStatus {
             expires_at: None,
         }
     }
-    /// Add expiration time with the format "hh:mm" to the mattermost custom status
-    pub fn expires_at(&mut self, time_str: &Option<String>) {
-        // do not set expiry time if set in the past
-        if let Some(expiry) = parse_from_hmstr(time_str) {
-            if Local::now().naive_local() < expiry {
-                self.expires_at = Some(
-                    Local
-                        .from_local_datetime(&expiry)
-                        .latest()
-                        .expect("Naive to local date conversion problem"),
-                );
-                self.duration = Some("date_and_time".to_owned());
-            } else {
-                debug!("now {:?} >= expiry {:?}", Local::now(), expiry);
+    /// Add expiration time to the mattermost custom status, a time of `expiry`
+    /// being taken on the current day of `clock`.
+    ///
+    /// A [`Preset`] sets the `duration` shown by the mattermost clients.
+    pub fn expires_at(&mut self, expiry: Option<Expiry>, clock: &dyn Clock) {
+        let now = clock.now();
+        let time = match expiry {
+            Some(Expiry::At(time)) => time,
+            Some(Expiry::Preset(preset)) => {
+                self.expires_at = Some(preset.expiry(now));
+                self.duration = Some(preset.name().to_owned());
+                return;
             }
+            Some(Expiry::Never) | None => return,
+        };
+        // do not set expiry time if set in the past
+        let expiry = now.date_naive().and_time(time.time());
+        if now.naive_local() < expiry {
+            // A time skipped by a daylight saving time change is moved
+            // forward by the change
+            self.expires_at = Local.from_local_datetime(&expiry).latest().or_else(|| {
+                Local
+                    .from_local_datetime(&(expiry + chrono::Duration::hours(1)))
+                    .earliest()
+            });
+            self.duration = Some("date_and_time".to_owned());
+        } else {
+            debug!("now {:?} >= expiry {:?}", now, expiry);
+        }
+    }
+
+    /// Keep the expiry time of the `previous` status sent with the same emoji
+    /// and [`Preset`] lasting a given length, until half of it is left, so that
+    /// the status is not sent again at each scan with an expiry a scan later
+    /// ```
+    /// use chrono::{Duration, Local, TimeZone};
+    /// use lib::timeofday::Expiry;
+    /// use lib::MMCustomStatus;
+    /// let now = Local.with_ymd_and_hms(2024, 3, 15, 10, 0, 0).unwrap();
+    /// let mut previous = MMCustomStatus::new("At home".to_string(), "house".to_string());
+    /// previous.expires_at("one_hour".parse().ok(), &now);
+    /// let later = now + Duration::minutes(20);
+    /// let mut status = previous.clone();
+    /// status.expires_at("one_hour".parse().ok(), &later);
+    /// status.keep_expiry_of(Some(&previous), &later);
+    /// assert_eq!(status, previous);
+    /// ```
+    pub fn keep_expiry_of(&mut self, previous: Option<&MMCustomStatus>, clock: &dyn Clock) {
+        let Some(length) = Preset::ALL
+            .iter()
+            .find(|preset| self.duration.as_deref() == Some(preset.name()))
+            .and_then(Preset::length)
+        else {
+            return;
+        };
+        if let Some(previous) = previous.filter(|previous| {
+            previous.emoji == self.emoji
+                && previous.duration == self.duration
+                && previous
+                    .expires_at
+                    .is_some_and(|expiry| expiry - clock.now() > length / 2)
+        }) {
+            self.expires_at = previous.expires_at;
         }
-        // let dt: NaiveDateTime = NaiveDate::from_ymd(2016, 7, 8).and_hms(9, 10, 11);
     }
     /// Send self as json, trying to login once in case of 401 failure.
     pub fn send(&mut self, session: &mut LoggedSession) -> Result<ureq::Response, MMSError> {
-        self.send_at(session, "/api/v4/users/me/status/custom")
+        let api_path = session.client.custom_status_path();
+        self.send_at(session, &api_path)
+    }
+
+    /// Get the current custom status of the user, `None` if not set
+    pub fn fetch(session: &mut LoggedSession) -> Result<Option<MMCustomStatus>, MMSError> {
+        let user: json::Value = session
+            .call("GET", "/api/v4/users/me", None)?
+            .into_json()
+            .map_err(|e| MMSError::HTTPRequestError(Box::new(e.into())))?;
+        // The custom status is stored as a json string in user props
+        match user["props"]["customStatus"].as_str() {
+            Some(custom) if !custom.is_empty() => {
+                let status: MMCustomStatus = json::from_str(custom)?;
+                debug!("Current custom status: {:?}", status);
+                Ok(Some(status))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Delete the current custom status of the user
+    pub fn clear(session: &mut LoggedSession) -> Result<ureq::Response, MMSError> {
+        let api_path = session.client.custom_status_path();
+        session.call("DELETE", &api_path, None)
     }
 }
 
 #[cfg(test)]
 mod send_should {
     use super::*;
+    use crate::mattermost::client::ClientOptions;
+    use crate::mattermost::testing::logged_session;
     use crate::mattermost::{BaseSession, Session};
+    use chrono::FixedOffset;
     use httpmock::prelude::*;
+    use proptest::prelude::*;
     use test_log::test; // Automatically trace tests
     #[test]
     fn send_required_json() -> Result<()> {
@@ -263,6 +370,79 @@ mod send_should {
         Ok(())
     }
     #[test]
+    fn fetch_custom_status() -> Result<()> {
+        let server = MockServer::start();
+        let mut user_mock = server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/users/me");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"user_id", "props": {
+                    "customStatus": "{\"emoji\":\"calendar\",\"text\":\"In a meeting\",\"duration\":\"today\"}"
+                }}));
+        });
+        let mut session = Session::new(&server.url("")).with_token("token").login()?;
+        let mut expected = MMCustomStatus::new("In a meeting".into(), "calendar".into());
+        expected.duration = Some("today".into());
+        assert_eq!(MMCustomStatus::fetch(&mut session)?, Some(expected));
+        // No custom status
+        user_mock.delete();
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/users/me");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"user_id", "props": {"customStatus": ""}}));
+        });
+        assert_eq!(MMCustomStatus::fetch(&mut session)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn send_through_plugin() -> Result<()> {
+        let server = MockServer::start();
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/users/me");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"user_id"}));
+        });
+        let plugin_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(PUT)
+                .path("/plugins/com.example.status/status/custom")
+                .json_body(serde_json::json!({"emoji":"emoji","text":"text"}));
+            resp_with.status(200).body("ok");
+        });
+        let mut session = Session::new(&server.url(""))
+            .with_client_options(ClientOptions {
+                plugin_path: Some("/plugins/com.example.status".to_string()),
+                ..Default::default()
+            })
+            .with_token("token")
+            .login()?;
+        MMCustomStatus::new("text".into(), "emoji".into()).send(&mut session)?;
+        plugin_mock.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn delete_custom_status() -> Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let delete_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(DELETE)
+                .header("Authorization", "Bearer token")
+                .path("/api/v4/users/me/status/custom");
+            resp_with.status(200).body("ok");
+        });
+        MMCustomStatus::clear(&mut session)?;
+        delete_mock.assert();
+        Ok(())
+    }
+    #[test]
     fn catch_api_error() -> Result<()> {
         // Start a lightweight mock server.
         let server = MockServer::start();
@@ -302,4 +482,136 @@ mod send_should {
         server_mock.assert();
         Ok(())
     }
+
+    /// Instants from 1970 to 2100 seen from offsets of -12h to +14h, by
+    /// quarters of an hour
+    fn instant() -> impl Strategy<Value = DateTime<FixedOffset>> {
+        (0..4_102_444_800i64, -48..=56i32).prop_map(|(timestamp, quarters)| {
+            FixedOffset::east_opt(quarters * 900)
+                .unwrap()
+                .timestamp_opt(timestamp, 0)
+                .unwrap()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn serialize_expiry_in_utc(time in instant()) {
+            let mut status = MMCustomStatus::new("text".into(), "emoji".into());
+            status.expires_at = Some(time.with_timezone(&Local));
+            let value = json::to_value(&status).unwrap();
+            let wire = value["expires_at"].as_str().unwrap();
+            prop_assert!(wire.ends_with('Z'));
+            prop_assert_eq!(
+                DateTime::parse_from_rfc3339(wire).unwrap().timestamp(),
+                time.timestamp()
+            );
+            // Any offset is read back as the same instant
+            let offset = json::json!({"expires_at": time.to_rfc3339()});
+            let read: MMCustomStatus = json::from_value(offset).unwrap();
+            prop_assert_eq!(read.expires_at, status.expires_at);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+        #[test]
+        fn send_expiry_in_utc(time in instant()) {
+            let server = MockServer::start();
+            let mut session = logged_session(&server).unwrap();
+            let expected = time.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%SZ").to_string();
+            let custom = server.mock(|expect, resp_with| {
+                expect
+                    .method(PUT)
+                    .path("/api/v4/users/me/status/custom")
+                    .json_body(json::json!({
+                        "emoji": "emoji",
+                        "text": "text",
+                        "duration": "date_and_time",
+                        "expires_at": expected,
+                    }));
+                resp_with.status(200);
+            });
+            let mut status = MMCustomStatus::new("text".into(), "emoji".into());
+            status.duration = Some("date_and_time".into());
+            status.expires_at = Some(time.with_timezone(&Local));
+            status.send(&mut session).unwrap();
+            custom.assert();
+        }
+    }
+}
+
+#[cfg(test)]
+mod presence_should {
+    use super::*;
+    use crate::mattermost::testing::logged_session;
+    use httpmock::prelude::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn send_dnd_with_end_time() -> crate::error::Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let end = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        let put_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(PUT)
+                .path("/api/v4/users/user_id/status")
+                .json_body(json::json!({
+                    "user_id": "user_id", "status": "dnd", "dnd_end_time": 1_700_000_000
+                }));
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(json::json!({
+                    "user_id": "user_id", "status": "dnd", "manual": true,
+                    "dnd_end_time": 1_700_000_000
+                }));
+        });
+        let sent = MMStatus::new(Status::Dnd, "user_id".to_string())
+            .with_dnd_end_time(end)
+            .send(&mut session)?;
+        put_mock.assert();
+        assert!(sent.manual);
+        assert_eq!(sent.dnd_end_time(), Some(end));
+        Ok(())
+    }
+
+    #[test]
+    fn fetch_current_presence() -> crate::error::Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/users/user_id/status");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(json::json!({
+                    "user_id": "user_id", "status": "away", "manual": false,
+                    "last_activity_at": 1_700_000_000_000_i64, "active_channel": "town-square",
+                    "dnd_end_time": 0
+                }));
+        });
+        let current = MMStatus::fetch(&mut session)?;
+        assert_eq!(current.status, Status::Away);
+        assert_eq!(current.active_channel.as_deref(), Some("town-square"));
+        assert_eq!(current.dnd_end_time(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn report_rejected_presence() -> crate::error::Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        server.mock(|expect, resp_with| {
+            expect.method(PUT).path("/api/v4/users/user_id/status");
+            resp_with.status(400).body("bad request");
+        });
+        let res = MMStatus::new(Status::Online, "user_id".to_string()).send(&mut session);
+        assert!(matches!(
+            res,
+            Err(MMSError::ApiError(ApiError { status: 400, .. }))
+        ));
+        Ok(())
+    }
 }
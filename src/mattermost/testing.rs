@@ -0,0 +1,18 @@
+//! Helpers shared by the tests using a mocked mattermost server
+use httpmock::prelude::*;
+
+use super::{BaseSession, LoggedSession, Session};
+use crate::error::Result;
+
+/// Mock the login of `user_id` on `server` and return its session, logged in
+/// with the token `token`
+pub(crate) fn logged_session(server: &MockServer) -> Result<LoggedSession> {
+    server.mock(|expect, resp_with| {
+        expect.method(GET).path("/api/v4/users/me");
+        resp_with
+            .status(200)
+            .header("content-type", "application/json")
+            .json_body(serde_json::json!({"id":"user_id"}));
+    });
+    Session::new(&server.url("")).with_token("token").login()
+}
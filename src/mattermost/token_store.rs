@@ -0,0 +1,235 @@
+//! Persistence of the session token obtained by a password login
+//!
+//! With `persist_session`, the token is kept in the OS keyring along with its
+//! creation time. It is reused at the next start while it is younger than
+//! [`MAX_AGE_SECS`] and accepted by the server, avoiding a password login at
+//! each boot (which trips the rate limits and MFA policies of some servers).
+use crate::error::{Error, Result};
+use crate::mattermost::client::ClientOptions;
+use crate::mattermost::{BaseSession, LoggedSession, Session};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+#[cfg(test)]
+use mockall::automock;
+
+/// Service name of the tokens in the OS keyring
+pub const KEYRING_SERVICE: &str = "automattermostatus-session";
+
+/// Age in seconds after which a stored token is not reused, the default
+/// session length of mattermost (30 days)
+pub const MAX_AGE_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Session token with its creation time
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct StoredToken {
+    /// session token
+    pub token: String,
+    /// UTC timestamp of the login creating `token`
+    pub created_at: i64,
+}
+
+impl StoredToken {
+    /// Return `true` if the token is younger than [`MAX_AGE_SECS`] at `now`
+    pub fn is_fresh(&self, now: i64) -> bool {
+        (0..MAX_AGE_SECS).contains(&(now - self.created_at))
+    }
+}
+
+/// Storage of the session token
+#[cfg_attr(test, automock)] // create MockTokenStore struct for tests
+pub trait TokenStore {
+    /// Return the stored token, if any
+    fn load(&self) -> Result<Option<StoredToken>>;
+    /// Replace the stored token by `token`
+    fn save(&self, token: &StoredToken) -> Result<()>;
+}
+
+/// [`TokenStore`] in the OS keyring, with an entry per user and server
+#[derive(Debug)]
+pub struct KeyringStore {
+    account: String,
+}
+
+impl KeyringStore {
+    /// Create the store of the token of `user` on the mattermost server at `url`
+    pub fn new(url: &str, user: &str) -> Self {
+        KeyringStore {
+            account: format!("{}@{}", user, url.trim_end_matches('/')),
+        }
+    }
+}
+
+impl KeyringStore {
+    /// Return the keyring entry of the token
+    fn entry(&self) -> Result<keyring::Entry> {
+        keyring::Entry::new(KEYRING_SERVICE, &self.account).map_err(|e| {
+            Error::Platform(anyhow::Error::new(e).context(format!(
+                "Opening OS keyring (user: {}, service: {})",
+                self.account, KEYRING_SERVICE
+            )))
+        })
+    }
+}
+
+impl TokenStore for KeyringStore {
+    fn load(&self) -> Result<Option<StoredToken>> {
+        let entry = self.entry()?;
+        match entry.get_password() {
+            Ok(json) => serde_json::from_str(&json)
+                .map(Some)
+                .context("Parsing the stored session token")
+                .map_err(Error::Auth),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(Error::Platform(anyhow::Error::new(e).context(format!(
+                "Querying OS keyring (user: {}, service: {})",
+                self.account, KEYRING_SERVICE
+            )))),
+        }
+    }
+
+    fn save(&self, token: &StoredToken) -> Result<()> {
+        let json = serde_json::to_string(token)
+            .context("Serializing the session token")
+            .map_err(Error::Platform)?;
+        self.entry()?.set_password(&json).map_err(|e| {
+            Error::Platform(anyhow::Error::new(e).context(format!(
+                "Storing in OS keyring (user: {}, service: {})",
+                self.account, KEYRING_SERVICE
+            )))
+        })
+    }
+}
+
+/// Log in to the server at `url` as `user`, reusing the token of `store` if it
+/// is still fresh at `now` and accepted by the server. A new token is stored
+/// after a password login.
+pub fn login(
+    store: &dyn TokenStore,
+    url: &str,
+    client: &ClientOptions,
+    user: &str,
+    password: &str,
+    now: i64,
+) -> Result<LoggedSession> {
+    match store.load() {
+        Ok(Some(stored)) if stored.is_fresh(now) => {
+            let mut session = Session::new(url);
+            session.with_client_options(client.clone());
+            match session.with_token(&stored.token).login() {
+                Ok(mut session) => {
+                    debug!("Reusing the session token created at {}", stored.created_at);
+                    session.remember_credentials(user, password);
+                    return Ok(session);
+                }
+                Err(e) => info!("Stored session token rejected ({}), logging in", e),
+            }
+        }
+        Ok(Some(_)) => debug!("Stored session token is too old"),
+        Ok(None) => (),
+        Err(e) => warn!("Fail to read the stored session token : {}", e),
+    }
+    let mut session = Session::new(url);
+    session.with_client_options(client.clone());
+    let session = session.with_credentials(user, password).login()?;
+    let stored = StoredToken {
+        token: session.token.clone(),
+        created_at: now,
+    };
+    if let Err(e) = store.save(&stored) {
+        warn!("Fail to store the session token : {}", e)
+    }
+    Ok(session)
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use httpmock::prelude::*;
+    use test_log::test; // Automatically trace tests
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn stored(created_at: i64) -> StoredToken {
+        StoredToken {
+            token: "stored".to_string(),
+            created_at,
+        }
+    }
+
+    #[test]
+    fn reuse_a_fresh_token() -> Result<()> {
+        let server = MockServer::start();
+        let me_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(GET)
+                .path("/api/v4/users/me")
+                .header("Authorization", "Bearer stored");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"user_id"}));
+        });
+        let login_mock = server.mock(|expect, resp_with| {
+            expect.method(POST).path("/api/v4/users/login");
+            resp_with.status(500);
+        });
+        let mut store = MockTokenStore::new();
+        store
+            .expect_load()
+            .returning(|| Ok(Some(stored(NOW - 3600))));
+        store.expect_save().never();
+        let session = login(
+            &store,
+            &server.url(""),
+            &ClientOptions::default(),
+            "bob",
+            "secret",
+            NOW,
+        )?;
+        assert_eq!(session.token, "stored");
+        me_mock.assert();
+        login_mock.assert_hits(0);
+        Ok(())
+    }
+
+    #[test]
+    fn login_again_with_an_old_or_rejected_token() -> Result<()> {
+        let server = MockServer::start();
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/users/me");
+            resp_with.status(401);
+        });
+        let login_mock = server.mock(|expect, resp_with| {
+            expect.method(POST).path("/api/v4/users/login");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .header("Token", "fresh")
+                .json_body(serde_json::json!({"id":"user_id"}));
+        });
+        for created_at in [NOW - MAX_AGE_SECS, NOW - 60] {
+            let mut store = MockTokenStore::new();
+            store
+                .expect_load()
+                .returning(move || Ok(Some(stored(created_at))));
+            store
+                .expect_save()
+                .withf(|token| token.token == "fresh" && token.created_at == NOW)
+                .times(1)
+                .returning(|_| Ok(()));
+            let session = login(
+                &store,
+                &server.url(""),
+                &ClientOptions::default(),
+                "bob",
+                "secret",
+                NOW,
+            )?;
+            assert_eq!(session.token, "fresh");
+        }
+        login_mock.assert_hits(2);
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+//! Detection of calls from the running meeting applications
+//!
+//! Push-to-talk users do not hold the microphone during a whole call, so the
+//! applications of `meeting_app_names` are looked for among the running
+//! processes. An entry `PROCESS::WINDOW` only matches while a window of
+//! `PROCESS` has a title containing `WINDOW` (e.g. `zoom::Zoom Meeting`). An
+//! entry `PROCESS` only matches while the process also uses the microphone, so
+//! that an application idling in the background is not taken for a call.
+//!
+//! Processes are listed with `ps` on linux and macOS and `tasklist` on windows.
+//! Window titles are read with `wmctrl` on linux (X11 only) and `tasklist` on
+//! windows; they are not available on macOS.
+use crate::command::CommandRunner;
+use crate::micscan;
+use anyhow::{Context, Result};
+use tracing::debug;
+
+/// Meeting application of `meeting_app_names`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeetingApp {
+    /// process name
+    pub process: String,
+    /// substring of the title of the call window, if any
+    pub window: Option<String>,
+}
+
+impl MeetingApp {
+    /// Parse an entry `PROCESS[::WINDOW]` of `meeting_app_names`
+    /// ```
+    /// use lib::meeting::MeetingApp;
+    /// let app = MeetingApp::parse("zoom::Zoom Meeting");
+    /// assert_eq!(app.process, "zoom");
+    /// assert_eq!(app.window.as_deref(), Some("Zoom Meeting"));
+    /// assert_eq!(MeetingApp::parse("obs").window, None);
+    /// ```
+    pub fn parse(entry: &str) -> Self {
+        match entry.split_once("::") {
+            Some((process, window)) if !window.is_empty() => MeetingApp {
+                process: process.to_string(),
+                window: Some(window.to_string()),
+            },
+            Some((process, _)) => MeetingApp {
+                process: process.to_string(),
+                window: None,
+            },
+            None => MeetingApp {
+                process: entry.to_string(),
+                window: None,
+            },
+        }
+    }
+
+    /// Return `true` if the application is among `processes`
+    fn is_running(&self, processes: &[Process]) -> bool {
+        processes.iter().any(|p| p.name == self.process)
+    }
+
+    /// Return `true` if the application is in a call according to `processes`
+    /// and to the names of the processes using the microphone `mic_users`
+    fn in_call(&self, processes: &[Process], mic_users: &[String]) -> bool {
+        match &self.window {
+            Some(window) => processes
+                .iter()
+                .filter(|p| p.name == self.process)
+                .any(|p| p.titles.iter().any(|title| title.contains(window))),
+            None => {
+                self.is_running(processes)
+                    && mic_users
+                        .iter()
+                        .any(|user| *user == self.process || user == micscan::UNKNOWN_MIC_USER)
+            }
+        }
+    }
+}
+
+/// Running process
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Process {
+    /// process identifier
+    pub pid: u32,
+    /// name of the executable
+    pub name: String,
+    /// titles of its windows, if known
+    pub titles: Vec<String>,
+}
+
+/// Return the names of the applications of `apps` being in a call
+pub fn apps_in_call(apps: &[MeetingApp], runner: &dyn CommandRunner) -> Result<Vec<String>> {
+    if apps.is_empty() {
+        return Ok(Vec::new());
+    }
+    let processes = running_processes(runner)?;
+    // The microphone is only checked for the running applications without a
+    // call window
+    let mic_users = match apps
+        .iter()
+        .any(|app| app.window.is_none() && app.is_running(&processes))
+    {
+        true => micscan::processes_owning_mic(runner)
+            .context("Listing the applications using the microphone")?,
+        false => Vec::new(),
+    };
+    let res: Vec<String> = apps
+        .iter()
+        .filter(|app| app.in_call(&processes, &mic_users))
+        .map(|app| app.process.clone())
+        .collect();
+    debug!("Meeting apps in call : {:?}", res);
+    Ok(res)
+}
+
+/// Return the running processes, with the titles of their windows
+#[cfg(target_os = "linux")]
+pub fn running_processes(runner: &dyn CommandRunner) -> Result<Vec<Process>> {
+    let output = runner
+        .run("ps", &["-e", "-o", "pid=,comm="])
+        .context("Listing processes with ps")?;
+    let mut processes = parse_ps(&output);
+    match runner.run("wmctrl", &["-lp"]) {
+        Ok(windows) => add_titles(&mut processes, &parse_wmctrl(&windows)),
+        Err(e) => debug!("Window titles unavailable (wmctrl): {}", e),
+    }
+    Ok(processes)
+}
+
+/// Return the running processes, without the titles of their windows
+#[cfg(target_os = "macos")]
+pub fn running_processes(runner: &dyn CommandRunner) -> Result<Vec<Process>> {
+    let output = runner
+        .run("ps", &["-ax", "-o", "pid=,comm="])
+        .context("Listing processes with ps")?;
+    Ok(parse_ps(&output))
+}
+
+/// Return the running processes, with the title of their main window
+#[cfg(target_os = "windows")]
+pub fn running_processes(runner: &dyn CommandRunner) -> Result<Vec<Process>> {
+    let output = runner
+        .run("tasklist", &["/v", "/fo", "csv", "/nh"])
+        .context("Listing processes with tasklist")?;
+    Ok(parse_tasklist(&output))
+}
+
+/// Parse the `pid=,comm=` output of `ps`, keeping the base name of the commands
+pub fn parse_ps(output: &str) -> Vec<Process> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (pid, command) = line.trim().split_once(char::is_whitespace)?;
+            let command = command.trim();
+            Some(Process {
+                pid: pid.parse().ok()?,
+                name: command.rsplit('/').next().unwrap_or(command).to_string(),
+                titles: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+/// Parse the output of `wmctrl -lp` (window id, desktop, pid, host, title) into
+/// pids and window titles
+pub fn parse_wmctrl(output: &str) -> Vec<(u32, String)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let pid = line.split_whitespace().nth(2)?.parse().ok()?;
+            let mut title = line.trim_start();
+            for _ in 0..4 {
+                title = title
+                    .split_once(char::is_whitespace)
+                    .map_or("", |(_, rest)| rest.trim_start());
+            }
+            Some((pid, title.trim_end().to_string()))
+        })
+        .collect()
+}
+
+/// Add the window titles of `windows` to their processes
+#[cfg(any(test, target_os = "linux"))]
+fn add_titles(processes: &mut [Process], windows: &[(u32, String)]) {
+    for (pid, title) in windows {
+        if let Some(process) = processes.iter_mut().find(|p| p.pid == *pid) {
+            process.titles.push(title.clone());
+        }
+    }
+}
+
+/// Parse the csv output of `tasklist /v /fo csv /nh`, whose first, second and
+/// last fields are the image name, the pid and the window title
+pub fn parse_tasklist(output: &str) -> Vec<Process> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line
+                .trim()
+                .strip_prefix('"')?
+                .strip_suffix('"')?
+                .split("\",\"")
+                .collect();
+            let title = fields.last()?;
+            Some(Process {
+                pid: fields.get(1)?.parse().ok()?,
+                name: fields.first()?.to_string(),
+                titles: match *title {
+                    "N/A" | "" => Vec::new(),
+                    title => vec![title.to_string()],
+                },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn parse_process_lists() {
+        assert_eq!(
+            parse_ps("  412 zoom\n 1337 /Applications/zoom.us.app/Contents/MacOS/zoom.us\n"),
+            vec![
+                Process {
+                    pid: 412,
+                    name: "zoom".to_string(),
+                    titles: vec![]
+                },
+                Process {
+                    pid: 1337,
+                    name: "zoom.us".to_string(),
+                    titles: vec![]
+                }
+            ]
+        );
+        assert_eq!(
+            parse_wmctrl(
+                "0x04400006  0 412    laptop Zoom Meeting\n\
+                 0x04600003 -1 2077   laptop Top Bar\n"
+            ),
+            vec![
+                (412, "Zoom Meeting".to_string()),
+                (2077, "Top Bar".to_string())
+            ]
+        );
+        assert_eq!(
+            parse_tasklist(
+                "\"Zoom.exe\",\"7380\",\"Console\",\"1\",\"151,312 K\",\"Running\",\
+                 \"PC\\bob\",\"0:00:42\",\"Zoom Meeting\"\n\
+                 \"svchost.exe\",\"912\",\"Services\",\"0\",\"9,012 K\",\"Unknown\",\
+                 \"N/A\",\"0:00:01\",\"N/A\"\n"
+            ),
+            vec![
+                Process {
+                    pid: 7380,
+                    name: "Zoom.exe".to_string(),
+                    titles: vec!["Zoom Meeting".to_string()]
+                },
+                Process {
+                    pid: 912,
+                    name: "svchost.exe".to_string(),
+                    titles: vec![]
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn match_call_windows() {
+        let mut processes = parse_ps("412 zoom\n500 obs\n");
+        let zoom = MeetingApp::parse("zoom::Zoom Meeting");
+        assert!(!zoom.in_call(&processes, &[]));
+        add_titles(&mut processes, &[(412, "Zoom Meeting".to_string())]);
+        assert!(zoom.in_call(&processes, &[]));
+    }
+
+    #[test]
+    fn require_the_microphone_without_call_window() {
+        let processes = parse_ps("412 zoom\n500 obs\n");
+        let obs = MeetingApp::parse("obs");
+        assert!(!obs.in_call(&processes, &[]));
+        assert!(!obs.in_call(&processes, &["zoom".to_string()]));
+        assert!(obs.in_call(&processes, &["obs".to_string()]));
+        assert!(obs.in_call(&processes, &[micscan::UNKNOWN_MIC_USER.to_string()]));
+        let teams = MeetingApp::parse("teams-for-linux");
+        assert!(!teams.in_call(&processes, &["teams-for-linux".to_string()]));
+    }
+}
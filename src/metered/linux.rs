@@ -0,0 +1,52 @@
+use crate::command::CommandRunner;
+use anyhow::Result;
+
+/// Return `true` if one of the devices described in the output of
+/// `nmcli -t -f GENERAL.STATE,GENERAL.METERED device show` is connected and
+/// metered, NetworkManager possibly having guessed it.
+fn extract_nmcli_metered(nmcli_output: &str) -> bool {
+    let mut connected = false;
+    for line in nmcli_output.lines() {
+        match line.split_once(':') {
+            Some(("GENERAL.STATE", state)) => connected = state.starts_with("100 "),
+            Some(("GENERAL.METERED", metered)) if connected && metered.starts_with("yes") => {
+                return true
+            }
+            _ => (),
+        }
+    }
+    false
+}
+
+/// Return `true` if a connected network device is metered according to
+/// NetworkManager.
+pub fn is_metered(runner: &dyn CommandRunner) -> Result<bool> {
+    let output = runner.run(
+        "nmcli",
+        &[
+            "-t",
+            "-f",
+            "GENERAL.STATE,GENERAL.METERED",
+            "device",
+            "show",
+        ],
+    )?;
+    Ok(extract_nmcli_metered(&output))
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn detect_connected_metered_device() {
+        let output = "GENERAL.STATE:100 (connected)\nGENERAL.METERED:no\n\n\
+                      GENERAL.STATE:30 (disconnected)\nGENERAL.METERED:yes\n\n\
+                      GENERAL.STATE:100 (connected)\nGENERAL.METERED:yes (guessed)\n";
+        assert!(extract_nmcli_metered(output));
+        let output = "GENERAL.STATE:100 (connected)\nGENERAL.METERED:no (guessed)\n\n\
+                      GENERAL.STATE:30 (disconnected)\nGENERAL.METERED:yes\n";
+        assert!(!extract_nmcli_metered(output));
+    }
+}
@@ -0,0 +1,21 @@
+//! Implement detection of metered connections (tethering on a phone, mobile
+//! broadband, …)
+//!
+//! Used with `respect_metered` to poll less often and skip non-essential API calls.
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "windows")]
+mod windows;
+
+#[cfg(target_os = "linux")]
+pub use linux::is_metered;
+#[cfg(target_os = "windows")]
+pub use windows::is_metered;
+
+/// Return `true` if the connection is metered
+///
+/// Always `false` as macOS does not expose this information.
+#[cfg(target_os = "macos")]
+pub fn is_metered(_runner: &dyn crate::command::CommandRunner) -> anyhow::Result<bool> {
+    Ok(false)
+}
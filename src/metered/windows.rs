@@ -0,0 +1,19 @@
+use crate::command::CommandRunner;
+use anyhow::Result;
+
+/// Return `true` if the internet connection is metered.
+///
+/// The cost type of the internet connection profile is `Unrestricted` unless
+/// the connection is metered (see
+/// [NetworkCostType](https://learn.microsoft.com/en-us/uwp/api/windows.networking.connectivity.networkcosttype)).
+pub fn is_metered(runner: &dyn CommandRunner) -> Result<bool> {
+    let output = runner.run(
+        "powershell",
+        &[
+            "-NoProfile",
+            "-Command",
+            "[Windows.Networking.Connectivity.NetworkInformation,Windows.Networking.Connectivity,ContentType=WindowsRuntime]::GetInternetConnectionProfile().GetConnectionCost().NetworkCostType",
+        ],
+    )?;
+    Ok(matches!(output.trim(), "Fixed" | "Variable"))
+}
@@ -0,0 +1,106 @@
+//! Parsing of the audio devices listed by the OS
+//!
+//! Used to know whether one of the `headset_names` is connected. The commands
+//! are run by the backend of each OS.
+use tracing::debug;
+
+/// Return the descriptions of the devices listed by `pactl list sinks`
+pub fn parse_pactl(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("Description:"))
+        .map(|description| description.trim().to_string())
+        .collect()
+}
+
+/// Return the names of the sound cards of `/proc/asound/cards`
+/// (e.g. ` 1 [Headset        ]: USB-Audio - Jabra EVOLVE 20 MS`)
+pub fn parse_asound_cards(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once("]: "))
+        .map(|(_, name)| match name.split_once(" - ") {
+            Some((_, name)) => name.trim().to_string(),
+            None => name.trim().to_string(),
+        })
+        .collect()
+}
+
+/// Return the names of the devices listed by `system_profiler SPAudioDataType`,
+/// the lines ending with `:` under `Devices:`
+pub fn parse_system_profiler(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("        ") && !line.starts_with("         "))
+        .filter_map(|line| line.trim().strip_suffix(':'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Return the friendly names printed one per line by `Get-PnpDevice`
+pub fn parse_pnp_devices(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Return the first of `devices` whose name contains one of the `headsets`,
+/// ignoring case
+/// ```
+/// use lib::micscan::devices::connected_headset;
+/// let devices = ["Built-in Audio Analog Stereo".to_string(), "WH-1000XM4".to_string()];
+/// assert_eq!(connected_headset(&["wh-1000".to_string()], &devices), Some("WH-1000XM4".to_string()));
+/// assert_eq!(connected_headset(&["Jabra".to_string()], &devices), None);
+/// ```
+pub fn connected_headset(headsets: &[String], devices: &[String]) -> Option<String> {
+    let res = devices
+        .iter()
+        .find(|device| {
+            let device = device.to_lowercase();
+            headsets
+                .iter()
+                .any(|headset| device.contains(&headset.to_lowercase()))
+        })
+        .cloned();
+    debug!("Audio devices {:?}, headset {:?}", devices, res);
+    res
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn list_devices_of_each_os() {
+        assert_eq!(
+            parse_pactl(
+                "Sink #0\n\tState: RUNNING\n\tName: bluez_output.00_1B_66.1\n\
+                 \tDescription: WH-1000XM4\n\tDriver: module-bluez5-device.c\n"
+            ),
+            ["WH-1000XM4"]
+        );
+        assert_eq!(
+            parse_asound_cards(
+                " 0 [PCH            ]: HDA-Intel - HDA Intel PCH\n\
+                 \x20                     HDA Intel PCH at 0xf1330000 irq 146\n\
+                 \x201 [Headset        ]: USB-Audio - Jabra EVOLVE 20 MS\n"
+            ),
+            ["HDA Intel PCH", "Jabra EVOLVE 20 MS"]
+        );
+        assert_eq!(
+            parse_system_profiler(
+                "Audio:\n\n    Devices:\n\n        AirPods Pro:\n\n          \
+                 Input Channels: 1\n        MacBook Pro Speakers:\n"
+            ),
+            ["AirPods Pro", "MacBook Pro Speakers"]
+        );
+        assert_eq!(
+            parse_pnp_devices("Speakers (Realtek(R) Audio)\r\nHeadset (Jabra Link 380)\r\n"),
+            ["Speakers (Realtek(R) Audio)", "Headset (Jabra Link 380)"]
+        );
+    }
+}
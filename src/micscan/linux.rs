@@ -1,3 +1,4 @@
+use crate::command::CommandRunner;
 use anyhow::Result;
 use procfs::process::Process;
 use tracing::debug;
@@ -103,9 +104,22 @@ pub fn alsa_processes_owning_mic() -> Result<Vec<String>> {
     Ok(res)
 }
 
+/// Return the names of the audio output devices, from pulseaudio (or pipewire)
+/// with `pactl`, or else from the ALSA sound cards.
+pub fn audio_devices(runner: &dyn CommandRunner) -> Result<Vec<String>> {
+    match runner.run("pactl", &["list", "sinks"]) {
+        Ok(output) if !output.is_empty() => Ok(super::devices::parse_pactl(&output)),
+        _ => Ok(super::devices::parse_asound_cards(&fs::read_to_string(
+            "/proc/asound/cards",
+        )?)),
+    }
+}
+
 /// Return the list of application name using the default microphone,
 /// either via pulseaudio or alsa depending upon compilation option.
-pub fn processes_owning_mic() -> Result<Vec<String>> {
+///
+/// No command is spawned, `_runner` is unused.
+pub fn processes_owning_mic(_runner: &dyn CommandRunner) -> Result<Vec<String>> {
     #[cfg(feature = "pulseaudio")]
     if let Ok(res) = pulseaudio_processes_using_mic() {
         return Ok(res);
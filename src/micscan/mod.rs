@@ -1,6 +1,8 @@
 //! Implement detection of process using microphone
 
-use tracing::{debug, error, info};
+use anyhow::{Context, Result};
+use tracing::{debug, info};
+pub mod devices;
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "macos")]
@@ -11,18 +13,62 @@ mod osx_parse;
 mod windows;
 
 #[cfg(target_os = "linux")]
-pub use linux::processes_owning_mic;
+pub use linux::{audio_devices, processes_owning_mic};
 #[cfg(target_os = "macos")]
-pub use osx::processes_owning_mic;
+pub use osx::{audio_devices, processes_owning_mic};
 #[cfg(target_os = "windows")]
-pub use windows::processes_owning_mic;
+pub use windows::{audio_devices, processes_owning_mic};
 
-use crate::config::Args;
+use crate::clock::{Clock, SharedClock};
+use crate::command::CommandRunner;
+use crate::events::{Event, EventBus};
 use crate::mattermost::{LoggedSession, MMStatus, Status};
+use crate::signal::Reading;
+use chrono::{DateTime, Duration, Local};
+
+/// Time left to the *do not disturb* status after the next check, so that it
+/// is renewed before it ends while the microphone is used, and ends by itself
+/// if we stop
+const DND_MARGIN_SECS: i64 = 120;
+
+/// Name given to the process using the microphone when the OS does not tell
+/// which one it is (macOS)
+pub const UNKNOWN_MIC_USER: &str = "unknown";
+
+/// Return `true` if one of the `watched` applications is among `names`
+pub fn uses_watched_app(watched: &[String], names: &[String]) -> bool {
+    for name in names {
+        if watched.contains(name) {
+            debug!("Watched app found: {:?}", name);
+            return true;
+        }
+    }
+    false
+}
+
+/// Return the connected headset among `headsets`, without listing the audio
+/// devices if there is none to look for
+pub fn find_headset(headsets: &[String], runner: &dyn CommandRunner) -> Result<Option<String>> {
+    if headsets.is_empty() {
+        return Ok(None);
+    }
+    Ok(devices::connected_headset(
+        headsets,
+        &audio_devices(runner)?,
+    ))
+}
 
 /// Store MicUsage state
 pub struct MicUsage {
     used: bool,
+    /// the user was already in *do not disturb* when the microphone got used
+    dnd_before: bool,
+    /// the presence was changed by another client while the microphone is used
+    overridden: bool,
+    /// end of the last *do not disturb* sent
+    dnd_end: Option<DateTime<Local>>,
+    /// source of the current time
+    clock: SharedClock,
 }
 
 impl Default for MicUsage {
@@ -34,34 +80,318 @@ impl Default for MicUsage {
 impl MicUsage {
     /// Create new MicUsage struct
     pub fn new() -> Self {
-        Self { used: false }
+        Self {
+            used: false,
+            dnd_before: false,
+            overridden: false,
+            dnd_end: None,
+            clock: SharedClock::default(),
+        }
     }
 
-    /// Update status to *do not disturb* if a known application use the mic
-    pub fn update_dnd_status(&mut self, args: &Args, session: &mut LoggedSession) -> &mut Self {
-        match processes_owning_mic() {
-            Ok(names) => {
-                info!("Apps using mic: {:?}", names);
-                let mut watched_app_found = false;
-                for name in names {
-                    if args.mic_app_names.contains(&name) {
-                        debug!("Watched app found: {:?}", name);
-                        watched_app_found = true;
-                        break;
-                    }
-                }
-                if watched_app_found {
-                    let mut status = MMStatus::new(Status::Dnd, session.user_id.clone());
-                    status.send(session);
-                    self.used = true;
-                } else if !watched_app_found && self.used {
-                    let mut status = MMStatus::new(Status::Online, session.user_id.clone());
-                    status.send(session);
-                    self.used = false;
+    /// Take the current time from `clock` instead of the system clock
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Update status to *do not disturb* while we are `busy`, according to the
+    /// aggregated readings of the busy signals (see [`crate::signal`]). Nothing
+    /// is changed while they are unknown.
+    ///
+    /// The *do not disturb* status ends [`DND_MARGIN_SECS`] after `next_check`,
+    /// the longest time until the next call.
+    ///
+    /// A presence set by another client (e.g. the phone app) is adopted: it is
+    /// not overwritten while the microphone is used, and a *do not disturb* set
+    /// before the microphone got used is not reset to online afterwards. A
+    /// presence found once the *do not disturb* sent has ended is not taken for
+    /// one set by another client.
+    ///
+    /// Transitions to and from *do not disturb* are published on `events`.
+    ///
+    /// Return the presence sent, if any, or the error met when sending it. A
+    /// failure to leave *do not disturb* is retried at the next call.
+    pub fn update_dnd_status(
+        &mut self,
+        busy: &Reading,
+        next_check: std::time::Duration,
+        session: &mut LoggedSession,
+        events: &EventBus,
+    ) -> Result<Option<Status>> {
+        let is_busy = match busy {
+            Reading::Busy(reasons) => {
+                info!("Do not disturb sources: {:?}", reasons);
+                true
+            }
+            Reading::Free => false,
+            Reading::Unknown(e) => {
+                debug!(
+                    "Keeping the presence as the busy signals are unknown: {}",
+                    e
+                );
+                return Ok(None);
+            }
+        };
+        if !is_busy && !self.used {
+            return Ok(None);
+        }
+        let now = self.clock.now();
+        let current = match MMStatus::fetch(session) {
+            Ok(current) => Some(current.status),
+            Err(e) => {
+                debug!("Unable to get current presence: {}", e);
+                None
+            }
+        };
+        if is_busy {
+            let entering = !self.used;
+            if entering {
+                self.dnd_before = current == Some(Status::Dnd);
+                self.overridden = false;
+            } else if !self.overridden && current.is_some_and(|s| s != Status::Dnd) {
+                if self.dnd_end.is_some_and(|end| now >= end) {
+                    info!("Do not disturb has ended, setting it again");
+                } else {
+                    info!("Presence changed to {:?} by another client", current);
+                    self.overridden = true;
                 }
             }
-            Err(e) => error!("{}", e),
+            if self.overridden {
+                return Ok(None);
+            }
+            let end = now
+                + Duration::from_std(next_check).unwrap_or_else(|_| Duration::zero())
+                + Duration::seconds(DND_MARGIN_SECS);
+            MMStatus::new(Status::Dnd, session.user_id.clone())
+                .with_dnd_end_time(end)
+                .send(session)
+                .context("Fail to set do not disturb")?;
+            // Entering is tried again on the next check if setting it failed
+            self.used = true;
+            self.dnd_end = Some(end);
+            if entering {
+                events.publish(Event::DndEntered);
+            }
+            Ok(Some(Status::Dnd))
+        } else {
+            let sent = match current {
+                Some(status) if status != Status::Dnd => {
+                    info!("Keeping presence {:?} set by another client", status);
+                    None
+                }
+                _ if self.dnd_before => {
+                    info!("Keeping do not disturb set by hand");
+                    None
+                }
+                _ => {
+                    MMStatus::new(Status::Online, session.user_id.clone())
+                        .send(session)
+                        .context("Fail to leave do not disturb")?;
+                    Some(Status::Online)
+                }
+            };
+            events.publish(Event::DndLeft);
+            self.used = false;
+            self.dnd_end = None;
+            Ok(sent)
         }
-        self
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::mattermost::testing::logged_session;
+    use chrono::TimeZone;
+    use httpmock::prelude::*;
+    use httpmock::Mock;
+    use std::sync::Arc;
+    use test_log::test; // Automatically trace tests
+
+    const MINUTE: std::time::Duration = std::time::Duration::from_secs(60);
+
+    fn presence<'a>(server: &'a MockServer, status: &str) -> Mock<'a> {
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/users/user_id/status");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"user_id":"user_id", "status": status}));
+        })
+    }
+
+    fn put<'a>(server: &'a MockServer, status: &str) -> Mock<'a> {
+        server.mock(|expect, resp_with| {
+            expect
+                .method(PUT)
+                .path("/api/v4/users/user_id/status")
+                .json_body_partial(format!("{{\"status\":\"{}\"}}", status));
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"user_id":"user_id", "status": status}));
+        })
+    }
+
+    #[test]
+    fn keep_dnd_set_before_meeting() -> anyhow::Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        presence(&server, "dnd");
+        let dnd = put(&server, "dnd");
+        let online = put(&server, "online");
+        let events = EventBus::new();
+        let mut mic = MicUsage::new();
+        let sent = mic.update_dnd_status(&zoom(), MINUTE, &mut session, &events)?;
+        assert_eq!(sent, Some(Status::Dnd));
+        let sent = mic.update_dnd_status(&Reading::Free, MINUTE, &mut session, &events)?;
+        assert_eq!(sent, None);
+        dnd.assert_hits(1);
+        online.assert_hits(0);
+        Ok(())
+    }
+
+    #[test]
+    fn adopt_presence_set_elsewhere() -> anyhow::Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let mut online_presence = presence(&server, "online");
+        let dnd = put(&server, "dnd");
+        let online = put(&server, "online");
+        let events = EventBus::new();
+        let mut mic = MicUsage::new();
+        mic.update_dnd_status(&zoom(), MINUTE, &mut session, &events)?;
+        dnd.assert_hits(1);
+        // The phone app sets the presence to away
+        online_presence.delete();
+        presence(&server, "away");
+        mic.update_dnd_status(&zoom(), MINUTE, &mut session, &events)?;
+        mic.update_dnd_status(&Reading::Free, MINUTE, &mut session, &events)?;
+        dnd.assert_hits(1);
+        online.assert_hits(0);
+        Ok(())
+    }
+
+    #[test]
+    fn set_dnd_during_calls_without_microphone() -> anyhow::Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let mut online_presence = presence(&server, "online");
+        let dnd = put(&server, "dnd");
+        let online = put(&server, "online");
+        let events = EventBus::new();
+        let mut mic = MicUsage::new();
+        let busy = crate::signal::aggregate(&[
+            Reading::Unknown("No sound card".to_string()),
+            Reading::Busy(vec!["teams-for-linux".to_string()]),
+        ]);
+        mic.update_dnd_status(&busy, MINUTE, &mut session, &events)?;
+        dnd.assert_hits(1);
+        online_presence.delete();
+        presence(&server, "dnd");
+        mic.update_dnd_status(&Reading::Free, MINUTE, &mut session, &events)?;
+        online.assert_hits(1);
+        Ok(())
+    }
+
+    #[test]
+    fn retry_to_leave_dnd_after_a_failure() -> anyhow::Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let mut online_presence = presence(&server, "online");
+        put(&server, "dnd");
+        let mut failing = server.mock(|expect, resp_with| {
+            expect
+                .method(PUT)
+                .path("/api/v4/users/user_id/status")
+                .json_body_partial(r#"{"status":"online"}"#);
+            resp_with.status(400);
+        });
+        let events = EventBus::new();
+        let mut mic = MicUsage::new();
+        mic.update_dnd_status(&zoom(), MINUTE, &mut session, &events)?;
+        online_presence.delete();
+        presence(&server, "dnd");
+        let sent = mic.update_dnd_status(&Reading::Free, MINUTE, &mut session, &events);
+        assert!(sent
+            .unwrap_err()
+            .to_string()
+            .contains("leave do not disturb"));
+        failing.delete();
+        let online = put(&server, "online");
+        let sent = mic.update_dnd_status(&Reading::Free, MINUTE, &mut session, &events)?;
+        assert_eq!(sent, Some(Status::Online));
+        online.assert_hits(1);
+        Ok(())
+    }
+
+    #[test]
+    fn notify_dnd_once_it_is_set() -> anyhow::Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        presence(&server, "online");
+        let mut failing = server.mock(|expect, resp_with| {
+            expect
+                .method(PUT)
+                .path("/api/v4/users/user_id/status")
+                .json_body_partial(r#"{"status":"dnd"}"#);
+            resp_with.status(500);
+        });
+        let mut events = EventBus::new();
+        let rx = events.channel();
+        let mut mic = MicUsage::new();
+        assert!(mic
+            .update_dnd_status(&zoom(), MINUTE, &mut session, &events)
+            .is_err());
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), []);
+        failing.delete();
+        put(&server, "dnd");
+        mic.update_dnd_status(&zoom(), MINUTE, &mut session, &events)?;
+        mic.update_dnd_status(&zoom(), MINUTE, &mut session, &events)?;
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [Event::DndEntered]);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_dnd_until_the_next_check() -> anyhow::Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        presence(&server, "online");
+        let start = Local.with_ymd_and_hms(2024, 1, 15, 10, 0, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let dnd = |end: DateTime<Local>| {
+            server.mock(|expect, resp_with| {
+                expect
+                    .method(PUT)
+                    .path("/api/v4/users/user_id/status")
+                    .json_body_partial(format!(
+                        r#"{{"status":"dnd","dnd_end_time":{}}}"#,
+                        end.timestamp()
+                    ));
+                resp_with
+                    .status(200)
+                    .header("content-type", "application/json")
+                    .json_body(serde_json::json!({"user_id":"user_id", "status": "dnd"}));
+            })
+        };
+        let next_check = std::time::Duration::from_secs(600);
+        let first = dnd(start + Duration::seconds(600 + DND_MARGIN_SECS));
+        let events = EventBus::new();
+        let mut mic = MicUsage::new().with_clock(SharedClock::from(clock.clone()));
+        mic.update_dnd_status(&zoom(), next_check, &mut session, &events)?;
+        first.assert_hits(1);
+        // The check came late and do not disturb has ended: it is not taken
+        // for a presence set by another client
+        clock.advance(Duration::seconds(900));
+        let again = dnd(clock.now() + Duration::seconds(600 + DND_MARGIN_SECS));
+        mic.update_dnd_status(&zoom(), next_check, &mut session, &events)?;
+        again.assert_hits(1);
+        Ok(())
+    }
+
+    fn zoom() -> Reading {
+        Reading::Busy(vec!["zoom".to_string()])
     }
 }
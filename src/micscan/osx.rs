@@ -1,16 +1,22 @@
 use super::osx_parse::extract_mic_in_use;
+use crate::command::CommandRunner;
 use anyhow::Result;
-use std::process::Command;
 //use tracing::debug;
 
 /// Return the list of application name using the default microphone,
 /// either via pulseaudio or alsa depending upon compilation option.
 /// TODO for macOS
-pub fn processes_owning_mic() -> Result<Vec<String>> {
+pub fn processes_owning_mic(runner: &dyn CommandRunner) -> Result<Vec<String>> {
     let mut res = Vec::new();
-    let output = Command::new("ioreg").args(&["-l"]).output()?;
-    if extract_mic_in_use(&String::from_utf8_lossy(&output.stdout)) {
-        res.push("unknown".to_string());
+    let output = runner.run("ioreg", &["-l"])?;
+    if extract_mic_in_use(&output) {
+        res.push(super::UNKNOWN_MIC_USER.to_string());
     }
     Ok(res)
 }
+
+/// Return the names of the audio devices listed by `system_profiler`
+pub fn audio_devices(runner: &dyn CommandRunner) -> Result<Vec<String>> {
+    let output = runner.run("system_profiler", &["SPAudioDataType"])?;
+    Ok(super::devices::parse_system_profiler(&output))
+}
@@ -1,19 +1,68 @@
+use crate::command::CommandRunner;
 use anyhow::{Context, Result};
 use tracing::{debug, error};
 use winreg::enums::*;
 use winreg::RegKey;
 
+/// Key of a user hive under which are all the applications that can use the
+/// microphone
+const MIC_INFO_PATH: &str = "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\CapabilityAccessManager\\ConsentStore\\microphone\\NonPackaged";
+
+/// Return whether the process runs as LocalSystem, as windows services do,
+/// whose `HKEY_CURRENT_USER` is the hive of the system account instead of the
+/// one of the user
+fn is_local_system() -> bool {
+    // The name of the account of the computer ends with `$`
+    std::env::var("USERNAME").is_ok_and(|name| name.ends_with('$'))
+}
+
+/// Return the loaded hives of the users, named after their SID, without the
+/// ones of the system accounts (`S-1-5-18`...) nor the classes of the users
+fn user_hives() -> Vec<(String, RegKey)> {
+    let users = RegKey::predef(HKEY_USERS);
+    users
+        .enum_keys()
+        .filter_map(|sid| sid.ok())
+        .filter(|sid| sid.starts_with("S-1-5-21-") && !sid.ends_with("_Classes"))
+        .filter_map(|sid| users.open_subkey(&sid).ok().map(|hive| (sid, hive)))
+        .collect()
+}
+
 /// Return the list of application name using the default microphone,
 /// by reading the database register.
-pub fn processes_owning_mic() -> Result<Vec<String>> {
+///
+/// When running as LocalSystem (as a windows service), the hives of all the
+/// users logged in are read, as the one of the current user belongs to the
+/// system account.
+///
+/// No command is spawned, `_runner` is unused.
+pub fn processes_owning_mic(_runner: &dyn CommandRunner) -> Result<Vec<String>> {
     let mut res = Vec::new();
-    let hklm = RegKey::predef(HKEY_CURRENT_USER);
+    if !is_local_system() {
+        push_processes_owning_mic(&RegKey::predef(HKEY_CURRENT_USER), &mut res)?;
+    } else {
+        let hives = user_hives();
+        if hives.is_empty() {
+            debug!("Running as LocalSystem without any user logged in");
+        }
+        for (sid, hive) in hives {
+            // Users who never used the microphone have no key
+            if let Err(e) = push_processes_owning_mic(&hive, &mut res) {
+                debug!("Microphone usage of {} unknown: {:#}", sid, e);
+            }
+        }
+    }
+    debug!("Process owning mic : {:?}", res);
+    Ok(res)
+}
 
+/// Push in `res` the names of the applications using the microphone according
+/// to the user `hive`
+fn push_processes_owning_mic(hive: &RegKey, res: &mut Vec<String>) -> Result<()> {
     //Retrieve the "parent" key : under it, all application that can used the micro.
-    let mic_info_path = "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\CapabilityAccessManager\\ConsentStore\\microphone\\NonPackaged";
-    let mic_used_key = hklm
-        .open_subkey(mic_info_path)
-        .context(format!("Opening key {:?} in base register", mic_info_path))?;
+    let mic_used_key = hive
+        .open_subkey(MIC_INFO_PATH)
+        .context(format!("Opening key {:?} in base register", MIC_INFO_PATH))?;
 
     //Iterate on "child" keys
     let keys = mic_used_key.enum_keys();
@@ -48,7 +97,20 @@ pub fn processes_owning_mic() -> Result<Vec<String>> {
             error!("Unable to open subkey: {:?} ", child_key);
         }
     }
+    Ok(())
+}
 
-    debug!("Process owning mic : {:?}", res);
-    Ok(res)
+/// Return the names of the connected audio endpoints
+pub fn audio_devices(runner: &dyn CommandRunner) -> Result<Vec<String>> {
+    let output = runner
+        .run(
+            "powershell",
+            &[
+                "-NoProfile",
+                "-Command",
+                "Get-PnpDevice -Class AudioEndpoint -Status OK | ForEach-Object FriendlyName",
+            ],
+        )
+        .context("Listing audio devices")?;
+    Ok(super::devices::parse_pnp_devices(&output))
 }
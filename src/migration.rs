@@ -0,0 +1,154 @@
+//! Migration of the configuration files written by previous versions
+//!
+//! The configuration file holds the `config_version` of its layout. An older
+//! file (without `config_version` before version 1) is migrated when loaded, one
+//! [`STEPS`] at a time, and the previous file is kept aside as
+//! `automattermostatus.toml.v<N>.bak`, so that an upgrade does not silently drop
+//! settings under keys which are no longer known. The file is edited in place:
+//! comments and layout are kept.
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::{value, Document, Key, Table};
+use tracing::info;
+
+/// Version of the layout of the configuration file
+pub const CONFIG_VERSION: i64 = 1;
+
+/// Migration of a configuration file from the version of its index to the next
+/// one, returning the description of its changes
+type Step = fn(&mut Table) -> Vec<String>;
+
+/// Migrations of each version, the first one migrating files without
+/// `config_version`
+const STEPS: [Step; CONFIG_VERSION as usize] = [token_keys];
+
+/// Rename `from` to `to` in `table`, unless `to` is already set, keeping its
+/// comments
+fn rename(table: &mut Table, from: &str, to: &str) -> Option<String> {
+    let (key, item) = table.remove_entry(from)?;
+    if table.contains_key(to) {
+        return Some(format!("`{}` dropped as `{}` is set", from, to));
+    }
+    table.insert_formatted(&Key::new(to).with_decor(key.decor().clone()), item);
+    Some(format!("`{}` renamed to `{}`", from, to))
+}
+
+/// Version 1: the secret may be a password since v0.1.8, the `mm_token*` keys
+/// are renamed `mm_secret*` and the secret stays a token
+fn token_keys(table: &mut Table) -> Vec<String> {
+    let res: Vec<String> = [("mm_token", "mm_secret"), ("mm_token_cmd", "mm_secret_cmd")]
+        .iter()
+        .filter_map(|(from, to)| rename(table, from, to))
+        .collect();
+    if !res.is_empty() && !table.contains_key("secret_type") {
+        table.insert("secret_type", value("Token"));
+        return res
+            .into_iter()
+            .chain(["`secret_type` set to `Token`".to_string()])
+            .collect();
+    }
+    res
+}
+
+/// Return `content` migrated to [`CONFIG_VERSION`] with the description of the
+/// changes, and its previous version, or `None` if it is up to date
+/// ```
+/// use lib::migration::migrate;
+/// let (content, changes, version) = migrate("# my token\nmm_token_cmd = 'pass mm'\n")
+///     .unwrap()
+///     .unwrap();
+/// assert!(content.contains("# my token\nmm_secret_cmd = 'pass mm'\n"));
+/// assert_eq!(changes[0], "`mm_token_cmd` renamed to `mm_secret_cmd`");
+/// assert_eq!(version, 0);
+/// assert_eq!(migrate(&content).unwrap(), None);
+/// ```
+pub fn migrate(content: &str) -> Result<Option<(String, Vec<String>, i64)>> {
+    let mut document: Document = content.parse().context("Parsing configuration")?;
+    let version = document
+        .get("config_version")
+        .and_then(|item| item.as_integer())
+        .unwrap_or_default();
+    if version >= CONFIG_VERSION {
+        return Ok(None);
+    }
+    let changes = STEPS
+        .iter()
+        .skip(usize::try_from(version).unwrap_or_default())
+        .flat_map(|step| step(document.as_table_mut()))
+        .collect();
+    document.insert("config_version", value(CONFIG_VERSION));
+    Ok(Some((document.to_string(), changes, version)))
+}
+
+/// Return the path keeping the version `version` of the configuration file at
+/// `path`
+pub fn backup_path(path: &Path, version: i64) -> PathBuf {
+    let mut res = path.as_os_str().to_owned();
+    res.push(format!(".v{}.bak", version));
+    res.into()
+}
+
+/// Migrate the configuration file at `path` to [`CONFIG_VERSION`], keeping the
+/// previous one aside
+pub fn migrate_file(path: &Path) -> Result<()> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Reading conf file {:?}", path))?;
+    let Some((migrated, changes, version)) = migrate(&content)? else {
+        return Ok(());
+    };
+    let backup = backup_path(path, version);
+    fs::write(&backup, &content).with_context(|| format!("Writing {:?}", backup))?;
+    fs::write(path, migrated).with_context(|| format!("Writing conf file {:?}", path))?;
+    info!(
+        "Configuration file {:?} migrated to version {} (previous one kept in {:?})",
+        path, CONFIG_VERSION, backup
+    );
+    for change in changes {
+        info!("  {}", change);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn keep_the_secret_a_token() -> Result<()> {
+        let (content, changes, _) = migrate(
+            "mm_url = 'https://mattermost.example.com'\nmm_token = 'xxx'\n\n[offdays]\nSat = 'Every'\n",
+        )?
+        .unwrap();
+        let config: toml::Value = toml::from_str(&content)?;
+        assert_eq!(config["mm_secret"].as_str(), Some("xxx"));
+        assert_eq!(config["secret_type"].as_str(), Some("Token"));
+        assert_eq!(config["config_version"].as_integer(), Some(CONFIG_VERSION));
+        assert_eq!(config["offdays"]["Sat"].as_str(), Some("Every"));
+        assert_eq!(changes.len(), 2);
+        // The secret type set by hand is kept
+        let (content, _, _) =
+            migrate("secret_type = 'Password'\nmm_token_cmd = 'pass mm'\n")?.unwrap();
+        assert!(content.contains("secret_type = 'Password'\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_the_previous_file() -> Result<()> {
+        let dir = Temp::new_dir()?;
+        let path = dir.join("automattermostatus.toml");
+        fs::write(&path, "mm_token_cmd = 'pass mm'\n")?;
+        migrate_file(&path)?;
+        assert_eq!(
+            fs::read_to_string(dir.join("automattermostatus.toml.v0.bak"))?,
+            "mm_token_cmd = 'pass mm'\n"
+        );
+        assert!(fs::read_to_string(&path)?.contains("config_version = 1\n"));
+        // Up to date files are left alone
+        migrate_file(&path)?;
+        assert!(!dir.join("automattermostatus.toml.v1.bak").exists());
+        Ok(())
+    }
+}
@@ -1,23 +1,42 @@
-//! This module Provide the [`Off`] trait and [`OffDays`] struct
+//! This module Provide the [`Off`] trait and [`OffDays`] struct, and the
+//! recurring [`FocusBlock`]s sharing the same schedule machinery
+use anyhow::anyhow;
 pub use chrono::Weekday;
-use chrono::{Datelike, Local, NaiveDate};
-use serde::{Deserialize, Serialize};
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use schemars::JsonSchema;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
+use std::str::FromStr;
 use tracing::{debug, trace};
 
-#[cfg(test)]
-use mockall::automock;
+use crate::clock::{Clock, SystemClock};
+use crate::i18n::parse_weekday;
+use crate::timeofday::TimeOfDay;
 
 /// Manage the time where the application shall not update the status because the user
 /// is not working
 pub trait Off {
+    /// Is the user off at the time given by `clock` ?
+    fn is_off_at(&self, clock: &dyn Clock) -> bool;
+
     /// Is the user off now ?
-    fn is_off_time(&self) -> bool;
+    fn is_off_time(&self) -> bool {
+        self.is_off_at(&SystemClock)
+    }
 }
 
 /// Struct for describing the parity of the week for which the out of work day apply
 /// Parity is given according to iso week number
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// It is parsed ignoring case, with or without the `Week` suffix:
+/// ```
+/// use lib::offtime::Parity;
+/// assert_eq!("odd".parse::<Parity>().unwrap(), Parity::OddWeek);
+/// assert_eq!("EVENWEEK".parse::<Parity>().unwrap(), Parity::EvenWeek);
+/// assert!("sometimes".parse::<Parity>().is_err());
+/// ```
+#[derive(Serialize, Debug, JsonSchema, Clone, Copy, PartialEq, Eq)]
 pub enum Parity {
     /// Day off for all weeks
     EveryWeek,
@@ -27,28 +46,59 @@ pub enum Parity {
     EvenWeek,
 }
 
-/// Struct olding a map of ([`Weekday`], [`Parity`]) descripting day offs.
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(transparent)]
-pub struct OffDays(HashMap<Weekday, Parity>);
+impl FromStr for Parity {
+    type Err = anyhow::Error;
 
-struct Time {}
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        match s.strip_suffix("week").unwrap_or(&s) {
+            "every" | "all" => Ok(Parity::EveryWeek),
+            "odd" => Ok(Parity::OddWeek),
+            "even" => Ok(Parity::EvenWeek),
+            _ => Err(anyhow!(
+                "`{}` is not a week parity (EveryWeek, OddWeek or EvenWeek)",
+                s
+            )),
+        }
+    }
+}
 
-/// Trait providing a `now` function.
-///
-/// The use of a trait instead of calling directly `Local::now` is needed in order to be able to
-/// mock time in tests
-#[cfg_attr(test, automock)] // create MockNow Struct for tests
-pub trait Now {
-    /// Returns current local time
-    fn now(&self) -> NaiveDate;
+impl<'de> Deserialize<'de> for Parity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
 }
-impl Now for Time {
-    fn now(&self) -> NaiveDate {
-        Local::now().date_naive()
+
+/// A day off given on the command line as `DAY[:PARITY]`, the parity defaulting
+/// to [`Parity::EveryWeek`]
+/// ```
+/// use lib::offtime::{OffDay, Parity, Weekday};
+/// let day: OffDay = "wed:odd".parse().unwrap();
+/// assert_eq!(day, OffDay(Weekday::Wed, Parity::OddWeek));
+/// assert_eq!("Sat".parse::<OffDay>().unwrap().1, Parity::EveryWeek);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffDay(pub Weekday, pub Parity);
+
+impl FromStr for OffDay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (day, parity) = s.split_once(':').unwrap_or((s, "EveryWeek"));
+        let day = parse_weekday(day).ok_or_else(|| anyhow!("`{}` is not a week day", day))?;
+        Ok(OffDay(day, parity.parse()?))
     }
 }
 
+/// Struct olding a map of ([`Weekday`], [`Parity`]) descripting day offs.
+///
+/// Week days may be given in any language supported by [`crate::i18n`].
+#[derive(Serialize, Debug, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct OffDays(#[schemars(with = "HashMap<String, Parity>")] HashMap<Weekday, Parity>);
+
 impl OffDays {
     /// Create new empty `OffDays` instance
     pub fn new() -> OffDays {
@@ -62,8 +112,7 @@ impl OffDays {
     /// The user is off if date day is in OffDays and either,
     /// - parity is all
     /// - parity match the current iso week number
-    fn is_off_at_date(&self, date: impl Now) -> bool {
-        let now = date.now();
+    fn is_off_at_date(&self, now: NaiveDate) -> bool {
         trace!("now: {:?}", now);
         trace!("now.weekday: {:?}", now.weekday());
         let res: bool;
@@ -92,38 +141,303 @@ impl OffDays {
     }
 }
 
+impl<'de> Deserialize<'de> for OffDays {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::<String, Parity>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(day, parity)| {
+                parse_weekday(&day)
+                    .map(|day| (day, parity))
+                    .ok_or_else(|| D::Error::custom(format!("`{}` is not a week day", day)))
+            })
+            .collect::<Result<_, _>>()
+            .map(OffDays)
+    }
+}
+
+impl FromIterator<OffDay> for OffDays {
+    fn from_iter<I: IntoIterator<Item = OffDay>>(iter: I) -> Self {
+        OffDays(
+            iter.into_iter()
+                .map(|OffDay(day, parity)| (day, parity))
+                .collect(),
+        )
+    }
+}
+
 impl Default for OffDays {
     fn default() -> Self {
         OffDays::new()
     }
 }
 
+/// Working period of the day, bounded by the optional `begin` and `end` times
+///
+/// When `end` is before `begin`, the working period spans midnight (night
+/// shifts). Times are compared as wall clock times, so that the period does not
+/// shift on daylight saving time transitions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    /// Start of the working period
+    pub begin: Option<TimeOfDay>,
+    /// End of the working period
+    pub end: Option<TimeOfDay>,
+}
+
+impl Schedule {
+    /// Is the local time `now` outside of the working period ?
+    /// ```
+    /// use lib::offtime::Schedule;
+    /// use chrono::NaiveDate;
+    /// let night = Schedule {
+    ///     begin: Some("22:00".parse().unwrap()),
+    ///     end: Some("6:00".parse().unwrap()),
+    /// };
+    /// let at = |h| NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(h, 0, 0).unwrap();
+    /// assert!(!night.is_off(at(23)));
+    /// assert!(night.is_off(at(12)));
+    /// ```
+    pub fn is_off(&self, now: NaiveDateTime) -> bool {
+        let now = now.time();
+        match (self.begin, self.end) {
+            (None, None) => false,
+            (Some(begin), None) => now < begin.time(),
+            (None, Some(end)) => now > end.time(),
+            (Some(begin), Some(end)) if begin <= end => now < begin.time() || now > end.time(),
+            // The working period spans midnight
+            (Some(begin), Some(end)) => now > end.time() && now < begin.time(),
+        }
+    }
+}
+
+impl Off for Schedule {
+    fn is_off_at(&self, clock: &dyn Clock) -> bool {
+        self.is_off(clock.now().naive_local())
+    }
+}
+
 impl Off for OffDays {
     /// The user is off if
     /// current day is in OffDays and either,
     /// - parity is all
     /// - parity match the current iso week number
-    fn is_off_time(&self) -> bool {
-        self.is_off_at_date(Time {})
+    fn is_off_at(&self, clock: &dyn Clock) -> bool {
+        self.is_off_at_date(clock.now().date_naive())
+    }
+}
+
+/// Recurring focus block given as `[DAYS] BEGIN-END` (e.g. `mon-fri 9:00-11:00`)
+///
+/// DAYS is a comma separated list of week days or ranges of week days, in any
+/// language supported by [`crate::i18n`], or `weekdays`. Without DAYS, the block
+/// recurs every day.
+/// ```
+/// use lib::offtime::FocusBlock;
+/// use chrono::NaiveDate;
+/// let block: FocusBlock = "mon-fri 9:00-11:00".parse().unwrap();
+/// let monday = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+/// assert!(block.is_active(monday.and_hms_opt(10, 0, 0).unwrap()));
+/// assert!(!block.is_active(monday.and_hms_opt(11, 30, 0).unwrap()));
+/// let saturday = NaiveDate::from_ymd_opt(2024, 1, 20).unwrap();
+/// assert!(!block.is_active(saturday.and_hms_opt(10, 0, 0).unwrap()));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FocusBlock {
+    /// days on which the block recurs
+    pub days: Vec<Weekday>,
+    /// period of the block during these days
+    pub schedule: Schedule,
+}
+
+impl FocusBlock {
+    /// Is the local time `now` within the block ?
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        self.days.contains(&now.weekday()) && !self.schedule.is_off(now)
+    }
+
+    /// End of the block
+    pub fn end(&self) -> Option<TimeOfDay> {
+        self.schedule.end
+    }
+}
+
+impl FromStr for FocusBlock {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, period) = match s.trim().rsplit_once(char::is_whitespace) {
+            Some((days, period)) => (parse_days(days)?, period),
+            None => (ALL_DAYS.to_vec(), s.trim()),
+        };
+        let (begin, end) = period
+            .split_once('-')
+            .ok_or_else(|| anyhow!("`{}` is not a period with the format hh:mm-hh:mm", period))?;
+        let (begin, end): (TimeOfDay, TimeOfDay) = (begin.parse()?, end.parse()?);
+        if begin >= end {
+            return Err(anyhow!("the focus block `{}` shall end after it begins", s));
+        }
+        Ok(FocusBlock {
+            days,
+            schedule: Schedule {
+                begin: Some(begin),
+                end: Some(end),
+            },
+        })
+    }
+}
+
+/// Days of the week, from Monday
+const ALL_DAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// Parse comma separated week days or ranges of week days (e.g. `mon-wed,fri`)
+fn parse_days(s: &str) -> anyhow::Result<Vec<Weekday>> {
+    let day = |day: &str| parse_weekday(day).ok_or_else(|| anyhow!("`{}` is not a week day", day));
+    let mut res = Vec::new();
+    for item in s.split(',').map(str::trim) {
+        if item.eq_ignore_ascii_case("weekdays") {
+            res.extend(&ALL_DAYS[..5]);
+            continue;
+        }
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (mut current, last) = (day(first)?, day(last)?);
+                res.push(current);
+                while current != last {
+                    current = current.succ();
+                    res.push(current);
+                }
+            }
+            None => res.push(day(item)?),
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod schedule_should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    fn schedule(begin: Option<&str>, end: Option<&str>) -> Schedule {
+        Schedule {
+            begin: begin.map(|t| t.parse().unwrap()),
+            end: end.map(|t| t.parse().unwrap()),
+        }
+    }
+
+    fn at(date: (i32, u32, u32), h: u32, m: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+            .unwrap()
+            .and_hms_opt(h, m, 0)
+            .unwrap()
+    }
+
+    const DAY: (i32, u32, u32) = (2024, 1, 15);
+
+    #[test]
+    fn never_be_off_without_bounds() {
+        let s = schedule(None, None);
+        assert!(!s.is_off(at(DAY, 0, 0)));
+        assert!(!s.is_off(at(DAY, 23, 59)));
+    }
+
+    #[test]
+    fn handle_a_single_bound() {
+        let s = schedule(Some("8:00"), None);
+        assert!(s.is_off(at(DAY, 7, 59)));
+        assert!(!s.is_off(at(DAY, 8, 0)));
+        assert!(!s.is_off(at(DAY, 23, 59)));
+        let s = schedule(None, Some("18:00"));
+        assert!(!s.is_off(at(DAY, 0, 0)));
+        assert!(!s.is_off(at(DAY, 18, 0)));
+        assert!(s.is_off(at(DAY, 18, 1)));
+    }
+
+    #[test]
+    fn handle_day_schedule() {
+        let s = schedule(Some("8:00"), Some("18:00"));
+        assert!(s.is_off(at(DAY, 7, 0)));
+        assert!(!s.is_off(at(DAY, 12, 0)));
+        assert!(s.is_off(at(DAY, 19, 0)));
+    }
+
+    #[test]
+    fn handle_night_shift() {
+        let s = schedule(Some("22:00"), Some("6:00"));
+        assert!(!s.is_off(at(DAY, 22, 0)));
+        assert!(!s.is_off(at(DAY, 23, 30)));
+        assert!(!s.is_off(at(DAY, 0, 0)));
+        assert!(!s.is_off(at(DAY, 6, 0)));
+        assert!(s.is_off(at(DAY, 6, 1)));
+        assert!(s.is_off(at(DAY, 12, 0)));
+    }
+
+    #[test]
+    fn follow_wall_clock_on_dst_transitions() {
+        use chrono::{LocalResult, TimeZone, Utc};
+        use chrono_tz::Europe::Paris;
+        // Wall clock in Paris at the UTC time `h:m` of `day`
+        let paris = |day, h, m| Utc.from_utc_datetime(&at(day, h, m)).with_timezone(&Paris);
+        let s = schedule(Some("1:00"), Some("3:00"));
+        // Spring forward: 02:30 is skipped, 01:59 being followed by 03:00
+        let spring = (2024, 3, 31);
+        assert_eq!(
+            Paris.from_local_datetime(&at(spring, 2, 30)),
+            LocalResult::None
+        );
+        assert!(!s.is_off(paris(spring, 0, 59).naive_local()));
+        assert!(!s.is_off(paris(spring, 1, 0).naive_local()));
+        assert!(s.is_off(paris(spring, 1, 30).naive_local()));
+        // Fall back: 02:30 happens twice, an hour apart
+        let fall = (2024, 10, 27);
+        assert_eq!(paris(fall, 0, 30).naive_local(), at(fall, 2, 30));
+        assert_eq!(paris(fall, 1, 30).naive_local(), at(fall, 2, 30));
+        assert!(!s.is_off(paris(fall, 0, 30).naive_local()));
+        assert!(!s.is_off(paris(fall, 1, 30).naive_local()));
+        assert!(s.is_off(paris(fall, 2, 30).naive_local()));
+        // The working hours follow the wall clock, whatever the UTC offset
+        let s = schedule(Some("8:00"), Some("18:00"));
+        for (day, offset) in [(spring, 2), (fall, 1)] {
+            assert!(!s.is_off(paris(day, 8 - offset, 0).naive_local()));
+            assert!(s.is_off(paris(day, 18 - offset, 30).naive_local()));
+        }
     }
 }
 
 #[cfg(test)]
 mod is_off_should {
     use super::*;
+    use crate::clock::MockClock;
     use anyhow::Result;
     use chrono::Weekday;
+    use chrono::{DateTime, Local, TimeZone};
     use test_log::test; // Automatically trace tests
 
+    /// Noon of the `weekday` of the iso week `week`
+    fn noon(year: i32, week: u32, weekday: Weekday) -> DateTime<Local> {
+        let date = NaiveDate::from_isoywd_opt(year, week, weekday).expect("Unable to convert date");
+        Local
+            .from_local_datetime(&date.and_hms_opt(12, 0, 0).unwrap())
+            .unwrap()
+    }
+
     #[test]
     fn return_false_when_day_dont_match() -> Result<()> {
         let mut leave = OffDays::new();
         leave.insert(Weekday::Mon, Parity::EveryWeek);
-        let mut mock = MockNow::new();
-        mock.expect_now().times(1).returning(|| {
-            NaiveDate::from_isoywd_opt(2015, 1, Weekday::Tue).expect("Unable to convert date")
-        });
-        assert_eq!(leave.is_off_at_date(mock), false);
+        let mut mock = MockClock::new();
+        mock.expect_now()
+            .times(1)
+            .returning(|| noon(2015, 1, Weekday::Tue));
+        assert_eq!(leave.is_off_at(&mock), false);
         Ok(())
     }
 
@@ -131,11 +445,11 @@ mod is_off_should {
     fn return_true_when_match_and_no_parity() -> Result<()> {
         let mut leave = OffDays::new();
         leave.insert(Weekday::Tue, Parity::EveryWeek);
-        let mut mock = MockNow::new();
-        mock.expect_now().times(1).returning(|| {
-            NaiveDate::from_isoywd_opt(2015, 1, Weekday::Tue).expect("Unable to convert date")
-        });
-        assert_eq!(leave.is_off_at_date(mock), true);
+        let mut mock = MockClock::new();
+        mock.expect_now()
+            .times(1)
+            .returning(|| noon(2015, 1, Weekday::Tue));
+        assert_eq!(leave.is_off_at(&mock), true);
         Ok(())
     }
 
@@ -144,18 +458,18 @@ mod is_off_should {
         let mut leave = OffDays::new();
         leave.insert(Weekday::Wed, Parity::OddWeek);
 
-        let mut mock = MockNow::new();
-        mock.expect_now().times(1).returning(|| {
-            NaiveDate::from_isoywd_opt(2015, 15, Weekday::Wed).expect("Unable to convert date")
-        });
-        assert_eq!(leave.is_off_at_date(mock), true);
+        let mut mock = MockClock::new();
+        mock.expect_now()
+            .times(1)
+            .returning(|| noon(2015, 15, Weekday::Wed));
+        assert_eq!(leave.is_off_at(&mock), true);
 
         leave.insert(Weekday::Thu, Parity::EvenWeek);
-        let mut mock = MockNow::new();
-        mock.expect_now().times(1).returning(|| {
-            NaiveDate::from_isoywd_opt(2015, 16, Weekday::Thu).expect("Unable to convert date")
-        });
-        assert_eq!(leave.is_off_at_date(mock), true);
+        let mut mock = MockClock::new();
+        mock.expect_now()
+            .times(1)
+            .returning(|| noon(2015, 16, Weekday::Thu));
+        assert_eq!(leave.is_off_at(&mock), true);
 
         Ok(())
     }
@@ -164,18 +478,82 @@ mod is_off_should {
     fn return_false_when_day_match_but_not_parity() -> Result<()> {
         let mut leave = OffDays::new();
         leave.insert(Weekday::Fri, Parity::EvenWeek);
-        let mut mock = MockNow::new();
-        mock.expect_now().times(1).returning(|| {
-            NaiveDate::from_isoywd_opt(2015, 15, Weekday::Fri).expect("Unable to convert date")
-        });
-        assert_eq!(leave.is_off_at_date(mock), false);
+        let mut mock = MockClock::new();
+        mock.expect_now()
+            .times(1)
+            .returning(|| noon(2015, 15, Weekday::Fri));
+        assert_eq!(leave.is_off_at(&mock), false);
 
         leave.insert(Weekday::Sun, Parity::OddWeek);
-        let mut mock = MockNow::new();
-        mock.expect_now().times(1).returning(|| {
-            NaiveDate::from_isoywd_opt(2015, 16, Weekday::Sun).expect("Unable to convert date")
-        });
-        assert_eq!(leave.is_off_at_date(mock), false);
+        let mut mock = MockClock::new();
+        mock.expect_now()
+            .times(1)
+            .returning(|| noon(2015, 16, Weekday::Sun));
+        assert_eq!(leave.is_off_at(&mock), false);
+        Ok(())
+    }
+
+    #[test]
+    fn accept_localized_weekdays() -> Result<()> {
+        let leave: OffDays = toml::from_str("lundi = 'EveryWeek'\nMittwoch = 'OddWeek'")?;
+        assert!(matches!(
+            leave.0.get(&Weekday::Mon),
+            Some(Parity::EveryWeek)
+        ));
+        assert!(matches!(leave.0.get(&Weekday::Wed), Some(Parity::OddWeek)));
+        let err = toml::from_str::<OffDays>("lunday = 'EveryWeek'").unwrap_err();
+        assert!(err.to_string().contains("`lunday` is not a week day"));
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_through_toml() -> Result<()> {
+        #[derive(Serialize, Deserialize, Debug)]
+        struct Conf {
+            offdays: OffDays,
+        }
+        let conf: Conf = toml::from_str("offdays = { wed = \"oddweek\", FRI = \"EveryWeek\" }")?;
+        let expected: OffDays = [
+            OffDay(Weekday::Wed, Parity::OddWeek),
+            OffDay(Weekday::Fri, Parity::EveryWeek),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(conf.offdays, expected);
+        let conf: Conf = toml::from_str(&toml::to_string(&conf)?)?;
+        assert_eq!(conf.offdays, expected);
+        let err = toml::from_str::<Conf>("offdays = { wed = \"weekly\" }").unwrap_err();
+        assert!(err.to_string().contains("`weekly` is not a week parity"));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod focus_block_should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn parse_days_and_periods() -> anyhow::Result<()> {
+        let block: FocusBlock = "weekdays 09:00-11:00".parse()?;
+        assert_eq!(block.days, ALL_DAYS[..5]);
+        assert_eq!(block.end(), Some("11:00".parse()?));
+        let block: FocusBlock = "ven-lun,mer 14:00-16:00".parse()?;
+        assert_eq!(
+            block.days,
+            [
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+                Weekday::Mon,
+                Weekday::Wed
+            ]
+        );
+        let block: FocusBlock = "7:30-8:00".parse()?;
+        assert_eq!(block.days, ALL_DAYS);
+        assert!("mon-fri 11:00-9:00".parse::<FocusBlock>().is_err());
+        assert!("someday 9:00-11:00".parse::<FocusBlock>().is_err());
+        assert!("mon 9:00".parse::<FocusBlock>().is_err());
         Ok(())
     }
 }
@@ -0,0 +1,81 @@
+use crate::command::CommandRunner;
+use anyhow::Result;
+use std::fs;
+use std::path::Path;
+use tracing::debug;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+fn read_attribute(supply: &Path, attribute: &str) -> String {
+    fs::read_to_string(supply.join(attribute))
+        .unwrap_or_default()
+        .trim()
+        .to_owned()
+}
+
+/// Return `true` if there is a battery and no online AC adapter among the
+/// power supplies described in `power_supply_dir`.
+fn on_battery_from(power_supply_dir: &Path) -> Result<bool> {
+    let mut has_battery = false;
+    for entry in fs::read_dir(power_supply_dir)? {
+        let supply = entry?.path();
+        match read_attribute(&supply, "type").as_str() {
+            "Mains" | "USB" if read_attribute(&supply, "online") == "1" => {
+                debug!("{:?} is online", supply);
+                return Ok(false);
+            }
+            "Battery" => has_battery = true,
+            _ => (),
+        }
+    }
+    Ok(has_battery)
+}
+
+/// Return `true` if the computer is running on battery
+///
+/// No command is spawned, `_runner` is unused.
+pub fn on_battery(_runner: &dyn CommandRunner) -> Result<bool> {
+    on_battery_from(Path::new(POWER_SUPPLY_DIR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    mod should {
+        use super::*;
+        use mktemp::Temp;
+
+        fn supply(dir: &Path, name: &str, kind: &str, online: &str) -> Result<()> {
+            let supply = dir.join(name);
+            fs::create_dir(&supply)?;
+            fs::write(supply.join("type"), format!("{}\n", kind))?;
+            fs::write(supply.join("online"), format!("{}\n", online))?;
+            Ok(())
+        }
+
+        #[test]
+        fn detect_battery() -> Result<()> {
+            let dir = Temp::new_dir()?;
+            supply(&dir, "AC", "Mains", "0")?;
+            supply(&dir, "BAT0", "Battery", "1")?;
+            assert!(on_battery_from(&dir)?);
+            Ok(())
+        }
+
+        #[test]
+        fn detect_ac() -> Result<()> {
+            let dir = Temp::new_dir()?;
+            supply(&dir, "AC", "Mains", "1")?;
+            supply(&dir, "BAT0", "Battery", "1")?;
+            assert!(!on_battery_from(&dir)?);
+            Ok(())
+        }
+
+        #[test]
+        fn consider_desktop_on_ac() -> Result<()> {
+            let dir = Temp::new_dir()?;
+            assert!(!on_battery_from(&dir)?);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,18 @@
+//! Implement detection of the power source (AC or battery)
+//!
+//! Used to poll less often when running on battery.
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "macos")]
+mod osx;
+#[cfg(any(test, target_os = "macos"))]
+mod osx_parse;
+#[cfg(target_os = "windows")]
+mod windows;
+
+#[cfg(target_os = "linux")]
+pub use linux::on_battery;
+#[cfg(target_os = "macos")]
+pub use osx::on_battery;
+#[cfg(target_os = "windows")]
+pub use windows::on_battery;
@@ -0,0 +1,9 @@
+use super::osx_parse::extract_pmset_on_battery;
+use crate::command::CommandRunner;
+use anyhow::Result;
+
+/// Return `true` if the computer is running on battery
+pub fn on_battery(runner: &dyn CommandRunner) -> Result<bool> {
+    let output = runner.run("pmset", &["-g", "batt"])?;
+    Ok(extract_pmset_on_battery(&output))
+}
@@ -0,0 +1,25 @@
+pub(crate) fn extract_pmset_on_battery(pmset_output: &str) -> bool {
+    pmset_output.contains("'Battery Power'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    mod should {
+        use super::*;
+        #[test]
+        fn detect_battery_power() {
+            let res = r#"Now drawing from 'Battery Power'
+ -InternalBattery-0 (id=4653155)	83%; discharging; 5:12 remaining present: true
+"#;
+            assert!(extract_pmset_on_battery(res));
+        }
+        #[test]
+        fn detect_ac_power() {
+            let res = r#"Now drawing from 'AC Power'
+ -InternalBattery-0 (id=4653155)	100%; charged; 0:00 remaining present: true
+"#;
+            assert!(!extract_pmset_on_battery(res));
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use crate::command::CommandRunner;
+use anyhow::Result;
+
+/// Return `true` if the computer is running on battery.
+///
+/// `BatteryStatus` is 1 when the battery is discharging (see
+/// [Win32_Battery](https://learn.microsoft.com/en-us/windows/win32/cimwin32prov/win32-battery)).
+pub fn on_battery(runner: &dyn CommandRunner) -> Result<bool> {
+    let output = runner.run(
+        "powershell",
+        &[
+            "-NoProfile",
+            "-Command",
+            "(Get-CimInstance -ClassName Win32_Battery).BatteryStatus",
+        ],
+    )?;
+    Ok(output.lines().any(|l| l.trim() == "1"))
+}
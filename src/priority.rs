@@ -0,0 +1,120 @@
+//! Resolution of conflicts between the subsystems writing presence and custom status.
+//!
+//! Each cycle, the active sources are ranked according to the `priority` policy
+//! (by default manual change > microphone > focus time > location > off time) and
+//! only the first one may write: the microphone sets the *do not disturb* presence,
+//! focus time sets both, the location and off time set the custom status, and a
+//! manual change forbids any automatic write.
+//!
+//! Leaving *do not disturb* when the microphone is no more in use is always allowed,
+//! so that the presence is not stuck.
+use tracing::debug;
+
+pub use crate::config::StatusSource;
+
+/// Default order of the sources, by decreasing priority
+pub const DEFAULT_PRIORITY: [StatusSource; 5] = [
+    StatusSource::Manual,
+    StatusSource::Mic,
+    StatusSource::Focus,
+    StatusSource::Location,
+    StatusSource::OffTime,
+];
+
+/// Sources active during one cycle
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Active {
+    /// the custom status has been changed by hand and the cooldown is not over
+    pub manual: bool,
+    /// a watched application uses the microphone, or a meeting application is
+    /// in a call
+    pub mic: bool,
+    /// a focus block is in progress
+    pub focus: bool,
+    /// a configured wifi location has been found
+    pub location: bool,
+    /// we are outside of the work period
+    pub off_time: bool,
+}
+
+impl Active {
+    fn contains(&self, source: StatusSource) -> bool {
+        match source {
+            StatusSource::Manual => self.manual,
+            StatusSource::Mic => self.mic,
+            StatusSource::Focus => self.focus,
+            StatusSource::Location => self.location,
+            StatusSource::OffTime => self.off_time,
+        }
+    }
+}
+
+/// Return `priority` completed with the missing sources in their default order
+pub fn complete(priority: &[StatusSource]) -> Vec<StatusSource> {
+    let mut res: Vec<StatusSource> = Vec::new();
+    for source in priority.iter().chain(DEFAULT_PRIORITY.iter()) {
+        if !res.contains(source) {
+            res.push(*source);
+        }
+    }
+    res
+}
+
+/// Return the first source of `priority` being `active`, the only one allowed to
+/// write during this cycle
+pub fn resolve(priority: &[StatusSource], active: Active) -> Option<StatusSource> {
+    let res = priority.iter().copied().find(|s| active.contains(*s));
+    debug!("Active sources {:?}, {:?} wins", active, res);
+    res
+}
+
+/// Return `true` if the custom status may be written when `winner` won
+pub fn may_set_custom_status(winner: Option<StatusSource>) -> bool {
+    matches!(
+        winner,
+        None | Some(StatusSource::Focus)
+            | Some(StatusSource::Location)
+            | Some(StatusSource::OffTime)
+    )
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn complete_partial_priority() {
+        assert_eq!(
+            complete(&[StatusSource::Location, StatusSource::Location]),
+            [
+                StatusSource::Location,
+                StatusSource::Manual,
+                StatusSource::Mic,
+                StatusSource::Focus,
+                StatusSource::OffTime
+            ]
+        );
+    }
+
+    #[test]
+    fn follow_priority_order() {
+        let active = Active {
+            mic: true,
+            location: true,
+            ..Default::default()
+        };
+        assert_eq!(resolve(&DEFAULT_PRIORITY, active), Some(StatusSource::Mic));
+        let priority = complete(&[StatusSource::Location]);
+        assert_eq!(resolve(&priority, active), Some(StatusSource::Location));
+        let manual = Active {
+            manual: true,
+            ..active
+        };
+        assert_eq!(
+            resolve(&DEFAULT_PRIORITY, manual),
+            Some(StatusSource::Manual)
+        );
+        assert_eq!(resolve(&DEFAULT_PRIORITY, Active::default()), None);
+    }
+}
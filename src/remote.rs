@@ -0,0 +1,167 @@
+//! Detection of the inbound remote sessions
+//!
+//! When working remotely on this computer (e.g. on the desktop left at the
+//! office), the location is the one of the computer, not ours. The remote
+//! sessions are looked for so that `remote_status` is set instead, and so that
+//! the presence is kept online with `remote_keep_online` while no local input
+//! is received.
+//!
+//! SSH sessions of the user are listed by `who` (not on windows). Remote
+//! desktops (VNC, RDP) are detected from the established connections to their
+//! ports, listed by `ss` on linux and `netstat` on macOS and windows.
+use crate::command::CommandRunner;
+use anyhow::{Context, Result};
+use tracing::debug;
+
+/// Port of the RDP servers
+const RDP_PORT: u16 = 3389;
+/// Ports of the VNC servers, for the first displays
+const VNC_PORTS: std::ops::RangeInclusive<u16> = 5900..=5909;
+
+/// Return the descriptions of the active inbound remote sessions (e.g. `ssh
+/// from 10.0.0.2`), those of `who` being restricted to `user`
+#[cfg(not(target_os = "windows"))]
+pub fn active_sessions(user: &str, runner: &dyn CommandRunner) -> Result<Vec<String>> {
+    let who = runner
+        .run("who", &[])
+        .context("Listing sessions with who")?;
+    let mut res = parse_who(&who, user);
+    #[cfg(target_os = "linux")]
+    let connections = runner.run("ss", &["-Htn"]);
+    #[cfg(target_os = "macos")]
+    let connections = runner.run("netstat", &["-an", "-p", "tcp"]);
+    match connections {
+        Ok(output) => res.extend(parse_connections(&output)),
+        Err(e) => debug!("Connections unavailable: {}", e),
+    }
+    debug!("Remote sessions : {:?}", res);
+    Ok(res)
+}
+
+/// Return the descriptions of the active inbound remote desktops (e.g. `RDP
+/// from 10.0.0.2`)
+#[cfg(target_os = "windows")]
+pub fn active_sessions(_user: &str, runner: &dyn CommandRunner) -> Result<Vec<String>> {
+    let output = runner
+        .run("netstat", &["-an", "-p", "tcp"])
+        .context("Listing connections with netstat")?;
+    let res = parse_connections(&output);
+    debug!("Remote sessions : {:?}", res);
+    Ok(res)
+}
+
+/// Parse the output of `who`, keeping the sessions of `user` opened from
+/// another host
+///
+/// The local graphical sessions (`(:0)`) and terminal multiplexers
+/// (`(tmux(1234).%0)`) also give a host between parentheses and are skipped.
+/// ```
+/// use lib::remote::parse_who;
+/// let who = "bob      tty2         2024-03-15 08:55 (:0)\n\
+///            bob      pts/1        2024-03-15 09:12 (10.0.0.2)\n\
+///            alice    pts/2        2024-03-15 09:30 (10.0.0.3)\n";
+/// assert_eq!(parse_who(who, "bob"), ["ssh from 10.0.0.2"]);
+/// ```
+pub fn parse_who(output: &str, user: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.split_whitespace().next() == Some(user))
+        .filter_map(|line| {
+            let host = line.trim_end().strip_suffix(')')?.split_once('(')?.1;
+            if host.is_empty() || host.starts_with(':') || host.starts_with("tmux") {
+                return None;
+            }
+            Some(format!("ssh from {}", host))
+        })
+        .collect()
+}
+
+/// Parse the established TCP connections listed by `ss -Htn` or `netstat -an`,
+/// keeping those to a local RDP or VNC server
+///
+/// The first address of a line is the local one, its port being separated by
+/// `:` (`ss`, windows) or `.` (macOS).
+pub fn parse_connections(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.contains("ESTAB"))
+        .filter_map(|line| {
+            let mut addresses = line
+                .split_whitespace()
+                .filter_map(|token| Some((token, port(token)?)));
+            let (_, local_port) = addresses.next()?;
+            let kind = match local_port {
+                RDP_PORT => "RDP",
+                port if VNC_PORTS.contains(&port) => "VNC",
+                _ => return None,
+            };
+            let peer = addresses.next().map_or("", |(peer, _)| peer);
+            Some(format!("{} from {}", kind, host(peer)))
+        })
+        .collect()
+}
+
+/// Return the port of `address` (`10.0.0.1:22`, `[::1]:22` or `10.0.0.1.22`)
+fn port(address: &str) -> Option<u16> {
+    let (host, port) = address.rsplit_once([':', '.'])?;
+    if host.is_empty() {
+        return None;
+    }
+    port.parse().ok()
+}
+
+/// Return `address` without its port
+fn host(address: &str) -> &str {
+    address
+        .rsplit_once([':', '.'])
+        .map_or(address, |(host, _)| {
+            host.trim_start_matches('[').trim_end_matches(']')
+        })
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn find_remote_desktops_among_connections() {
+        assert_eq!(
+            parse_connections(
+                "ESTAB 0      0      192.168.1.10:5901 192.168.1.20:51234\n\
+                 ESTAB 0      0      192.168.1.10:22   192.168.1.20:51240\n\
+                 LISTEN 0     4096   0.0.0.0:3389      0.0.0.0:*\n"
+            ),
+            ["VNC from 192.168.1.20"]
+        );
+        assert_eq!(
+            parse_connections(
+                "tcp4       0      0  192.168.1.10.5900      192.168.1.20.51234     ESTABLISHED\n"
+            ),
+            ["VNC from 192.168.1.20"]
+        );
+        assert_eq!(
+            parse_connections(
+                "  Proto  Local Address          Foreign Address        State\n  \
+                 TCP    192.168.1.10:3389      192.168.1.20:51234     ESTABLISHED\n  \
+                 TCP    192.168.1.10:49712     52.1.2.3:443           ESTABLISHED\n"
+            ),
+            ["RDP from 192.168.1.20"]
+        );
+    }
+
+    #[test]
+    fn skip_local_sessions() {
+        assert!(parse_who(
+            "bob      :0           2024-03-15 08:55 (:0)\n\
+             bob      pts/3        2024-03-15 09:00 (tmux(4242).%0)\n\
+             bob      pts/4        2024-03-15 09:01\n",
+            "bob"
+        )
+        .is_empty());
+        assert_eq!(
+            parse_who("bob pts/1 2024-03-15 09:12 (laptop.example.com)\n", "bob"),
+            ["ssh from laptop.example.com"]
+        );
+    }
+}
@@ -0,0 +1,264 @@
+//! Record raw scan results and replay them without any mattermost server
+//!
+//! With `--record <file>`, each scan is appended to `file` as a JSON line. Such a
+//! recording can then be given to `--replay <file>`: the status decisions are
+//! computed as in the main loop, but are only logged and published as
+//! [`Event`]s instead of being sent to mattermost.
+//!
+//! With `--ssid-from-stdin`, the same dry-run pipeline is fed with the lines read
+//! on the standard input (see [`Scan::from_ssid_line`]).
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tracing::info;
+
+use crate::config::Config;
+use crate::events::{Event, EventBus};
+use crate::micscan::uses_watched_app;
+use crate::priority;
+use crate::state::{Location, LocationRule};
+
+/// Raw results of one iteration of the main loop
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Scan {
+    /// Time of the scan
+    pub timestamp: DateTime<Local>,
+    /// Whether the user was off at scan time
+    pub off_time: bool,
+    /// Visible SSIDs (empty during off time)
+    pub ssids: Vec<String>,
+    /// Watched applications using the microphone, or the error message of the
+    /// mic scan
+    pub mic_apps: Result<Vec<String>, String>,
+}
+
+impl Scan {
+    /// Build a scan from a line of comma separated visible SSIDs, taken now.
+    ///
+    /// Spaces around SSIDs and empty SSIDs are ignored, so that an empty line
+    /// means that no wifi is visible. No application is using the microphone.
+    /// ```
+    /// use lib::replay::Scan;
+    /// let scan = Scan::from_ssid_line("home, guest,", false);
+    /// assert_eq!(scan.ssids, vec!["home", "guest"]);
+    /// ```
+    pub fn from_ssid_line(line: &str, off_time: bool) -> Self {
+        Self {
+            timestamp: Local::now(),
+            off_time,
+            ssids: line
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect(),
+            mic_apps: Ok(Vec::new()),
+        }
+    }
+}
+
+/// Append [`Scan`]s to a recording file
+#[derive(Debug)]
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    /// Open (or create) the recording at `path`
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Opening recording {:?}", path))?;
+        Ok(Self { file })
+    }
+
+    /// Append `scan` to the recording
+    pub fn record(&mut self, scan: &Scan) -> Result<()> {
+        let line = serde_json::to_string(scan).context("Serializing scan")?;
+        writeln!(self.file, "{}", line).context("Writing recording")?;
+        Ok(())
+    }
+}
+
+/// Read the [`Scan`]s of the recording at `path`
+pub fn load(path: &Path) -> Result<Vec<Scan>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Reading recording {:?}", path))?;
+    content
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .enumerate()
+        .map(|(i, l)| {
+            serde_json::from_str(l)
+                .with_context(|| format!("Parsing line {} of recording {:?}", i + 1, path))
+        })
+        .collect()
+}
+
+/// Replay `scans` and publish on `events` the changes that would have been
+/// sent to mattermost.
+pub fn replay(
+    config: &Config,
+    rules: &[LocationRule],
+    scans: impl IntoIterator<Item = Scan>,
+    events: &EventBus,
+) {
+    let mut location = Location::Unknown;
+    let mut dnd = false;
+    for scan in scans {
+        let new_location = crate::find_location(
+            rules,
+            &scan.ssids,
+            scan.off_time,
+            config.match_case_insensitive,
+        );
+        let mic = scan
+            .mic_apps
+            .as_ref()
+            .is_ok_and(|names| uses_watched_app(&config.mic_app_names, names));
+        let winner = priority::resolve(
+            &config.priority,
+            priority::Active {
+                mic,
+                location: matches!(&new_location, Location::Known(wifi) if !wifi.is_empty()),
+                off_time: scan.off_time,
+                ..Default::default()
+            },
+        );
+        if priority::may_set_custom_status(winner)
+            && new_location != Location::Unknown
+            && new_location != location
+        {
+            let mut status = rules
+                .iter()
+                .find(|rule| rule.location == new_location)
+                .expect("Internal error: the location is found among the rules")
+                .status(scan.timestamp)
+                .clone();
+            if !scan.off_time {
+                status.expires_at(config.expires_at, &scan.timestamp);
+            }
+            info!("{} [dry-run] sending {:?}", scan.timestamp, status);
+            events.publish(Event::StatusSent(status));
+            events.publish(Event::LocationChanged {
+                from: location,
+                to: new_location.clone(),
+            });
+            location = new_location;
+        }
+        match &scan.mic_apps {
+            Ok(names) => {
+                let used = uses_watched_app(&config.mic_app_names, names);
+                // Leaving do not disturb is always allowed
+                if used != dnd && (winner == Some(priority::StatusSource::Mic) || !used) {
+                    info!("{} [dry-run] do not disturb: {}", scan.timestamp, used);
+                    events.publish(if used {
+                        Event::DndEntered
+                    } else {
+                        Event::DndLeft
+                    });
+                    dnd = used;
+                }
+            }
+            Err(e) => info!("{} mic scan failed: {}", scan.timestamp, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::config::{Args, SecretType, StatusSource};
+    use crate::mattermost::MMCustomStatus;
+    use crate::timeofday::Expiry;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    fn scan(ssids: &[&str], mic_apps: &[&str]) -> Scan {
+        Scan {
+            timestamp: Local::now(),
+            off_time: false,
+            ssids: ssids.iter().map(|s| s.to_string()).collect(),
+            mic_apps: Ok(mic_apps.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn load_recorded_scans() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let scans = vec![scan(&["home"], &[]), scan(&["office", "guest"], &["zoom"])];
+        let mut recorder = Recorder::new(&temp)?;
+        for s in &scans {
+            recorder.record(s)?;
+        }
+        assert_eq!(load(&temp)?, scans);
+        Ok(())
+    }
+
+    #[test]
+    fn replay_decisions() -> Result<()> {
+        let config = Args {
+            status: vec!["home::house::at home".to_string()],
+            mic_app_names: vec!["zoom".to_string()],
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            expires_at: Some(Expiry::Never),
+            ..Default::default()
+        }
+        .validate()?;
+        let rules = crate::prepare_status(&config)?;
+        let mut bus = EventBus::new();
+        let rx = bus.channel();
+        let scans = vec![
+            scan(&["home"], &[]),
+            scan(&["home"], &["zoom"]),
+            scan(&["elsewhere"], &[]),
+        ];
+        replay(&config, &rules, scans, &bus);
+        let home = MMCustomStatus::new("at home".to_string(), "house".to_string());
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![
+                Event::StatusSent(home),
+                Event::LocationChanged {
+                    from: Location::Unknown,
+                    to: Location::Known("home".to_string())
+                },
+                Event::DndEntered,
+                Event::DndLeft,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn follow_priority() -> Result<()> {
+        let config = Args {
+            status: vec!["home::house::at home".to_string()],
+            mic_app_names: vec!["zoom".to_string()],
+            mm_secret: Some("AAA".to_string()),
+            secret_type: Some(SecretType::Token),
+            priority: vec![StatusSource::Location],
+            ..Default::default()
+        }
+        .validate()?;
+        let rules = crate::prepare_status(&config)?;
+        let mut bus = EventBus::new();
+        let rx = bus.channel();
+        let scans = vec![scan(&["home"], &["zoom"]), scan(&["elsewhere"], &["zoom"])];
+        replay(&config, &rules, scans, &bus);
+        // Do not disturb is only entered once the location is unknown
+        assert_eq!(
+            rx.try_iter()
+                .filter(|e| matches!(e, Event::DndEntered))
+                .count(),
+            1
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+//! Secret files encrypted with age or GPG
+//!
+//! `mm_secret_file` may be encrypted, so that it can be versioned with the
+//! dotfiles of the user. The encryption is recognized from the content of the
+//! file, which is decrypted by the `age` (with the identity file
+//! `mm_secret_identity`) or `gpg` commands.
+use crate::error::{Error, Result};
+use anyhow::anyhow;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use tracing::debug;
+
+/// Encryption of a secret file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// plain text
+    None,
+    /// [age](https://age-encryption.org) encrypted, binary or armored
+    Age,
+    /// OpenPGP encrypted, binary or armored
+    Gpg,
+}
+
+/// Return the encryption of a file starting with `content`
+/// ```
+/// use lib::secret::encrypted::{encryption, Encryption};
+/// assert_eq!(encryption(b"age-encryption.org/v1\n-> X25519 ..."), Encryption::Age);
+/// assert_eq!(encryption(b"-----BEGIN PGP MESSAGE-----\n"), Encryption::Gpg);
+/// assert_eq!(encryption(b"s3cret\n"), Encryption::None);
+/// ```
+pub fn encryption(content: &[u8]) -> Encryption {
+    if content.starts_with(b"age-encryption.org/")
+        || content.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----")
+    {
+        Encryption::Age
+    } else if content.starts_with(b"-----BEGIN PGP MESSAGE-----")
+        // Binary OpenPGP packet tags have their high bit set, unlike text
+        || (content.first().is_some_and(|byte| byte & 0x80 != 0)
+            && std::str::from_utf8(content).is_err())
+    {
+        Encryption::Gpg
+    } else {
+        Encryption::None
+    }
+}
+
+/// Return the decrypted content of the file at `path`, encrypted with
+/// `encryption`, `identity` being the age identity file
+pub fn decrypt(encryption: Encryption, path: &Path, identity: Option<&Path>) -> Result<String> {
+    let (program, mut command) = match encryption {
+        Encryption::None => {
+            return Err(Error::Config(anyhow!("{:?} is not encrypted", path)));
+        }
+        Encryption::Age => {
+            let identity = identity.ok_or_else(|| {
+                Error::Config(anyhow!(
+                    "`mm_secret_identity` is required to decrypt {:?} with age",
+                    path
+                ))
+            })?;
+            let mut command = Command::new("age");
+            command.arg("--decrypt").arg("--identity").arg(identity);
+            ("age", command)
+        }
+        Encryption::Gpg => {
+            let mut command = Command::new("gpg");
+            command.args(["--decrypt", "--quiet", "--batch"]);
+            ("gpg", command)
+        }
+    };
+    debug!("Decrypting {:?} with {}", path, program);
+    let output = command.arg(path).output().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::Config(anyhow!(
+            "`{}` is required to decrypt {:?} but is not installed",
+            program,
+            path
+        )),
+        _ => Error::Platform(anyhow::Error::new(e).context(format!("Running {}", program))),
+    })?;
+    if !output.status.success() {
+        return Err(Error::Auth(anyhow!(
+            "Fail to decrypt {:?} with {}: {}",
+            path,
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn recognize_binary_and_armored_files() {
+        assert_eq!(
+            encryption(b"-----BEGIN AGE ENCRYPTED FILE-----\nYWdl"),
+            Encryption::Age
+        );
+        assert_eq!(encryption(&[0x85, 0x02, 0x0c, 0xff, 0xfe]), Encryption::Gpg);
+        assert_eq!(encryption("pässword".as_bytes()), Encryption::None);
+        assert_eq!(encryption(b""), Encryption::None);
+    }
+
+    #[test]
+    fn require_an_identity_for_age() {
+        let res = decrypt(Encryption::Age, Path::new("secret.age"), None);
+        let Err(Error::Config(e)) = res else {
+            panic!("Unexpected {:?}", res)
+        };
+        assert!(e.to_string().contains("mm_secret_identity"));
+    }
+}
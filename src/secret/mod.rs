@@ -0,0 +1,467 @@
+//! Retrieval of the mattermost secret
+//!
+//! The secret is looked up by a chain of [`SecretProvider`]s, in the order of the
+//! `secret_providers` parameter (see [`SecretSource`]). The first provider
+//! returning a secret wins. A provider which is not configured is skipped, and
+//! one which fails is logged before trying the next one. The literal
+//! `mm_secret` is only used when no provider returns a secret.
+use crate::config::{Args, SecretType};
+use crate::error::{Error, Result};
+use anyhow::{anyhow, Context};
+use clap::ValueEnum;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::Command;
+use tracing::{debug, info, warn};
+use vault::{Vault, VaultItem, VaultSecret};
+
+pub mod encrypted;
+pub mod pass;
+pub mod vault;
+
+#[cfg(test)]
+use mockall::automock;
+
+/// Providers of the secret, listed by the `secret_providers` parameter
+#[derive(ValueEnum, Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretSource {
+    /// OS keyring entry of `mm_user` in `keyring_service`
+    Keyring,
+    /// entry `pass_entry` of the `pass` password store
+    Pass,
+    /// 1Password secret reference `op_item`
+    OnePassword,
+    /// password of the Bitwarden item `bw_item`
+    Bitwarden,
+    /// standard output of `mm_secret_cmd`
+    Command,
+    /// content of `mm_secret_file`
+    File,
+    /// environment variable named by `mm_secret_env`
+    Env,
+    /// hidden input on the terminal
+    Prompt,
+}
+
+/// Keyring service of the secret typed on the terminal, if `keyring_service` is
+/// not set
+pub const DEFAULT_KEYRING_SERVICE: &str = "automattermostatus";
+
+/// Order used when `secret_providers` is empty
+pub const DEFAULT_ORDER: [SecretSource; 7] = [
+    SecretSource::Keyring,
+    SecretSource::Pass,
+    SecretSource::OnePassword,
+    SecretSource::Bitwarden,
+    SecretSource::Command,
+    SecretSource::File,
+    SecretSource::Env,
+];
+
+/// Source of the mattermost secret
+#[cfg_attr(test, automock)] // create MockSecretProvider struct for tests
+pub trait SecretProvider {
+    /// Name of the provider in the logs
+    fn name(&self) -> String;
+    /// Return the secret, or `None` if the provider is not configured
+    fn secret(&self) -> Result<Option<String>>;
+    /// Secret read from a vault by [`SecretProvider::secret`], to be read again
+    /// when it is rotated, if any
+    fn vault_secret(&self) -> Option<VaultSecret> {
+        None
+    }
+}
+
+/// Secret stored in the OS keyring
+#[derive(Debug)]
+pub struct KeyringProvider {
+    /// keyring service
+    pub service: Option<String>,
+    /// keyring user
+    pub user: Option<String>,
+    /// libsecret collection on linux, credential target name on windows
+    pub target: Option<String>,
+}
+
+impl SecretProvider for KeyringProvider {
+    fn name(&self) -> String {
+        "OS keyring".to_string()
+    }
+
+    fn secret(&self) -> Result<Option<String>> {
+        let (Some(service), Some(user)) = (&self.service, &self.user) else {
+            if self.user.is_some() {
+                warn!("User is defined for keyring lookup but service is not");
+                info!("Skipping keyring lookup");
+            }
+            return Ok(None);
+        };
+        let context = || format!("Querying OS keyring (user: {}, service: {})", user, service);
+        let entry = match &self.target {
+            Some(target) => keyring::Entry::new_with_target(target, service, user),
+            None => keyring::Entry::new(service, user),
+        }
+        .with_context(context)
+        .map_err(Error::Platform)?;
+        entry
+            .get_password()
+            .map(Some)
+            .with_context(context)
+            .map_err(Error::Auth)
+    }
+}
+
+/// Secret printed by a command
+#[derive(Debug)]
+pub struct CommandProvider {
+    /// command line, split into shell words
+    pub command: Option<String>,
+}
+
+impl SecretProvider for CommandProvider {
+    fn name(&self) -> String {
+        "mm_secret_cmd".to_string()
+    }
+
+    fn secret(&self) -> Result<Option<String>> {
+        let Some(command) = &self.command else {
+            return Ok(None);
+        };
+        let params = shell_words::split(command)
+            .context("Splitting mm_secret_cmd into shell words")
+            .map_err(Error::Config)?;
+        if params.is_empty() {
+            return Err(Error::Config(anyhow!("mm_secret_cmd is empty")));
+        }
+        debug!("Running command {}", command);
+        let output = Command::new(&params[0])
+            .args(&params[1..])
+            .output()
+            .context(format!("Error when running {}", &command))
+            .map_err(Error::Platform)?;
+        let secret = String::from_utf8_lossy(&output.stdout);
+        if secret.len() == 0 {
+            return Err(Error::Auth(anyhow!(
+                "command '{}' returns nothing",
+                &command
+            )));
+        }
+        // /!\ Do not spit secret on stdout on released binary.
+        Ok(Some(secret.to_string()))
+    }
+}
+
+/// Secret stored in a file, possibly encrypted (see [`encrypted`]), without its
+/// trailing new line
+#[derive(Debug)]
+pub struct FileProvider {
+    /// path of the file
+    pub path: Option<PathBuf>,
+    /// age identity file decrypting the file
+    pub identity: Option<PathBuf>,
+}
+
+impl SecretProvider for FileProvider {
+    fn name(&self) -> String {
+        "mm_secret_file".to_string()
+    }
+
+    fn secret(&self) -> Result<Option<String>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        let content = fs::read(path)
+            .with_context(|| format!("Reading {:?}", path))
+            .map_err(Error::Auth)?;
+        let content = match encrypted::encryption(&content) {
+            encrypted::Encryption::None => String::from_utf8(content)
+                .with_context(|| format!("Reading {:?}", path))
+                .map_err(Error::Auth)?,
+            encryption => encrypted::decrypt(encryption, path, self.identity.as_deref())?,
+        };
+        let secret = content.trim_end_matches(['\n', '\r']);
+        if secret.is_empty() {
+            return Err(Error::Auth(anyhow!("{:?} is empty", path)));
+        }
+        Ok(Some(secret.to_string()))
+    }
+}
+
+/// Secret stored in an environment variable
+#[derive(Debug)]
+pub struct EnvProvider {
+    /// name of the variable
+    pub var: Option<String>,
+}
+
+impl SecretProvider for EnvProvider {
+    fn name(&self) -> String {
+        "mm_secret_env".to_string()
+    }
+
+    fn secret(&self) -> Result<Option<String>> {
+        let Some(var) = &self.var else {
+            return Ok(None);
+        };
+        match std::env::var(var) {
+            Ok(secret) if !secret.is_empty() => Ok(Some(secret)),
+            Ok(_) | Err(std::env::VarError::NotPresent) => {
+                debug!("Environment variable {} is not set", var);
+                Ok(None)
+            }
+            Err(e) => Err(Error::Auth(
+                anyhow::Error::new(e).context(format!("Reading environment variable {}", var)),
+            )),
+        }
+    }
+}
+
+/// Secret typed on the terminal, when there is one
+#[derive(Debug)]
+pub struct PromptProvider {
+    /// what is asked for (e.g. `mattermost password of bob`)
+    pub label: String,
+}
+
+impl SecretProvider for PromptProvider {
+    fn name(&self) -> String {
+        "prompt".to_string()
+    }
+
+    fn secret(&self) -> Result<Option<String>> {
+        if !io::stdin().is_terminal() {
+            debug!("Not prompting for the secret without a terminal");
+            return Ok(None);
+        }
+        rpassword::prompt_password(format!("{}: ", self.label))
+            .map(|secret| Some(secret).filter(|s| !s.is_empty()))
+            .context("Reading secret")
+            .map_err(Error::Platform)
+    }
+}
+
+/// Return what is asked for on the terminal (e.g. `mattermost password of bob`)
+fn prompt_label(args: &Args) -> String {
+    let secret = match args.secret_type {
+        Some(SecretType::Token) => "token",
+        Some(SecretType::Password) => "password",
+        None => "secret",
+    };
+    match &args.mm_user {
+        Some(user) => format!("mattermost {} of {}", secret, user),
+        None => format!("mattermost {}", secret),
+    }
+}
+
+/// Return the providers listed by `args.secret_providers`, or the ones of
+/// [`DEFAULT_ORDER`] if none is.
+pub fn chain(args: &Args) -> Vec<Box<dyn SecretProvider>> {
+    let sources: &[SecretSource] = match args.secret_providers.is_empty() {
+        true => &DEFAULT_ORDER,
+        false => &args.secret_providers,
+    };
+    sources
+        .iter()
+        .map(|source| -> Box<dyn SecretProvider> {
+            match source {
+                SecretSource::Keyring => Box::new(KeyringProvider {
+                    service: args.keyring_service.clone(),
+                    user: args.mm_user.clone(),
+                    target: args.keyring_target.clone(),
+                }),
+                SecretSource::Pass => Box::new(pass::PassProvider {
+                    entry: args.pass_entry.clone(),
+                    program: args
+                        .pass_program
+                        .clone()
+                        .unwrap_or_else(|| pass::DEFAULT_PROGRAM.to_string()),
+                }),
+                SecretSource::OnePassword => Box::new(vault::VaultProvider::new(
+                    args.op_item.clone().map(|item| VaultItem {
+                        vault: Vault::OnePassword,
+                        item,
+                    }),
+                )),
+                SecretSource::Bitwarden => Box::new(vault::VaultProvider::new(
+                    args.bw_item.clone().map(|item| VaultItem {
+                        vault: Vault::Bitwarden,
+                        item,
+                    }),
+                )),
+                SecretSource::Command => Box::new(CommandProvider {
+                    command: args.mm_secret_cmd.clone(),
+                }),
+                SecretSource::File => Box::new(FileProvider {
+                    path: args.mm_secret_file.clone(),
+                    identity: args.mm_secret_identity.clone(),
+                }),
+                SecretSource::Env => Box::new(EnvProvider {
+                    var: args.mm_secret_env.clone(),
+                }),
+                SecretSource::Prompt => Box::new(PromptProvider {
+                    label: prompt_label(args),
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Return the secret of the first provider of `providers` returning one, with
+/// the vault secret it comes from.
+///
+/// Failures are logged, and the last one is returned if no provider returns a
+/// secret.
+pub fn resolve(
+    providers: &[Box<dyn SecretProvider>],
+) -> Result<Option<(String, Option<VaultSecret>)>> {
+    let mut failure = None;
+    for provider in providers {
+        match provider.secret() {
+            Ok(Some(secret)) => {
+                debug!("Secret found with {}", provider.name());
+                return Ok(Some((secret, provider.vault_secret())));
+            }
+            Ok(None) => debug!("No secret from {}", provider.name()),
+            Err(e) => {
+                warn!("Fail to get the secret with {} : {:#}", provider.name(), e);
+                failure = Some(e);
+            }
+        }
+    }
+    failure.map_or(Ok(None), Err)
+}
+
+/// Ask on the terminal for the secret no provider returned, and offer to store
+/// it in the OS keyring for the next runs. Return `None` without terminal.
+pub fn prompt_fallback(args: &Args) -> Result<Option<String>> {
+    let prompt = PromptProvider {
+        label: prompt_label(args),
+    };
+    let Some(secret) = prompt.secret()? else {
+        return Ok(None);
+    };
+    let Some(user) = &args.mm_user else {
+        return Ok(Some(secret));
+    };
+    let service = args
+        .keyring_service
+        .as_deref()
+        .unwrap_or(DEFAULT_KEYRING_SERVICE);
+    if !ask_to_store(service, user, &mut io::stdin().lock()).unwrap_or(false) {
+        return Ok(Some(secret));
+    }
+    let entry = match &args.keyring_target {
+        Some(target) => keyring::Entry::new_with_target(target, service, user),
+        None => keyring::Entry::new(service, user),
+    };
+    match entry.and_then(|entry| entry.set_password(&secret)) {
+        Ok(()) if args.keyring_service.is_none() => info!(
+            "Secret stored, set `keyring_service = '{}'` in the configuration file to use it",
+            service
+        ),
+        Ok(()) => info!("Secret stored in the OS keyring"),
+        Err(e) => warn!("Fail to store the secret in the OS keyring : {}", e),
+    }
+    Ok(Some(secret))
+}
+
+/// Ask whether the secret of `user` shall be stored in the keyring `service`,
+/// and return `true` if the answer read on `input` is yes.
+/// ```
+/// use lib::secret::ask_to_store;
+/// assert!(ask_to_store("automattermostatus", "bob", &mut "y\n".as_bytes()).unwrap());
+/// assert!(!ask_to_store("automattermostatus", "bob", &mut "\n".as_bytes()).unwrap());
+/// ```
+pub fn ask_to_store(service: &str, user: &str, input: &mut dyn BufRead) -> io::Result<bool> {
+    eprint!(
+        "Store it in the OS keyring (service `{}`, user `{}`)? [y/N] ",
+        service, user
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    fn provider(secret: fn() -> Result<Option<String>>) -> Box<dyn SecretProvider> {
+        let mut provider = MockSecretProvider::new();
+        provider.expect_name().return_const("mock".to_string());
+        provider.expect_secret().returning(secret);
+        provider.expect_vault_secret().return_const(None);
+        Box::new(provider)
+    }
+
+    #[test]
+    fn use_the_first_provider_returning_a_secret() -> Result<()> {
+        let providers = vec![
+            provider(|| Ok(None)),
+            provider(|| Err(Error::Auth(anyhow!("locked")))),
+            provider(|| Ok(Some("first".to_string()))),
+            provider(|| Ok(Some("second".to_string()))),
+        ];
+        assert!(matches!(
+            resolve(&providers)?,
+            Some((secret, None)) if secret == "first"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn return_the_last_failure_without_secret() {
+        let providers = vec![
+            provider(|| Err(Error::Auth(anyhow!("locked")))),
+            provider(|| Ok(None)),
+        ];
+        assert!(matches!(resolve(&providers), Err(Error::Auth(_))));
+        assert!(matches!(resolve(&[provider(|| Ok(None))]), Ok(None)));
+    }
+
+    #[test]
+    fn read_the_secret_file_without_new_line() -> Result<()> {
+        let dir = Temp::new_dir().unwrap();
+        let path = dir.join("secret");
+        fs::write(&path, "s3cret\n").unwrap();
+        let file = FileProvider {
+            path: Some(path),
+            identity: None,
+        };
+        assert_eq!(file.secret()?, Some("s3cret".to_string()));
+        let missing = FileProvider {
+            path: Some(dir.join("missing")),
+            identity: None,
+        };
+        assert!(missing.secret().is_err());
+        let unset = FileProvider {
+            path: None,
+            identity: None,
+        };
+        assert_eq!(unset.secret()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn follow_the_configured_order() {
+        let args = Args {
+            secret_providers: vec![SecretSource::Env, SecretSource::Keyring],
+            ..Default::default()
+        };
+        let names: Vec<String> = chain(&args).iter().map(|p| p.name()).collect();
+        assert_eq!(names, ["mm_secret_env", "OS keyring"]);
+        assert_eq!(chain(&Args::default()).len(), DEFAULT_ORDER.len());
+        let args = Args {
+            mm_user: Some("bob".to_string()),
+            secret_type: Some(SecretType::Token),
+            ..Default::default()
+        };
+        assert_eq!(prompt_label(&args), "mattermost token of bob");
+    }
+}
@@ -0,0 +1,155 @@
+//! Secret stored by the standard unix password manager `pass` (or `gopass`)
+//!
+//! `pass show <pass_entry>` decrypts the entry with GPG, whose agent may ask for a
+//! passphrase. Its failures are turned into messages telling what to fix, and
+//! the secret is cached for the life of the process so that the agent is
+//! solicited only once.
+use super::SecretProvider;
+use crate::error::{Error, Result};
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::io;
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+use tracing::debug;
+
+/// Password manager used when `pass_program` is not set
+pub const DEFAULT_PROGRAM: &str = "pass";
+
+/// Secrets already decrypted, by program and entry
+static CACHE: OnceLock<Mutex<HashMap<(String, String), String>>> = OnceLock::new();
+
+/// Secret of an entry of the password store
+#[derive(Debug)]
+pub struct PassProvider {
+    /// entry of the store (e.g. `work/mattermost-token`)
+    pub entry: Option<String>,
+    /// `pass` or a compatible program like `gopass`
+    pub program: String,
+}
+
+impl SecretProvider for PassProvider {
+    fn name(&self) -> String {
+        self.program.clone()
+    }
+
+    fn secret(&self) -> Result<Option<String>> {
+        let Some(entry) = &self.entry else {
+            return Ok(None);
+        };
+        let key = (self.program.clone(), entry.clone());
+        let cache = CACHE.get_or_init(Default::default);
+        if let Some(secret) = cache.lock().unwrap().get(&key) {
+            debug!("Reusing the secret of {} {}", self.program, entry);
+            return Ok(Some(secret.clone()));
+        }
+        debug!("Running {} show {}", self.program, entry);
+        let output = Command::new(&self.program)
+            .args(["show", entry])
+            .output()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => {
+                    Error::Config(anyhow!("`{}` is not installed", self.program))
+                }
+                _ => Error::Platform(
+                    anyhow::Error::new(e)
+                        .context(format!("Running {} show {}", self.program, entry)),
+                ),
+            })?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let mut message = format!(
+                "`{} show {}` failed: {}",
+                self.program,
+                entry,
+                stderr.trim()
+            );
+            if let Some(hint) = hint(&stderr) {
+                message.push_str(", ");
+                message.push_str(hint);
+            }
+            return Err(Error::Auth(anyhow!(message)));
+        }
+        // The secret is the first line, the next ones being metadata
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let secret = match stdout.lines().next() {
+            Some(line) if !line.is_empty() => line.to_string(),
+            _ => {
+                return Err(Error::Auth(anyhow!(
+                    "`{} show {}` returns nothing",
+                    self.program,
+                    entry
+                )))
+            }
+        };
+        cache.lock().unwrap().insert(key, secret.clone());
+        Ok(Some(secret))
+    }
+}
+
+/// Return what the user may do about the error output `stderr` of `pass show`,
+/// if known
+/// ```
+/// use lib::secret::pass::hint;
+/// assert!(hint("Error: work/mm is not in the password store.").unwrap().contains("pass_entry"));
+/// assert!(hint("gpg: decryption failed: No secret key").unwrap().contains("GPG key"));
+/// assert_eq!(hint("Segmentation fault"), None);
+/// ```
+pub fn hint(stderr: &str) -> Option<&'static str> {
+    if stderr.contains("is not in the password store") {
+        Some("check `pass_entry`")
+    } else if stderr.contains("No secret key") {
+        Some("the GPG key of the password store is not available")
+    } else if stderr.contains("Inappropriate ioctl for device")
+        || stderr.contains("No pinentry")
+        || stderr.contains("Timeout")
+    {
+        Some(
+            "the GPG agent cannot ask for the passphrase, \
+             unlock the key beforehand (e.g. with `pass show` in a terminal)",
+        )
+    } else if stderr.contains("Operation cancelled") {
+        Some("the passphrase was not entered")
+    } else if stderr.contains("No agent running") || stderr.contains("can't connect to the agent") {
+        Some("the GPG agent is not running")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn reuse_the_cached_secret() -> Result<()> {
+        let provider = PassProvider {
+            entry: Some("work/mattermost".to_string()),
+            program: "not-a-password-manager".to_string(),
+        };
+        assert!(matches!(provider.secret(), Err(Error::Config(_))));
+        CACHE.get_or_init(Default::default).lock().unwrap().insert(
+            (
+                "not-a-password-manager".to_string(),
+                "work/mattermost".to_string(),
+            ),
+            "s3cret".to_string(),
+        );
+        assert_eq!(provider.secret()?, Some("s3cret".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn explain_locked_keys() {
+        assert!(
+            hint("gpg: public key decryption failed: Inappropriate ioctl for device")
+                .unwrap()
+                .contains("unlock the key")
+        );
+        assert_eq!(
+            hint("gpg: decryption failed: Operation cancelled"),
+            Some("the passphrase was not entered")
+        );
+    }
+}
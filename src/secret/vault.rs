@@ -0,0 +1,516 @@
+//! Secrets stored in the vault of 1Password (`op`) or Bitwarden (`bw`)
+//!
+//! These vaults are locked until the user signs in. Run from a terminal, the
+//! user is asked once to unlock the vault, and the session obtained is reused
+//! for the next reads. Run as a service, a locked vault is reported with what to
+//! do about it.
+//!
+//! As the secret may be rotated in the vault while running, it is fetched again
+//! when mattermost rejects it, by the session logged in with it (see
+//! [`crate::mattermost::LoggedSession::relogin`]), and the new one is used for
+//! the next logins.
+//!
+//! The sessions are given to the command line interfaces by their environment
+//! variable, as their command line may be read by any user.
+use super::SecretProvider;
+use crate::command::{CommandRunner, SystemRunner};
+use crate::error::{Error, Result};
+use anyhow::anyhow;
+use derivative::Derivative;
+use std::cell::RefCell;
+use std::io::{self, IsTerminal};
+use std::process::Output;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+/// Password manager with a vault to unlock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Vault {
+    /// 1Password, read with `op read <secret reference>`
+    OnePassword,
+    /// Bitwarden, read with `bw get password <item>`
+    Bitwarden,
+}
+
+impl Vault {
+    /// Name of the password manager
+    pub fn name(self) -> &'static str {
+        match self {
+            Vault::OnePassword => "1Password",
+            Vault::Bitwarden => "Bitwarden",
+        }
+    }
+
+    /// Command line interface of the password manager
+    pub fn program(self) -> &'static str {
+        match self {
+            Vault::OnePassword => "op",
+            Vault::Bitwarden => "bw",
+        }
+    }
+
+    /// Arguments reading the secret of `item`
+    pub fn read_args(self, item: &str) -> Vec<&str> {
+        match self {
+            Vault::OnePassword => vec!["read", item],
+            Vault::Bitwarden => vec!["get", "password", item],
+        }
+    }
+
+    /// Arguments unlocking the vault and printing the session
+    fn unlock_args(self) -> &'static [&'static str] {
+        match self {
+            Vault::OnePassword => &["signin"],
+            Vault::Bitwarden => &["unlock", "--raw"],
+        }
+    }
+
+    /// Return the name and the value of the environment variable giving the
+    /// session printed by `vault.unlock_args()` to the next commands
+    /// ```
+    /// use lib::secret::vault::Vault;
+    /// assert_eq!(
+    ///     Vault::Bitwarden.session("c2Vzc2lvbg==\n"),
+    ///     Some(("BW_SESSION".to_string(), "c2Vzc2lvbg==".to_string()))
+    /// );
+    /// assert_eq!(
+    ///     Vault::OnePassword.session("export OP_SESSION_my=\"c2Vzc2lvbg\"\n# eval me\n"),
+    ///     Some(("OP_SESSION_my".to_string(), "c2Vzc2lvbg".to_string()))
+    /// );
+    /// assert_eq!(Vault::OnePassword.session(""), None);
+    /// ```
+    pub fn session(self, stdout: &str) -> Option<(String, String)> {
+        match self {
+            // `export OP_SESSION_<account>="…"`, or `$env:OP_SESSION_<account>="…"`
+            // in PowerShell
+            Vault::OnePassword => stdout.lines().find_map(|line| {
+                let start = line.find("OP_SESSION_")?;
+                let (name, value) = line[start..].split_once('=')?;
+                Some((name.to_string(), value.trim().trim_matches('"').to_string()))
+            }),
+            Vault::Bitwarden => Some(stdout.trim())
+                .filter(|session| !session.is_empty())
+                .map(|session| ("BW_SESSION".to_string(), session.to_string())),
+        }
+    }
+
+    /// Return `true` if the error output `stderr` tells that the vault is
+    /// locked or that the user is not signed in
+    /// ```
+    /// use lib::secret::vault::Vault;
+    /// assert!(Vault::Bitwarden.is_locked("Vault is locked."));
+    /// assert!(Vault::OnePassword.is_locked(
+    ///     "[ERROR] 2024/03/15 10:00:00 You are not currently signed in."
+    /// ));
+    /// assert!(!Vault::Bitwarden.is_locked("Not found."));
+    /// ```
+    pub fn is_locked(self, stderr: &str) -> bool {
+        match self {
+            Vault::OnePassword => {
+                stderr.contains("not currently signed in")
+                    || stderr.contains("account is not signed in")
+                    || stderr.contains("session expired")
+            }
+            Vault::Bitwarden => {
+                stderr.contains("Vault is locked") || stderr.contains("You are not logged in")
+            }
+        }
+    }
+
+    /// What to do about a locked vault when running without a terminal
+    fn unlock_hint(self) -> &'static str {
+        match self {
+            Vault::OnePassword => {
+                "set `OP_SERVICE_ACCOUNT_TOKEN` in the environment of the service, \
+                 or enable the integration with the 1Password desktop application"
+            }
+            Vault::Bitwarden => {
+                "run `bw unlock` and set the `BW_SESSION` it prints in the environment \
+                 of the service"
+            }
+        }
+    }
+}
+
+/// Item of a vault holding the secret
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VaultItem {
+    /// vault of the item
+    pub vault: Vault,
+    /// secret reference (`op://…`) for 1Password, name or id for Bitwarden
+    pub item: String,
+}
+
+/// Secret of a [`VaultItem`], read with the session unlocking its vault
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct VaultSecret {
+    /// item holding the secret
+    pub item: VaultItem,
+    /// session obtained by unlocking the vault, as the name and the value of
+    /// its environment variable
+    #[derivative(Debug = "ignore")]
+    session: Option<(String, String)>,
+    /// runner of the command line interface of the vault
+    #[derivative(Debug = "ignore")]
+    runner: Arc<dyn CommandRunner + Send + Sync>,
+}
+
+impl VaultSecret {
+    /// Read the secret of `item` with the command line interface run by
+    /// `runner`
+    pub fn new(item: VaultItem, runner: Arc<dyn CommandRunner + Send + Sync>) -> Self {
+        VaultSecret {
+            item,
+            session: None,
+            runner,
+        }
+    }
+
+    /// Read the secret, asking to unlock the vault if it is locked and
+    /// `interactive` is set. The session unlocking the vault is kept for the
+    /// next reads.
+    pub fn fetch(&mut self, interactive: bool) -> Result<String> {
+        let vault = self.item.vault;
+        let output = self
+            .item
+            .read(self.runner.as_ref(), self.session.as_ref())?;
+        if output.status.success() {
+            return first_line(&output, vault);
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !vault.is_locked(&stderr) {
+            return Err(Error::Auth(anyhow!(
+                "`{}` failed: {}",
+                vault.program(),
+                stderr.trim()
+            )));
+        }
+        if !interactive {
+            return Err(Error::Auth(anyhow!(
+                "The {} vault is locked: {}",
+                vault.name(),
+                vault.unlock_hint()
+            )));
+        }
+        info!("Unlocking the {} vault", vault.name());
+        let unlock = self
+            .runner
+            .output(vault.program(), vault.unlock_args(), &[], true)
+            .map_err(|e| spawn_error(e, vault))?;
+        let session = match vault.session(&String::from_utf8_lossy(&unlock.stdout)) {
+            Some(session) if unlock.status.success() => session,
+            _ => {
+                return Err(Error::Auth(anyhow!(
+                    "Fail to unlock the {} vault",
+                    vault.name()
+                )))
+            }
+        };
+        let output = self.item.read(self.runner.as_ref(), Some(&session))?;
+        self.session = Some(session);
+        match output.status.success() {
+            true => first_line(&output, vault),
+            false => Err(Error::Auth(anyhow!(
+                "`{}` failed: {}",
+                vault.program(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+        }
+    }
+
+    /// Read the secret again, after synchronizing the local copy of the vault
+    /// for Bitwarden, to get a secret rotated since the last read
+    pub fn refetch(&mut self) -> Result<String> {
+        if self.item.vault == Vault::Bitwarden {
+            match self.runner.output(
+                self.item.vault.program(),
+                &["sync"],
+                &envs(&self.session),
+                false,
+            ) {
+                Ok(output) if output.status.success() => debug!("Bitwarden vault synchronized"),
+                Ok(output) => debug!(
+                    "Fail to synchronize the Bitwarden vault : {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(e) => debug!("Fail to synchronize the Bitwarden vault : {}", e),
+            }
+        }
+        self.fetch(io::stdin().is_terminal())
+    }
+}
+
+impl VaultItem {
+    /// Run the command reading the secret, within `session` if any
+    fn read(
+        &self,
+        runner: &dyn CommandRunner,
+        session: Option<&(String, String)>,
+    ) -> Result<Output> {
+        debug!(
+            "Running {} {}",
+            self.vault.program(),
+            self.vault.read_args(&self.item).join(" ")
+        );
+        runner
+            .output(
+                self.vault.program(),
+                &self.vault.read_args(&self.item),
+                &envs(&session.cloned()),
+                false,
+            )
+            .map_err(|e| spawn_error(e, self.vault))
+    }
+}
+
+/// Return the environment variables giving `session` to a command
+fn envs(session: &Option<(String, String)>) -> Vec<(&str, &str)> {
+    session
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+/// Return the first line of the standard output of `output`
+fn first_line(output: &Output, vault: Vault) -> Result<String> {
+    match String::from_utf8_lossy(&output.stdout).lines().next() {
+        Some(line) if !line.is_empty() => Ok(line.to_string()),
+        _ => Err(Error::Auth(anyhow!(
+            "`{}` returns nothing",
+            vault.program()
+        ))),
+    }
+}
+
+/// Return the error of a command of `vault` which could not be run
+fn spawn_error(e: io::Error, vault: Vault) -> Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => Error::Config(anyhow!(
+            "`{}` ({} command line interface) is not installed",
+            vault.program(),
+            vault.name()
+        )),
+        _ => Error::Platform(anyhow::Error::new(e).context(format!("Running {}", vault.program()))),
+    }
+}
+
+/// Secret of a vault item
+#[derive(Debug)]
+pub struct VaultProvider {
+    /// item holding the secret, if configured
+    pub item: Option<VaultItem>,
+    /// secret read by [`SecretProvider::secret`]
+    pub read: RefCell<Option<VaultSecret>>,
+}
+
+impl VaultProvider {
+    /// Read the secret of `item`, if configured
+    pub fn new(item: Option<VaultItem>) -> Self {
+        VaultProvider {
+            item,
+            read: RefCell::new(None),
+        }
+    }
+}
+
+impl SecretProvider for VaultProvider {
+    fn name(&self) -> String {
+        match &self.item {
+            Some(item) => item.vault.name().to_string(),
+            None => "vault".to_string(),
+        }
+    }
+
+    fn secret(&self) -> Result<Option<String>> {
+        let Some(item) = &self.item else {
+            return Ok(None);
+        };
+        let mut vault = VaultSecret::new(item.clone(), Arc::new(SystemRunner));
+        let secret = vault.fetch(io::stdin().is_terminal())?;
+        self.read.replace(Some(vault));
+        Ok(Some(secret))
+    }
+
+    fn vault_secret(&self) -> Option<VaultSecret> {
+        self.read.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::command::MockCommandRunner;
+    use crate::mattermost::{BaseSession, Session};
+    use httpmock::prelude::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use test_log::test; // Automatically trace tests
+
+    /// Output of a command exiting with `code`
+    #[cfg(unix)]
+    fn output(code: i32, stdout: &str, stderr: &str) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+        Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn report_a_locked_vault_without_terminal() {
+        let item = VaultItem {
+            vault: Vault::OnePassword,
+            item: "op://Work/Mattermost/locked".to_string(),
+        };
+        // Any other call, such as unlocking, fails the test
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_output()
+            .withf(|program, args, _, interactive| {
+                program == "op" && args == ["read", "op://Work/Mattermost/locked"] && !interactive
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(output(1, "", "You are not currently signed in.")));
+        let error = VaultSecret::new(item, Arc::new(runner))
+            .fetch(false)
+            .unwrap_err();
+        let Error::Auth(e) = error else {
+            panic!("Unexpected {:?}", error)
+        };
+        assert!(e.to_string().starts_with("The 1Password vault is locked"));
+        assert!(e.to_string().contains("OP_SERVICE_ACCOUNT_TOKEN"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unlock_the_vault_once() -> Result<()> {
+        let item = VaultItem {
+            vault: Vault::Bitwarden,
+            item: "mattermost".to_string(),
+        };
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_output()
+            .withf(|program, args, envs, _| {
+                program == "bw" && args == ["get", "password", "mattermost"] && envs.is_empty()
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(output(1, "", "Vault is locked.")));
+        runner
+            .expect_output()
+            .withf(|program, args, _, interactive| {
+                program == "bw" && args == ["unlock", "--raw"] && *interactive
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(output(0, "c2Vzc2lvbg==", "")));
+        // The session is not on the command line
+        runner
+            .expect_output()
+            .withf(|program, args, envs, _| {
+                program == "bw"
+                    && args == ["get", "password", "mattermost"]
+                    && envs == [("BW_SESSION", "c2Vzc2lvbg==")]
+            })
+            .times(2)
+            .returning(|_, _, _, _| Ok(output(0, "s3cret\n", "")));
+        let mut vault = VaultSecret::new(item, Arc::new(runner));
+        assert_eq!(vault.fetch(true)?, "s3cret");
+        assert_eq!(vault.fetch(true)?, "s3cret");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn relogin_with_the_rotated_secret() -> Result<()> {
+        let item = VaultItem {
+            vault: Vault::OnePassword,
+            item: "op://Work/Mattermost/rotated".to_string(),
+        };
+        // The password is rotated in the vault after the first read
+        let reads = AtomicUsize::new(0);
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_output()
+            .withf(|program, args, _, _| {
+                program == "op" && args == ["read", "op://Work/Mattermost/rotated"]
+            })
+            .times(2)
+            .returning(
+                move |_, _, _, _| match reads.fetch_add(1, Ordering::Relaxed) {
+                    0 => Ok(output(0, "old\n", "")),
+                    _ => Ok(output(0, "n3w\n", "")),
+                },
+            );
+        let mut vault = VaultSecret::new(item, Arc::new(runner));
+        let password = vault.fetch(false)?;
+        let server = MockServer::start();
+        let login = |password: &str, status: u16, token: &str| {
+            server.mock(|expect, resp_with| {
+                expect
+                    .method(POST)
+                    .path("/api/v4/users/login")
+                    .json_body(serde_json::json!({"login_id": "bob", "password": password}));
+                resp_with
+                    .status(status)
+                    .header("content-type", "application/json")
+                    .header("Token", token)
+                    .json_body(serde_json::json!({"id": "user_id"}));
+            })
+        };
+        let mut old_login = login("old", 200, "first");
+        let mut session = Session::new(&server.url(""))
+            .with_credentials("bob", &password)
+            .login()?;
+        session.follow_vault(vault);
+        old_login.delete();
+        login("old", 401, "");
+        let new_login = login("n3w", 200, "second");
+        server.mock(|expect, resp_with| {
+            expect
+                .method(GET)
+                .path("/api/v4/users/me")
+                .header("Authorization", "Bearer first");
+            resp_with.status(401);
+        });
+        let me = server.mock(|expect, resp_with| {
+            expect
+                .method(GET)
+                .path("/api/v4/users/me")
+                .header("Authorization", "Bearer second");
+            resp_with.status(200).body("{}");
+        });
+        session.call("GET", "/api/v4/users/me", None)?;
+        new_login.assert();
+        me.assert();
+        assert_eq!(session.take_relogins(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn read_the_secret_with_the_vault_cli() {
+        assert_eq!(
+            Vault::OnePassword.read_args("op://Work/Mattermost/token"),
+            ["read", "op://Work/Mattermost/token"]
+        );
+        assert_eq!(
+            Vault::Bitwarden.read_args("mattermost"),
+            ["get", "password", "mattermost"]
+        );
+        assert!(Vault::Bitwarden.is_locked("You are not logged in."));
+        assert!(!Vault::OnePassword.is_locked("Vault is locked."));
+    }
+
+    #[test]
+    fn report_a_missing_cli() {
+        let error = spawn_error(io::ErrorKind::NotFound.into(), Vault::OnePassword);
+        let Error::Config(e) = error else {
+            panic!("Unexpected {:?}", error)
+        };
+        assert_eq!(
+            e.to_string(),
+            "`op` (1Password command line interface) is not installed"
+        );
+    }
+}
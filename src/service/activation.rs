@@ -0,0 +1,172 @@
+//! systemd socket activation of the metrics endpoint (linux only)
+//!
+//! The service binds nothing by itself. When started by a socket unit (such as
+//! `distrib/automattermostatus.socket`), the listening sockets passed by
+//! systemd (`LISTEN_FDS`) serve the counters of the status update loop in
+//! Prometheus text format, one HTTP response per connection. Both TCP and unix
+//! stream sockets are accepted, so that `systemd --user` sandboxing may keep
+//! the service off the network.
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::state::{Cache, State};
+
+/// First file descriptor passed by systemd
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// How long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listening socket passed by systemd
+#[derive(Debug)]
+pub enum Listener {
+    /// `ListenStream` of an address and a port
+    Tcp(TcpListener),
+    /// `ListenStream` of a path
+    Unix(UnixListener),
+}
+
+/// Return the number of sockets passed to the process `pid` according to the
+/// `LISTEN_PID` and `LISTEN_FDS` variables
+/// ```
+/// use lib::service::activation::fd_count;
+/// assert_eq!(fd_count(Some("42"), Some("2"), 42), 2);
+/// // Passed to another process
+/// assert_eq!(fd_count(Some("41"), Some("2"), 42), 0);
+/// assert_eq!(fd_count(None, None, 42), 0);
+/// ```
+pub fn fd_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    match (listen_pid.and_then(|p| p.parse::<u32>().ok()), listen_fds) {
+        (Some(listen_pid), Some(fds)) if listen_pid == pid => fds.parse().unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+/// Take the listening sockets passed by systemd, unsetting the variables so
+/// that the spawned commands do not inherit them
+pub fn listeners() -> Vec<Listener> {
+    let count = fd_count(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    (0..count)
+        .map(|i| {
+            let fd = LISTEN_FDS_START + i as RawFd;
+            // SAFETY: systemd passes the sockets from `LISTEN_FDS_START` on,
+            // owned by nobody else in the process
+            let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+            // Only the address of an inet socket is understood
+            match tcp.local_addr() {
+                Ok(_) => Listener::Tcp(tcp),
+                // SAFETY: the descriptor has just been released by `tcp`
+                Err(_) => Listener::Unix(unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) }),
+            }
+        })
+        .collect()
+}
+
+/// Return the HTTP response to a metrics request, with the counters of the
+/// state persisted in `cache`
+pub fn response(cache: &Cache) -> String {
+    match State::new(cache) {
+        Ok(state) => {
+            let body = state.counters().to_string();
+            format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        Err(e) => {
+            warn!("Unable to read the state for the metrics: {:#}", e);
+            "HTTP/1.0 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_string()
+        }
+    }
+}
+
+/// Read the request headers of `stream`, then answer with the counters of the
+/// state persisted in `cache`
+fn answer<S: io::Read + Write>(stream: S, cache: &Cache) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    reader.get_mut().write_all(response(cache).as_bytes())
+}
+
+/// Answer each of the `incoming` connections, whose read timeout is set by
+/// `timeout`
+fn answer_all<S: io::Read + Write>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    timeout: impl Fn(&S, Option<Duration>) -> io::Result<()>,
+    cache: &Cache,
+) {
+    for stream in incoming {
+        let res = stream.and_then(|stream| {
+            timeout(&stream, Some(READ_TIMEOUT))?;
+            answer(stream, cache)
+        });
+        if let Err(e) = res {
+            debug!("Fail to serve metrics: {}", e)
+        }
+    }
+}
+
+/// Serve the metrics of the state persisted in `state_file` on each of
+/// `listeners`, in background threads
+pub fn serve(listeners: Vec<Listener>, state_file: PathBuf) {
+    for listener in listeners {
+        let cache = Cache::new(state_file.clone());
+        info!("Serving metrics on {:?}", listener);
+        thread::spawn(move || match listener {
+            Listener::Tcp(listener) => answer_all(
+                listener.incoming(),
+                std::net::TcpStream::set_read_timeout,
+                &cache,
+            ),
+            Listener::Unix(listener) => answer_all(
+                listener.incoming(),
+                std::os::unix::net::UnixStream::set_read_timeout,
+                &cache,
+            ),
+        });
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use mktemp::Temp;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn serve_the_counters() -> anyhow::Result<()> {
+        let dir = Temp::new_dir()?;
+        let socket = dir.join("metrics.sock");
+        serve(
+            vec![Listener::Unix(UnixListener::bind(&socket)?)],
+            dir.join("automattermostatus.state"),
+        );
+        let mut stream = UnixStream::connect(&socket)?;
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("\nautomattermostatus_scans_total 0\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! Mac os launch agent of the service
+//!
+//! The agent is written in `~/Library/LaunchAgents` and loaded in the gui domain
+//! of the user. Launchd restarts `service run` when it fails, at most every
+//! [`THROTTLE_SECS`] seconds, and sends its standard output and error to files of
+//! `~/Library/Logs/automattermostatus`, truncated by [`rotate_output`] when they
+//! grow too large.
+use super::{run, CommandRunner, LAUNCHD_LABEL, SERVICE_NAME};
+use crate::error::{Error, Result};
+use anyhow::{anyhow, Context};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Minimum delay in seconds between two launches of the service
+pub const THROTTLE_SECS: u32 = 30;
+
+/// Size above which the standard output and error files are rotated
+const MAX_OUTPUT_SIZE: u64 = 1024 * 1024;
+
+/// Names of the files receiving the standard output and error of the service
+const OUTPUT_FILES: [&str; 2] = ["stdout.log", "stderr.log"];
+
+/// Return the directory of the service logs in `home`
+pub fn log_dir(home: &Path) -> PathBuf {
+    home.join("Library/Logs").join(SERVICE_NAME)
+}
+
+/// Return the path of the launch agent in `home`
+pub fn agent_path(home: &Path) -> PathBuf {
+    home.join("Library/LaunchAgents")
+        .join(format!("{}.plist", LAUNCHD_LABEL))
+}
+
+/// Escape `s` for an xml text node
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Return the launch agent running `exe service run` and logging in `log_dir`
+pub fn plist(exe: &Path, log_dir: &Path) -> String {
+    let path = |p: &Path| escape(&p.display().to_string());
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Label</key>
+	<string>{label}</string>
+	<key>ProgramArguments</key>
+	<array>
+		<string>{exe}</string>
+		<string>service</string>
+		<string>run</string>
+	</array>
+	<key>RunAtLoad</key>
+	<true/>
+	<key>KeepAlive</key>
+	<dict>
+		<key>SuccessfulExit</key>
+		<false/>
+	</dict>
+	<key>ThrottleInterval</key>
+	<integer>{throttle}</integer>
+	<key>ProcessType</key>
+	<string>Background</string>
+	<key>StandardOutPath</key>
+	<string>{stdout}</string>
+	<key>StandardErrorPath</key>
+	<string>{stderr}</string>
+</dict>
+</plist>
+"#,
+        label = LAUNCHD_LABEL,
+        exe = path(exe),
+        throttle = THROTTLE_SECS,
+        stdout = path(&log_dir.join(OUTPUT_FILES[0])),
+        stderr = path(&log_dir.join(OUTPUT_FILES[1])),
+    )
+}
+
+/// Return the gui domain of the current user (`gui/<uid>`)
+fn domain(runner: &dyn CommandRunner) -> Result<String> {
+    Ok(format!("gui/{}", run(runner, "id", &["-u"])?.trim()))
+}
+
+/// Write the launch agent of `exe` in `home` and load it
+pub fn install(runner: &dyn CommandRunner, exe: &Path, home: &Path, log_dir: &Path) -> Result<()> {
+    let agent = agent_path(home);
+    if let Some(dir) = agent.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Creating {:?}", dir))
+            .map_err(Error::Platform)?;
+    }
+    fs::create_dir_all(log_dir)
+        .with_context(|| format!("Creating {:?}", log_dir))
+        .map_err(Error::Platform)?;
+    info!("Writing {:?}", agent);
+    fs::write(&agent, plist(exe, log_dir))
+        .with_context(|| format!("Writing {:?}", agent))
+        .map_err(Error::Platform)?;
+    let agent = agent.display().to_string();
+    run(
+        runner,
+        "launchctl",
+        &["bootstrap", &domain(runner)?, &agent],
+    )?;
+    Ok(())
+}
+
+/// Unload the launch agent and delete it, with the standard output and error
+/// files of `log_dir`
+pub fn uninstall(runner: &dyn CommandRunner, home: &Path, log_dir: &Path) -> Result<()> {
+    let agent = agent_path(home);
+    if !agent.exists() {
+        return Err(Error::Platform(anyhow!("{:?} does not exist", agent)));
+    }
+    let target = format!("{}/{}", domain(runner)?, LAUNCHD_LABEL);
+    run(runner, "launchctl", &["bootout", &target])?;
+    fs::remove_file(&agent)
+        .with_context(|| format!("Deleting {:?}", agent))
+        .map_err(Error::Platform)?;
+    for name in OUTPUT_FILES {
+        for path in [log_dir.join(name), log_dir.join(format!("{}.1", name))] {
+            if path.exists() {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Deleting {:?}", path))
+                    .map_err(Error::Platform)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copy the standard output and error files of `log_dir` larger than 1 MiB to
+/// `<name>.1`, then truncate them.
+///
+/// They are truncated instead of renamed, as launchd keeps them open.
+pub fn rotate_output(log_dir: &Path) -> Result<()> {
+    for name in OUTPUT_FILES {
+        let path = log_dir.join(name);
+        let size = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => continue,
+        };
+        if size > MAX_OUTPUT_SIZE {
+            fs::copy(&path, log_dir.join(format!("{}.1", name)))
+                .and_then(|_| OpenOptions::new().write(true).open(&path)?.set_len(0))
+                .with_context(|| format!("Rotating {:?}", path))
+                .map_err(Error::Platform)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::command::MockCommandRunner;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn install_and_uninstall_launch_agent() -> Result<()> {
+        let home = Temp::new_dir().unwrap();
+        let log_dir = log_dir(&home);
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .withf(|program, _| program == "id")
+            .returning(|_, _| Ok("501\n".to_string()));
+        let agent = agent_path(&home).display().to_string();
+        runner
+            .expect_run()
+            .withf(move |program, args| {
+                program == "launchctl" && args == ["bootstrap", "gui/501", &agent]
+            })
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        runner
+            .expect_run()
+            .withf(|program, args| {
+                program == "launchctl"
+                    && args == ["bootout", "gui/501/com.github.matclab.automattermostatus"]
+            })
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        install(
+            &runner,
+            Path::new("/Applications/A&B/automattermostatus"),
+            &home,
+            &log_dir,
+        )?;
+        let content = fs::read_to_string(agent_path(&home)).unwrap();
+        assert!(content.contains("<string>/Applications/A&amp;B/automattermostatus</string>"));
+        assert!(content.contains("<key>ThrottleInterval</key>\n\t<integer>30</integer>"));
+        assert!(content.contains(&format!(
+            "<string>{}</string>",
+            log_dir.join("stderr.log").display()
+        )));
+        fs::write(log_dir.join("stdout.log"), "started\n").unwrap();
+        uninstall(&runner, &home, &log_dir)?;
+        assert!(!agent_path(&home).exists());
+        assert!(!log_dir.join("stdout.log").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_large_output_files() -> Result<()> {
+        let log_dir = Temp::new_dir().unwrap();
+        let large = vec![b'x'; MAX_OUTPUT_SIZE as usize + 1];
+        fs::write(log_dir.join("stderr.log"), &large).unwrap();
+        fs::write(log_dir.join("stdout.log"), "small\n").unwrap();
+        rotate_output(&log_dir)?;
+        assert_eq!(fs::metadata(log_dir.join("stderr.log")).unwrap().len(), 0);
+        assert_eq!(fs::read(log_dir.join("stderr.log.1")).unwrap(), large);
+        assert_eq!(
+            fs::read_to_string(log_dir.join("stdout.log")).unwrap(),
+            "small\n"
+        );
+        assert!(!log_dir.join("stdout.log.1").exists());
+        Ok(())
+    }
+}
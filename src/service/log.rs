@@ -0,0 +1,117 @@
+//! Daily log files of `service run`
+//!
+//! Logs are appended to `automattermostatus.log.<yyyy-mm-dd>` in the service log
+//! directory. A new file is opened when the day changes, and the files older
+//! than the retention are then deleted.
+use chrono::{Local, NaiveDate};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Prefix of the log file names, followed by `.<yyyy-mm-dd>`
+pub const LOG_FILE_PREFIX: &str = "automattermostatus.log";
+
+/// Log file rotated every day
+#[derive(Debug)]
+pub struct DailyFile {
+    dir: PathBuf,
+    retention_days: u32,
+    current: Option<(NaiveDate, File)>,
+}
+
+impl DailyFile {
+    /// Create the log directory `dir`, keeping `retention_days` files (all of
+    /// them if `0`)
+    pub fn new(dir: &Path, retention_days: u32) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(DailyFile {
+            dir: dir.to_path_buf(),
+            retention_days,
+            current: None,
+        })
+    }
+
+    /// Return the file of `day`, opening it and pruning the old ones if needed
+    fn file(&mut self, day: NaiveDate) -> io::Result<&mut File> {
+        if !matches!(&self.current, Some((current, _)) if *current == day) {
+            let path = self
+                .dir
+                .join(format!("{}.{}", LOG_FILE_PREFIX, day.format("%Y-%m-%d")));
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.current = Some((day, file));
+            prune(&self.dir, day, self.retention_days)?;
+        }
+        match &mut self.current {
+            Some((_, file)) => Ok(file),
+            None => unreachable!("log file opened above"),
+        }
+    }
+}
+
+impl Write for DailyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file(Local::now().date_naive())?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some((_, file)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Delete the log files of `dir` dated more than `retention_days` days before
+/// `today` (none if `retention_days` is `0`)
+pub fn prune(dir: &Path, today: NaiveDate, retention_days: u32) -> io::Result<()> {
+    if retention_days == 0 {
+        return Ok(());
+    }
+    let oldest = today - chrono::Duration::days(i64::from(retention_days) - 1);
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let day = path
+            .file_name()
+            .and_then(|name| {
+                name.to_str()?
+                    .strip_prefix(LOG_FILE_PREFIX)?
+                    .strip_prefix('.')
+            })
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+        if matches!(day, Some(day) if day < oldest) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, d).unwrap()
+    }
+
+    #[test]
+    fn rotate_and_prune_daily_files() -> io::Result<()> {
+        let dir = Temp::new_dir().unwrap();
+        fs::write(dir.join("automattermostatus.log.2024-01-10"), "old\n")?;
+        fs::write(dir.join("notes.txt"), "kept\n")?;
+        let mut log = DailyFile::new(&dir, 3)?;
+        writeln!(log.file(day(12))?, "first")?;
+        writeln!(log.file(day(12))?, "second")?;
+        assert_eq!(
+            fs::read_to_string(dir.join("automattermostatus.log.2024-01-12"))?,
+            "first\nsecond\n"
+        );
+        assert!(dir.join("automattermostatus.log.2024-01-10").exists());
+        writeln!(log.file(day(13))?, "third")?;
+        assert!(!dir.join("automattermostatus.log.2024-01-10").exists());
+        assert!(dir.join("automattermostatus.log.2024-01-12").exists());
+        assert!(dir.join("notes.txt").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+//! Control of automattermostatus running as a background service
+//!
+//! The service is driven through the tools of the platform service manager
+//! (`sc.exe` on windows, `launchctl` on mac os and `systemctl --user` on linux),
+//! spawned by a [`CommandRunner`] so that all platforms can be tested anywhere.
+use crate::command::CommandRunner;
+use crate::error::{Error, Result};
+use anyhow::{anyhow, Context};
+use derivative::Derivative;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+use tracing::debug;
+
+#[cfg(target_os = "linux")]
+pub mod activation;
+pub mod launchd;
+pub mod log;
+pub mod systemd;
+#[cfg(target_os = "windows")]
+pub mod windows;
+
+/// Name of the service for the windows service control manager and systemd
+pub const SERVICE_NAME: &str = "automattermostatus";
+
+/// Label of the launchd agent
+pub const LAUNCHD_LABEL: &str = "com.github.matclab.automattermostatus";
+
+/// Name of the directory of `state_dir` holding the service logs
+pub const LOG_DIR_NAME: &str = "logs";
+
+/// Exit codes of the service after which the service manager does not restart
+/// it in vain: mattermost account deactivated or token revoked, invalid
+/// configuration
+pub use crate::error::{EXIT_ACCOUNT_DISABLED, EXIT_CONFIG};
+
+/// Description of the service shown by the service manager
+const DESCRIPTION: &str = "Update mattermost status according to the visible wifi networks";
+
+/// Number of state queries while waiting for the windows service to stop
+const STOP_POLLS: u32 = 30;
+
+/// Platform service manager
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Manager {
+    /// Windows service control manager
+    Scm,
+    /// Mac os launchd
+    Launchd,
+    /// Linux systemd user instance
+    Systemd,
+}
+
+/// State of the service as reported by the service manager
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceState {
+    /// The service is not known by the service manager
+    NotInstalled,
+    /// The service is installed but not running
+    Stopped,
+    /// The service is running
+    Running,
+    /// The service is starting, stopping, failed…
+    Other(String),
+}
+
+impl fmt::Display for ServiceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceState::NotInstalled => write!(f, "not installed"),
+            ServiceState::Stopped => write!(f, "stopped"),
+            ServiceState::Running => write!(f, "running"),
+            ServiceState::Other(state) => write!(f, "{}", state),
+        }
+    }
+}
+
+/// Options of [`Manager::install`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstallOptions {
+    /// account the windows service runs as, instead of LocalSystem
+    pub account: Option<Account>,
+    /// install a systemd system unit with an instance per user, instead of a
+    /// user unit
+    pub system: bool,
+}
+
+/// Account the windows service runs as, instead of LocalSystem
+#[derive(Derivative, Clone, PartialEq, Eq)]
+#[derivative(Debug)]
+pub struct Account {
+    /// `DOMAIN\USER`, or `.\USER` for a local account
+    pub name: String,
+    /// password of the account
+    #[derivative(Debug = "ignore")]
+    pub password: Option<String>,
+}
+
+impl Manager {
+    /// Return the service manager of the current platform
+    pub fn current() -> Self {
+        if cfg!(target_os = "windows") {
+            Manager::Scm
+        } else if cfg!(target_os = "macos") {
+            Manager::Launchd
+        } else {
+            Manager::Systemd
+        }
+    }
+
+    /// Query the state of the service
+    pub fn state(&self, runner: &dyn CommandRunner) -> Result<ServiceState> {
+        let output = match self {
+            Manager::Scm => run(runner, "sc.exe", &["query", SERVICE_NAME])?,
+            Manager::Launchd => run(runner, "launchctl", &["list", LAUNCHD_LABEL])?,
+            Manager::Systemd => run(
+                runner,
+                "systemctl",
+                &[
+                    "--user",
+                    "show",
+                    SERVICE_NAME,
+                    "--property=LoadState,ActiveState",
+                ],
+            )?,
+        };
+        Ok(match self {
+            Manager::Scm => parse_sc_query(&output),
+            Manager::Launchd => parse_launchctl_list(&output),
+            Manager::Systemd => parse_systemctl_show(&output),
+        })
+    }
+
+    /// Stop the service if it is running, then start it
+    pub fn restart(&self, runner: &dyn CommandRunner) -> Result<()> {
+        match self {
+            Manager::Scm => {
+                if self.state(runner)? != ServiceState::Stopped {
+                    run(runner, "sc.exe", &["stop", SERVICE_NAME])?;
+                }
+                // `sc.exe stop` returns before the service is stopped
+                let mut polls = 0;
+                while self.state(runner)? != ServiceState::Stopped {
+                    polls += 1;
+                    if polls >= STOP_POLLS {
+                        return Err(Error::Platform(anyhow!(
+                            "Service {} did not stop",
+                            SERVICE_NAME
+                        )));
+                    }
+                    sleep(Duration::from_secs(1));
+                }
+                run(runner, "sc.exe", &["start", SERVICE_NAME])?;
+            }
+            Manager::Launchd => {
+                let uid = run(runner, "id", &["-u"])?;
+                let target = format!("gui/{}/{}", uid.trim(), LAUNCHD_LABEL);
+                run(runner, "launchctl", &["kickstart", "-k", &target])?;
+            }
+            Manager::Systemd => {
+                run(runner, "systemctl", &["--user", "restart", SERVICE_NAME])?;
+            }
+        }
+        match self.state(runner)? {
+            ServiceState::NotInstalled => Err(Error::Platform(anyhow!(
+                "Service {} is not installed",
+                SERVICE_NAME
+            ))),
+            ServiceState::Stopped => Err(Error::Platform(anyhow!(
+                "Service {} did not start",
+                SERVICE_NAME
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Register the service running `exe service run`, then start it
+    ///
+    /// The windows service runs as `options.account` if given, and as
+    /// LocalSystem otherwise. The other service managers run it as the current
+    /// user, or as the user of each instance of a systemd system unit.
+    pub fn install(
+        &self,
+        runner: &dyn CommandRunner,
+        exe: &Path,
+        options: &InstallOptions,
+    ) -> Result<()> {
+        self.check_options(options.account.is_some(), options.system)?;
+        match self {
+            Manager::Scm => install_scm(runner, exe, options.account.as_ref()),
+            Manager::Launchd => {
+                let home = home_dir()?;
+                launchd::install(runner, exe, &home, &launchd::log_dir(&home))
+            }
+            Manager::Systemd => systemd::install(
+                runner,
+                exe,
+                &systemd::unit_dir(options.system)?,
+                options.system,
+                sudo_user().as_deref(),
+            ),
+        }
+    }
+
+    /// Stop the service if needed and remove it from the service manager (the
+    /// systemd system unit if `system` is set)
+    pub fn uninstall(&self, runner: &dyn CommandRunner, system: bool) -> Result<()> {
+        self.check_options(false, system)?;
+        match self {
+            Manager::Scm => uninstall_scm(runner),
+            Manager::Launchd => {
+                let home = home_dir()?;
+                launchd::uninstall(runner, &home, &launchd::log_dir(&home))
+            }
+            Manager::Systemd => systemd::uninstall(
+                runner,
+                &systemd::unit_dir(system)?,
+                system,
+                sudo_user().as_deref(),
+            ),
+        }
+    }
+
+    /// Fail if an `account` or a `system` unit is asked to a service manager
+    /// not supporting them
+    fn check_options(&self, account: bool, system: bool) -> Result<()> {
+        if account && *self != Manager::Scm {
+            return Err(Error::Platform(anyhow!(
+                "`--account` is only supported by the windows service manager"
+            )));
+        }
+        if system && *self != Manager::Systemd {
+            return Err(Error::Platform(anyhow!(
+                "`--system` is only supported by systemd"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Register the windows service running `exe service run` as `account`, then
+/// start it
+///
+/// The password of the account is not given to `sc.exe`, whose command line
+/// any user may read, but to the service control manager API.
+fn install_scm(runner: &dyn CommandRunner, exe: &Path, account: Option<&Account>) -> Result<()> {
+    match account.and_then(|account| Some((account, account.password.as_ref()?))) {
+        Some((account, password)) => create_scm_service(exe, account, password)?,
+        None => {
+            let bin_path = format!("\"{}\" service run", exe.display());
+            let mut args = vec![
+                "create",
+                SERVICE_NAME,
+                "binPath=",
+                &bin_path,
+                "start=",
+                "auto",
+                "DisplayName=",
+                SERVICE_NAME,
+            ];
+            if let Some(account) = account {
+                args.extend(["obj=", &account.name]);
+            }
+            sc(runner, &args)?;
+        }
+    }
+    sc(runner, &["description", SERVICE_NAME, DESCRIPTION])?;
+    sc(runner, &["start", SERVICE_NAME])?;
+    Ok(())
+}
+
+/// Register the windows service running `exe service run` as `account`,
+/// authenticated by `password`
+#[cfg(target_os = "windows")]
+fn create_scm_service(exe: &Path, account: &Account, password: &str) -> Result<()> {
+    windows::create_service(exe, account, password)
+        .with_context(|| format!("Creating service {} as {}", SERVICE_NAME, account.name))
+        .map_err(Error::Platform)
+}
+
+/// Register the windows service running `exe service run` as `account`,
+/// authenticated by `password`
+#[cfg(not(target_os = "windows"))]
+fn create_scm_service(_exe: &Path, _account: &Account, _password: &str) -> Result<()> {
+    Err(Error::Platform(anyhow!(
+        "A service account password is only supported on windows"
+    )))
+}
+
+/// Stop the windows service if needed and delete it
+fn uninstall_scm(runner: &dyn CommandRunner) -> Result<()> {
+    match Manager::Scm.state(runner)? {
+        ServiceState::NotInstalled => {
+            return Err(Error::Platform(anyhow!(
+                "Service {} is not installed",
+                SERVICE_NAME
+            )))
+        }
+        ServiceState::Stopped => (),
+        _ => {
+            sc(runner, &["stop", SERVICE_NAME])?;
+        }
+    }
+    sc(runner, &["delete", SERVICE_NAME])?;
+    Ok(())
+}
+
+/// Return the user who ran `sudo`, whose systemd instance is enabled
+fn sudo_user() -> Option<String> {
+    std::env::var("SUDO_USER")
+        .ok()
+        .filter(|user| !user.is_empty())
+}
+
+/// Return the home directory of the user
+fn home_dir() -> Result<PathBuf> {
+    directories_next::BaseDirs::new()
+        .map(|dirs| dirs.home_dir().to_path_buf())
+        .ok_or_else(|| Error::Platform(anyhow!("Home directory not found")))
+}
+
+/// Run `sc.exe` with `args`, failing if it reports a failure
+fn sc(runner: &dyn CommandRunner, args: &[&str]) -> Result<String> {
+    let output = run(runner, "sc.exe", args)?;
+    // e.g. `[SC] CreateService FAILED 1073:`
+    if output.contains("FAILED") {
+        return Err(Error::Platform(anyhow!("{}", output.trim())));
+    }
+    Ok(output)
+}
+
+/// Ask the password of the windows `account` on the terminal, without echoing it
+#[cfg(target_os = "windows")]
+pub fn prompt_password(account: &str) -> Result<String> {
+    rpassword::prompt_password(format!("Password of {}: ", account))
+        .context("Reading password")
+        .map_err(Error::Platform)
+}
+
+/// Ask the password of the windows `account` on the terminal, without echoing it
+#[cfg(not(target_os = "windows"))]
+pub fn prompt_password(_account: &str) -> Result<String> {
+    Err(Error::Platform(anyhow!(
+        "`--password-prompt` is only supported on windows"
+    )))
+}
+
+/// Run `program` with `args` through `runner`
+fn run(runner: &dyn CommandRunner, program: &str, args: &[&str]) -> Result<String> {
+    debug!("Running {} {}", program, args.join(" "));
+    runner
+        .run(program, args)
+        .with_context(|| format!("Running {}", program))
+        .map_err(Error::Platform)
+}
+
+/// Parse the output of `sc.exe query`
+fn parse_sc_query(output: &str) -> ServiceState {
+    // `OpenService FAILED 1060` : the specified service does not exist
+    if output.contains("1060") {
+        return ServiceState::NotInstalled;
+    }
+    let state = output
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "STATE")
+        .and_then(|(_, value)| value.split_whitespace().nth(1));
+    match state {
+        Some("RUNNING") => ServiceState::Running,
+        Some("STOPPED") => ServiceState::Stopped,
+        Some(state) => ServiceState::Other(state.to_lowercase().replace('_', " ")),
+        None => ServiceState::NotInstalled,
+    }
+}
+
+/// Parse the output of `launchctl list <label>`, empty if the agent is not loaded
+fn parse_launchctl_list(output: &str) -> ServiceState {
+    if output.trim().is_empty() {
+        ServiceState::NotInstalled
+    } else if output.contains("\"PID\" =") {
+        ServiceState::Running
+    } else {
+        ServiceState::Stopped
+    }
+}
+
+/// Parse the output of `systemctl show --property=LoadState,ActiveState`
+fn parse_systemctl_show(output: &str) -> ServiceState {
+    let property = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .map(str::trim)
+    };
+    match (property("LoadState"), property("ActiveState")) {
+        (Some("not-found") | None, _) => ServiceState::NotInstalled,
+        (_, Some("active")) => ServiceState::Running,
+        (_, Some("inactive")) => ServiceState::Stopped,
+        (_, Some(state)) => ServiceState::Other(state.to_string()),
+        (_, None) => ServiceState::NotInstalled,
+    }
+}
+
+/// Return the directory holding the service logs:
+/// `~/Library/Logs/automattermostatus` on mac os, and the `logs` directory of
+/// `state_dir` elsewhere
+pub fn log_dir(state_dir: &Path) -> PathBuf {
+    match (Manager::current(), home_dir()) {
+        (Manager::Launchd, Ok(home)) => launchd::log_dir(&home),
+        _ => state_dir.join(LOG_DIR_NAME),
+    }
+}
+
+/// Return the last `count` error lines of the most recent log file of `log_dir`
+pub fn last_errors(log_dir: &Path, count: usize) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(log_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(Error::Platform(
+                anyhow!(e).context(format!("Reading {:?}", log_dir)),
+            ))
+        }
+    };
+    // Log files are suffixed with their date, hence the latest is the greatest
+    let latest = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(log::LOG_FILE_PREFIX))
+        })
+        .max();
+    let Some(latest) = latest else {
+        return Ok(Vec::new());
+    };
+    let content = fs::read_to_string(&latest)
+        .with_context(|| format!("Reading {:?}", latest))
+        .map_err(Error::Platform)?;
+    let errors: Vec<_> = content
+        .lines()
+        .filter(|line| line.contains("ERROR"))
+        .map(str::to_string)
+        .collect();
+    Ok(errors[errors.len().saturating_sub(count)..].to_vec())
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::command::MockCommandRunner;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn parse_service_manager_states() {
+        let running = "
+SERVICE_NAME: automattermostatus
+        TYPE               : 10  WIN32_OWN_PROCESS
+        STATE              : 4  RUNNING
+                                (STOPPABLE, NOT_PAUSABLE, ACCEPTS_SHUTDOWN)
+        WIN32_EXIT_CODE    : 0  (0x0)
+";
+        assert_eq!(parse_sc_query(running), ServiceState::Running);
+        assert_eq!(
+            parse_sc_query("        STATE              : 3  STOP_PENDING\n"),
+            ServiceState::Other("stop pending".to_string())
+        );
+        assert_eq!(
+            parse_sc_query(
+                "[SC] EnumQueryServicesStatus:OpenService FAILED 1060:\n\nThe specified service does not exist as an installed service.\n"
+            ),
+            ServiceState::NotInstalled
+        );
+        assert_eq!(parse_launchctl_list(""), ServiceState::NotInstalled);
+        assert_eq!(
+            parse_launchctl_list(
+                "{\n\t\"LimitLoadToSessionType\" = \"Aqua\";\n\t\"PID\" = 812;\n};\n"
+            ),
+            ServiceState::Running
+        );
+        assert_eq!(
+            parse_systemctl_show("LoadState=loaded\nActiveState=failed\n"),
+            ServiceState::Other("failed".to_string())
+        );
+        assert_eq!(
+            parse_systemctl_show("LoadState=not-found\nActiveState=inactive\n"),
+            ServiceState::NotInstalled
+        );
+    }
+
+    #[test]
+    fn restart_windows_service_once_stopped() -> Result<()> {
+        let mut runner = MockCommandRunner::new();
+        let mut queries = vec![
+            "STATE : 4  RUNNING",
+            "STATE : 3  STOP_PENDING",
+            "STATE : 1  STOPPED",
+            "STATE : 2  START_PENDING",
+        ]
+        .into_iter();
+        runner
+            .expect_run()
+            .withf(|program, args| program == "sc.exe" && args[0] == "query")
+            .times(4)
+            .returning(move |_, _| Ok(queries.next().unwrap().to_string()));
+        runner
+            .expect_run()
+            .withf(|program, args| program == "sc.exe" && args == ["stop", SERVICE_NAME])
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        runner
+            .expect_run()
+            .withf(|program, args| program == "sc.exe" && args == ["start", SERVICE_NAME])
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        Manager::Scm.restart(&runner)
+    }
+
+    #[test]
+    fn install_windows_service_as_user() -> Result<()> {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .withf(|program, args| {
+                program == "sc.exe"
+                    && args
+                        == [
+                            "create",
+                            SERVICE_NAME,
+                            "binPath=",
+                            "\"C:\\bin\\automattermostatus.exe\" service run",
+                            "start=",
+                            "auto",
+                            "DisplayName=",
+                            SERVICE_NAME,
+                            "obj=",
+                            "NT AUTHORITY\\LocalService",
+                        ]
+            })
+            .times(1)
+            .returning(|_, _| Ok("[SC] CreateService SUCCESS\n".to_string()));
+        runner
+            .expect_run()
+            .withf(|program, args| program == "sc.exe" && args[0] == "description")
+            .times(1)
+            .returning(|_, _| Ok("[SC] ChangeServiceConfig2 SUCCESS\n".to_string()));
+        runner
+            .expect_run()
+            .withf(|program, args| program == "sc.exe" && args == ["start", SERVICE_NAME])
+            .times(1)
+            .returning(|_, _| {
+                Ok("[SC] StartService FAILED 1069:\n\nThe service did not start due to a logon failure.\n".to_string())
+            });
+        let options = InstallOptions {
+            account: Some(Account {
+                name: "NT AUTHORITY\\LocalService".to_string(),
+                password: None,
+            }),
+            system: false,
+        };
+        let exe = Path::new("C:\\bin\\automattermostatus.exe");
+        let err = Manager::Scm.install(&runner, exe, &options).unwrap_err();
+        assert!(err.to_string().contains("logon failure"));
+        // Accounts are specific to windows
+        assert!(Manager::Systemd.install(&runner, exe, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn never_give_the_account_password_to_sc() -> Result<()> {
+        // Any call of the runner fails the test
+        let runner = MockCommandRunner::new();
+        let account = Account {
+            name: "CORP\\me".to_string(),
+            password: Some("secret".to_string()),
+        };
+        assert!(!format!("{:?}", account).contains("secret"));
+        let options = InstallOptions {
+            account: Some(account),
+            system: false,
+        };
+        let exe = Path::new("C:\\bin\\automattermostatus.exe");
+        assert!(Manager::Scm.install(&runner, exe, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn print_last_errors_of_latest_log() -> Result<()> {
+        let dir = Temp::new_dir().unwrap();
+        fs::write(
+            dir.join("automattermostatus.log.2024-01-14"),
+            "2024-01-14 ERROR old\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("automattermostatus.log.2024-01-15"),
+            "ERROR first\n INFO fine\nERROR second\nERROR third\n",
+        )
+        .unwrap();
+        assert_eq!(last_errors(&dir, 2)?, vec!["ERROR second", "ERROR third"]);
+        assert!(last_errors(&dir.join("missing"), 2)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Linux systemd unit of the service
+//!
+//! By default, a user unit is written in `~/.config/systemd/user` and enabled in
+//! the user service manager. With `--system`, a template unit
+//! `automattermostatus@.service` is written in `/etc/systemd/system`, each
+//! instance running as the user it is named after, for machines shared by
+//! several users.
+use super::{run, CommandRunner, DESCRIPTION, EXIT_ACCOUNT_DISABLED, EXIT_CONFIG, SERVICE_NAME};
+use crate::error::{Error, Result};
+use anyhow::{anyhow, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Directory of the system units
+pub const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Delay in seconds before restarting the service after a failure
+const RESTART_SECS: u32 = 30;
+
+/// Return the name of the unit (the template for `system`)
+pub fn unit_name(system: bool) -> String {
+    match system {
+        true => format!("{}@.service", SERVICE_NAME),
+        false => format!("{}.service", SERVICE_NAME),
+    }
+}
+
+/// Return the content of the unit running `exe service run`
+pub fn unit(exe: &Path, system: bool) -> String {
+    format!(
+        "[Unit]
+Description={description}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+ExecStart=\"{exe}\" service run
+Restart=on-failure
+RestartSec={restart}
+RestartPreventExitStatus={account_disabled} {config}
+{user}# The home directory stays writable for the state files and secret commands
+ProtectSystem=full
+PrivateTmp=true
+NoNewPrivileges=true
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK
+RestrictRealtime=true
+LockPersonality=true
+
+[Install]
+WantedBy={target}
+",
+        description = DESCRIPTION,
+        exe = exe.display(),
+        restart = RESTART_SECS,
+        account_disabled = EXIT_ACCOUNT_DISABLED,
+        config = EXIT_CONFIG,
+        user = if system { "User=%i\n" } else { "" },
+        target = if system {
+            "multi-user.target"
+        } else {
+            "default.target"
+        },
+    )
+}
+
+/// Run `systemctl` with `args` in the user or system service manager
+fn systemctl(runner: &dyn CommandRunner, system: bool, args: &[&str]) -> Result<String> {
+    let mut all = Vec::with_capacity(args.len() + 1);
+    if !system {
+        all.push("--user");
+    }
+    all.extend(args);
+    run(runner, "systemctl", &all)
+}
+
+/// Write the unit of `exe` in `unit_dir`, then enable and start it (the
+/// instance of `user` for a `system` unit)
+pub fn install(
+    runner: &dyn CommandRunner,
+    exe: &Path,
+    unit_dir: &Path,
+    system: bool,
+    user: Option<&str>,
+) -> Result<()> {
+    let path = unit_dir.join(unit_name(system));
+    fs::create_dir_all(unit_dir)
+        .with_context(|| format!("Creating {:?}", unit_dir))
+        .map_err(Error::Platform)?;
+    info!("Writing {:?}", path);
+    fs::write(&path, unit(exe, system))
+        .with_context(|| format!("Writing {:?}", path))
+        .map_err(Error::Platform)?;
+    systemctl(runner, system, &["daemon-reload"])?;
+    match (system, user) {
+        (false, _) => {
+            systemctl(runner, system, &["enable", "--now", &unit_name(false)])?;
+        }
+        (true, Some(user)) => {
+            let instance = format!("{}@{}.service", SERVICE_NAME, user);
+            systemctl(runner, system, &["enable", "--now", &instance])?;
+        }
+        (true, None) => warn!(
+            "Enable the service of each user with `systemctl enable --now {}@<user>.service`",
+            SERVICE_NAME
+        ),
+    }
+    Ok(())
+}
+
+/// Stop and disable the unit of `unit_dir` (the instance of `user` for a
+/// `system` unit), then delete it
+pub fn uninstall(
+    runner: &dyn CommandRunner,
+    unit_dir: &Path,
+    system: bool,
+    user: Option<&str>,
+) -> Result<()> {
+    let path = unit_dir.join(unit_name(system));
+    if !path.exists() {
+        return Err(Error::Platform(anyhow!("{:?} does not exist", path)));
+    }
+    match (system, user) {
+        (false, _) => {
+            systemctl(runner, system, &["disable", "--now", &unit_name(false)])?;
+        }
+        (true, Some(user)) => {
+            let instance = format!("{}@{}.service", SERVICE_NAME, user);
+            systemctl(runner, system, &["disable", "--now", &instance])?;
+        }
+        (true, None) => (),
+    }
+    fs::remove_file(&path)
+        .with_context(|| format!("Deleting {:?}", path))
+        .map_err(Error::Platform)?;
+    systemctl(runner, system, &["daemon-reload"])?;
+    Ok(())
+}
+
+/// Return the directory of the units: `/etc/systemd/system` for `system`, and
+/// `systemd/user` in the configuration directory of the user otherwise
+pub fn unit_dir(system: bool) -> Result<PathBuf> {
+    if system {
+        return Ok(PathBuf::from(SYSTEM_UNIT_DIR));
+    }
+    directories_next::BaseDirs::new()
+        .map(|dirs| dirs.config_dir().join("systemd/user"))
+        .ok_or_else(|| Error::Platform(anyhow!("Configuration directory not found")))
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::command::MockCommandRunner;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn generate_hardened_units() {
+        let user = unit(Path::new("/usr/bin/automattermostatus"), false);
+        assert!(user.contains("ExecStart=\"/usr/bin/automattermostatus\" service run\n"));
+        assert!(user.contains("After=network-online.target\n"));
+        assert!(user.contains("RestartPreventExitStatus=4 5\n"));
+        assert!(user.contains("ProtectSystem=full\nPrivateTmp=true\n"));
+        assert!(user.contains("RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK\n"));
+        assert!(user.contains("WantedBy=default.target\n"));
+        assert!(!user.contains("User="));
+        let system = unit(Path::new("/usr/bin/automattermostatus"), true);
+        assert!(system.contains("User=%i\n"));
+        assert!(system.contains("WantedBy=multi-user.target\n"));
+    }
+
+    #[test]
+    fn install_and_uninstall_system_unit() -> Result<()> {
+        let dir = Temp::new_dir().unwrap();
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .withf(|program, args| program == "systemctl" && args == ["daemon-reload"])
+            .times(2)
+            .returning(|_, _| Ok(String::new()));
+        runner
+            .expect_run()
+            .withf(|program, args| {
+                program == "systemctl"
+                    && args == ["enable", "--now", "automattermostatus@alice.service"]
+            })
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        runner
+            .expect_run()
+            .withf(|program, args| {
+                program == "systemctl"
+                    && args == ["disable", "--now", "automattermostatus@alice.service"]
+            })
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        install(
+            &runner,
+            Path::new("/usr/bin/automattermostatus"),
+            &dir,
+            true,
+            Some("alice"),
+        )?;
+        assert!(dir.join("automattermostatus@.service").exists());
+        uninstall(&runner, &dir, true, Some("alice"))?;
+        assert!(!dir.join("automattermostatus@.service").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! Windows service control manager glue of `service run`
+//!
+//! The service control manager expects the service process to register a
+//! control handler and to report its state, or it kills it after 30 seconds.
+use super::{Account, SERVICE_NAME};
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info};
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+/// Time given to the loop of the service to stop, as it waits by steps of a
+/// few seconds (see [`crate::wakeup`]) and may clear the status
+const STOP_WAIT_HINT: Duration = Duration::from_secs(20);
+
+/// Loop of the service, kept until the dispatcher calls `service_main`. It
+/// returns once the flag it is given is set.
+type ServiceLoop = Box<dyn FnOnce(Arc<AtomicBool>) -> anyhow::Result<()> + Send>;
+
+static SERVICE_LOOP: Mutex<Option<ServiceLoop>> = Mutex::new(None);
+static STATUS_HANDLE: Mutex<Option<ServiceStatusHandle>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Run `service_loop` as the windows service until it returns, the flag it is
+/// given being set when the service is stopped
+pub fn run(
+    service_loop: impl FnOnce(Arc<AtomicBool>) -> anyhow::Result<()> + Send + 'static,
+) -> windows_service::Result<()> {
+    *SERVICE_LOOP.lock().expect("service loop lock") = Some(Box::new(service_loop));
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+/// Report `state` to the service control manager
+fn report(state: ServiceState, exit_code: ServiceExitCode) {
+    let Some(handle) = *STATUS_HANDLE.lock().expect("status handle lock") else {
+        return;
+    };
+    let status = ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code,
+        checkpoint: 0,
+        wait_hint: match state {
+            ServiceState::StopPending => STOP_WAIT_HINT,
+            _ => Duration::default(),
+        },
+        process_id: None,
+    };
+    if let Err(e) = handle.set_service_status(status) {
+        error!("Fail to report service state : {}", e);
+    }
+}
+
+/// Register the windows service running `exe service run` as `account`,
+/// authenticated by `password`
+///
+/// Unlike the command line of `sc.exe`, which any user may read, the password
+/// is only given to the service control manager.
+pub fn create_service(
+    exe: &Path,
+    account: &Account,
+    password: &str,
+) -> windows_service::Result<()> {
+    let manager =
+        ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_NAME),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: exe.to_path_buf(),
+        launch_arguments: vec![OsString::from("service"), OsString::from("run")],
+        dependencies: vec![],
+        account_name: Some(OsString::from(&account.name)),
+        account_password: Some(OsString::from(password)),
+    };
+    manager.create_service(&info, ServiceAccess::QUERY_STATUS)?;
+    Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler = {
+        let stop = Arc::clone(&stop);
+        move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                info!("Stopping service");
+                report(ServiceState::StopPending, ServiceExitCode::Win32(0));
+                // The loop returns at its next step, clearing the status if
+                // `clear_on_exit` is set
+                stop.store(true, Ordering::Relaxed);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+    match service_control_handler::register(SERVICE_NAME, handler) {
+        Ok(handle) => *STATUS_HANDLE.lock().expect("status handle lock") = Some(handle),
+        Err(e) => {
+            error!("Fail to register the service control handler : {}", e);
+            return;
+        }
+    }
+    report(ServiceState::Running, ServiceExitCode::Win32(0));
+    let service_loop = SERVICE_LOOP.lock().expect("service loop lock").take();
+    let exit_code = match service_loop.map(|service_loop| service_loop(stop)) {
+        // Exit code of the error category (see `crate::error`)
+        Some(Err(e)) => {
+            error!("{:?}", e);
+            ServiceExitCode::ServiceSpecific(crate::error::exit_code(&e) as u32)
+        }
+        _ => ServiceExitCode::Win32(0),
+    };
+    info!("Service stopped");
+    report(ServiceState::Stopped, exit_code);
+}
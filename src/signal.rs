@@ -0,0 +1,241 @@
+//! Signals telling whether we are busy
+//!
+//! Each [`PresenceSignal`] (the microphone, the calls of the meeting
+//! applications, a connected headset, focus time…) is read at each cycle and
+//! tells whether we are [`Reading::Busy`], [`Reading::Free`] or whether it is
+//! [`Reading::Unknown`]. The readings are aggregated per [`StatusSource`] they
+//! feed and ranked by [`crate::priority`], so that a new busy signal (e.g. the
+//! camera, a screen share or the calendar) only has to implement the trait and
+//! to be added to [`configured`].
+use std::fmt;
+use tracing::info;
+
+use crate::command::CommandRunner;
+use crate::config::{Config, StatusSource};
+use crate::meeting::{self, MeetingApp};
+use crate::micscan::{self, uses_watched_app};
+
+/// What a signal tells during a cycle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reading {
+    /// we are busy, because of the given reasons (e.g. application names)
+    Busy(Vec<String>),
+    /// we are not busy
+    Free,
+    /// the signal could not be read, with the reason
+    Unknown(String),
+}
+
+impl Reading {
+    /// Return `true` if we are busy
+    pub fn is_busy(&self) -> bool {
+        matches!(self, Reading::Busy(_))
+    }
+}
+
+/// Return the aggregation of `readings`: busy for the reasons of all the busy
+/// ones if any, else unknown if any is unknown, else free
+/// ```
+/// use lib::signal::{aggregate, Reading};
+/// let readings = [
+///     Reading::Unknown("No sound card".to_string()),
+///     Reading::Busy(vec!["teams-for-linux".to_string()]),
+/// ];
+/// assert_eq!(aggregate(&readings), Reading::Busy(vec!["teams-for-linux".to_string()]));
+/// assert!(matches!(aggregate(&readings[..1]), Reading::Unknown(_)));
+/// assert_eq!(aggregate(&[]), Reading::Free);
+/// ```
+pub fn aggregate<'a>(readings: impl IntoIterator<Item = &'a Reading>) -> Reading {
+    let mut reasons = Vec::new();
+    let mut unknown = Vec::new();
+    for reading in readings {
+        match reading {
+            Reading::Busy(why) => reasons.extend(why.iter().cloned()),
+            Reading::Free => (),
+            Reading::Unknown(why) => unknown.push(why.as_str()),
+        }
+    }
+    if !reasons.is_empty() {
+        Reading::Busy(reasons)
+    } else if !unknown.is_empty() {
+        Reading::Unknown(unknown.join("; "))
+    } else {
+        Reading::Free
+    }
+}
+
+/// Return the readings of `signals` feeding `source`
+pub fn of(
+    signals: &[(StatusSource, Reading)],
+    source: StatusSource,
+) -> impl Iterator<Item = &Reading> {
+    signals
+        .iter()
+        .filter(move |(s, _)| *s == source)
+        .map(|(_, reading)| reading)
+}
+
+/// Signal telling whether we are busy
+pub trait PresenceSignal: fmt::Debug + Send + Sync {
+    /// Name of the signal, used in the logs
+    fn name(&self) -> &'static str;
+
+    /// Source of status fed by the signal
+    fn source(&self) -> StatusSource {
+        StatusSource::Mic
+    }
+
+    /// Read the signal, OS commands being spawned through `runner`
+    fn read(&self, runner: &dyn CommandRunner) -> Reading;
+}
+
+/// A watched application uses the microphone
+#[derive(Debug)]
+pub struct Microphone {
+    /// watched applications (`mic_app_names`)
+    pub apps: Vec<String>,
+}
+
+impl PresenceSignal for Microphone {
+    fn name(&self) -> &'static str {
+        "microphone"
+    }
+
+    fn read(&self, runner: &dyn CommandRunner) -> Reading {
+        match micscan::processes_owning_mic(runner) {
+            Ok(names) => {
+                info!("Apps using mic: {:?}", names);
+                let watched: Vec<String> = names
+                    .into_iter()
+                    .filter(|name| uses_watched_app(&self.apps, std::slice::from_ref(name)))
+                    .collect();
+                busy_if_any(watched)
+            }
+            Err(e) => Reading::Unknown(format!("{:#}", e)),
+        }
+    }
+}
+
+/// A meeting application is in a call
+#[derive(Debug)]
+pub struct Meetings {
+    /// watched applications (`meeting_app_names`)
+    pub apps: Vec<MeetingApp>,
+}
+
+impl PresenceSignal for Meetings {
+    fn name(&self) -> &'static str {
+        "meetings"
+    }
+
+    fn read(&self, runner: &dyn CommandRunner) -> Reading {
+        match meeting::apps_in_call(&self.apps, runner) {
+            Ok(apps) => busy_if_any(apps),
+            Err(e) => Reading::Unknown(format!("{:#}", e)),
+        }
+    }
+}
+
+/// A headset is connected, with `headset_dnd`
+#[derive(Debug)]
+pub struct Headset {
+    /// headsets to look for (`headset_names`)
+    pub names: Vec<String>,
+}
+
+impl PresenceSignal for Headset {
+    fn name(&self) -> &'static str {
+        "headset"
+    }
+
+    fn read(&self, runner: &dyn CommandRunner) -> Reading {
+        match micscan::find_headset(&self.names, runner) {
+            Ok(headset) => busy_if_any(headset.into_iter().collect()),
+            Err(e) => Reading::Unknown(format!("{:#}", e)),
+        }
+    }
+}
+
+/// A focus block or a focus session is in progress, as found by the main loop
+#[derive(Debug)]
+pub struct FocusTime {
+    /// focus time is in progress
+    pub active: bool,
+}
+
+impl PresenceSignal for FocusTime {
+    fn name(&self) -> &'static str {
+        "focus time"
+    }
+
+    fn source(&self) -> StatusSource {
+        StatusSource::Focus
+    }
+
+    fn read(&self, _runner: &dyn CommandRunner) -> Reading {
+        match self.active {
+            true => Reading::Busy(vec!["focus time".to_string()]),
+            false => Reading::Free,
+        }
+    }
+}
+
+/// Return busy for `reasons`, or free if there is none
+fn busy_if_any(reasons: Vec<String>) -> Reading {
+    match reasons.is_empty() {
+        true => Reading::Free,
+        false => Reading::Busy(reasons),
+    }
+}
+
+/// Return the signals read by the scans of each cycle according to `config`
+pub fn configured(config: &Config) -> Vec<Box<dyn PresenceSignal>> {
+    let mut res: Vec<Box<dyn PresenceSignal>> = vec![Box::new(Microphone {
+        apps: config.mic_app_names.clone(),
+    })];
+    if !config.meeting_apps.is_empty() {
+        res.push(Box::new(Meetings {
+            apps: config.meeting_apps.clone(),
+        }));
+    }
+    if config.headset_dnd && !config.headset_names.is_empty() {
+        res.push(Box::new(Headset {
+            names: config.headset_names.clone(),
+        }));
+    }
+    res
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::command::MockCommandRunner;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn aggregate_readings_per_source() {
+        let signals = [
+            (StatusSource::Mic, Reading::Free),
+            (
+                StatusSource::Focus,
+                FocusTime { active: true }.read(&MockCommandRunner::new()),
+            ),
+            (
+                StatusSource::Mic,
+                Reading::Unknown("No sound card".to_string()),
+            ),
+        ];
+        assert_eq!(
+            aggregate(of(&signals, StatusSource::Focus)),
+            Reading::Busy(vec!["focus time".to_string()])
+        );
+        assert_eq!(
+            aggregate(of(&signals, StatusSource::Mic)),
+            Reading::Unknown("No sound card".to_string())
+        );
+        assert_eq!(
+            aggregate(of(&signals, StatusSource::Location)),
+            Reading::Free
+        );
+    }
+}
@@ -3,29 +3,193 @@
 //! The [`State`] also provide the [`State::update_status`] function used to propagate the custom status
 //! state to the mattermost instance
 use anyhow::{Context, Result};
-use chrono::Utc;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use directories_next::ProjectDirs;
+use std::fmt;
 use std::fs;
-use tracing::{debug, info};
+use std::mem;
+use tracing::{debug, error, info};
 
-use crate::mattermost::{LoggedSession, MMCustomStatus};
+use crate::clock::{Clock, SharedClock};
+use crate::events::{Event, EventBus};
+use crate::mattermost::{LoggedSession, MMCustomStatus, Status};
+use crate::wifiscan::Network;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Name of the state file in the state directory
+pub const STATE_FILE_NAME: &str = "automattermostatus.state";
+
+/// Return the name of the state file of the instance logged as `user` on the
+/// mattermost server at `url`, so that instances using different servers or
+/// accounts do not share their state
+/// ```
+/// use lib::state::file_name;
+/// assert_eq!(
+///     file_name("https://mm.example.com:8065/", Some("bob")),
+///     "automattermostatus-bob@mm.example.com_8065.state"
+/// );
+/// assert_eq!(file_name("https://mm.example.com", None), "automattermostatus-mm.example.com.state");
+/// ```
+pub fn file_name(url: &str, user: Option<&str>) -> String {
+    let server = url
+        .split_once("://")
+        .map_or(url, |(_, rest)| rest)
+        .trim_end_matches('/');
+    let profile = match user {
+        Some(user) => format!("{}@{}", user, server),
+        None => server.to_string(),
+    };
+    let profile: String = profile
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '@' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("automattermostatus-{}.state", profile)
+}
+
+fn project_dirs() -> ProjectDirs {
+    ProjectDirs::from("net", "ams", "automattermostatus").expect("Unable to find a project dir")
+}
+
+/// Return the default state directory
+///
+/// On linux, it is `$XDG_STATE_HOME/automattermostatus` (defaulting to
+/// `~/.local/state/automattermostatus`), and the local data directory on other OS.
+pub fn default_dir() -> PathBuf {
+    #[cfg(target_os = "linux")]
+    {
+        let xdg_state_home = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")));
+        if let Some(dir) = xdg_state_home {
+            return dir.join("automattermostatus");
+        }
+    }
+    project_dirs().data_local_dir().to_owned()
+}
+
+/// Return the cache directory where the state was stored by previous versions
+pub fn legacy_dir() -> PathBuf {
+    project_dirs().cache_dir().to_owned()
+}
+
+/// Move the files of the `from` directory which do not exist in the `to`
+/// directory, and remove `from` if it is then empty.
+///
+/// Used to migrate the state stored by previous versions in the cache directory,
+/// which is periodically wiped by cleanup tools.
+pub fn migrate(from: &Path, to: &Path) -> Result<()> {
+    if !from.is_dir() || from == to {
+        return Ok(());
+    }
+    fs::create_dir_all(to).with_context(|| format!("Creating state dir {:?}", to))?;
+    for entry in fs::read_dir(from).with_context(|| format!("Reading dir {:?}", from))? {
+        let source = entry?.path();
+        let target = match source.file_name() {
+            Some(name) if source.is_file() => to.join(name),
+            _ => continue,
+        };
+        if target.exists() {
+            continue;
+        }
+        // Renaming fails when both directories are on different file systems
+        if fs::rename(&source, &target).is_err() {
+            fs::copy(&source, &target)
+                .with_context(|| format!("Copying {:?} to {:?}", source, target))?;
+            fs::remove_file(&source).with_context(|| format!("Removing {:?}", source))?;
+        }
+        info!("Moved {:?} to {:?}", source, target);
+    }
+    // Fails if some files were not migrated, which is fine
+    let _ = fs::remove_dir(from);
+    Ok(())
+}
 
 /// If more than MAX_SECS_BEFORE_FORCE_UPDATE are elapsed, we forcibly update
 /// mattermost custom status to the expected value even if there was no change in visible
 /// wifi SSIDs.
 const MAX_SECS_BEFORE_FORCE_UPDATE: u64 = 60 * 60;
 
+/// Policy used to decide if an unchanged custom status shall be sent again
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Refresh {
+    /// Only send the custom status if it has changed
+    Never,
+    /// Also send it if it was not sent for more than `MAX_SECS_BEFORE_FORCE_UPDATE`
+    Periodic,
+    /// Always send the custom status
+    Now,
+}
+
 /// Struct implementing a cache for the application state
 #[derive(Debug)]
 pub struct Cache {
     path: PathBuf,
+    /// file of the [`Requests`], next to the state file
+    requests_path: PathBuf,
 }
 
 impl Cache {
     /// Create a cache at location `path`.
     pub fn new(path: impl Into<PathBuf>) -> Self {
-        Self { path: path.into() }
+        let path = path.into();
+        Self {
+            requests_path: path.with_extension("requests"),
+            path,
+        }
+    }
+}
+
+/// Write `content` to `path` through a temporary file, so that a reader never
+/// sees a partly written file
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, content).with_context(|| format!("Writing to file {:?}", temp))?;
+    fs::rename(&temp, path).with_context(|| format!("Moving {:?} to {:?}", temp, path))
+}
+
+/// Requests made by hand to the status update loop (see `location set`,
+/// `focus start` and `pause`)
+///
+/// They are kept in their own file, written by the commands and only read by
+/// the status update loop, so that a request made while the loop runs is not
+/// overwritten when the loop saves its state.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Requests {
+    /// Location set by hand, and the UTC timestamp until which it holds (for
+    /// ever if `None`)
+    #[serde(default)]
+    pinned: Option<(Location, Option<i64>)>,
+    /// UTC timestamp of the end of the focus session started by hand
+    #[serde(default)]
+    focus_until: Option<i64>,
+    /// UTC timestamp until which the status update loop leaves the status and
+    /// presence alone
+    #[serde(default)]
+    paused_until: Option<i64>,
+}
+
+impl Requests {
+    /// Read the requests persisted in `cache`, none if there are not any
+    fn load(cache: &Cache) -> Self {
+        fs::read_to_string(&cache.requests_path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    /// Apply `change` to the requests persisted in `cache`, and return them
+    fn update(cache: &Cache, change: impl FnOnce(&mut Self)) -> Result<Self> {
+        let mut res = Self::load(cache);
+        change(&mut res);
+        write_file(&cache.requests_path, &serde_json::to_string(&res)?)
+            .context("Writing requests")?;
+        Ok(res)
     }
 }
 
@@ -38,11 +202,355 @@ pub enum Location {
     Unknown,
 }
 
+/// Custom statuses of a location, built from the `status` triplets
+///
+/// The rules are kept in the order of the triplets, and are evaluated from the
+/// first to the last one (see [`crate::find_location`]).
+#[derive(Debug, PartialEq, Clone)]
+pub struct LocationRule {
+    /// Location matched by the rule
+    pub location: Location,
+    /// Statuses of the location, used in turn when several triplets share its
+    /// wifi substring (see [`crate::DuplicatePolicy`]). Never empty.
+    pub statuses: Vec<MMCustomStatus>,
+}
+
+impl LocationRule {
+    /// Return the status used on the day of `now` (see [`crate::rotation_index`])
+    pub fn status(&self, now: DateTime<Local>) -> &MMCustomStatus {
+        &self.statuses[crate::rotation_index(self.statuses.len(), now)]
+    }
+}
+
+/// How a location was detected, kept along with it so that history and logs
+/// tell why it was chosen
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum Evidence {
+    /// A visible network SSID contains the wifi substring of the location
+    Wifi(Network),
+    /// The last known location is kept as no known SSID was found during the
+    /// grace period (see [`crate::grace`])
+    Grace,
+    /// We are outside of the work period
+    OffTime,
+    /// The location was set by hand with `location set`
+    Manual,
+}
+
+impl Evidence {
+    /// Return the visible network matching the location, if any
+    pub fn network(&self) -> Option<&Network> {
+        match self {
+            Evidence::Wifi(network) => Some(network),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Evidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Evidence::Wifi(network) => write!(f, "wifi ({})", network),
+            Evidence::Grace => write!(f, "grace period"),
+            Evidence::OffTime => write!(f, "off time"),
+            Evidence::Manual => write!(f, "hand"),
+        }
+    }
+}
+
+/// Activity counters of the status update loop since its start
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Counters {
+    /// UTC timestamp of the start of the loop
+    pub since: i64,
+    /// wifi scans done
+    pub scans: u64,
+    /// custom statuses sent
+    pub updates: u64,
+    /// custom statuses that could not be sent
+    pub failures: u64,
+    /// new logins after the session token expired
+    pub relogins: u64,
+    /// presences sent to enter or leave *do not disturb*
+    #[serde(default)]
+    pub presences: u64,
+    /// presences that could not be sent
+    #[serde(default)]
+    pub presence_failures: u64,
+}
+
+/// Prometheus text format of the counters
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# Counters since {}", local_time(self.since))?;
+        for (name, help, value) in [
+            ("scans", "Wifi scans done", self.scans),
+            ("updates", "Custom statuses sent", self.updates),
+            (
+                "failures",
+                "Custom statuses that could not be sent",
+                self.failures,
+            ),
+            ("relogins", "Logins after an expired session", self.relogins),
+            (
+                "presences",
+                "Presences sent to enter or leave do not disturb",
+                self.presences,
+            ),
+            (
+                "presence_failures",
+                "Presences that could not be sent",
+                self.presence_failures,
+            ),
+        ] {
+            writeln!(f, "# HELP automattermostatus_{}_total {}", name, help)?;
+            writeln!(f, "# TYPE automattermostatus_{}_total counter", name)?;
+            writeln!(f, "automattermostatus_{}_total {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
 /// State containing at least location info
 #[derive(Serialize, Deserialize, Debug)]
 pub struct State {
     location: Location,
     lastchange_timestamp: i64,
+    /// Last custom status successfully sent to mattermost
+    #[serde(default)]
+    last_status: Option<MMCustomStatus>,
+    /// Timestamp of the last weekly summary sent to the user
+    #[serde(default)]
+    summary_timestamp: i64,
+    /// Newer release the user was told about
+    #[serde(default)]
+    notified_version: Option<String>,
+    /// Expiry date of the token the user was told about
+    #[serde(default)]
+    notified_token_expiry: Option<NaiveDate>,
+    /// Timestamp of the detection of a manual change of the custom status
+    #[serde(default)]
+    manual_change_timestamp: i64,
+    /// Duration in seconds during which manual changes are not overwritten
+    #[serde(skip)]
+    manual_change_cooldown: u32,
+    /// Source of the current time
+    #[serde(skip)]
+    clock: SharedClock,
+    /// Whether the status texts end with a machine tag, ignored when looking
+    /// for manual changes
+    #[serde(skip)]
+    tagged: bool,
+    /// Timestamp of the last custom status successfully sent
+    #[serde(default)]
+    last_success_timestamp: i64,
+    /// Last error met when logging in or sending the custom status or the
+    /// presence, and its timestamp, until the next successful update
+    #[serde(default)]
+    last_error: Option<(i64, String)>,
+    /// How the location was detected when the custom status was last sent
+    #[serde(default)]
+    evidence: Option<Evidence>,
+    /// Requests made by hand, persisted in their own file
+    #[serde(skip)]
+    requests: Requests,
+    /// UTC timestamp of the last ping of the server, and its error if it failed
+    #[serde(default)]
+    last_ping: Option<(i64, Option<String>)>,
+    /// Activity of the status update loop since its start
+    #[serde(default)]
+    counters: Counters,
+    /// Number of consecutive failures to send the custom status
+    #[serde(default)]
+    failure_streak: u32,
+    /// Failure streak told to the user by a direct message (never if `0`)
+    #[serde(skip)]
+    report_failures_after: u32,
+}
+
+/// Separator between the status text and the machine tag (see `machine_tag`)
+pub const TAG_SEPARATOR: &str = " · ";
+
+/// Return `text` without the machine tag of any instance
+/// ```
+/// use lib::state::untagged;
+/// assert_eq!(untagged("At home · laptop"), "At home");
+/// assert_eq!(untagged("At home"), "At home");
+/// ```
+pub fn untagged(text: &str) -> &str {
+    text.rsplit_once(TAG_SEPARATOR)
+        .map_or(text, |(text, _)| text)
+}
+
+/// Return `status` whose `{ssid}` and `{signal}` placeholders are replaced by
+/// the SSID and signal strength of `network`, and `{hostname}` by the host name
+/// ```
+/// use lib::state::render;
+/// use lib::wifiscan::Network;
+/// use lib::MMCustomStatus;
+/// let status = MMCustomStatus::new("On site ({ssid}, {signal})".to_string(), "office".to_string());
+/// let network = Network::with_quality("CORP-FL3", 96);
+/// assert_eq!(render(&status, Some(&network)).text, "On site (CORP-FL3, -52 dBm)");
+/// ```
+pub fn render(status: &MMCustomStatus, network: Option<&Network>) -> MMCustomStatus {
+    let signal = match network.and_then(|n| n.signal) {
+        Some(signal) => format!("{} dBm", signal),
+        None => "unknown signal".to_string(),
+    };
+    let mut res = status.clone();
+    res.text = res
+        .text
+        .replace("{ssid}", network.map_or("", |n| &n.ssid))
+        .replace("{signal}", &signal)
+        .replace("{hostname}", crate::utils::hostname());
+    res
+}
+
+/// Format the UTC `timestamp` as local time, `0` meaning never
+fn local_time(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) if timestamp != 0 => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        _ => "never".to_string(),
+    }
+}
+
+/// Human readable name of `location`
+fn label(location: &Location) -> &str {
+    match location {
+        Location::Known(wifi) if wifi.is_empty() => "off time",
+        Location::Known(wifi) => wifi,
+        Location::Unknown => "unknown",
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Location: {} (since {})",
+            label(&self.location),
+            local_time(self.lastchange_timestamp)
+        )?;
+        if let Some(evidence) = &self.evidence {
+            writeln!(f, "Detected by: {}", evidence)?;
+        }
+        if let (Some(location), Some((_, until))) = (self.pinned_location(), &self.requests.pinned)
+        {
+            match until {
+                Some(until) => writeln!(
+                    f,
+                    "Pinned location: {} (until {})",
+                    label(location),
+                    local_time(*until)
+                )?,
+                None => writeln!(f, "Pinned location: {}", label(location))?,
+            }
+        }
+        if let Some(until) = self.focus_until() {
+            writeln!(f, "Focus until: {}", local_time(until))?;
+        }
+        if let Some(until) = self.paused_until() {
+            writeln!(f, "Paused until: {}", local_time(until))?;
+        }
+        match &self.last_status {
+            Some(status) => writeln!(f, "Last status sent: {}", status)?,
+            None => writeln!(f, "Last status sent: none")?,
+        }
+        writeln!(
+            f,
+            "Last successful API call: {}",
+            local_time(self.last_success_timestamp)
+        )?;
+        match &self.last_error {
+            Some((timestamp, error)) => {
+                writeln!(f, "Last error: {} ({})", error, local_time(*timestamp))?
+            }
+            None => writeln!(f, "Last error: none")?,
+        }
+        match &self.last_ping {
+            Some((timestamp, None)) => {
+                writeln!(f, "Server ping: up ({})", local_time(*timestamp))
+            }
+            Some((timestamp, Some(error))) => {
+                writeln!(
+                    f,
+                    "Server ping: down, {} ({})",
+                    error,
+                    local_time(*timestamp)
+                )
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Error met at a UTC timestamp
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Failure {
+    /// UTC timestamp of the error
+    pub timestamp: i64,
+    /// description of the error
+    pub error: String,
+}
+
+/// Ping of the server at a UTC timestamp
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Ping {
+    /// UTC timestamp of the ping
+    pub timestamp: i64,
+    /// why the server is down, `None` if it is up
+    pub error: Option<String>,
+}
+
+/// Custom status and presence currently set on the server
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Remote {
+    /// current custom status, if any
+    pub custom_status: Option<MMCustomStatus>,
+    /// current presence
+    pub presence: Status,
+}
+
+/// What `status show` tells of the state, serialized by `--output json`
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Summary {
+    /// wifi substring of the current location (empty for off time), `None` if
+    /// unknown
+    pub location: Option<String>,
+    /// UTC timestamp of the last location change
+    pub since: i64,
+    /// how the location was detected
+    pub evidence: Option<Evidence>,
+    /// wifi substring of the location set by hand, if it still holds
+    pub pinned_location: Option<String>,
+    /// UTC timestamp until which the location set by hand holds
+    pub pinned_until: Option<i64>,
+    /// UTC timestamp of the end of the focus session in progress
+    pub focus_until: Option<i64>,
+    /// UTC timestamp of the end of the pause in progress
+    pub paused_until: Option<i64>,
+    /// last custom status sent
+    pub last_status: Option<MMCustomStatus>,
+    /// UTC timestamp of the last custom status successfully sent
+    pub last_success: i64,
+    /// last error met when sending the custom status
+    pub last_error: Option<Failure>,
+    /// last ping of the server (`ping_server`)
+    pub server_ping: Option<Ping>,
+    /// activity counters, with `--stats`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counters: Option<Counters>,
+    /// status set on the server, with `--remote`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<Remote>,
+}
+
+/// Return the wifi substring of `location`, `None` if unknown
+fn wifi(location: &Location) -> Option<String> {
+    match location {
+        Location::Known(wifi) => Some(wifi.clone()),
+        Location::Unknown => None,
+    }
 }
 
 impl State {
@@ -50,65 +558,457 @@ impl State {
     /// or by creating an empty default one.
     pub fn new(cache: &Cache) -> Result<Self> {
         if let Ok(json) = &fs::read(&cache.path) {
-            if let Ok(res) = serde_json::from_str::<State>(&String::from_utf8_lossy(json)) {
+            if let Ok(mut res) = serde_json::from_str::<State>(&String::from_utf8_lossy(json)) {
                 debug!("Previous known location `{:?}`", res.location);
+                res.requests = Requests::load(cache);
                 return Ok(res);
             }
         }
         Ok(Self {
             location: Location::Unknown,
             lastchange_timestamp: 0,
+            last_status: None,
+            summary_timestamp: 0,
+            notified_version: None,
+            notified_token_expiry: None,
+            manual_change_timestamp: 0,
+            manual_change_cooldown: 0,
+            tagged: false,
+            last_success_timestamp: 0,
+            last_error: None,
+            evidence: None,
+            requests: Requests::load(cache),
+            last_ping: None,
+            counters: Counters::default(),
+            failure_streak: 0,
+            report_failures_after: 0,
+            clock: SharedClock::default(),
         })
     }
 
+    /// Take the current time from `clock` instead of the system clock
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Return the source of the current time
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// Current UTC timestamp according to the clock of the state
+    fn now(&self) -> i64 {
+        self.clock.now().timestamp()
+    }
+
+    /// Do not overwrite the custom status for `cooldown` seconds after the user
+    /// changed it by hand (`0` to disable the detection)
+    pub fn with_manual_change_cooldown(mut self, cooldown: u32) -> Self {
+        self.manual_change_cooldown = cooldown;
+        self
+    }
+
+    /// Ignore the machine tags ending the status texts when looking for manual
+    /// changes, so that the status set by another computer is not taken for one
+    pub fn with_machine_tag(mut self, tagged: bool) -> Self {
+        self.tagged = tagged;
+        self
+    }
+
+    /// Tell the user by a direct message when the custom status is sent again
+    /// after `failures` consecutive failures (never if `0`)
+    pub fn with_failure_report(mut self, failures: u32) -> Self {
+        self.report_failures_after = failures;
+        self
+    }
+
+    /// Return the activity counters of the status update loop
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Reset the activity counters when the status update loop starts
+    pub fn reset_counters(&mut self, cache: &Cache) -> Result<()> {
+        self.counters = Counters {
+            since: self.now(),
+            ..Default::default()
+        };
+        self.save(cache)
+    }
+
+    /// Count a cycle of the status update loop, during which the session
+    /// logged in `relogins` times again, and persist state on disk
+    pub fn count_cycle(&mut self, relogins: u64, cache: &Cache) -> Result<()> {
+        self.counters.scans += 1;
+        self.counters.relogins += relogins;
+        self.save(cache)
+    }
+
+    /// Remember the result of the ping of the server, persisted with the next
+    /// counted cycle
+    pub fn record_ping(&mut self, result: &Result<(), crate::error::Error>) {
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        self.last_ping = Some((self.now(), error));
+    }
+
+    /// Count the presence sent to enter or leave *do not disturb*, if any, or
+    /// remember the error met when sending it. Persisted with the next counted
+    /// cycle.
+    pub fn record_presence(&mut self, result: &Result<Option<Status>>) {
+        match result {
+            Ok(Some(_)) => {
+                self.counters.presences += 1;
+                self.last_error = None;
+            }
+            Ok(None) => (),
+            Err(e) => {
+                self.counters.presence_failures += 1;
+                self.last_error = Some((self.now(), format!("{:#}", e)));
+            }
+        }
+    }
+
+    /// Remember the error met when logging in, and persist it on disk
+    pub fn record_login_failure(
+        &mut self,
+        error: &crate::error::Error,
+        cache: &Cache,
+    ) -> Result<()> {
+        self.last_error = Some((self.now(), format!("Login to mattermost: {:#}", error)));
+        self.save(cache)
+    }
+
     /// Update state with location and ensure persisting of state on disk
     pub fn set_location(&mut self, location: Location, cache: &Cache) -> Result<()> {
-        info!("Set location to `{:?}`", location);
+        match &self.evidence {
+            Some(evidence) => info!("Set location to `{:?}` by {}", location, evidence),
+            None => info!("Set location to `{:?}`", location),
+        }
         self.location = location;
-        self.lastchange_timestamp = Utc::now().timestamp();
-        fs::write(
+        self.lastchange_timestamp = self.now();
+        self.save(cache)
+    }
+
+    /// Pin the location to `location` until the UTC timestamp `until` (for ever
+    /// if `None`), whatever the visible networks, and persist the request on disk
+    pub fn pin(&mut self, location: Location, until: Option<i64>, cache: &Cache) -> Result<()> {
+        info!("Pin location to `{:?}` until {:?}", location, until);
+        self.requests =
+            Requests::update(cache, |requests| requests.pinned = Some((location, until)))?;
+        Ok(())
+    }
+
+    /// Go back to the location detected by scans and persist the request on disk
+    pub fn unpin(&mut self, cache: &Cache) -> Result<()> {
+        self.requests = Requests::update(cache, |requests| requests.pinned = None)?;
+        Ok(())
+    }
+
+    /// Return the summary of the state printed by `status show`, without the
+    /// counters nor the status set on the server
+    pub fn summary(&self) -> Summary {
+        let pinned_location = self.pinned_location();
+        Summary {
+            location: wifi(&self.location),
+            since: self.lastchange_timestamp,
+            evidence: self.evidence.clone(),
+            pinned_location: pinned_location.and_then(wifi),
+            pinned_until: pinned_location
+                .and(self.requests.pinned.as_ref())
+                .and_then(|(_, until)| *until),
+            focus_until: self.focus_until(),
+            paused_until: self.paused_until(),
+            last_status: self.last_status.clone(),
+            last_success: self.last_success_timestamp,
+            last_error: self.last_error.as_ref().map(|(timestamp, error)| Failure {
+                timestamp: *timestamp,
+                error: error.clone(),
+            }),
+            server_ping: self.last_ping.as_ref().map(|(timestamp, error)| Ping {
+                timestamp: *timestamp,
+                error: error.clone(),
+            }),
+            counters: None,
+            remote: None,
+        }
+    }
+
+    /// Return the last location detected or set
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    /// Return the last custom status sent
+    pub fn last_status(&self) -> Option<&MMCustomStatus> {
+        self.last_status.as_ref()
+    }
+
+    /// Return the location set by hand, if it still holds
+    pub fn pinned_location(&self) -> Option<&Location> {
+        match &self.requests.pinned {
+            Some((location, None)) => Some(location),
+            Some((location, Some(until))) if self.now() < *until => Some(location),
+            _ => None,
+        }
+    }
+
+    /// Start a focus session until the UTC timestamp `until`, and persist the
+    /// request on disk
+    pub fn start_focus(&mut self, until: i64, cache: &Cache) -> Result<()> {
+        info!("Focus until {}", local_time(until));
+        self.requests = Requests::update(cache, |requests| requests.focus_until = Some(until))?;
+        Ok(())
+    }
+
+    /// Stop the focus session and persist the request on disk
+    pub fn stop_focus(&mut self, cache: &Cache) -> Result<()> {
+        self.requests = Requests::update(cache, |requests| requests.focus_until = None)?;
+        Ok(())
+    }
+
+    /// Return the end of the focus session, if it is in progress
+    pub fn focus_until(&self) -> Option<i64> {
+        self.requests
+            .focus_until
+            .filter(|until| self.now() < *until)
+    }
+
+    /// Pause the status update loop until the UTC timestamp `until`, and
+    /// persist the request on disk
+    pub fn pause(&mut self, until: i64, cache: &Cache) -> Result<()> {
+        info!("Paused until {}", local_time(until));
+        self.requests = Requests::update(cache, |requests| requests.paused_until = Some(until))?;
+        Ok(())
+    }
+
+    /// Resume the status update loop before the end of the pause and persist
+    /// the request on disk
+    pub fn resume(&mut self, cache: &Cache) -> Result<()> {
+        self.requests = Requests::update(cache, |requests| requests.paused_until = None)?;
+        Ok(())
+    }
+
+    /// Return the end of the pause, if it is in progress
+    pub fn paused_until(&self) -> Option<i64> {
+        self.requests
+            .paused_until
+            .filter(|until| self.now() < *until)
+    }
+
+    /// Read again the requests persisted in `cache`, as they are made by
+    /// another process (see `location set`, `focus start` and `pause`)
+    pub fn reload_requests(&mut self, cache: &Cache) {
+        let requests = Requests::load(cache);
+        if requests.pinned != self.requests.pinned {
+            debug!("Pinned location changed to {:?}", requests.pinned);
+        }
+        if requests.focus_until != self.requests.focus_until {
+            debug!("Focus session changed to {:?}", requests.focus_until);
+        }
+        if requests.paused_until != self.requests.paused_until {
+            debug!("Pause changed to {:?}", requests.paused_until);
+        }
+        self.requests = requests;
+    }
+
+    /// Forget the last custom status sent, so that it is sent again at next
+    /// update, and persist state on disk
+    pub fn forget_status(&mut self, cache: &Cache) -> Result<()> {
+        self.last_status = None;
+        self.save(cache)
+    }
+
+    /// Timestamp of the last weekly summary sent to the user
+    pub fn summary_timestamp(&self) -> i64 {
+        self.summary_timestamp
+    }
+
+    /// Update the timestamp of the last weekly summary and persist state on disk
+    pub fn set_summary_timestamp(&mut self, timestamp: i64, cache: &Cache) -> Result<()> {
+        self.summary_timestamp = timestamp;
+        self.save(cache)
+    }
+
+    /// Newer release the user was told about
+    pub fn notified_version(&self) -> Option<&str> {
+        self.notified_version.as_deref()
+    }
+
+    /// Update the newer release the user was told about and persist state on disk
+    pub fn set_notified_version(&mut self, version: &str, cache: &Cache) -> Result<()> {
+        self.notified_version = Some(version.to_string());
+        self.save(cache)
+    }
+
+    /// Expiry date of the token the user was told about
+    pub fn notified_token_expiry(&self) -> Option<NaiveDate> {
+        self.notified_token_expiry
+    }
+
+    /// Update the expiry date of the token the user was told about and persist
+    /// state on disk
+    pub fn set_notified_token_expiry(
+        &mut self,
+        expires_on: NaiveDate,
+        cache: &Cache,
+    ) -> Result<()> {
+        self.notified_token_expiry = Some(expires_on);
+        self.save(cache)
+    }
+
+    /// Return `true` if the user changed the custom status we sent by hand
+    /// less than `manual_change_cooldown` seconds ago.
+    ///
+    /// A cleared custom status is not considered as a manual change, as it may
+    /// just have expired.
+    fn in_manual_change_cooldown(&mut self, session: &mut LoggedSession, cache: &Cache) -> bool {
+        if self.manual_change_cooldown == 0 {
+            return false;
+        }
+        let now = self.now();
+        let untagged = |text| if self.tagged { untagged(text) } else { text };
+        if let (0, Some(last)) = (self.manual_change_timestamp, &self.last_status) {
+            match MMCustomStatus::fetch(session) {
+                Ok(Some(current))
+                    if (&current.emoji, untagged(&current.text))
+                        != (&last.emoji, untagged(&last.text)) =>
+                {
+                    info!("Custom status manually changed to {}", current);
+                    self.manual_change_timestamp = now;
+                    if let Err(e) = self.save(cache) {
+                        error!("{:#}", e)
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => debug!("Unable to get current custom status: {}", e),
+            }
+        }
+        if self.manual_change_pending() {
+            info!(
+                "Not overwriting manual custom status for {}s",
+                i64::from(self.manual_change_cooldown) - (now - self.manual_change_timestamp)
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Return `true` while the cooldown following a manual change of the
+    /// custom status is not over
+    pub fn manual_change_pending(&self) -> bool {
+        self.manual_change_timestamp != 0
+            && self.now() - self.manual_change_timestamp < self.manual_change_cooldown.into()
+    }
+
+    /// Tell the user about the failures preceding a successful send, if they
+    /// are at least `report_failures_after`, and reset their count
+    fn report_failure_streak(&mut self, session: &mut LoggedSession) {
+        let failures = mem::take(&mut self.failure_streak);
+        if self.report_failures_after == 0 || failures < self.report_failures_after {
+            return;
+        }
+        let message = format!(
+            "automattermostatus on host {} failed {} times to update your status: {}",
+            crate::utils::hostname(),
+            failures,
+            self.last_error.as_ref().map_or("unknown error", |(_, e)| e)
+        );
+        info!("{}", message);
+        if let Err(e) = session.send_direct_message(&message) {
+            error!("Fail to report the failures : {}", e)
+        }
+    }
+
+    fn save(&self, cache: &Cache) -> Result<()> {
+        write_file(
             &cache.path,
-            serde_json::to_string(&self)
+            &serde_json::to_string(&self)
                 .unwrap_or_else(|_| panic!("Serialization of State Failed :{:?}", &self)),
         )
-        .with_context(|| format!("Writing to cache file {:?}", cache.path))?;
-        Ok(())
+        .with_context(|| format!("Writing to cache file {:?}", cache.path))
     }
 
     /// Update mattermost status depending upon current state
     ///
-    /// If `current_location` is Unknown, then nothing is changed.
-    /// If `current_location` is still the same for more than `MAX_SECS_BEFORE_FORCE_UPDATE`
+    /// If there is no `status` (as for an Unknown location), then nothing is changed.
+    /// If `current_location` and the status to send are the same as the last ones sent,
+    /// nothing is sent unless `refresh` policy asks for it (see [`Refresh`]):
     /// then we force update the mattermost status in order to catch up with desynchronise state
     /// Else we update mattermost status to the one associated to `current_location`.
+    ///
+    /// The `{ssid}` and `{signal}` placeholders of the status text are replaced by
+    /// the ones of the visible network matching `current_location` according to
+    /// `evidence` (see [`render`]). A change of `evidence` alone does not trigger
+    /// an update.
+    ///
+    /// Location changes, sent status and send failures are published on `events`.
+    #[allow(clippy::too_many_arguments)]
     pub fn update_status(
         &mut self,
         current_location: Location,
-        status: Option<&mut MMCustomStatus>,
+        status: Option<&MMCustomStatus>,
+        evidence: Option<&Evidence>,
         session: &mut LoggedSession,
         cache: &Cache,
-        delay_between_polling: u64,
+        events: &EventBus,
+        refresh: Refresh,
     ) -> Result<()> {
-        if current_location == Location::Unknown {
+        let Some(template) = status else {
             return Ok(());
-        } else if current_location == self.location {
-            // Less than max seconds have elapsed.
-            // No need to update MM status again
-            let elapsed_sec: u64 = (Utc::now().timestamp() - self.lastchange_timestamp)
-                .try_into()
-                .unwrap();
-            if delay_between_polling * 2 < elapsed_sec
-                && elapsed_sec <= MAX_SECS_BEFORE_FORCE_UPDATE
-            {
+        };
+        if refresh != Refresh::Now
+            && current_location == self.location
+            && self.last_status.as_ref()
+                == Some(&render(
+                    template,
+                    self.evidence.as_ref().and_then(Evidence::network),
+                ))
+        {
+            // A clock gone back before the last change (NTP correction) makes
+            // the status stale
+            let elapsed_sec =
+                u64::try_from(self.now() - self.lastchange_timestamp).unwrap_or(u64::MAX);
+            if refresh == Refresh::Never || elapsed_sec <= MAX_SECS_BEFORE_FORCE_UPDATE {
+                // Less than max seconds have elapsed.
+                // No need to update MM status again
                 debug!(
                     "No change for {}s : no update to mattermost status",
-                    MAX_SECS_BEFORE_FORCE_UPDATE
+                    elapsed_sec
                 );
                 return Ok(());
             }
         }
+        if self.in_manual_change_cooldown(session, cache) {
+            return Ok(());
+        }
         // We update the status on MM
-        status.unwrap().send(session)?;
+        let mut status = render(template, evidence.and_then(Evidence::network));
+        if let Err(e) = status.send(session) {
+            self.counters.failures += 1;
+            self.failure_streak += 1;
+            events.publish(Event::SendFailed(e.to_string()));
+            let e = anyhow::Error::new(e);
+            self.last_error = Some((self.now(), format!("{:#}", e)));
+            if let Err(e) = self.save(cache) {
+                error!("{:#}", e)
+            }
+            return Err(e);
+        }
+        events.publish(Event::StatusSent(status.clone()));
+        self.counters.updates += 1;
+        self.report_failure_streak(session);
+        self.last_status = Some(status);
+        self.evidence = evidence.cloned();
+        self.last_success_timestamp = self.now();
+        self.last_error = None;
+        self.manual_change_timestamp = 0;
+        if current_location != self.location {
+            events.publish(Event::LocationChanged {
+                from: self.location.clone(),
+                to: current_location.clone(),
+            });
+        }
         // We update the location (only if setting mattermost status succeed)
         self.set_location(current_location, cache)?;
         Ok(())
@@ -118,7 +1018,12 @@ impl State {
 #[cfg(test)]
 mod should {
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::mattermost::testing::logged_session;
+    use crate::mattermost::{BaseSession, Session};
+    use httpmock::prelude::*;
     use mktemp::Temp;
+    use std::sync::Arc;
     use test_log::test; // Automatically trace tests
     #[test]
     fn remember_state() -> Result<()> {
@@ -134,4 +1039,449 @@ mod should {
         assert_eq!(state.location, Location::Known("work".to_string()));
         Ok(())
     }
+
+    #[test]
+    fn keep_the_last_error_until_an_update() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?;
+        let error = crate::error::Error::Auth(anyhow::anyhow!("Login authentication failed"));
+        state.record_login_failure(&error, &cache)?;
+        assert!(State::new(&cache)?
+            .to_string()
+            .contains("Last error: Login to mattermost: Login authentication failed ("));
+        state.record_presence(&Ok(None));
+        assert!(state.summary().last_error.is_some());
+        state.record_presence(&Err(anyhow::anyhow!("Fail to set do not disturb")));
+        assert!(state
+            .to_string()
+            .contains("Last error: Fail to set do not disturb ("));
+        state.record_presence(&Ok(Some(Status::Dnd)));
+        assert!(state.to_string().contains("Last error: none\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn count_cycles() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?;
+        state.count_cycle(0, &cache)?;
+        state.reset_counters(&cache)?;
+        state.count_cycle(1, &cache)?;
+        state.record_presence(&Ok(Some(Status::Dnd)));
+        state.record_presence(&Err(anyhow::anyhow!("Fail to leave do not disturb")));
+        state.count_cycle(0, &cache)?;
+        let state = State::new(&cache)?;
+        assert_eq!(state.counters().scans, 2);
+        assert_eq!(state.counters().relogins, 1);
+        assert_eq!(state.counters().presences, 1);
+        assert_eq!(state.counters().presence_failures, 1);
+        let stats = state.counters().to_string();
+        assert!(stats.contains("\nautomattermostatus_scans_total 2\n"));
+        assert!(stats.contains("# TYPE automattermostatus_updates_total counter\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn pin_location() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?;
+        let mut other = State::new(&cache)?;
+        other.pin(Location::Known("office".to_string()), None, &cache)?;
+        assert_eq!(state.pinned_location(), None);
+        state.reload_requests(&cache);
+        assert_eq!(
+            state.pinned_location(),
+            Some(&Location::Known("office".to_string()))
+        );
+        assert!(state.to_string().contains("Pinned location: office\n"));
+        let summary = serde_json::to_value(state.summary())?;
+        assert_eq!(summary["pinned_location"], "office");
+        assert_eq!(summary["location"], serde_json::Value::Null);
+        assert!(summary.get("counters").is_none());
+        // An expired pin is ignored
+        let past = state.clock().now().timestamp() - 1;
+        state.pin(Location::Known("office".to_string()), Some(past), &cache)?;
+        assert_eq!(state.pinned_location(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn reload_focus_sessions() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?;
+        let mut other = State::new(&cache)?;
+        let until = state.clock().now().timestamp() + 1500;
+        other.start_focus(until, &cache)?;
+        assert_eq!(state.focus_until(), None);
+        state.reload_requests(&cache);
+        assert_eq!(state.focus_until(), Some(until));
+        assert!(state.to_string().contains("Focus until: "));
+        other.stop_focus(&cache)?;
+        state.reload_requests(&cache);
+        assert_eq!(state.focus_until(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn reload_pauses() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?;
+        let mut other = State::new(&cache)?;
+        let until = state.clock().now().timestamp() + 3600;
+        other.pause(until, &cache)?;
+        state.reload_requests(&cache);
+        assert_eq!(state.paused_until(), Some(until));
+        assert!(state.to_string().contains("Paused until: "));
+        other.resume(&cache)?;
+        state.reload_requests(&cache);
+        assert_eq!(state.paused_until(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_requests_made_while_running() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?;
+        state.reload_requests(&cache);
+        // Made while the status update loop sleeps
+        let until = state.clock().now().timestamp() + 1500;
+        let office = Location::Known("office".to_string());
+        State::new(&cache)?.start_focus(until, &cache)?;
+        State::new(&cache)?.pin(office.clone(), None, &cache)?;
+        state.count_cycle(0, &cache)?;
+        assert_eq!(State::new(&cache)?.focus_until(), Some(until));
+        state.reload_requests(&cache);
+        assert_eq!(state.focus_until(), Some(until));
+        assert_eq!(state.pinned_location(), Some(&office));
+        Ok(())
+    }
+
+    #[test]
+    fn show_the_last_ping() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?;
+        assert!(!state.to_string().contains("Server ping"));
+        state.record_ping(&Err(crate::error::Error::Network(anyhow::anyhow!(
+            "connection refused"
+        ))));
+        state.count_cycle(0, &cache)?;
+        let state = State::new(&cache)?;
+        assert!(state
+            .to_string()
+            .contains("Server ping: down, connection refused ("));
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_legacy_state() -> Result<()> {
+        let legacy = Temp::new_dir().unwrap();
+        let state_dir = Temp::new_dir().unwrap().join("state");
+        fs::write(legacy.join(STATE_FILE_NAME), "legacy")?;
+        fs::write(legacy.join("other"), "legacy")?;
+        fs::create_dir_all(&state_dir)?;
+        fs::write(state_dir.join("other"), "new")?;
+        migrate(&legacy, &state_dir)?;
+        assert_eq!(
+            fs::read_to_string(state_dir.join(STATE_FILE_NAME))?,
+            "legacy"
+        );
+        assert_eq!(fs::read_to_string(state_dir.join("other"))?, "new");
+        // The file which was not migrated is kept
+        assert!(legacy.join("other").exists());
+        assert!(!legacy.join(STATE_FILE_NAME).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn report_last_error() -> Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        server.mock(|expect, resp_with| {
+            expect.method(PUT).path("/api/v4/users/me/status/custom");
+            resp_with.status(500).body("oops");
+        });
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?;
+        assert!(state.to_string().ends_with(
+            "Last status sent: none\nLast successful API call: never\nLast error: none\n"
+        ));
+        let status = MMCustomStatus::new("home".to_string(), "house".to_string());
+        assert!(state
+            .update_status(
+                Location::Known("home".to_string()),
+                Some(&status),
+                None,
+                &mut session,
+                &cache,
+                &EventBus::new(),
+                Refresh::Now,
+            )
+            .is_err());
+        // The error is persisted
+        let state = State::new(&cache)?;
+        assert_eq!(state.counters().failures, 1);
+        assert!(state
+            .to_string()
+            .contains("/api/v4/users/me/status/custom: status code 500"));
+        Ok(())
+    }
+
+    #[test]
+    fn report_failures_once_sent_again() -> Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let mut failing = server.mock(|expect, resp_with| {
+            expect.method(PUT).path("/api/v4/users/me/status/custom");
+            resp_with.status(400).body("oops");
+        });
+        server.mock(|expect, resp_with| {
+            expect.method(POST).path("/api/v4/channels/direct");
+            resp_with
+                .status(201)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"channel_id"}));
+        });
+        let post_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(POST)
+                .path("/api/v4/posts")
+                .body_contains("failed 2 times to update your status")
+                .body_contains("status code 400");
+            resp_with.status(201).body("{}");
+        });
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?.with_failure_report(2);
+        let status = MMCustomStatus::new("home".to_string(), "house".to_string());
+        let update = |state: &mut State, session: &mut LoggedSession| {
+            state.update_status(
+                Location::Known("home".to_string()),
+                Some(&status),
+                None,
+                session,
+                &cache,
+                &EventBus::new(),
+                Refresh::Now,
+            )
+        };
+        assert!(update(&mut state, &mut session).is_err());
+        assert!(update(&mut state, &mut session).is_err());
+        failing.delete();
+        server.mock(|expect, resp_with| {
+            expect.method(PUT).path("/api/v4/users/me/status/custom");
+            resp_with.status(200).body("ok");
+        });
+        update(&mut state, &mut session)?;
+        update(&mut state, &mut session)?;
+        post_mock.assert_hits(1);
+        Ok(())
+    }
+
+    #[test]
+    fn respect_manual_changes() -> Result<()> {
+        let server = MockServer::start();
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/users/me");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"user_id", "props": {
+                    "customStatus": "{\"emoji\":\"calendar\",\"text\":\"In a meeting\"}"
+                }}));
+        });
+        let put_mock = server.mock(|expect, resp_with| {
+            expect.method(PUT).path("/api/v4/users/me/status/custom");
+            resp_with.status(200).body("ok");
+        });
+        let mut session = Session::new(&server.url("")).with_token("token").login()?;
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?.with_manual_change_cooldown(3600);
+        state.last_status = Some(MMCustomStatus::new("home".to_string(), "house".to_string()));
+        let status = MMCustomStatus::new("office".to_string(), "office".to_string());
+        state.update_status(
+            Location::Known("office".to_string()),
+            Some(&status),
+            None,
+            &mut session,
+            &cache,
+            &EventBus::new(),
+            Refresh::Now,
+        )?;
+        put_mock.assert_hits(0);
+        assert_ne!(state.manual_change_timestamp, 0);
+
+        // Once the cooldown is elapsed, the status is overwritten
+        state.manual_change_timestamp -= 3600;
+        state.update_status(
+            Location::Known("office".to_string()),
+            Some(&status),
+            None,
+            &mut session,
+            &cache,
+            &EventBus::new(),
+            Refresh::Now,
+        )?;
+        put_mock.assert_hits(1);
+        Ok(())
+    }
+
+    #[test]
+    fn skip_redundant_updates() -> Result<()> {
+        let server = MockServer::start();
+        let login_mock = server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v4/users/me");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({"id":"user_id"}));
+        });
+        let home_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(PUT)
+                .path("/api/v4/users/me/status/custom")
+                .json_body(serde_json::json!({"emoji":"house","text":"home"}));
+            resp_with.status(200).body("ok");
+        });
+        let away_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(PUT)
+                .path("/api/v4/users/me/status/custom")
+                .json_body(serde_json::json!({"emoji":"car","text":"home"}));
+            resp_with.status(200).body("ok");
+        });
+        let mut session = Session::new(&server.url("")).with_token("token").login()?;
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let mut state = State::new(&cache)?;
+        let events = EventBus::new();
+        let home = Location::Known("home".to_string());
+        let status = MMCustomStatus::new("home".to_string(), "house".to_string());
+
+        state.update_status(
+            home.clone(),
+            Some(&status),
+            None,
+            &mut session,
+            &cache,
+            &events,
+            Refresh::Periodic,
+        )?;
+        state.update_status(
+            home.clone(),
+            Some(&status),
+            None,
+            &mut session,
+            &cache,
+            &events,
+            Refresh::Periodic,
+        )?;
+        // Last sent status is persisted
+        let mut state = State::new(&cache)?;
+        state.update_status(
+            home.clone(),
+            Some(&status),
+            None,
+            &mut session,
+            &cache,
+            &events,
+            Refresh::Periodic,
+        )?;
+        home_mock.assert_hits(1);
+
+        // A changed payload is sent even if the location is the same
+        let status = MMCustomStatus::new("home".to_string(), "car".to_string());
+        state.update_status(
+            home,
+            Some(&status),
+            None,
+            &mut session,
+            &cache,
+            &events,
+            Refresh::Periodic,
+        )?;
+        away_mock.assert_hits(1);
+        login_mock.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn force_update_after_an_hour() -> Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let put_mock = server.mock(|expect, resp_with| {
+            expect.method(PUT).path("/api/v4/users/me/status/custom");
+            resp_with.status(200).body("ok");
+        });
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let clock = Arc::new(ManualClock::new(
+            Local.with_ymd_and_hms(2024, 1, 15, 8, 0, 0).unwrap(),
+        ));
+        let mut state = State::new(&cache)?.with_clock(SharedClock::from(clock.clone()));
+        let home = Location::Known("home".to_string());
+        let status = MMCustomStatus::new("home".to_string(), "house".to_string());
+        let mut update = |state: &mut State| {
+            state.update_status(
+                home.clone(),
+                Some(&status),
+                None,
+                &mut session,
+                &cache,
+                &EventBus::new(),
+                Refresh::Periodic,
+            )
+        };
+        update(&mut state)?;
+        clock.advance(chrono::Duration::minutes(59));
+        update(&mut state)?;
+        put_mock.assert_hits(1);
+        clock.advance(chrono::Duration::minutes(2));
+        update(&mut state)?;
+        put_mock.assert_hits(2);
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_after_a_backward_clock_jump() -> Result<()> {
+        let server = MockServer::start();
+        let mut session = logged_session(&server)?;
+        let put_mock = server.mock(|expect, resp_with| {
+            expect.method(PUT).path("/api/v4/users/me/status/custom");
+            resp_with.status(200).body("ok");
+        });
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let cache = Cache::new(temp);
+        let clock = Arc::new(ManualClock::new(
+            Local.with_ymd_and_hms(2024, 1, 15, 8, 0, 0).unwrap(),
+        ));
+        let mut state = State::new(&cache)?.with_clock(SharedClock::from(clock.clone()));
+        let home = Location::Known("home".to_string());
+        let status = MMCustomStatus::new("home".to_string(), "house".to_string());
+        let mut update = |state: &mut State| {
+            state.update_status(
+                home.clone(),
+                Some(&status),
+                None,
+                &mut session,
+                &cache,
+                &EventBus::new(),
+                Refresh::Periodic,
+            )
+        };
+        update(&mut state)?;
+        put_mock.assert_hits(1);
+        clock.advance(-chrono::Duration::hours(2));
+        update(&mut state)?;
+        put_mock.assert_hits(2);
+        Ok(())
+    }
 }
@@ -0,0 +1,320 @@
+//! Time spent per location, derived from the history of detected locations
+//!
+//! Each detected location change is appended to a history file (JSON lines) in the
+//! state directory. A location lasts until the next change, but never past the end
+//! of its day, so that a computer switched off in the evening does not count the
+//! whole night.
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::debug;
+
+use crate::state::{Evidence, Location};
+
+/// Name of the history file in the state directory
+pub const HISTORY_FILE_NAME: &str = "automattermostatus.history";
+
+/// Location detected at `timestamp`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Entry {
+    /// UTC timestamp of the detection
+    pub timestamp: i64,
+    /// Detected location
+    pub location: Location,
+    /// How the location was detected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evidence: Option<Evidence>,
+}
+
+/// History of location changes persisted in a file
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    last: Option<Location>,
+}
+
+impl History {
+    /// Open the history stored at `path` (created on first record)
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let mut res = Self {
+            path: path.into(),
+            last: None,
+        };
+        res.last = res
+            .entries()
+            .ok()
+            .and_then(|e| e.last().cloned())
+            .map(|e| e.location);
+        res
+    }
+
+    /// Append `location`, detected according to `evidence`, to the history if it
+    /// differs from the last recorded one
+    pub fn record(&mut self, location: &Location, evidence: Option<&Evidence>) -> Result<()> {
+        if self.last.as_ref() == Some(location) {
+            return Ok(());
+        }
+        let entry = Entry {
+            timestamp: Utc::now().timestamp(),
+            location: location.clone(),
+            evidence: evidence.cloned(),
+        };
+        debug!("Recording {:?} in history", entry);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Opening history file {:?}", self.path))?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)
+            .with_context(|| format!("Writing to history file {:?}", self.path))?;
+        self.last = Some(location.clone());
+        Ok(())
+    }
+
+    /// Return the recorded entries, oldest first
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        fs::read_to_string(&self.path)
+            .with_context(|| format!("Reading history file {:?}", self.path))?
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| serde_json::from_str(l).context("Parsing history file"))
+            .collect()
+    }
+}
+
+/// Human readable name of `location`
+fn label(location: &Location) -> String {
+    match location {
+        Location::Known(wifi) if wifi.is_empty() => "off time".to_string(),
+        Location::Known(wifi) => wifi.to_owned(),
+        Location::Unknown => "unknown".to_string(),
+    }
+}
+
+/// Return local midnight at the start of the day of `time`
+fn start_of_day(time: DateTime<Local>) -> DateTime<Local> {
+    Local
+        .from_local_datetime(&time.date_naive().and_time(NaiveTime::MIN))
+        .earliest()
+        .unwrap_or(time)
+}
+
+/// Time spent in each location between `from` and `to`
+pub fn totals(
+    entries: &[Entry],
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> BTreeMap<String, Duration> {
+    let mut res = BTreeMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let Some(start) = Local.timestamp_opt(entry.timestamp, 0).single() else {
+            continue;
+        };
+        let end_of_day = start_of_day(start) + Duration::days(1);
+        let end = entries
+            .get(i + 1)
+            .and_then(|next| Local.timestamp_opt(next.timestamp, 0).single())
+            .map_or(end_of_day, |next| next.min(end_of_day));
+        let (start, end) = (start.max(from), end.min(to));
+        if start < end {
+            *res.entry(label(&entry.location))
+                .or_insert_with(Duration::zero) += end - start;
+        }
+    }
+    res
+}
+
+/// Format `duration` as hours and minutes
+fn hm(duration: Duration) -> String {
+    format!(
+        "{}h{:02}",
+        duration.num_hours(),
+        duration.num_minutes() % 60
+    )
+}
+
+/// Time spent in each location during a day
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Day {
+    /// the day
+    pub date: NaiveDate,
+    /// seconds spent per location
+    pub seconds: BTreeMap<String, i64>,
+}
+
+/// Time spent in each location during an iso week, day by day
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Week {
+    /// year of the Monday of the week
+    pub year: i32,
+    /// iso week number
+    pub week: u32,
+    /// elapsed days of the week, from Monday
+    pub days: Vec<Day>,
+    /// seconds spent per location during the week
+    pub seconds: BTreeMap<String, i64>,
+}
+
+/// Return the seconds of each duration of `totals`
+fn seconds(totals: BTreeMap<String, Duration>) -> BTreeMap<String, i64> {
+    totals
+        .into_iter()
+        .map(|(location, duration)| (location, duration.num_seconds()))
+        .collect()
+}
+
+/// Format `seconds` per location as hours and minutes
+fn details(seconds: &BTreeMap<String, i64>) -> String {
+    seconds
+        .iter()
+        .map(|(l, s)| format!("{} {}", l, hm(Duration::seconds(*s))))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for Week {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Week {} of {}", self.week, self.year)?;
+        for day in &self.days {
+            writeln!(
+                f,
+                "- {} {}: {}",
+                day.date.weekday(),
+                day.date.format("%d/%m"),
+                details(&day.seconds)
+            )?;
+        }
+        writeln!(f, "Total: {}", details(&self.seconds))
+    }
+}
+
+/// Daily and weekly time spent per location for the week of `day`, up to `now`
+pub fn week(entries: &[Entry], day: DateTime<Local>, now: DateTime<Local>) -> Week {
+    let monday = start_of_day(day) - Duration::days(day.weekday().num_days_from_monday().into());
+    let days = (0..7)
+        .map(|offset| monday + Duration::days(offset))
+        .take_while(|from| *from <= now)
+        .map(|from| Day {
+            date: from.date_naive(),
+            seconds: seconds(totals(entries, from, (from + Duration::days(1)).min(now))),
+        })
+        .collect();
+    Week {
+        year: monday.year(),
+        week: monday.iso_week().week(),
+        days,
+        seconds: seconds(totals(
+            entries,
+            monday,
+            (monday + Duration::days(7)).min(now),
+        )),
+    }
+}
+
+/// Daily and weekly time spent per location for the week of `day`, up to `now`,
+/// as text
+pub fn weekly_summary(entries: &[Entry], day: DateTime<Local>, now: DateTime<Local>) -> String {
+    week(entries, day, now).to_string()
+}
+
+/// Return `true` when a weekly summary is due on `now`, that is on Monday if
+/// the last one was not sent during the same week.
+pub fn weekly_summary_due(last_sent: i64, now: DateTime<Local>) -> bool {
+    let last_week = Local
+        .timestamp_opt(last_sent, 0)
+        .single()
+        .map(|d| d.iso_week());
+    now.weekday() == Weekday::Mon && last_week != Some(now.iso_week())
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    fn at(day: u32, hour: u32) -> DateTime<Local> {
+        // 2023-01-02 is a Monday
+        Local.with_ymd_and_hms(2023, 1, day, hour, 0, 0).unwrap()
+    }
+
+    fn entry(time: DateTime<Local>, wifi: &str) -> Entry {
+        Entry {
+            timestamp: time.timestamp(),
+            location: Location::Known(wifi.to_string()),
+            evidence: None,
+        }
+    }
+
+    #[test]
+    fn record_evidence() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let mut history = History::new(&temp);
+        let evidence = Evidence::Wifi(crate::wifiscan::Network::with_quality("home-5G", 80));
+        history.record(&Location::Known("home".to_string()), Some(&evidence))?;
+        history.record(&Location::Known("".to_string()), Some(&Evidence::OffTime))?;
+        let evidences: Vec<_> = history.entries()?.into_iter().map(|e| e.evidence).collect();
+        assert_eq!(evidences, vec![Some(evidence), Some(Evidence::OffTime)]);
+        Ok(())
+    }
+
+    #[test]
+    fn record_only_changes() -> Result<()> {
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let mut history = History::new(&temp);
+        history.record(&Location::Known("home".to_string()), None)?;
+        history.record(&Location::Known("home".to_string()), None)?;
+        let mut history = History::new(&temp);
+        history.record(&Location::Known("home".to_string()), None)?;
+        history.record(&Location::Unknown, None)?;
+        let locations: Vec<Location> = history.entries()?.into_iter().map(|e| e.location).collect();
+        assert_eq!(
+            locations,
+            vec![Location::Known("home".to_string()), Location::Unknown]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sum_time_per_location() {
+        let entries = vec![
+            entry(at(2, 8), "home"),
+            entry(at(2, 10), "corp"),
+            entry(at(2, 18), ""),
+            entry(at(3, 9), "corp"),
+        ];
+        let res = totals(&entries, at(2, 0), at(3, 12));
+        assert_eq!(res["home"], Duration::hours(2));
+        assert_eq!(res["corp"], Duration::hours(11));
+        // Off time stops at midnight
+        assert_eq!(res["off time"], Duration::hours(6));
+    }
+
+    #[test]
+    fn summarize_week() {
+        let entries = vec![entry(at(2, 9), "home"), entry(at(3, 9), "corp")];
+        let summary = weekly_summary(&entries, at(3, 12), at(3, 12));
+        assert_eq!(
+            summary,
+            "Week 1 of 2023\n- Mon 02/01: home 15h00\n- Tue 03/01: corp 3h00\nTotal: corp 3h00, home 15h00\n"
+        );
+        let week = serde_json::to_value(week(&entries, at(3, 12), at(3, 12))).unwrap();
+        assert_eq!(week["days"][1]["date"], "2023-01-03");
+        assert_eq!(week["seconds"]["home"], 15 * 3600);
+    }
+
+    #[test]
+    fn send_summary_once_on_monday() {
+        assert!(weekly_summary_due(0, at(2, 9)));
+        assert!(!weekly_summary_due(at(2, 8).timestamp(), at(2, 9)));
+        assert!(!weekly_summary_due(0, at(3, 9)));
+    }
+}
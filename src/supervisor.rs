@@ -0,0 +1,113 @@
+//! Recovery from repeated failures of the main loop.
+//!
+//! Instead of logging the same error every `delay` seconds forever, the
+//! [`Supervisor`] counts the consecutive failed iterations (wifi scan or
+//! mattermost API failures). Once `max_failures` is reached, the session and wifi
+//! handles are rebuilt and the delay between iterations is doubled, up to
+//! [`MAX_BACKOFF_SECS`], until an iteration succeeds.
+use tracing::{info, warn};
+
+/// Maximum delay in seconds between two iterations while failing
+pub const MAX_BACKOFF_SECS: u32 = 60 * 60;
+
+/// Return `delay` doubled `attempts` times, capped to [`MAX_BACKOFF_SECS`] (but
+/// never shorter than `delay`)
+/// ```
+/// use lib::supervisor::backoff;
+/// assert_eq!(backoff(60, 0), 60);
+/// assert_eq!(backoff(60, 2), 240);
+/// assert_eq!(backoff(60, 10), 3600);
+/// ```
+pub fn backoff(delay: u32, attempts: u32) -> u32 {
+    delay
+        .saturating_mul(2u32.saturating_pow(attempts))
+        .min(MAX_BACKOFF_SECS)
+        .max(delay)
+}
+
+/// What shall be done after an iteration
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Action {
+    /// Go on with the current handles
+    Continue,
+    /// Rebuild the session and wifi handles
+    Rebuild,
+}
+
+/// Counter of consecutive failed iterations
+#[derive(Debug)]
+pub struct Supervisor {
+    max_failures: u32,
+    failures: u32,
+    rebuilds: u32,
+}
+
+impl Supervisor {
+    /// Rebuild the handles every `max_failures` consecutive failures (never if `0`)
+    pub fn new(max_failures: u32) -> Self {
+        Self {
+            max_failures,
+            failures: 0,
+            rebuilds: 0,
+        }
+    }
+
+    /// Record the outcome of an iteration and return what shall be done
+    pub fn record(&mut self, success: bool) -> Action {
+        if success {
+            if self.failures > 0 {
+                info!("Recovered after {} failed iteration(s)", self.failures);
+            }
+            self.failures = 0;
+            self.rebuilds = 0;
+            return Action::Continue;
+        }
+        self.failures += 1;
+        if self.max_failures != 0 && self.failures.is_multiple_of(self.max_failures) {
+            self.rebuilds += 1;
+            warn!(
+                "{} consecutive failed iterations, rebuilding session and wifi handles",
+                self.failures
+            );
+            return Action::Rebuild;
+        }
+        Action::Continue
+    }
+
+    /// Delay to wait before the next iteration, `delay` being the nominal one
+    pub fn delay(&self, delay: u32) -> u32 {
+        backoff(delay, self.rebuilds)
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn rebuild_and_back_off_on_repeated_failures() {
+        let mut supervisor = Supervisor::new(3);
+        assert_eq!(supervisor.record(false), Action::Continue);
+        assert_eq!(supervisor.record(false), Action::Continue);
+        assert_eq!(supervisor.delay(60), 60);
+        assert_eq!(supervisor.record(false), Action::Rebuild);
+        assert_eq!(supervisor.delay(60), 120);
+        for _ in 0..2 {
+            supervisor.record(false);
+        }
+        assert_eq!(supervisor.record(false), Action::Rebuild);
+        assert_eq!(supervisor.delay(60), 240);
+        assert_eq!(supervisor.record(true), Action::Continue);
+        assert_eq!(supervisor.delay(60), 60);
+    }
+
+    #[test]
+    fn never_rebuild_when_disabled() {
+        let mut supervisor = Supervisor::new(0);
+        for _ in 0..10 {
+            assert_eq!(supervisor.record(false), Action::Continue);
+        }
+        assert_eq!(supervisor.delay(60), 60);
+    }
+}
@@ -0,0 +1,184 @@
+//! Opt-in reporting of crashes and fatal errors (`telemetry` feature).
+//!
+//! When `crash_report` is set, panics and errors ending the main loop are appended
+//! to a report file in the state directory, and also sent to a Sentry instance
+//! when `crash_report_dsn` is set, so that the reason why the agent died on a user
+//! laptop is not lost.
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::panic;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tracing::{debug, error};
+
+/// Name of the crash report file in the state directory
+pub const REPORT_FILE_NAME: &str = "automattermostatus.crash";
+
+/// Sentry project endpoint, parsed from a DSN `https://<key>@<host>/<project>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dsn {
+    key: String,
+    store_url: String,
+}
+
+impl FromStr for Dsn {
+    type Err = anyhow::Error;
+
+    fn from_str(dsn: &str) -> Result<Self> {
+        let (scheme, rest) = dsn
+            .split_once("://")
+            .ok_or_else(|| anyhow!("Missing scheme in DSN {:?}", dsn))?;
+        let (key, rest) = rest
+            .split_once('@')
+            .ok_or_else(|| anyhow!("Missing key in DSN {:?}", dsn))?;
+        let (host, project) = rest
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .ok_or_else(|| anyhow!("Missing project in DSN {:?}", dsn))?;
+        // A DSN key may hold a deprecated secret part after a `:`
+        let key = key.split(':').next().unwrap_or_default();
+        if key.is_empty() || project.is_empty() {
+            return Err(anyhow!("Invalid DSN {:?}", dsn));
+        }
+        Ok(Self {
+            key: key.to_owned(),
+            store_url: format!("{}://{}/api/{}/store/", scheme, host, project),
+        })
+    }
+}
+
+/// Writer of crash reports
+#[derive(Debug, Clone)]
+pub struct Reporter {
+    file: PathBuf,
+    dsn: Option<Dsn>,
+}
+
+impl Reporter {
+    /// Report to `file`, and to the Sentry `dsn` if any
+    pub fn new(file: impl Into<PathBuf>, dsn: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            file: file.into(),
+            dsn: dsn
+                .map(str::parse)
+                .transpose()
+                .context("Parsing crash_report_dsn")?,
+        })
+    }
+
+    /// Report panics before calling the previous panic hook
+    pub fn install_panic_hook(self) {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let backtrace = std::backtrace::Backtrace::force_capture();
+            self.report("fatal", &info.to_string(), &backtrace.to_string());
+            previous(info)
+        }));
+    }
+
+    /// Report the error which ended the application
+    pub fn report_error(&self, error: &anyhow::Error) {
+        self.report("error", &format!("{:#}", error), &format!("{:?}", error));
+    }
+
+    fn report(&self, level: &str, message: &str, details: &str) {
+        if let Err(e) = self.write(level, message, details) {
+            error!("Fail to write crash report : {:#}", e)
+        }
+        if let Some(dsn) = &self.dsn {
+            if let Err(e) = send(dsn, level, message) {
+                error!("Fail to send crash report : {:#}", e)
+            }
+        }
+    }
+
+    fn write(&self, level: &str, message: &str, details: &str) -> Result<()> {
+        let mut report = format!(
+            "== {} {} (automattermostatus {}, {})\n",
+            Utc::now().to_rfc3339(),
+            level,
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS
+        );
+        let _ = writeln!(report, "{}\n{}", message, details);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)
+            .with_context(|| format!("Opening crash report file {:?}", self.file))?;
+        file.write_all(report.as_bytes())
+            .with_context(|| format!("Writing crash report file {:?}", self.file))?;
+        debug!("Crash report written to {:?}", self.file);
+        Ok(())
+    }
+}
+
+/// Send an event to the Sentry `dsn`
+fn send(dsn: &Dsn, level: &str, message: &str) -> Result<()> {
+    let event_id: String = (0..32).map(|_| fastrand::digit(16)).collect();
+    ureq::post(&dsn.store_url)
+        .set(
+            "X-Sentry-Auth",
+            &format!(
+                "Sentry sentry_version=7, sentry_key={}, sentry_client=automattermostatus/{}",
+                dsn.key,
+                env!("CARGO_PKG_VERSION")
+            ),
+        )
+        .send_json(serde_json::json!({
+            "event_id": event_id,
+            "timestamp": Utc::now().to_rfc3339(),
+            "platform": "native",
+            "level": level,
+            "release": concat!("automattermostatus@", env!("CARGO_PKG_VERSION")),
+            "message": { "formatted": message },
+            "contexts": { "os": { "name": std::env::consts::OS } },
+        }))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use httpmock::prelude::*;
+    use mktemp::Temp;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn parse_dsn() -> Result<()> {
+        let dsn: Dsn = "https://abc123@o1.ingest.sentry.io/42".parse()?;
+        assert_eq!(
+            dsn,
+            Dsn {
+                key: "abc123".to_string(),
+                store_url: "https://o1.ingest.sentry.io/api/42/store/".to_string()
+            }
+        );
+        assert!("https://o1.ingest.sentry.io/42".parse::<Dsn>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_send_report() -> Result<()> {
+        let server = MockServer::start();
+        let store_mock = server.mock(|expect, resp_with| {
+            expect
+                .method(POST)
+                .path("/api/42/store/")
+                .header_exists("X-Sentry-Auth")
+                .body_contains("\"level\":\"error\"");
+            resp_with.status(200);
+        });
+        let temp = Temp::new_file().unwrap().to_path_buf();
+        let dsn = format!("http://key@{}/42", server.address());
+        let reporter = Reporter::new(&temp, Some(&dsn))?;
+        reporter.report_error(&anyhow!("boom").context("Running loop"));
+        store_mock.assert();
+        let report = std::fs::read_to_string(&temp)?;
+        assert!(report.contains("Running loop: boom"));
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+//! Typed time of day values used by the `begin`, `end` and `expires_at` options
+//!
+//! Values are validated when the configuration is loaded, so that a typo such as
+//! `12:3O` is reported instead of being silently read as `12:00`.
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Time of the day with the format `hh:mm` (or `hh`)
+/// ```
+/// use lib::timeofday::TimeOfDay;
+/// let time: TimeOfDay = "8:05".parse().unwrap();
+/// assert_eq!(time.to_string(), "08:05");
+/// assert!("12:3O".parse::<TimeOfDay>().is_err());
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(NaiveTime);
+
+impl TimeOfDay {
+    /// Return the wall clock time of `self`
+    pub fn time(&self) -> NaiveTime {
+        self.0
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hh, mm) = s.trim().split_once(':').unwrap_or((s.trim(), "0"));
+        match (hh.parse(), mm.parse()) {
+            (Ok(hh), Ok(mm)) => NaiveTime::from_hms_opt(hh, mm, 0).map(TimeOfDay),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow!("`{}` is not a time with the format hh:mm", s))
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0.hour(), self.0.minute())
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+/// Expiration presets of the custom status dialog of the mattermost clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// 30 minutes after the status is set
+    ThirtyMinutes,
+    /// an hour after the status is set
+    OneHour,
+    /// 4 hours after the status is set
+    FourHours,
+    /// at the end of the day
+    Today,
+    /// at the end of the week, on sunday
+    ThisWeek,
+}
+
+impl Preset {
+    /// All the presets
+    pub const ALL: [Preset; 5] = [
+        Preset::ThirtyMinutes,
+        Preset::OneHour,
+        Preset::FourHours,
+        Preset::Today,
+        Preset::ThisWeek,
+    ];
+
+    /// Return the `duration` value of the custom status using the preset
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::ThirtyMinutes => "thirty_minutes",
+            Preset::OneHour => "one_hour",
+            Preset::FourHours => "four_hours",
+            Preset::Today => "today",
+            Preset::ThisWeek => "this_week",
+        }
+    }
+
+    /// Return how long the custom status lasts, for the presets not ending at a
+    /// given day
+    pub fn length(&self) -> Option<Duration> {
+        match self {
+            Preset::ThirtyMinutes => Some(Duration::minutes(30)),
+            Preset::OneHour => Some(Duration::hours(1)),
+            Preset::FourHours => Some(Duration::hours(4)),
+            Preset::Today | Preset::ThisWeek => None,
+        }
+    }
+
+    /// Return when a custom status set at `now` expires
+    /// ```
+    /// use chrono::{Local, TimeZone};
+    /// use lib::timeofday::Preset;
+    /// // A friday
+    /// let now = Local.with_ymd_and_hms(2024, 3, 15, 10, 0, 0).unwrap();
+    /// let expiry = |preset: Preset| preset.expiry(now).format("%a %H:%M").to_string();
+    /// assert_eq!(expiry(Preset::OneHour), "Fri 11:00");
+    /// assert_eq!(expiry(Preset::Today), "Fri 23:59");
+    /// assert_eq!(expiry(Preset::ThisWeek), "Sun 23:59");
+    /// ```
+    pub fn expiry(&self, now: DateTime<Local>) -> DateTime<Local> {
+        let days = match self {
+            Preset::Today => 0,
+            Preset::ThisWeek => 6 - now.weekday().num_days_from_monday(),
+            _ => return now + self.length().unwrap_or_else(Duration::zero),
+        };
+        let end = (now.date_naive() + Duration::days(days.into()))
+            .and_hms_opt(23, 59, 59)
+            .expect("valid time");
+        Local.from_local_datetime(&end).latest().unwrap_or(now)
+    }
+}
+
+/// Expiration of the custom status: either `0` for no expiration, a
+/// [`TimeOfDay`], or a [`Preset`] name such as `one_hour`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Expiry {
+    /// The custom status does not expire
+    Never,
+    /// The custom status expires at this time of the current day
+    At(TimeOfDay),
+    /// The custom status expires as with a preset of the mattermost clients
+    Preset(Preset),
+}
+
+impl FromStr for Expiry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "0" {
+            return Ok(Expiry::Never);
+        }
+        match Preset::ALL.iter().find(|preset| preset.name() == s.trim()) {
+            Some(preset) => Ok(Expiry::Preset(*preset)),
+            None => s.parse().map(Expiry::At).map_err(|_| {
+                anyhow!(
+                    "`{}` is neither a time with the format hh:mm nor one of {}",
+                    s,
+                    Preset::ALL.map(|preset| preset.name()).join(", ")
+                )
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expiry::Never => write!(f, "0"),
+            Expiry::At(time) => time.fmt(f),
+            Expiry::Preset(preset) => write!(f, "{}", preset.name()),
+        }
+    }
+}
+
+impl TryFrom<String> for Expiry {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Expiry> for String {
+    fn from(expiry: Expiry) -> Self {
+        expiry.to_string()
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn parse_valid_times() {
+        for (s, expected) in [
+            ("07:01", "07:01"),
+            ("7:1", "07:01"),
+            ("12", "12:00"),
+            ("23:39", "23:39"),
+        ] {
+            assert_eq!(s.parse::<TimeOfDay>().unwrap().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_times() {
+        for s in ["biii", ":12:30", "12:3O", "24:00", "12:60", ""] {
+            assert!(s.parse::<TimeOfDay>().is_err(), "{:?} accepted", s);
+        }
+    }
+
+    #[test]
+    fn parse_expiry() {
+        assert_eq!("0".parse::<Expiry>().unwrap(), Expiry::Never);
+        assert_eq!(
+            "17:30".parse::<Expiry>().unwrap(),
+            Expiry::At("17:30".parse().unwrap())
+        );
+        assert_eq!(
+            "four_hours".parse::<Expiry>().unwrap(),
+            Expiry::Preset(Preset::FourHours)
+        );
+        assert!("0:0:0".parse::<Expiry>().is_err());
+        for preset in Preset::ALL {
+            assert_eq!(
+                Expiry::Preset(preset)
+                    .to_string()
+                    .parse::<Expiry>()
+                    .unwrap(),
+                Expiry::Preset(preset)
+            );
+        }
+    }
+
+    #[test]
+    fn fail_on_invalid_config_value() {
+        #[derive(Deserialize, Debug)]
+        struct Conf {
+            #[allow(dead_code)]
+            begin: TimeOfDay,
+        }
+        let err = toml::from_str::<Conf>("begin = \"12:3O\"").unwrap_err();
+        assert!(err.to_string().contains("`12:3O` is not a time"));
+    }
+}
@@ -0,0 +1,182 @@
+//! Windows notifications of the custom statuses sent
+//!
+//! Windows users rarely have a terminal open, so with `toast_notifications`
+//! the expiry notices of the token (see [`crate::token_expiry`]) are shown in a
+//! toast, and each custom status sent is shown in a toast with two buttons:
+//! - *Pause 1h* opens the `automattermostatus:pause` URI, registered in the
+//!   user classes to run `automattermostatus pause 1h`;
+//! - *Open log* opens the log directory.
+//!
+//! The toast is shown by PowerShell through the WinRT notification API, so that
+//! no extra dependency is needed. As windows services run outside of the user
+//! session, nothing is shown when running as a service.
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use crate::command::CommandRunner;
+use crate::events::{Event, Observer};
+use crate::mattermost::MMCustomStatus;
+
+/// URI scheme of the toast buttons
+pub const URI_SCHEME: &str = "automattermostatus";
+/// Duration of the pause of the *Pause* button
+pub const PAUSE_DURATION: &str = "1h";
+/// Application user model id of PowerShell, allowed to show toasts without
+/// registering our own
+const POWERSHELL_APP_ID: &str =
+    r"{1AC14E77-02E7-4E5D-B744-2EB1AE5198B7}\WindowsPowerShell\v1.0\powershell.exe";
+
+/// Return the XML of the toast showing `status`, whose *Open log* button opens
+/// `log_dir`
+/// ```
+/// use lib::MMCustomStatus;
+/// use lib::toast::toast_xml;
+/// let status = MMCustomStatus::new("At <home>".to_string(), "house".to_string());
+/// let xml = toast_xml(&status, std::path::Path::new("/var/log"));
+/// assert!(xml.contains("<text>:house: At &lt;home&gt;</text>"));
+/// assert!(xml.contains(r#"arguments="automattermostatus:pause""#));
+/// ```
+pub fn toast_xml(status: &MMCustomStatus, log_dir: &Path) -> String {
+    let log_uri = url::Url::from_directory_path(log_dir)
+        .map_or_else(|_| log_dir.display().to_string(), String::from);
+    format!(
+        "<toast><visual><binding template=\"ToastGeneric\">\
+         <text>Mattermost status</text><text>:{}: {}</text>\
+         </binding></visual><actions>\
+         <action content=\"Pause {}\" activationType=\"protocol\" arguments=\"{}:pause\"/>\
+         <action content=\"Open log\" activationType=\"protocol\" arguments=\"{}\"/>\
+         </actions></toast>",
+        escape(&status.emoji),
+        escape(&status.text),
+        PAUSE_DURATION,
+        URI_SCHEME,
+        escape(&log_uri)
+    )
+}
+
+/// Return the XML of the toast showing `message`
+/// ```
+/// use lib::toast::message_xml;
+/// let xml = message_xml("Expires in 5 days (2024-03-15)");
+/// assert!(xml.contains("<text>Expires in 5 days (2024-03-15)</text>"));
+/// ```
+pub fn message_xml(message: &str) -> String {
+    format!(
+        "<toast><visual><binding template=\"ToastGeneric\">\
+         <text>Mattermost status</text><text>{}</text>\
+         </binding></visual></toast>",
+        escape(message)
+    )
+}
+
+/// Return the PowerShell script showing the toast `xml`
+fn script(xml: &str) -> String {
+    format!(
+        "[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, \
+         ContentType = WindowsRuntime] | Out-Null\n\
+         [Windows.Data.Xml.Dom.XmlDocument, Windows.Data.Xml.Dom.XmlDocument, \
+         ContentType = WindowsRuntime] | Out-Null\n\
+         $xml = New-Object Windows.Data.Xml.Dom.XmlDocument\n\
+         $xml.LoadXml('{}')\n\
+         [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('{}')\
+         .Show([Windows.UI.Notifications.ToastNotification]::new($xml))",
+        xml.replace('\'', "''"),
+        POWERSHELL_APP_ID
+    )
+}
+
+/// Escape the XML special characters of `text`
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Return the command run by the `automattermostatus:pause` URI
+pub fn pause_command(exe: &Path) -> String {
+    format!("\"{}\" pause {}", exe.display(), PAUSE_DURATION)
+}
+
+/// Register the URI scheme of the toast buttons for the current user, run by
+/// `exe`
+#[cfg(target_os = "windows")]
+pub fn register_uri_scheme(exe: &Path) -> std::io::Result<()> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+    let (key, _) = RegKey::predef(HKEY_CURRENT_USER)
+        .create_subkey(format!("Software\\Classes\\{}", URI_SCHEME))?;
+    key.set_value("", &format!("URL:{}", URI_SCHEME))?;
+    key.set_value("URL Protocol", &"")?;
+    let (command, _) = key.create_subkey("shell\\open\\command")?;
+    command.set_value("", &pause_command(exe))
+}
+
+/// [`Observer`] showing a toast for each custom status sent and each expiry
+/// notice
+#[derive(Debug)]
+pub struct Toasts<R: CommandRunner> {
+    /// directory opened by the *Open log* button
+    pub log_dir: PathBuf,
+    /// runner of PowerShell
+    pub runner: R,
+}
+
+impl<R: CommandRunner> Observer for Toasts<R> {
+    fn notify(&self, event: &Event) {
+        let xml = match event {
+            Event::StatusSent(status) => toast_xml(status, &self.log_dir),
+            Event::ExpiryNotified(message) => message_xml(message),
+            _ => return,
+        };
+        let script = script(&xml);
+        if let Err(e) = self.runner.run(
+            "powershell",
+            &["-NoProfile", "-NonInteractive", "-Command", &script],
+        ) {
+            debug!("Fail to show the notification : {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::command::MockCommandRunner;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn show_a_toast_for_each_status_sent() {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .withf(|program, args| {
+                program == "powershell" && args.last().is_some_and(|s| s.contains("It''s me"))
+            })
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        runner
+            .expect_run()
+            .withf(|_, args| args.last().is_some_and(|s| s.contains("expires tomorrow")))
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        let toasts = Toasts {
+            log_dir: PathBuf::from("/tmp/logs"),
+            runner,
+        };
+        toasts.notify(&Event::DndEntered);
+        toasts.notify(&Event::StatusSent(MMCustomStatus::new(
+            "It's me".to_string(),
+            "wave".to_string(),
+        )));
+        toasts.notify(&Event::ExpiryNotified("expires tomorrow".to_string()));
+    }
+
+    #[test]
+    fn pause_from_the_uri() {
+        assert_eq!(
+            pause_command(Path::new(r"C:\Program Files\automattermostatus.exe")),
+            r#""C:\Program Files\automattermostatus.exe" pause 1h"#
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Warning before the expiry of the personal access token or of the session
+//!
+//! Mattermost does not tell when a personal access token expires, and an
+//! expired token only shows up as failed updates. With `token_expires_on`, the
+//! user is told once by a direct message `token_expiry_notice_days` days
+//! before, while the token still works. The expiry of the session of a password
+//! login is told by mattermost, and is told the same way.
+//!
+//! The notice is also published as [`crate::events::Event::ExpiryNotified`], shown
+//! as a desktop notification with `toast_notifications`.
+use chrono::NaiveDate;
+
+/// Credential whose expiry is told
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Credential {
+    /// personal access token, expiring at `token_expires_on`
+    Token,
+    /// session of a password login, whose expiry is told by mattermost
+    Session,
+}
+
+/// Return `true` if the user shall be told at `today` that the token expires
+/// at `expires_on`, `notified` being the expiry date already told about
+/// ```
+/// use chrono::NaiveDate;
+/// use lib::token_expiry::notice_due;
+/// let expires_on = NaiveDate::from_ymd_opt(2024, 3, 15).unwrap();
+/// let day = |d| NaiveDate::from_ymd_opt(2024, 3, d).unwrap();
+/// assert!(!notice_due(expires_on, 7, day(7), None));
+/// assert!(notice_due(expires_on, 7, day(8), None));
+/// assert!(notice_due(expires_on, 7, day(15), None));
+/// assert!(!notice_due(expires_on, 7, day(16), None));
+/// assert!(!notice_due(expires_on, 7, day(10), Some(expires_on)));
+/// assert!(!notice_due(expires_on, 0, day(15), None));
+/// ```
+pub fn notice_due(
+    expires_on: NaiveDate,
+    notice_days: u32,
+    today: NaiveDate,
+    notified: Option<NaiveDate>,
+) -> bool {
+    notice_days > 0
+        && notified != Some(expires_on)
+        && (0..=i64::from(notice_days)).contains(&(expires_on - today).num_days())
+}
+
+/// Return the message telling the user that the `credential` expires at
+/// `expires_on`
+pub fn message(credential: Credential, expires_on: NaiveDate, today: NaiveDate) -> String {
+    let days = (expires_on - today).num_days();
+    let when = match days {
+        0 => "today".to_string(),
+        1 => "tomorrow".to_string(),
+        _ => format!("in {} days", days),
+    };
+    match credential {
+        Credential::Token => format!(
+            "The personal access token used by automattermostatus expires {} ({}). \
+             Create a new one and update `mm_secret` before, or your status will \
+             stop being updated.",
+            when, expires_on
+        ),
+        Credential::Session => format!(
+            "The mattermost session of automattermostatus expires {} ({}). It logs \
+             in again with the password then: update `mm_secret` before if it has \
+             changed, or your status will stop being updated.",
+            when, expires_on
+        ),
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn tell_the_remaining_days() {
+        let expires_on = NaiveDate::from_ymd_opt(2024, 3, 15).unwrap();
+        let day = |d| NaiveDate::from_ymd_opt(2024, 3, d).unwrap();
+        let token = |today| message(Credential::Token, expires_on, today);
+        assert!(
+            token(day(15)).contains("token used by automattermostatus expires today (2024-03-15)")
+        );
+        assert!(token(day(14)).contains("expires tomorrow"));
+        assert!(token(day(10)).contains("expires in 5 days"));
+        assert!(message(Credential::Session, expires_on, day(10))
+            .starts_with("The mattermost session of automattermostatus expires in 5 days"));
+    }
+}
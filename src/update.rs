@@ -0,0 +1,142 @@
+//! Notification of new releases
+//!
+//! With `update_check`, the latest version published on crates.io is fetched at
+//! startup and then daily. A newer version is logged each time, and told once
+//! to the user with a direct message on mattermost.
+use crate::error::{Error, Result};
+use anyhow::anyhow;
+use tracing::debug;
+
+/// URL of the crates.io description of automattermostatus
+pub const RELEASES_URL: &str = "https://crates.io/api/v1/crates/automattermostatus";
+
+/// Delay in seconds between two checks
+pub const CHECK_INTERVAL_SECS: i64 = 24 * 60 * 60;
+
+/// Version of the running binary
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Return the latest stable version published at `url` (see [`RELEASES_URL`])
+pub fn latest_version(agent: &ureq::Agent, url: &str) -> Result<String> {
+    debug!("GET {}", url);
+    let json: serde_json::Value = agent
+        .get(url)
+        .call()?
+        .into_json()
+        .map_err(|e| Error::Network(e.into()))?;
+    json["crate"]["max_stable_version"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| Error::Network(anyhow!("No `max_stable_version` in {}", url)))
+}
+
+/// Return `true` if the version `latest` has a higher precedence than `current`
+/// following the semantic versioning rules: a pre-release is older than its
+/// release, and build metadata is ignored
+/// ```
+/// use lib::update::is_newer;
+/// assert!(is_newer("0.2.10", "0.2.5"));
+/// assert!(is_newer("1.0.0", "0.9"));
+/// assert!(!is_newer("0.2.5", "0.2.5"));
+/// assert!(!is_newer("0.2.4", "0.2.5-beta"));
+/// assert!(is_newer("0.2.5", "0.2.5-beta"));
+/// assert!(!is_newer("0.2.5-beta", "0.2.5"));
+/// assert!(is_newer("0.2.5-beta.11", "0.2.5-beta.2"));
+/// assert!(is_newer("0.2.5-rc.1", "0.2.5-beta"));
+/// assert!(!is_newer("0.2.5+build.2", "0.2.5+build.1"));
+/// ```
+pub fn is_newer(latest: &str, current: &str) -> bool {
+    precedence(latest) > precedence(current)
+}
+
+/// Part of a pre-release, numeric ones being older than alphanumeric ones
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier<'a> {
+    Numeric(u64),
+    Alphanumeric(&'a str),
+}
+
+/// Pre-release of a version, a release being newer than all its pre-releases
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Stage<'a> {
+    PreRelease(Vec<Identifier<'a>>),
+    Release,
+}
+
+/// Return the version numbers of `version`, padded to three, and its stage
+fn precedence(version: &str) -> (Vec<u64>, Stage<'_>) {
+    let version = version.split('+').next().unwrap_or_default();
+    let (core, pre) = match version.split_once('-') {
+        Some((core, pre)) => (core, Some(pre)),
+        None => (version, None),
+    };
+    let mut numbers: Vec<u64> = core
+        .split('.')
+        .map_while(|part| part.parse().ok())
+        .collect();
+    if numbers.len() < 3 {
+        numbers.resize(3, 0);
+    }
+    let stage = match pre {
+        Some(pre) => Stage::PreRelease(
+            pre.split('.')
+                .map(|part| match part.parse() {
+                    Ok(number) => Identifier::Numeric(number),
+                    Err(_) => Identifier::Alphanumeric(part),
+                })
+                .collect(),
+        ),
+        None => Stage::Release,
+    };
+    (numbers, stage)
+}
+
+/// Return `true` if no check was done since `CHECK_INTERVAL_SECS` before `now`
+pub fn check_due(last_check: Option<i64>, now: i64) -> bool {
+    last_check.is_none_or(|last| now - last >= CHECK_INTERVAL_SECS)
+}
+
+/// Return the message telling the user that `latest` is available
+pub fn message(latest: &str) -> String {
+    format!(
+        "automattermostatus {} is available (running {}). See {}",
+        latest,
+        CURRENT_VERSION,
+        env!("CARGO_PKG_REPOSITORY")
+    )
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use httpmock::prelude::*;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn fetch_latest_stable_version() -> Result<()> {
+        let server = MockServer::start();
+        server.mock(|expect, resp_with| {
+            expect.method(GET).path("/api/v1/crates/automattermostatus");
+            resp_with
+                .status(200)
+                .header("content-type", "application/json")
+                .json_body(serde_json::json!({
+                    "crate": {"max_version": "0.3.0-rc1", "max_stable_version": "0.2.6"}
+                }));
+        });
+        let agent = ureq::Agent::new();
+        assert_eq!(
+            latest_version(&agent, &server.url("/api/v1/crates/automattermostatus"))?,
+            "0.2.6"
+        );
+        assert!(latest_version(&agent, &server.url("/missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_daily() {
+        assert!(check_due(None, 1000));
+        assert!(!check_due(Some(1000), 1000 + CHECK_INTERVAL_SECS - 1));
+        assert!(check_due(Some(1000), 1000 + CHECK_INTERVAL_SECS));
+    }
+}
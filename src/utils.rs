@@ -1,38 +1,80 @@
 //! Simple utilities functions
-use chrono::{Local, NaiveDateTime};
-use tracing::warn;
+use anyhow::{anyhow, Result};
+use std::process::Command;
+use std::sync::OnceLock;
+use std::time::Duration;
+use unicode_normalization::UnicodeNormalization;
 
-/// Parse a string with the expected format "hh:mm" and return a [`NaiveDateTime`]
-/// for the current day at time "hh:mm"
-///
-/// If `mm` is not parsable we return a datetime set at `hh:00`.
+/// Return `duration` increased by a random number of seconds between 0 and
+/// `max_jitter`.
+pub fn with_jitter(duration: Duration, max_jitter: u32) -> Duration {
+    duration + Duration::from_secs(fastrand::u32(0..=max_jitter).into())
+}
 
-pub fn parse_from_hmstr(time_str: &Option<String>) -> Option<NaiveDateTime> {
-    if let Some(ref s) = time_str {
-        let splitted: Vec<&str> = s.split(':').collect();
-        let hh: u32 = match splitted[0].parse() {
-            Ok(h) => h,
-            Err(_) => {
-                warn!("Unable to get hour from {:?}", &time_str);
-                return None;
-            }
-        };
-        let mm = if splitted.len() < 2 {
-            0
-        } else {
-            match splitted[1].parse() {
-                Ok(m) => m,
-                Err(_) => {
-                    warn!("Unable to get minutes from {:?}", &time_str);
-                    0
-                }
+/// Return `s` in Unicode normalization form C, lowercased if `case_insensitive`,
+/// so that SSIDs can be compared regardless of their encoding.
+/// ```
+/// use lib::utils::normalize;
+/// assert_eq!(normalize("Caf\u{65}\u{301}", true), normalize("CAF\u{e9}", true));
+/// ```
+pub fn normalize(s: &str, case_insensitive: bool) -> String {
+    let s: String = s.nfc().collect();
+    if case_insensitive {
+        s.to_lowercase()
+    } else {
+        s
+    }
+}
+
+/// Return the host name of the computer, or an empty string if unknown
+pub fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| {
+        Command::new("hostname")
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+            .unwrap_or_default()
+    })
+}
+
+/// Parse a duration made of numbers followed by a unit among `d`, `h`, `m` and
+/// `s` (seconds being the default unit).
+/// ```
+/// use lib::utils::parse_duration;
+/// use std::time::Duration;
+/// assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+/// assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+/// ```
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || anyhow!("`{}` is not a duration such as `2h` or `1h30m`", s);
+    let mut secs: u64 = 0;
+    let mut number = String::new();
+    for c in s.trim().chars() {
+        let unit = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue;
             }
+            'd' => 24 * 60 * 60,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
         };
-
-        Local::now().date_naive().and_hms_opt(hh, mm, 0)
-    } else {
-        None
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        secs = value
+            .checked_mul(unit)
+            .and_then(|v| v.checked_add(secs))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        secs = secs.checked_add(value).ok_or_else(invalid)?;
+    } else if s.trim().is_empty() {
+        return Err(invalid());
     }
+    Ok(Duration::from_secs(secs))
 }
 
 #[cfg(test)]
@@ -41,23 +83,19 @@ mod should {
     use test_log::test; // Automatically trace tests
 
     #[test]
-    fn return_none_if_unparsable() {
-        assert_eq!(None, parse_from_hmstr(&None));
-        assert_eq!(None, parse_from_hmstr(&Some("biii".to_string())));
-        assert_eq!(None, parse_from_hmstr(&Some(":12:30".to_string())));
-    }
-    #[test]
-    fn return_hour_if_mn_is_unparsable() {
-        let expect = Local::now().date_naive().and_hms_opt(12, 00, 0);
-        assert_eq!(expect, parse_from_hmstr(&Some("12:3O".to_string())));
-        assert_eq!(expect, parse_from_hmstr(&Some("12".to_string())));
+    fn add_bounded_jitter() {
+        let delay = Duration::from_secs(60);
+        assert_eq!(with_jitter(delay, 0), delay);
+        for _ in 0..100 {
+            let res = with_jitter(delay, 10);
+            assert!(delay <= res && res <= delay + Duration::from_secs(10));
+        }
     }
+
     #[test]
-    fn return_expected_date() {
-        let expect = Local::now().date_naive().and_hms_opt(7, 1, 0);
-        assert_eq!(expect, parse_from_hmstr(&Some("07:01".to_string())));
-        assert_eq!(expect, parse_from_hmstr(&Some("7:1".to_string())));
-        let expect = Local::now().date_naive().and_hms_opt(23, 39, 0);
-        assert_eq!(expect, parse_from_hmstr(&Some("23:39".to_string())));
+    fn reject_invalid_durations() {
+        for s in ["", "h", "2x", "1h30mm", "-2h"] {
+            assert!(parse_duration(s).is_err(), "{:?} accepted", s);
+        }
     }
 }
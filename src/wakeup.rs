@@ -0,0 +1,172 @@
+//! Heuristic detection of system resume and clock jumps while waiting between
+//! two scans.
+//!
+//! When the computer is suspended, the process does not run but the wall clock
+//! keeps going. The wait is thus done by small steps, a step whose wall clock
+//! duration is much longer than expected being taken for a resume. The OS sleep
+//! notifications (logind `PrepareForSleep`, windows power broadcasts, IOKit) are
+//! not listened to: the resume is only noticed at the end of the step, up to 5s
+//! late, and a suspend shorter than 30s goes unnoticed.
+//!
+//! The same comparison notices the wall clock jumping backward (NTP sync after
+//! boot) or the local time zone changing (travel, daylight saving time). The
+//! expiry times and off times computed against the previous clock are then
+//! stale, and the next scan shall not wait.
+use chrono::{DateTime, FixedOffset, Local};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tracing::info;
+
+/// Duration of each waiting step
+const STEP: Duration = Duration::from_secs(5);
+/// A step lasting more than `STEP + JUMP_THRESHOLD`, or ending more than
+/// `JUMP_THRESHOLD` before it started, is considered as a resume or a clock jump
+const JUMP_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// Wait for `duration` and return `true` as soon as the wall clock jumps, as
+/// after a system resume, or `false` if the whole `duration` has elapsed or
+/// `stop` is set.
+pub fn sleep_until_clock_jump(duration: Duration, stop: &AtomicBool) -> bool {
+    wait(
+        duration,
+        || Local::now().fixed_offset(),
+        thread::sleep,
+        stop,
+    )
+}
+
+fn wait(
+    duration: Duration,
+    now: impl Fn() -> DateTime<FixedOffset>,
+    sleep: impl Fn(Duration),
+    stop: &AtomicBool,
+) -> bool {
+    let threshold = chrono::Duration::from_std(JUMP_THRESHOLD).expect("valid threshold");
+    let mut remaining = duration;
+    while !remaining.is_zero() && !stop.load(Ordering::Relaxed) {
+        let step = remaining.min(STEP);
+        let before = now();
+        sleep(step);
+        let after = now();
+        let elapsed = after.signed_duration_since(before);
+        if after.offset() != before.offset() {
+            info!(
+                "Time zone change detected ({} to {})",
+                before.offset(),
+                after.offset()
+            );
+            return true;
+        }
+        if elapsed < -threshold {
+            info!("Clock jump detected ({}s backward)", -elapsed.num_seconds());
+            return true;
+        }
+        if elapsed.to_std().unwrap_or_default() > step + JUMP_THRESHOLD {
+            info!(
+                "System resume or clock jump detected ({}s ahead)",
+                elapsed.num_seconds()
+            );
+            return true;
+        }
+        remaining -= step;
+    }
+    false
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use chrono::TimeZone;
+    use std::cell::{Cell, RefCell};
+    use test_log::test; // Automatically trace tests
+
+    fn epoch() -> DateTime<FixedOffset> {
+        FixedOffset::east_opt(3600)
+            .unwrap()
+            .timestamp_opt(0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn wait_whole_duration_without_resume() {
+        let clock = Cell::new(epoch());
+        let steps = RefCell::new(Vec::new());
+        let resumed = wait(
+            Duration::from_secs(12),
+            || clock.get(),
+            |d| {
+                steps.borrow_mut().push(d);
+                clock.set(clock.get() + chrono::Duration::from_std(d).unwrap())
+            },
+            &AtomicBool::new(false),
+        );
+        assert!(!resumed);
+        assert_eq!(
+            *steps.borrow(),
+            [
+                Duration::from_secs(5),
+                Duration::from_secs(5),
+                Duration::from_secs(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn return_early_on_resume() {
+        let clock = Cell::new(epoch());
+        let calls = Cell::new(0);
+        let resumed = wait(
+            Duration::from_secs(60),
+            || clock.get(),
+            |d| {
+                calls.set(calls.get() + 1);
+                // Computer is suspended for an hour during second step
+                let asleep = if calls.get() == 2 {
+                    Duration::from_secs(3600)
+                } else {
+                    Duration::ZERO
+                };
+                clock.set(clock.get() + chrono::Duration::from_std(d + asleep).unwrap())
+            },
+            &AtomicBool::new(false),
+        );
+        assert!(resumed);
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn return_early_on_clock_jump() {
+        // NTP sets the clock back an hour, then the time zone changes
+        for jump in [
+            |t: DateTime<FixedOffset>| t - chrono::Duration::hours(1),
+            |t: DateTime<FixedOffset>| t.with_timezone(&FixedOffset::east_opt(7200).unwrap()),
+        ] {
+            let clock = Cell::new(epoch());
+            let jumped = wait(
+                Duration::from_secs(60),
+                || clock.get(),
+                |d| clock.set(jump(clock.get() + chrono::Duration::from_std(d).unwrap())),
+                &AtomicBool::new(false),
+            );
+            assert!(jumped);
+        }
+    }
+
+    #[test]
+    fn return_early_on_stop() {
+        let stop = AtomicBool::new(false);
+        let calls = Cell::new(0);
+        let resumed = wait(
+            Duration::from_secs(60),
+            || Local::now().fixed_offset(),
+            |_| {
+                calls.set(calls.get() + 1);
+                stop.store(true, Ordering::Relaxed)
+            },
+            &stop,
+        );
+        assert!(!resumed);
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+use super::linux_parse::extract_nmcli_ssid;
+use super::osx_parse::extract_airport_ssid;
+use super::windows_parse::extract_netsh_ssid;
+use crate::wifiscan::{CommandRunner, ScanParser, WifiError, WifiInterface};
+
+/// User supplied command listing the visible SSIDs (see `wifi_scan_cmd`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanCommand {
+    program: String,
+    args: Vec<String>,
+    parser: ScanParser,
+}
+
+impl ScanCommand {
+    /// Build a scan command from its shell words, whose output is parsed by
+    /// `parser`.
+    pub fn new(words: Vec<String>, parser: ScanParser) -> Option<Self> {
+        let (program, args) = words.split_first()?;
+        Some(Self {
+            program: program.to_owned(),
+            args: args.to_vec(),
+            parser,
+        })
+    }
+}
+
+/// Wifi interface for any operating system, relying on a custom command.
+impl WifiInterface for ScanCommand {
+    /// Always `true` as we have no way to know
+    fn is_wifi_enabled(&self, _runner: &dyn CommandRunner) -> Result<bool, WifiError> {
+        Ok(true)
+    }
+
+    fn visible_ssid(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        let args: Vec<&str> = self.args.iter().map(String::as_str).collect();
+        let stdout = runner
+            .run(&self.program, &args)
+            .map_err(WifiError::IoError)?;
+        Ok(match self.parser {
+            ScanParser::Nmcli => extract_nmcli_ssid(&stdout),
+            ScanParser::Lines => stdout
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .map(String::from)
+                .collect(),
+            ScanParser::Netsh => extract_netsh_ssid(&stdout),
+            ScanParser::Airport => extract_airport_ssid(&stdout),
+        })
+    }
+
+    /// Always empty as saved networks are only known by the built-in backends
+    fn saved_ssid(&self, _runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::command::MockCommandRunner;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn run_command_and_parse_lines() -> Result<(), WifiError> {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .withf(|program, args| program == "iwctl" && args == ["station", "wlan0"])
+            .times(1)
+            .returning(|_, _| Ok("  home \n\nguest\n".to_string()));
+        let cmd = ScanCommand::new(
+            vec!["iwctl".into(), "station".into(), "wlan0".into()],
+            ScanParser::Lines,
+        )
+        .unwrap();
+        assert_eq!(cmd.visible_ssid(&runner)?, vec!["home", "guest"]);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_empty_command() {
+        assert_eq!(ScanCommand::new(Vec::new(), ScanParser::Lines), None);
+    }
+}
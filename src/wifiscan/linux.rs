@@ -1,34 +1,190 @@
-use crate::wifiscan::{WiFi, WifiError, WifiInterface};
-use std::process::Command;
+use super::linux_parse::{
+    extract_nmcli_device_state, extract_nmcli_networks, extract_nmcli_ssid,
+    extract_nmcli_wifi_connections,
+};
+use crate::wifiscan::{
+    CommandRunner, Network, RescanPolicy, WiFi, WifiError, WifiHealth, WifiInterface,
+};
 
 impl WiFi {
     /// Create linux `WiFi` interface
     pub fn new(interface: &str) -> Self {
         WiFi {
             interface: interface.to_owned(),
+            rescan: RescanPolicy::No,
         }
     }
 }
 
+impl WiFi {
+    /// Return the `fields` of the networks known by NetworkManager, triggering
+    /// a scan according to `rescan`.
+    fn list(
+        &self,
+        runner: &dyn CommandRunner,
+        fields: &str,
+        rescan: RescanPolicy,
+    ) -> Result<String, WifiError> {
+        let rescan = match rescan {
+            RescanPolicy::Auto => "auto",
+            RescanPolicy::Yes => "yes",
+            RescanPolicy::No => "no",
+        };
+        runner
+            .run(
+                "nmcli",
+                &[
+                    "-t", "-m", "tabular", "-f", fields, "device", "wifi", "list", "--rescan",
+                    rescan,
+                ],
+            )
+            .map_err(WifiError::IoError)
+    }
+}
+
 /// Wifi interface for linux operating system.
 /// This provides basic functionalities for wifi interface.
 impl WifiInterface for WiFi {
     /// Check if wireless network adapter is enabled.
-    fn is_wifi_enabled(&self) -> Result<bool, WifiError> {
-        let output = Command::new("nmcli")
-            .args(["radio", "wifi"])
-            .output()
+    fn is_wifi_enabled(&self, runner: &dyn CommandRunner) -> Result<bool, WifiError> {
+        let output = runner
+            .run("nmcli", &["radio", "wifi"])
             .map_err(WifiError::IoError)?;
 
-        Ok(String::from_utf8_lossy(&output.stdout).contains("enabled"))
+        Ok(output.contains("enabled"))
+    }
+
+    /// Check the radio, then the state of the wifi device of `interface` (or of
+    /// the first one if there is no such device), so that a stopped
+    /// NetworkManager or an unplugged adapter is told apart from a disabled
+    /// wifi.
+    fn health(&self, runner: &dyn CommandRunner) -> WifiHealth {
+        match self.is_wifi_enabled(runner) {
+            Ok(true) => (),
+            Ok(false) => return WifiHealth::Disabled,
+            Err(e) => return WifiHealth::Unavailable(format!("nmcli radio wifi: {}", e)),
+        }
+        let devices = match runner.run("nmcli", &["-t", "-f", "DEVICE,TYPE,STATE", "device"]) {
+            Ok(devices) => devices,
+            Err(e) => return WifiHealth::Unavailable(format!("nmcli device: {}", e)),
+        };
+        match extract_nmcli_device_state(&devices, &self.interface).as_deref() {
+            None => WifiHealth::Unavailable("no wifi adapter".to_string()),
+            // The adapter is blocked, e.g. by a hardware switch
+            Some("unavailable") => WifiHealth::Disabled,
+            Some("unmanaged") => {
+                WifiHealth::Unavailable("the wifi adapter is not managed by NetworkManager".into())
+            }
+            Some(_) => WifiHealth::Enabled,
+        }
+    }
+
+    /// Return SSIDs known by NetworkManager, triggering a scan according to
+    /// the [`RescanPolicy`].
+    fn visible_ssid(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        Ok(extract_nmcli_ssid(&self.list(
+            runner,
+            "SSID",
+            self.rescan,
+        )?))
+    }
+
+    /// Return networks known by NetworkManager with their signal strength
+    fn visible_networks(&self, runner: &dyn CommandRunner) -> Result<Vec<Network>, WifiError> {
+        Ok(extract_nmcli_networks(&self.list(
+            runner,
+            "SSID,SIGNAL",
+            self.rescan,
+        )?))
     }
 
-    fn visible_ssid(&self) -> Result<Vec<String>, WifiError> {
-        let output = Command::new("nmcli")
-            .args(["-t", "-m", "tabular", "-f", "SSID", "device", "wifi"])
-            .output()
+    /// Return networks known by NetworkManager once it has scanned again,
+    /// whatever the [`RescanPolicy`]
+    fn rescanned_networks(&self, runner: &dyn CommandRunner) -> Result<Vec<Network>, WifiError> {
+        Ok(extract_nmcli_networks(&self.list(
+            runner,
+            "SSID,SIGNAL",
+            RescanPolicy::Yes,
+        )?))
+    }
+
+    /// Return the names of NetworkManager wifi connections (usually their SSID)
+    fn saved_ssid(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        let stdout = runner
+            .run("nmcli", &["-t", "-f", "TYPE,NAME", "connection", "show"])
             .map_err(WifiError::IoError)?;
-        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
-        Ok(stdout.split('\n').map(str::to_string).collect())
+        Ok(extract_nmcli_wifi_connections(&stdout))
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::command::MockCommandRunner;
+    use test_log::test; // Automatically trace tests
+
+    #[test]
+    fn list_ssids_with_rescan_policy() -> Result<(), WifiError> {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .withf(|program, args| program == "nmcli" && args.ends_with(&["--rescan", "auto"]))
+            .times(1)
+            .returning(|_, _| Ok("home\nguest".to_string()));
+        let wifi = WiFi::new("wlan0").with_rescan_policy(RescanPolicy::Auto);
+        assert_eq!(wifi.visible_ssid(&runner)?, vec!["home", "guest"]);
+        Ok(())
+    }
+
+    #[test]
+    fn rescan_whatever_the_policy() -> Result<(), WifiError> {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .withf(|program, args| program == "nmcli" && args.ends_with(&["--rescan", "yes"]))
+            .times(1)
+            .returning(|_, _| {
+                Ok("home:70
+"
+                .to_string())
+            });
+        let wifi = WiFi::new("wlan0");
+        assert_eq!(wifi.rescanned_networks(&runner)?[0].ssid, "home");
+        Ok(())
+    }
+
+    #[test]
+    fn detect_disabled_wifi() -> Result<(), WifiError> {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .returning(|_, _| Ok("disabled\n".to_string()));
+        assert!(!WiFi::new("wlan0").is_wifi_enabled(&runner)?);
+        Ok(())
+    }
+
+    #[test]
+    fn tell_unplugged_adapter_from_disabled_wifi() {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .withf(|_, args| args == ["radio", "wifi"])
+            .returning(|_, _| Ok("enabled\n".to_string()));
+        runner
+            .expect_run()
+            .withf(|_, args| args.ends_with(&["device"]))
+            .returning(|_, _| Ok("enp0s31f6:ethernet:connected\nlo:loopback:unmanaged\n".into()));
+        assert_eq!(
+            WiFi::new("wlan0").health(&runner),
+            WifiHealth::Unavailable("no wifi adapter".to_string())
+        );
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run()
+            .returning(|_, _| Err(std::io::Error::other("NetworkManager is not running")));
+        assert!(matches!(
+            WiFi::new("wlan0").health(&runner),
+            WifiHealth::Unavailable(_)
+        ));
     }
 }
@@ -0,0 +1,170 @@
+use super::Network;
+
+/// Extract the SSIDs from the output of `nmcli -t -f SSID device wifi list`.
+///
+/// In terse mode, `nmcli` escapes `:` and `\` with a backslash. Empty lines
+/// (hidden networks or trailing newline) are dropped, and an SSID seen by
+/// several access points is only returned once.
+pub(crate) fn extract_nmcli_ssid(nmcli_output: &str) -> Vec<String> {
+    let mut res: Vec<String> = Vec::new();
+    for line in nmcli_output.lines() {
+        let ssid = unescape(line);
+        if !ssid.is_empty() && !res.contains(&ssid) {
+            res.push(ssid);
+        }
+    }
+    res
+}
+
+/// Extract the networks from the output of
+/// `nmcli -t -f SSID,SIGNAL device wifi list`.
+///
+/// An SSID seen by several access points is only returned once, with the
+/// strongest signal.
+pub(crate) fn extract_nmcli_networks(nmcli_output: &str) -> Vec<Network> {
+    let mut res: Vec<Network> = Vec::new();
+    // SIGNAL does not contain any `:`, so the last one is the field separator
+    for (ssid, signal) in nmcli_output.lines().filter_map(|l| l.rsplit_once(':')) {
+        let ssid = unescape(ssid);
+        if ssid.is_empty() {
+            continue;
+        }
+        let network = match signal.parse() {
+            Ok(quality) => Network::with_quality(ssid, quality),
+            Err(_) => Network::new(ssid),
+        };
+        match res.iter_mut().find(|n| n.ssid == network.ssid) {
+            Some(known) if known.signal < network.signal => *known = network,
+            Some(_) => (),
+            None => res.push(network),
+        }
+    }
+    res
+}
+
+/// Extract the names of wifi connections from the output of
+/// `nmcli -t -f TYPE,NAME connection show`.
+pub(crate) fn extract_nmcli_wifi_connections(nmcli_output: &str) -> Vec<String> {
+    nmcli_output
+        .lines()
+        // TYPE does not contain any `:`, so the first one is the field separator
+        .filter_map(|l| l.split_once(':'))
+        .filter(|(kind, _)| *kind == "802-11-wireless")
+        .map(|(_, name)| unescape(name))
+        .collect()
+}
+
+/// Extract the state of the wifi device `interface` from the output of
+/// `nmcli -t -f DEVICE,TYPE,STATE device`, or of the first wifi device if
+/// `interface` is not one of them (e.g. the default `wlan0`).
+///
+/// Return `None` if there is no wifi device at all.
+pub(crate) fn extract_nmcli_device_state(nmcli_output: &str, interface: &str) -> Option<String> {
+    let devices: Vec<(String, &str)> = nmcli_output
+        .lines()
+        // TYPE and STATE do not contain any `:`, so the last two are separators
+        .filter_map(|l| {
+            let (rest, state) = l.rsplit_once(':')?;
+            let (device, kind) = rest.rsplit_once(':')?;
+            (kind == "wifi").then(|| (unescape(device), state))
+        })
+        .collect();
+    devices
+        .iter()
+        .find(|(device, _)| device == interface)
+        .or_else(|| devices.first())
+        .map(|(_, state)| state.to_string())
+}
+
+/// Remove the backslashes escaping characters in `nmcli` terse output
+fn unescape(field: &str) -> String {
+    let mut res = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => res.extend(chars.next()),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    mod should {
+        use super::*;
+        use anyhow::Result;
+        #[test]
+        fn extract_expected_ssid() -> Result<()> {
+            let res = r#"corporate
+my\:home\\net
+
+corporate
+guest
+"#;
+
+            assert_eq!(
+                extract_nmcli_ssid(res),
+                ["corporate", r"my:home\net", "guest"]
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn extract_networks_with_signal() -> Result<()> {
+            let res = "corporate:40\nmy\\:home:70\n:90\ncorporate:96\n";
+            assert_eq!(
+                extract_nmcli_networks(res),
+                [
+                    Network::with_quality("corporate", 96),
+                    Network::with_quality("my:home", 70)
+                ]
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn extract_wifi_connections() -> Result<()> {
+            let res = r#"802-11-wireless:corporate
+802-3-ethernet:Wired connection 1
+802-11-wireless:my\:home
+bridge:docker0
+"#;
+
+            assert_eq!(
+                extract_nmcli_wifi_connections(res),
+                ["corporate", "my:home"]
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn extract_device_state() -> Result<()> {
+            let res = "wlp3s0:wifi:connected\n\
+                       p2p-dev-wlp3s0:wifi-p2p:disconnected\n\
+                       enp0s31f6:ethernet:unavailable\n\
+                       wlx00c0ca:wifi:unmanaged\n";
+            assert_eq!(
+                extract_nmcli_device_state(res, "wlx00c0ca").as_deref(),
+                Some("unmanaged")
+            );
+            // `wlan0` is missing, the first wifi device is used
+            assert_eq!(
+                extract_nmcli_device_state(res, "wlan0").as_deref(),
+                Some("connected")
+            );
+            assert_eq!(
+                extract_nmcli_device_state("enp0s31f6:ethernet:connected\n", "wlan0"),
+                None
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn return_nothing_for_empty_output() -> Result<()> {
+            assert!(extract_nmcli_ssid("\n\n").is_empty());
+            Ok(())
+        }
+    }
+}
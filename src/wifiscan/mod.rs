@@ -1,29 +1,87 @@
 //! Implement wifi SSID scan for linux, windows and mac os.
 // Mostly courtesy of https://github.com/tnkemdilim/wifi-rs
 
+mod custom;
 #[cfg(target_os = "linux")]
 mod linux;
+mod linux_parse;
 #[cfg(target_os = "macos")]
 mod osx;
-#[cfg(any(test, target_os = "macos"))]
+// Parsers are also used by `ScanCommand` on all platforms
 mod osx_parse;
 #[cfg(target_os = "windows")]
 mod windows;
-#[cfg(any(test, target_os = "windows"))]
 mod windows_parse;
 // We include all modules for tests as tests do not depend upon specific platform
 //#[cfg(test)]
 //mod osx;
 
+pub use crate::command::CommandRunner;
+pub use crate::config::{RescanPolicy, ScanParser};
+pub use custom::ScanCommand;
+use serde::{Deserialize, Serialize};
 use std::{fmt, io};
 use thiserror::Error;
 
+/// Visible wifi network
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Network {
+    /// network SSID
+    pub ssid: String,
+    /// signal strength in dBm, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+}
+
+impl Network {
+    /// Create a network whose signal strength is unknown
+    pub fn new(ssid: impl Into<String>) -> Self {
+        Self {
+            ssid: ssid.into(),
+            signal: None,
+        }
+    }
+
+    /// Create a network whose signal quality is given in percent, as reported
+    /// by NetworkManager or netsh, converted to dBm
+    /// ```
+    /// use lib::wifiscan::Network;
+    /// assert_eq!(Network::with_quality("home", 96).signal, Some(-52));
+    /// ```
+    pub fn with_quality(ssid: impl Into<String>, quality: u8) -> Self {
+        Self {
+            ssid: ssid.into(),
+            signal: Some(i32::from(quality.min(100)) / 2 - 100),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.signal {
+            Some(signal) => write!(f, "{}, {} dBm", self.ssid, signal),
+            None => write!(f, "{}", self.ssid),
+        }
+    }
+}
+
 /// Wireless network interface.
 #[derive(Debug)]
 pub struct WiFi {
     #[allow(dead_code)]
     /// wifi interface name
     pub interface: String,
+    #[allow(dead_code)]
+    /// rescan policy used when getting visible SSIDs
+    pub rescan: RescanPolicy,
+}
+
+impl WiFi {
+    /// Set the [`RescanPolicy`] used when getting visible SSIDs
+    pub fn with_rescan_policy(mut self, rescan: RescanPolicy) -> Self {
+        self.rescan = rescan;
+        self
+    }
 }
 
 #[derive(Debug, Error)]
@@ -39,18 +97,69 @@ pub enum WifiError {
     #[allow(missing_docs)]
     #[error("Wifi IO Error")]
     IoError(#[from] io::Error),
+    /// The wifi backend (e.g. NetworkManager) or adapter cannot be reached
+    #[error("Wifi backend unavailable: {0}")]
+    Unavailable(String),
+    /// The wifi interface does not provide the feature
+    #[error("{0} is not supported on this platform")]
+    Unsupported(&'static str),
+}
+
+/// Health of the wifi, checked before each scan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WifiHealth {
+    /// the wifi can be scanned
+    Enabled,
+    /// the wifi is switched off, the location being unknown
+    Disabled,
+    /// the backend or the adapter is missing, with the reason
+    Unavailable(String),
 }
 
 /// Wifi interface for an operating system.
 /// This provides basic functionalities for wifi interface.
+///
+/// OS commands are spawned through `runner`.
 pub trait WifiInterface: fmt::Debug {
     /// Check if the wifi interface on host machine is enabled.
-    fn is_wifi_enabled(&self) -> Result<bool, WifiError> {
-        unimplemented!();
+    fn is_wifi_enabled(&self, _runner: &dyn CommandRunner) -> Result<bool, WifiError> {
+        Err(WifiError::Unsupported("Checking the wifi"))
     }
 
     /// Return visible SSIDs
-    fn visible_ssid(&self) -> Result<Vec<String>, WifiError> {
-        unimplemented!();
+    fn visible_ssid(&self, _runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        Err(WifiError::Unsupported("Scanning the wifi"))
+    }
+
+    /// Return the health of the wifi interface, its backend being unavailable
+    /// when it cannot be checked
+    fn health(&self, runner: &dyn CommandRunner) -> WifiHealth {
+        match self.is_wifi_enabled(runner) {
+            Ok(true) => WifiHealth::Enabled,
+            Ok(false) => WifiHealth::Disabled,
+            Err(e) => WifiHealth::Unavailable(e.to_string()),
+        }
+    }
+
+    /// Return visible networks, with their signal strength when the backend
+    /// provides it
+    fn visible_networks(&self, runner: &dyn CommandRunner) -> Result<Vec<Network>, WifiError> {
+        Ok(self
+            .visible_ssid(runner)?
+            .into_iter()
+            .map(Network::new)
+            .collect())
+    }
+
+    /// Return visible networks after a new scan, bypassing the results cached
+    /// by the OS, e.g. after a system resume. Backends without such a cache
+    /// return [`WifiInterface::visible_networks`].
+    fn rescanned_networks(&self, runner: &dyn CommandRunner) -> Result<Vec<Network>, WifiError> {
+        self.visible_networks(runner)
+    }
+
+    /// Return the names of the wifi networks saved by the OS
+    fn saved_ssid(&self, _runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        Err(WifiError::Unsupported("Listing the saved wifi networks"))
     }
 }
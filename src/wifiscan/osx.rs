@@ -1,12 +1,12 @@
-use super::osx_parse::extract_airport_ssid;
-use crate::wifiscan::{WiFi, WifiError, WifiInterface};
-use std::process::Command;
+use super::osx_parse::{extract_airport_ssid, extract_preferred_networks};
+use crate::wifiscan::{CommandRunner, RescanPolicy, WiFi, WifiError, WifiInterface};
 
 impl WiFi {
     /// Create MacOS `WiFi` interface
     pub fn new(interface: &str) -> Self {
         WiFi {
             interface: interface.to_owned(),
+            rescan: RescanPolicy::No,
         }
     }
 }
@@ -14,23 +14,31 @@ impl WiFi {
 /// Wifi interface for osx operating system.
 /// This provides basic functionalities for wifi interface.
 impl WifiInterface for WiFi {
-    fn is_wifi_enabled(&self) -> Result<bool, WifiError> {
-        let output = Command::new("networksetup")
-            .args(&["radio", "wifi"])
-            .output()
+    fn is_wifi_enabled(&self, runner: &dyn CommandRunner) -> Result<bool, WifiError> {
+        let output = runner
+            .run("networksetup", &["radio", "wifi"])
             .map_err(|err| WifiError::IoError(err))?;
 
-        Ok(String::from_utf8_lossy(&output.stdout).contains("enabled"))
+        Ok(output.contains("enabled"))
     }
 
-    fn visible_ssid(&self) -> Result<Vec<String>, WifiError> {
-        let output = Command::new(
-            "/System/Library/PrivateFrameworks/Apple80211.framework/Versions/A/Resources/airport ",
-        )
-        .args(&["scan"])
-        .output()
-        .map_err(|err| WifiError::IoError(err))?;
-        let stdout = String::from_utf8_lossy(&output.stdout).to_owned();
+    fn visible_ssid(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        let stdout = runner
+            .run(
+                "/System/Library/PrivateFrameworks/Apple80211.framework/Versions/A/Resources/airport ",
+                &["scan"],
+            )
+            .map_err(|err| WifiError::IoError(err))?;
         Ok(extract_airport_ssid(&stdout))
     }
+
+    fn saved_ssid(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        let stdout = runner
+            .run(
+                "networksetup",
+                &["-listpreferredwirelessnetworks", &self.interface],
+            )
+            .map_err(|err| WifiError::IoError(err))?;
+        Ok(extract_preferred_networks(&stdout))
+    }
 }
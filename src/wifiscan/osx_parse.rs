@@ -40,6 +40,19 @@ pub(crate) fn extract_airport_ssid(airport_output: &str) -> Vec<String> {
     txt
 }
 
+/// Extract the SSIDs from the output of
+/// `networksetup -listpreferredwirelessnetworks <interface>`
+#[cfg(any(test, target_os = "macos"))]
+pub(crate) fn extract_preferred_networks(networksetup_output: &str) -> Vec<String> {
+    networksetup_output
+        .lines()
+        // Networks are indented by a tab, after a "Preferred networks on en0:" header
+        .filter(|x| x.starts_with('\t'))
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -55,5 +68,15 @@ mod tests {
             );
             Ok(())
         }
+
+        #[test]
+        fn extract_expected_preferred_networks() -> Result<()> {
+            let res = "Preferred networks on en0:\n\tNEUF_5EE4\n\tCorp Office\n";
+            assert_eq!(
+                extract_preferred_networks(res),
+                ["NEUF_5EE4", "Corp Office"]
+            );
+            Ok(())
+        }
     }
 }
@@ -1,12 +1,12 @@
-use super::windows_parse::extract_netsh_ssid;
-use crate::wifiscan::{WiFi, WifiError, WifiInterface};
-use std::process::Command;
+use super::windows_parse::{extract_netsh_profiles, extract_netsh_ssid};
+use crate::wifiscan::{CommandRunner, RescanPolicy, WiFi, WifiError, WifiInterface};
 
 impl WiFi {
     /// Create windows `WiFi` interface
     pub fn new(interface: &str) -> Self {
         WiFi {
             interface: interface.to_owned(),
+            rescan: RescanPolicy::No,
         }
     }
 }
@@ -15,26 +15,33 @@ impl WiFi {
 /// This provides basic functionalities for wifi interface.
 impl WifiInterface for WiFi {
     /// Check if wireless network adapter is enabled.
-    fn is_wifi_enabled(&self) -> Result<bool, WifiError> {
-        let output = Command::new("netsh")
-            .args(&[
-                "wlan",
-                "show",
-                "interface",
-                &format!("name= \"{}\"", self.interface),
-            ])
-            .output()
+    fn is_wifi_enabled(&self, runner: &dyn CommandRunner) -> Result<bool, WifiError> {
+        let output = runner
+            .run(
+                "netsh",
+                &[
+                    "wlan",
+                    "show",
+                    "interface",
+                    &format!("name= \"{}\"", self.interface),
+                ],
+            )
             .map_err(|err| WifiError::IoError(err))?;
 
-        Ok(!String::from_utf8_lossy(&output.stdout).contains("There is no wireless interface"))
+        Ok(!output.contains("There is no wireless interface"))
     }
 
-    fn visible_ssid(&self) -> Result<Vec<String>, WifiError> {
-        let output = Command::new("netsh")
-            .args(&["wlan", "show", "networks"])
-            .output()
+    fn visible_ssid(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        let stdout = runner
+            .run("netsh", &["wlan", "show", "networks"])
             .map_err(|err| WifiError::IoError(err))?;
-        let stdout = String::from_utf8_lossy(&output.stdout).to_owned();
         Ok(extract_netsh_ssid(&stdout))
     }
+
+    fn saved_ssid(&self, runner: &dyn CommandRunner) -> Result<Vec<String>, WifiError> {
+        let stdout = runner
+            .run("netsh", &["wlan", "show", "profiles"])
+            .map_err(|err| WifiError::IoError(err))?;
+        Ok(extract_netsh_profiles(&stdout))
+    }
 }
@@ -13,6 +13,17 @@ pub(crate) fn extract_netsh_ssid(netsh_output: &str) -> Vec<String> {
         .collect()
 }
 
+/// Extract the profile names from the output of `netsh wlan show profiles`
+#[cfg(any(test, target_os = "windows"))]
+pub(crate) fn extract_netsh_profiles(netsh_output: &str) -> Vec<String> {
+    netsh_output
+        .lines()
+        .filter(|x| x.trim_start().starts_with("All User Profile"))
+        .filter_map(|x| x.split_once(':'))
+        .map(|(_, name)| name.trim().to_owned())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -52,5 +63,24 @@ SSID 4 : BTOpenzoneXXX
             );
             Ok(())
         }
+
+        #[test]
+        fn extract_expected_profiles() -> Result<()> {
+            let res = r#"
+Profiles on interface Wi-Fi:
+
+Group policy profiles (read only)
+---------------------------------
+    <None>
+
+User profiles
+-------------
+    All User Profile     : HomeNet
+    All User Profile     : Corp: Guest
+"#;
+
+            assert_eq!(extract_netsh_profiles(res), ["HomeNet", "Corp: Guest"]);
+            Ok(())
+        }
     }
 }